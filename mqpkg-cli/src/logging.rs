@@ -2,19 +2,37 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
-use log::{LevelFilter, Metadata, Record};
+use std::sync::{Arc, Mutex};
+
+use log::{Level, LevelFilter, Metadata, Record};
 use pretty_env_logger::env_logger::Logger;
 
 use crate::progress::SuspendableBars;
 
+/// Every warning that has been logged, so that they can be included in output
+/// that is meant for machines.
+#[derive(Clone, Default)]
+pub(crate) struct Warnings(Arc<Mutex<Vec<String>>>);
+
+impl Warnings {
+    pub(crate) fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
 struct IndicatifAwareLogger {
     internal: Logger,
     bars: SuspendableBars,
+    warnings: Warnings,
 }
 
 impl IndicatifAwareLogger {
-    fn new(internal: Logger, bars: SuspendableBars) -> IndicatifAwareLogger {
-        IndicatifAwareLogger { internal, bars }
+    fn new(internal: Logger, bars: SuspendableBars, warnings: Warnings) -> IndicatifAwareLogger {
+        IndicatifAwareLogger {
+            internal,
+            bars,
+            warnings,
+        }
     }
 
     fn install(self) {
@@ -31,19 +49,30 @@ impl log::Log for IndicatifAwareLogger {
     }
 
     fn log(&self, record: &Record) {
+        if record.level() == Level::Warn && self.enabled(record.metadata()) {
+            self.warnings
+                .0
+                .lock()
+                .unwrap()
+                .push(record.args().to_string());
+        }
+
         self.bars.suspended(|| self.internal.log(record))
     }
 
     fn flush(&self) {}
 }
 
-pub(crate) fn setup(level: LevelFilter, bars: SuspendableBars) {
+pub(crate) fn setup(level: LevelFilter, bars: SuspendableBars) -> Warnings {
+    let warnings = Warnings::default();
     let logger = IndicatifAwareLogger::new(
         pretty_env_logger::formatted_builder()
             .filter_level(level)
             .build(),
         bars,
+        warnings.clone(),
     );
 
     logger.install();
+    warnings
 }
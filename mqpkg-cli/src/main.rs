@@ -2,27 +2,51 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
+use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(feature = "miette-errors")]
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Context, Result};
 use camino::Utf8PathBuf;
-use clap::{Parser, Subcommand};
+use clap::{ArgEnum, Parser, Subcommand};
 use clap_verbosity_flag::{Verbosity, WarnLevel};
-use console::Term;
-use indicatif::{ProgressBar, ProgressStyle};
+use console::{measure_text_width, pad_str, style, Alignment, Term};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use log::info;
+use serde::{Deserialize, Serialize};
+use url::Url;
 use vfs::{PhysicalFS, VfsPath};
 
-use mqpkg::{Config, Installer, InstallerError, PackageSpecifier, SolverError};
+use mqpkg::{
+    check_repository, CheckError, Config, ConfigPath, ConflictReport, DBError, Database,
+    Incompatibility, InstallPlan, InstallSummary, InstallTimings, InstalledPackage, Installer,
+    InstallerError, LockFile, OutdatedPackage, PackageChange, PackageDetail, PackageName,
+    PackageSpecifier, SearchResult, SolverError, TransactionLogEntry, UninstallError,
+};
 
+#[cfg(feature = "miette-errors")]
+use mqpkg::ConfigSource;
+
+use crate::output::{CliOutput, ErrorKind, HumanizedError, OutputFormat};
 use crate::progress::SuspendableBars;
 
 pub(crate) mod progress;
 
 mod logging;
+mod output;
 
 const LOGNAME: &str = "mqpkg";
 
+const FROZEN_FILENAME: &str = "requirements-frozen.yml";
+
+// The configuration that we're using, so that errors about packages which are
+// pinned in it can point at those pins.
+#[cfg(feature = "miette-errors")]
+static CONFIG_SOURCE: OnceLock<Arc<ConfigSource>> = OnceLock::new();
+
 #[derive(Debug, Parser)]
 #[clap(version)]
 struct Cli {
@@ -32,36 +56,339 @@ struct Cli {
     #[clap(global = true, short, long)]
     target: Option<Utf8PathBuf>,
 
+    /// Disable reporting download stats to repositories that ask for them.
+    #[clap(global = true, long)]
+    no_stats: bool,
+
+    /// Always download packages, and check repository metadata with the
+    /// server, even if they are already cached.
+    #[clap(global = true, long)]
+    no_cache: bool,
+
+    /// Fail if any repository can't be fetched.
+    #[clap(global = true, long)]
+    fail_fast: bool,
+
+    /// Check every package against the RustSec advisory database checked
+    /// out at this path before installing it.
+    #[cfg(feature = "rustsec")]
+    #[clap(global = true, long, value_name = "PATH")]
+    advisory_db: Option<PathBuf>,
+
+    /// Install packages even if they have critical security advisories.
+    #[cfg(feature = "rustsec")]
+    #[clap(global = true, long)]
+    ignore_advisories: bool,
+
+    /// Run the post install scripts that packages declare.
+    #[clap(global = true, long)]
+    scripts: bool,
+
+    /// Don't run the post install scripts that packages declare, even if
+    /// --scripts is given.
+    #[clap(global = true, long)]
+    no_scripts: bool,
+
+    /// Show what would change, without actually changing anything.
+    #[clap(global = true, short = 'n', long)]
+    dry_run: bool,
+
+    /// Don't use any proxy, even if one is configured or set in the environment.
+    #[clap(global = true, long)]
+    no_proxy: bool,
+
+    /// Only use repository metadata and packages that are already cached.
+    #[clap(global = true, long)]
+    offline: bool,
+
+    /// Use this system wide configuration file, instead of /etc/mqpkg/mqpkg.yml.
+    #[clap(global = true, long)]
+    system_config: Option<PathBuf>,
+
+    /// Use this per user configuration file, instead of
+    /// ~/.config/mqpkg/mqpkg.yml.
+    #[clap(global = true, long)]
+    user_config: Option<PathBuf>,
+
+    /// Give up on resolving dependencies if it takes longer than this many
+    /// seconds.
+    #[clap(global = true, long, value_name = "SECONDS")]
+    solver_timeout: Option<u64>,
+
+    /// Never make more than this many HTTP requests at once, 1 fetches
+    /// everything one at a time.
+    #[clap(global = true, long, value_name = "N")]
+    concurrency: Option<usize>,
+
+    /// Print how long each phase of the operation took.
+    #[clap(global = true, long)]
+    timing: bool,
+
+    /// How to format the output, anything other than human is meant for other
+    /// programs to read.
+    #[clap(global = true, long, arg_enum, default_value = "human")]
+    format: OutputFormat,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
+#[derive(Debug, Clone, ArgEnum)]
+enum ExportFormat {
+    /// What has been requested, as the `packages:` list that mqpkg.yml reads.
+    Yaml,
+    /// The whole pkgdb.
+    Json,
+}
+
+// The YAML form of an export, which is laid out so that it can be pasted into
+// mqpkg.yml as is.
+#[derive(Debug, Serialize, Deserialize)]
+struct Requirements {
+    packages: Vec<PackageSpecifier>,
+}
+
+#[derive(Debug, Subcommand)]
+enum RepoCommands {
+    /// Fetch the index at a url, or path, and check that it's valid.
+    Check { url: String },
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
+    Init {},
     Install {
-        #[clap(required = true)]
+        #[clap(required_unless_present = "locked", parse(try_from_str = parse_arg))]
         packages: Vec<PackageSpecifier>,
+
+        /// Install exactly what is in the lock file, without resolving.
+        #[clap(long, conflicts_with = "packages")]
+        locked: bool,
+    },
+    /// Remove requested packages, along with anything that was only installed
+    /// because they depended on it.
+    Uninstall {
+        #[clap(required = true, parse(try_from_str = parse_arg))]
+        packages: Vec<PackageName>,
+    },
+    /// Upgrade packages to the newest versions that their requirements allow.
+    Upgrade {
+        #[clap(
+            conflicts_with = "all",
+            required_unless_present = "all",
+            parse(try_from_str = parse_arg)
+        )]
+        packages: Vec<PackageSpecifier>,
+
+        /// Upgrade everything that is installed.
+        #[clap(long)]
+        all: bool,
+
+        /// Allow moving to a new major version, by loosening what was
+        /// requested to anything newer than what is installed.
+        #[clap(long)]
+        major: bool,
+    },
+    Update {
+        #[clap(required = true, parse(try_from_str = parse_arg))]
+        packages: Vec<PackageName>,
+    },
+    /// Pin a package to exactly `name@version` in the pkgdb.
+    Pin {
+        package: String,
+    },
+    /// Constrain a package with a requirement in the pins of mqpkg.yml.
+    PinConfig {
+        #[clap(parse(try_from_str = parse_arg))]
+        package: PackageName,
+        requirement: semver::VersionReq,
+    },
+    /// Remove every pin on a package, both the one that `pin` adds to the
+    /// pkgdb, and the one that `pin-config` adds to mqpkg.yml.
+    Unpin {
+        #[clap(parse(try_from_str = parse_arg))]
+        package: PackageName,
+    },
+    /// Export what has been requested, or the whole pkgdb, to stdout.
+    Export {
+        #[clap(long, arg_enum, default_value = "json")]
+        format: ExportFormat,
+    },
+    /// Replace what has been requested, and install it, or replace the whole
+    /// pkgdb, with something that was previously exported.
+    Import {
+        #[clap(long, arg_enum, default_value = "json")]
+        format: ExportFormat,
+
+        path: PathBuf,
+    },
+    /// Print the exact version of every locked package, one `name=version` per
+    /// line.
+    Freeze {
+        /// Write them to requirements-frozen.yml in the target directory
+        /// instead, in the same form as export.
+        #[clap(long)]
+        save: bool,
+    },
+    /// Copy the pkgdb to a new directory.
+    Backup {
+        dest: PathBuf,
+    },
+    /// Replace the pkgdb with a copy that was made with backup.
+    Restore {
+        src: PathBuf,
+    },
+    /// Remove cached archives and repository metadata that are no longer
+    /// needed.
+    Gc {},
+    /// List the transactions that have changed the pkgdb.
+    History {},
+    /// Undo the transaction with this id, and everything since, by going back
+    /// to what had been requested before it.
+    Rollback {
+        id: u64,
+    },
+    /// Verify what is installed and cached against the lock file.
+    Check {},
+    /// Explain why a package is installed.
+    Why {
+        #[clap(parse(try_from_str = parse_arg))]
+        package: PackageName,
+    },
+    /// Explain why a version of a package would not be selected.
+    WhyNot {
+        #[clap(parse(try_from_str = parse_arg))]
+        package: PackageSpecifier,
+    },
+    /// List the installed packages, using only what is in the pkgdb and the
+    /// repository metadata that is already cached.
+    List {
+        /// Show the packages as a tree of their dependencies.
+        #[clap(long)]
+        tree: bool,
+
+        /// Only show this many levels of dependencies in the tree.
+        #[clap(long, requires = "tree")]
+        depth: Option<u32>,
+
+        /// Only show packages whose names contain this.
+        #[clap(long)]
+        filter: Option<String>,
+    },
+    /// List the installed packages that have a newer version available, exiting
+    /// with a failure if there are any.
+    Outdated {},
+    /// Show the installed packages as a tree of their dependencies.
+    Tree {
+        /// Write the tree as a Graphviz graph, for use with `dot -Tsvg`.
+        #[clap(long)]
+        dot: bool,
+
+        /// Write the tree as a Mermaid flowchart.
+        #[clap(long, conflicts_with = "dot")]
+        mermaid: bool,
+    },
+    /// Show the details of a package, whether it is installed or only available.
+    Show {
+        #[clap(parse(try_from_str = parse_arg))]
+        package: PackageName,
+    },
+    /// Work with a repository directly, without needing a configuration.
+    Repo {
+        #[clap(subcommand)]
+        command: RepoCommands,
+    },
+    /// Find available packages by name or description.
+    Search {
+        query: String,
+
+        /// Show at most this many of the best matches.
+        #[clap(long, default_value = "20")]
+        limit: usize,
     },
-    Uninstall {},
-    Upgrade {},
 }
 
 fn main() -> Result<()> {
     // Parse our CLI parameters.
-    let cli = Cli::parse();
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(err) => {
+            // Help and version aren't really errors, and are only ever meant
+            // for a person to read.
+            let format = requested_format();
+            if format.is_human() || !err.use_stderr() {
+                err.exit();
+            }
+            let err = anyhow::Error::new(err);
+            Term::stdout().write_line(&CliOutput::error(&err).render(format)?)?;
+            std::process::exit(2);
+        }
+    };
+    let format = cli.format;
+
+    // When something else is reading our output, errors have to be part of
+    // that output too, or there's no reliable way to tell what went wrong.
+    match run(cli) {
+        Err(err) if !format.is_human() => {
+            Term::stdout().write_line(&CliOutput::error(&err).render(format)?)?;
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+// When our arguments couldn't be parsed, we don't know what format was asked
+// for either, so we look for it ourselves.
+fn requested_format() -> OutputFormat {
+    let mut format = OutputFormat::Human;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--format") {
+            Some("") => args.next(),
+            Some(value) => value.strip_prefix('=').map(|v| v.to_string()),
+            None => continue,
+        };
+        if let Some(value) = value.and_then(|v| OutputFormat::from_str(&v, true).ok()) {
+            format = value;
+        }
+    }
+    format
+}
 
+fn run(cli: Cli) -> Result<()> {
     // Setup a few items for our console and progress bar handling
     let term = Term::stdout();
     let bars = SuspendableBars::new();
     let style = ProgressStyle::default_bar().progress_chars("█▇▆▅▄▃▂▁  ");
+    let download_style = style
+        .clone()
+        .template("{prefix:>20} [{bar:40}] {bytes}/{total_bytes}")
+        .expect("invalid download progress template");
 
     // Setup our logging.
     let render_bars =
         cli.verbose.log_level().or(Some(log::Level::Error)).unwrap() >= log::Level::Warn;
-    logging::setup(cli.verbose.log_level_filter(), bars.clone());
+    let warnings = logging::setup(cli.verbose.log_level_filter(), bars.clone());
 
-    // Build our VFS, Config, and Installer objects, and a HashMap to hold our
-    // progress bars.
+    // Checking a repository doesn't need a target directory at all.
+    if let Commands::Repo {
+        command: RepoCommands::Check { url },
+    } = &cli.command
+    {
+        let url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(_) => Url::from_file_path(dunce::canonicalize(url)?)
+                .map_err(|_| anyhow!("'{}' is not a url or a path", url))?,
+        };
+        let report = check_repository(&url).with_context(|| format!("invalid index at {}", url))?;
+        term.write_line(&format!(
+            "{} is valid, with {} package(s), and {} release(s)",
+            report.url, report.packages, report.releases
+        ))?;
+        return Ok(());
+    }
+
+    // Build our VFS, Config, and Installer objects.
     let root = match cli.target {
         Some(target) => canonicalize(target)?,
         None => Config::find(current_dir()?).with_context(|| {
@@ -73,14 +400,75 @@ fn main() -> Result<()> {
     };
     info!(target: LOGNAME, "using root directory: '{}'", root);
     let fs: VfsPath = PhysicalFS::new(PathBuf::from(&root)).into();
-    let config =
-        Config::load(&fs).with_context(|| format!("invalid target directory '{}'", root))?;
-    let mut pkg = Installer::new(config, fs, root.as_str())
-        .with_context(|| format!("could not initialize in '{}'", root))?;
+
+    // None of these need an Installer, since they only touch the pkgdb or
+    // our configuration directly.
+    if let Commands::Init {} = &cli.command {
+        Database::initialize(&fs).with_context(|| format!("could not initialize '{}'", root))?;
+        return Ok(());
+    }
+    if let Commands::PinConfig {
+        package,
+        requirement,
+    } = &cli.command
+    {
+        Config::pin(&fs, package, requirement)
+            .with_context(|| format!("could not pin {} in '{}'", package, root))?;
+        return Ok(());
+    }
+    // The pin in our configuration has to go before we load it, the one in the
+    // pkgdb goes along with everything else that needs an Installer.
+    let unpinned_config = match &cli.command {
+        Commands::Unpin { package } => Config::unpin(&fs, package)
+            .with_context(|| format!("could not unpin {} in '{}'", package, root))?,
+        _ => false,
+    };
+
+    // We load the lock file before anything else, so that we don't bother
+    // fetching anything if it's missing.
+    let lock = match &cli.command {
+        Commands::Install { locked: true, .. } => Some(
+            LockFile::load(&fs)
+                .with_context(|| format!("could not load the lock file in '{}'", root))?
+                .ok_or_else(|| anyhow!("no lock file found in '{}'", root))?,
+        ),
+        _ => None,
+    };
+
+    let system_config = match cli.system_config {
+        Some(path) => ConfigPath::Explicit(path),
+        None => ConfigPath::Default(Config::system_config_path()),
+    };
+    let user_config = match cli.user_config {
+        Some(path) => Some(ConfigPath::Explicit(path)),
+        None => Config::user_config_path().map(ConfigPath::Default),
+    };
+    let config = Config::load_hierarchy(&fs, &system_config, user_config.as_ref())
+        .with_context(|| format!("invalid target directory '{}'", root))?
+        .config;
+    #[cfg(feature = "miette-errors")]
+    if let Some(source) = config.source() {
+        let _ = CONFIG_SOURCE.set(Arc::clone(source));
+    }
+    let mut builder = Installer::builder(config, fs, root.as_str())
+        .report_stats(!cli.no_stats)
+        .use_cache(!cli.no_cache)
+        .fail_fast(cli.fail_fast)
+        .run_scripts(cli.scripts && !cli.no_scripts)
+        .dry_run(cli.dry_run)
+        .use_proxy(!cli.no_proxy)
+        .offline(cli.offline);
+    if let Some(seconds) = cli.solver_timeout {
+        builder = builder.solver_timeout(Duration::from_secs(seconds));
+    }
+    #[cfg(feature = "rustsec")]
+    {
+        builder = builder.ignore_advisories(cli.ignore_advisories);
+    }
 
     // Setup our console callback
-    if !cli.verbose.is_silent() {
-        pkg.with_console(|msg| {
+    if !cli.verbose.is_silent() && cli.format.is_human() {
+        builder = builder.console(|msg| {
             bars.suspended(|| {
                 term.write_line(msg).ok();
             });
@@ -89,32 +477,924 @@ fn main() -> Result<()> {
 
     // Setup our progress callbacks.
     if render_bars {
-        pkg.with_progress_start(|len| {
-            bars.with_bar(ProgressBar::new(len).with_style(style.clone()))
-        });
-        pkg.with_progress_spinner(|msg| {
-            bars.with_bar(ProgressBar::new_spinner().with_message(msg))
-        });
-        pkg.with_progress_update(|bar, delta| bar.inc(delta));
-        pkg.with_progress_finish(|bar| bar.finish_and_clear());
+        builder = builder
+            .progress_start(|len| bars.with_bar(ProgressBar::new(len).with_style(style.clone())))
+            .progress_spinner(|msg| bars.with_bar(ProgressBar::new_spinner().with_message(msg)))
+            .progress_update(|bar, delta| bar.inc(delta))
+            .progress_finish(|bar| bar.finish_and_clear())
+            .download_start(|len, name| {
+                bars.with_bar(
+                    ProgressBar::new(len)
+                        .with_style(download_style.clone())
+                        .with_prefix(name.to_string()),
+                )
+            })
+            .download_update(|bar, delta| bar.inc(delta))
+            .download_finish(|bar| bar.finish_and_clear());
+    }
+
+    let mut pkg = builder
+        .build()
+        .with_context(|| format!("could not initialize in '{}'", root))?;
+    if cli.no_cache {
+        pkg.with_cache_ttl(Duration::ZERO);
+    }
+    if let Some(concurrency) = cli.concurrency {
+        pkg.with_max_concurrent_requests(concurrency);
     }
+    #[cfg(feature = "rustsec")]
+    if let Some(path) = &cli.advisory_db {
+        let db = mqpkg::RustsecDb::open(path)
+            .with_context(|| format!("could not open advisory database '{}'", path.display()))?;
+        pkg.with_advisory_db(db);
+    }
+
+    // Ctrl-C while we're resolving stops the resolve cleanly, so that the
+    // transaction it's part of gets rolled back. Any other time, it stops us
+    // outright, just like it would have without a handler.
+    let cancel = pkg.cancel_handle();
+    ctrlc::set_handler(move || {
+        if cancel.is_resolving() {
+            cancel.cancel();
+        } else {
+            std::process::exit(130);
+        }
+    })
+    .context("could not set the Ctrl-C handler")?;
 
     // Actually dispatch to our commands.
-    match &cli.command {
-        Commands::Install { packages } => match pkg.install(packages) {
-            Ok(v) => Ok(v),
-            Err(InstallerError::ResolverError(SolverError::NoSolution(mut dt))) => {
-                dt.collapse_no_versions();
-                Err(SolverError::humanized(
-                    "unable to resolve packages to a set that satisfies all requirements",
-                    *dt,
-                )
-                .into())
+    if let Commands::Why { package } = &cli.command {
+        let chain = pkg.why(package).map_err(humanize)?;
+        term.write_str(&chain.render())?;
+        return Ok(());
+    }
+    if let Commands::Pin { package } = &cli.command {
+        let (name, version) = package
+            .split_once('@')
+            .ok_or_else(|| anyhow!("'{}' is not of the form name@version", package))?;
+        let name: PackageName = name.parse()?;
+        let version: semver::Version = version.parse()?;
+        pkg.pin(&name, &version).map_err(humanize)?;
+        return Ok(());
+    }
+    if let Commands::Unpin { package } = &cli.command {
+        match pkg.unpin(package) {
+            // Having been pinned in either place is enough.
+            Err(InstallerError::DatabaseError(DBError::NotPinned(_))) if unpinned_config => {}
+            result => result.map_err(humanize)?,
+        }
+        return Ok(());
+    }
+    if let Commands::WhyNot { package } = &cli.command {
+        let explanation = pkg.why_not(package).map_err(humanize)?;
+        term.write_line(&explanation)?;
+        return Ok(());
+    }
+    if let Commands::Backup { dest } = &cli.command {
+        pkg.backup(&physical_path(dest)?)
+            .with_context(|| format!("could not back up to '{}'", dest.display()))?;
+        return Ok(());
+    }
+    if let Commands::Restore { src } = &cli.command {
+        pkg.restore(&physical_path(src)?)
+            .with_context(|| format!("could not restore from '{}'", src.display()))?;
+        return Ok(());
+    }
+    if let Commands::Export {
+        format: ExportFormat::Json,
+    } = &cli.command
+    {
+        let exported = pkg
+            .export_json()
+            .with_context(|| format!("could not export '{}'", root))?;
+        term.write_line(&serde_json::to_string_pretty(&exported)?)?;
+        return Ok(());
+    }
+    if let Commands::Import {
+        format: ExportFormat::Json,
+        path,
+    } = &cli.command
+    {
+        let data =
+            std::fs::read(path).with_context(|| format!("could not read '{}'", path.display()))?;
+        pkg.import_json(&serde_json::from_slice(&data)?)
+            .with_context(|| format!("could not import into '{}'", root))?;
+        return Ok(());
+    }
+    if let Commands::Export {
+        format: ExportFormat::Yaml,
+    } = &cli.command
+    {
+        let packages = pkg.export().map_err(humanize)?;
+        term.write_str(&serde_yaml::to_string(&Requirements { packages })?)?;
+        return Ok(());
+    }
+    if let Commands::Freeze { save } = &cli.command {
+        let packages = pkg.freeze().map_err(humanize)?;
+        if *save {
+            let data = serde_yaml::to_string(&Requirements { packages })?;
+            std::fs::write(PathBuf::from(&root).join(FROZEN_FILENAME), data)
+                .with_context(|| format!("could not write {} in '{}'", FROZEN_FILENAME, root))?;
+        } else {
+            for package in packages.iter() {
+                term.write_line(&package.to_string())?;
+            }
+        }
+        return Ok(());
+    }
+    if let Commands::Gc {} = &cli.command {
+        let summary = pkg.gc().map_err(humanize)?;
+        let verb = if cli.dry_run {
+            "Would remove"
+        } else {
+            "Removed"
+        };
+        if cli.dry_run {
+            for path in summary.removed_files.iter() {
+                term.write_line(&format!("{} {}", verb, path.display()))?;
+            }
+        }
+        term.write_line(&format!(
+            "{} {} file(s), freeing {}",
+            verb,
+            summary.removed_files.len(),
+            HumanBytes(summary.bytes_freed)
+        ))?;
+        return Ok(());
+    }
+    if let Commands::History {} = &cli.command {
+        let history = pkg.history().map_err(humanize)?;
+        print_history(&term, &history)?;
+        return Ok(());
+    }
+    if let Commands::Check {} = &cli.command {
+        let errors = pkg.check().map_err(humanize)?;
+        for err in errors.iter() {
+            term.write_line(&check_message(err))?;
+        }
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "found {} problem(s) with the installation",
+                errors.len()
+            ));
+        }
+        return Ok(());
+    }
+    if let Commands::Tree { dot, mermaid } = &cli.command {
+        let tree = pkg.tree().map_err(humanize)?;
+        let output = if *dot {
+            tree.to_dot()
+        } else if *mermaid {
+            tree.to_mermaid()
+        } else {
+            tree.render()
+        };
+        term.write_str(&output)?;
+        return Ok(());
+    }
+    if let Commands::List {
+        tree: true,
+        depth,
+        filter,
+    } = &cli.command
+    {
+        let mut tree = pkg.cached_tree().map_err(humanize)?;
+        if let Some(filter) = filter {
+            tree.root.retain(|node| name_matches(&node.name, filter));
+        }
+        if cli.format.is_human() {
+            term.write_str(&tree.render_collapsed(*depth))?;
+        } else {
+            let output = CliOutput::Tree {
+                warnings: warnings.take(),
+                root: &tree.root,
+            };
+            term.write_line(&output.render(cli.format)?)?;
+        }
+        return Ok(());
+    }
+    if let Commands::List { filter, .. } = &cli.command {
+        let mut installed = pkg.list().map_err(humanize)?;
+        if let Some(filter) = filter {
+            installed.retain(|p| name_matches(&p.name, filter));
+        }
+        if cli.format.is_human() {
+            print_installed(&term, &installed)?;
+        } else {
+            let output = CliOutput::Installed {
+                warnings: warnings.take(),
+                packages: &installed,
+            };
+            term.write_line(&output.render(cli.format)?)?;
+        }
+        return Ok(());
+    }
+    if let Commands::Outdated {} = &cli.command {
+        let outdated = pkg.outdated().map_err(humanize)?;
+        print_outdated(&term, &outdated)?;
+        if !outdated.is_empty() {
+            return Err(anyhow!("{} package(s) are outdated", outdated.len()));
+        }
+        return Ok(());
+    }
+    if let Commands::Show { package } = &cli.command {
+        let detail = pkg
+            .show(package)
+            .map_err(humanize)?
+            .ok_or_else(|| anyhow!("Package {} not found", package))?;
+        if cli.format.is_human() {
+            print_detail(&term, &detail)?;
+        } else {
+            let output = CliOutput::Detail {
+                warnings: warnings.take(),
+                package: &detail,
+            };
+            term.write_line(&output.render(cli.format)?)?;
+        }
+        return Ok(());
+    }
+    if let Commands::Search { query, limit } = &cli.command {
+        let found = match pkg.search(query) {
+            Err(InstallerError::NoRepositories) => {
+                return Err(anyhow!(
+                    "No repositories configured. Add one to mqpkg.yml first."
+                ))
+            }
+            result => result.map_err(humanize)?,
+        };
+        let shown = &found[..found.len().min(*limit)];
+        if cli.format.is_human() {
+            print_packages(&term, shown, query)?;
+            if !found.is_empty() {
+                term.write_line(&format!(
+                    "Showing {} of {} results",
+                    shown.len(),
+                    found.len()
+                ))?;
             }
-            Err(err) => Err(err.into()),
+        } else {
+            let output = CliOutput::Search {
+                warnings: warnings.take(),
+                total: found.len(),
+                packages: shown,
+            };
+            term.write_line(&output.render(cli.format)?)?;
+        }
+        return Ok(());
+    }
+
+    // A dry run of an install only needs to know what would change, which we
+    // can work out without going through the whole install.
+    if let Commands::Install { packages, .. } = &cli.command {
+        if cli.dry_run && lock.is_none() && cli.format.is_human() {
+            let plan = pkg.diff(packages).map_err(humanize)?;
+            print_install_plan(&term, &plan)?;
+            return Ok(());
+        }
+    }
+
+    let summary = match &cli.command {
+        Commands::Install { packages, .. } => match &lock {
+            Some(lock) => pkg.install_locked(lock),
+            None => pkg.install(packages),
         },
-        _ => Err(anyhow!("command not implemented")),
+        Commands::Uninstall { packages } => match pkg.uninstall(packages) {
+            // Something that was never requested might still be installed as
+            // a dependency, in which case the error already says why we can't
+            // remove it, otherwise there's just nothing to remove.
+            Err(InstallerError::UninstallError(UninstallError::NotRequested(name)))
+                if !pkg.list().map_err(humanize)?.iter().any(|p| p.name == name) =>
+            {
+                return Err(anyhow!("Package {} is not installed", name));
+            }
+            result => result,
+        },
+        Commands::Update { packages } => pkg.update(packages),
+        Commands::Upgrade {
+            packages,
+            all,
+            major,
+        } => {
+            let packages = (!*all).then_some(packages.as_slice());
+            if *major {
+                pkg.upgrade_major(packages)
+            } else {
+                pkg.upgrade(packages)
+            }
+        }
+        Commands::Rollback { id } => pkg.rollback(*id),
+        Commands::Import { path, .. } => pkg.import(&load_requirements(path)?),
+        _ => return Err(anyhow!("command not implemented")),
     }
+    .map_err(humanize)?;
+
+    if !cli.format.is_human() {
+        term.write_line(&CliOutput::changes(&summary, warnings.take()).render(cli.format)?)?;
+        return Ok(());
+    }
+
+    if cli.dry_run {
+        print_plan(&term, &summary)?;
+    } else if !cli.verbose.is_silent() {
+        match &cli.command {
+            Commands::Upgrade { .. } => print_upgrade_summary(&term, &summary)?,
+            _ => print_summary(&term, &summary)?,
+        }
+    }
+    if cli.timing {
+        print_timings(&term, &summary.timings)?;
+    }
+
+    Ok(())
+}
+
+fn load_requirements(path: &Path) -> Result<Vec<PackageSpecifier>> {
+    let data =
+        std::fs::read(path).with_context(|| format!("could not read '{}'", path.display()))?;
+    let requirements: Requirements = serde_yaml::from_slice(&data)
+        .with_context(|| format!("invalid requirements in '{}'", path.display()))?;
+
+    Ok(requirements.packages)
+}
+
+// Parse an argument, and when it isn't valid, show where in it the problem is,
+// rather than only describing it.
+#[cfg(feature = "miette-errors")]
+fn parse_arg<T>(value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: miette::Diagnostic + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .map_err(|err| format!("{:?}", miette::Report::new(err)))
+}
+
+#[cfg(not(feature = "miette-errors"))]
+fn parse_arg<T>(value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|err: T::Err| err.to_string())
+}
+
+// Names are compared in their canonical form, so the filter has to be too.
+fn name_matches(name: &PackageName, filter: &str) -> bool {
+    name.canonical()
+        .contains(&filter.to_ascii_lowercase().replace('-', "_"))
+}
+
+fn humanize(err: InstallerError) -> anyhow::Error {
+    // Rewording the error loses its type, so what kind of error it was has to
+    // be worked out first.
+    let kind = ErrorKind::of(&err);
+    match err {
+        InstallerError::ResolverError(SolverError::NoSolution(mut dt)) => {
+            let msg = "unable to resolve packages to a set that satisfies all requirements";
+            let report = SolverError::explain(&dt);
+
+            // When any of the packages in the conflict were pinned, pointing at
+            // those pins in the config is more useful than our own formatting.
+            #[cfg(feature = "miette-errors")]
+            if let Some(source) = CONFIG_SOURCE.get().filter(|source| {
+                report
+                    .incompatibilities
+                    .iter()
+                    .any(|i| source.span(i.package()).is_some())
+            }) {
+                dt.collapse_no_versions();
+                let humanized =
+                    SolverError::humanized(msg, *dt).with_source(Some(Arc::clone(source)));
+                let message = format!("{:?}", miette::Report::new(humanized));
+                return HumanizedError::new(kind, message).into();
+            }
+
+            if !report.is_empty() {
+                let message = format!("{}\n\n{}", msg, format_conflicts(&report));
+                return HumanizedError::new(kind, message).into();
+            }
+
+            dt.collapse_no_versions();
+            let message = SolverError::humanized(msg, *dt).to_string();
+            HumanizedError::new(kind, message).into()
+        }
+        err => err.into(),
+    }
+}
+
+fn format_conflicts(report: &ConflictReport) -> String {
+    let mut blocks = Vec::new();
+    for incompatibility in report.incompatibilities.iter() {
+        let mut lines = Vec::new();
+        match incompatibility {
+            Incompatibility::Conflict {
+                package,
+                required_by,
+            } => {
+                lines.push(format!(
+                    "{} is required in ways that conflict:",
+                    style(package).red().bold()
+                ));
+                for by in required_by.iter() {
+                    let who = match &by.package {
+                        Some(name) => format!("{} {}", style(name).cyan(), by.versions),
+                        None => by.to_string(),
+                    };
+                    lines.push(format!(
+                        "    {} {} {} {}",
+                        who,
+                        by.verb(),
+                        style(package).red(),
+                        by.requirement
+                    ));
+                }
+            }
+            Incompatibility::NoVersions { package, versions } => {
+                lines.push(format!(
+                    "{} has no versions matching {}",
+                    style(package).red().bold(),
+                    versions
+                ));
+            }
+            Incompatibility::UnavailableDependencies { package, versions } => {
+                lines.push(format!(
+                    "{} {} has dependencies that could not be determined",
+                    style(package).red().bold(),
+                    versions
+                ));
+            }
+        }
+        blocks.push(lines.join("\n"));
+    }
+
+    blocks.join("\n\n")
+}
+
+fn print_summary(term: &Term, summary: &InstallSummary) -> Result<()> {
+    let diff = &summary.diff;
+    if diff.is_empty() {
+        term.write_line("No changes needed")?;
+    }
+
+    for change in diff.installed.iter() {
+        term.write_line(&format!(
+            "Installed {} {}",
+            change.name,
+            version(&change.to)
+        ))?;
+    }
+    for change in diff.upgraded.iter().chain(diff.downgraded.iter()) {
+        let verb = if change.from < change.to {
+            "Upgraded"
+        } else {
+            "Downgraded"
+        };
+        term.write_line(&format!(
+            "{verb} {} {} → {}",
+            change.name,
+            version(&change.from),
+            version(&change.to)
+        ))?;
+    }
+    for change in diff.removed.iter() {
+        term.write_line(&format!(
+            "Removed {} {}",
+            change.name,
+            version(&change.from)
+        ))?;
+    }
+
+    Ok(())
+}
+
+// Upgrades are mostly about what moved up, so that all goes on one line, and
+// anything else that had to change is called out after it.
+fn print_upgrade_summary(term: &Term, summary: &InstallSummary) -> Result<()> {
+    let diff = &summary.diff;
+    if diff.is_empty() {
+        term.write_line("No changes needed")?;
+        return Ok(());
+    }
+
+    let changes = |changes: &[PackageChange]| {
+        changes
+            .iter()
+            .map(|c| format!("{} {} → {}", c.name, version(&c.from), version(&c.to)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if !diff.upgraded.is_empty() {
+        term.write_line(&format!("Upgraded: {}", changes(&diff.upgraded)))?;
+    }
+    if !diff.downgraded.is_empty() {
+        term.write_line(&format!(
+            "{} {}",
+            style("Downgraded to satisfy the upgrade:").yellow(),
+            changes(&diff.downgraded)
+        ))?;
+    }
+    for change in diff.installed.iter() {
+        term.write_line(&format!(
+            "Installed {} {}",
+            change.name,
+            version(&change.to)
+        ))?;
+    }
+    for change in diff.removed.iter() {
+        term.write_line(&format!(
+            "Removed {} {}",
+            change.name,
+            version(&change.from)
+        ))?;
+    }
+
+    Ok(())
+}
+
+fn print_plan(term: &Term, summary: &InstallSummary) -> Result<()> {
+    let diff = &summary.diff;
+    if diff.is_empty() {
+        term.write_line("No changes needed")?;
+    }
+
+    let mut lines = Vec::new();
+    for change in diff
+        .installed
+        .iter()
+        .chain(diff.upgraded.iter())
+        .chain(diff.downgraded.iter())
+        .chain(diff.removed.iter())
+    {
+        if let Some(from) = &change.from {
+            lines.push((&change.name, '-', from));
+        }
+        if let Some(to) = &change.to {
+            lines.push((&change.name, '+', to));
+        }
+    }
+    lines.sort();
+
+    for (name, sign, version) in lines {
+        term.write_line(&format!("{sign}{name}@{version}"))?;
+    }
+
+    Ok(())
+}
+
+fn print_install_plan(term: &Term, plan: &InstallPlan) -> Result<()> {
+    if plan.is_empty() {
+        term.write_line("No changes needed")?;
+        return Ok(());
+    }
+
+    let mut lines = Vec::new();
+    for package in plan.to_install.iter() {
+        lines.push((&package.name, '+', &package.version));
+    }
+    for package in plan.to_remove.iter() {
+        lines.push((&package.name, '-', &package.version));
+    }
+    for (from, to) in plan.to_upgrade.iter() {
+        lines.push((&from.name, '-', &from.version));
+        lines.push((&to.name, '+', &to.version));
+    }
+    lines.sort();
+
+    for (name, sign, version) in lines {
+        term.write_line(&format!("{sign}{name}@{version}"))?;
+    }
+
+    Ok(())
+}
+
+fn print_timings(term: &Term, timings: &InstallTimings) -> Result<()> {
+    for (phase, ms) in [
+        ("fetch metadata", timings.fetch_metadata_ms),
+        ("resolve", timings.resolve_ms),
+        ("download", timings.download_ms),
+        ("extract", timings.extract_ms),
+        ("commit", timings.commit_ms),
+    ] {
+        term.write_line(&format!("{phase:>14}: {ms}ms"))?;
+    }
+
+    Ok(())
+}
+
+fn print_installed(term: &Term, packages: &[InstalledPackage]) -> Result<()> {
+    if packages.is_empty() {
+        term.write_line("No packages installed")?;
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 4]> = packages
+        .iter()
+        .map(|p| {
+            [
+                p.name.to_string(),
+                p.resolved_version.to_string(),
+                p.requested_version
+                    .as_ref()
+                    .map(|r| r.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                p.source_name.clone().unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+    print_table(term, ["Name", "Version", "Requested", "Source"], &rows)
+}
+
+fn print_outdated(term: &Term, packages: &[OutdatedPackage]) -> Result<()> {
+    if packages.is_empty() {
+        term.write_line("All packages are up to date")?;
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 4]> = packages
+        .iter()
+        .map(|p| {
+            [
+                p.name.to_string(),
+                p.installed.to_string(),
+                p.available.to_string(),
+                p.source.clone(),
+            ]
+        })
+        .collect();
+    print_table(term, ["Name", "Installed", "Available", "Source"], &rows)
+}
+
+fn print_history(term: &Term, history: &[TransactionLogEntry]) -> Result<()> {
+    if history.is_empty() {
+        term.write_line("No transactions recorded")?;
+        return Ok(());
+    }
+
+    // The newest transactions are the ones that are most likely to be undone,
+    // so they come first.
+    let rows: Vec<[String; 4]> = history
+        .iter()
+        .rev()
+        .map(|entry| {
+            [
+                entry.id.to_string(),
+                age(entry.timestamp),
+                entry.operation.to_string(),
+                history_changes(entry),
+            ]
+        })
+        .collect();
+    print_table(term, ["ID", "When", "Operation", "Changes"], &rows)
+}
+
+// A short description of everything that a transaction installed, removed, or
+// changed the version of.
+fn history_changes(entry: &TransactionLogEntry) -> String {
+    let (before, after) = (entry.before(), entry.after());
+    let names: BTreeSet<&PackageName> = before
+        .installed
+        .keys()
+        .chain(after.installed.keys())
+        .collect();
+
+    let changes: Vec<String> = names
+        .into_iter()
+        .filter_map(
+            |name| match (before.installed.get(name), after.installed.get(name)) {
+                (None, Some(to)) => Some(format!("+{} {}", name, to.version)),
+                (Some(from), None) => Some(format!("-{} {}", name, from.version)),
+                (Some(from), Some(to)) if from.version != to.version => {
+                    Some(format!("{} {} → {}", name, from.version, to.version))
+                }
+                _ => None,
+            },
+        )
+        .collect();
+
+    if changes.is_empty() {
+        "-".to_string()
+    } else {
+        changes.join(", ")
+    }
+}
+
+// How long ago `time` was, roughly, since that's easier to read than a date.
+fn age(time: SystemTime) -> String {
+    let secs = match SystemTime::now().duration_since(time) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => return "just now".to_string(),
+    };
+
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn print_detail(term: &Term, detail: &PackageDetail) -> Result<()> {
+    term.write_line(&format!(
+        "{}",
+        style(format!("{} {}", detail.name, detail.version)).bold()
+    ))?;
+    if let Some(description) = &detail.description {
+        term.write_line(description)?;
+    }
+    term.write_line("")?;
+
+    let field = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let installed = match (detail.installed, detail.install_time) {
+        (false, _) => "no".to_string(),
+        (true, Some(time)) => age(time),
+        (true, None) => "yes".to_string(),
+    };
+    for (label, value) in [
+        ("Installed", installed),
+        (
+            "Requested",
+            field(detail.requested_constraint.as_ref().map(|r| r.to_string())),
+        ),
+        (
+            "Homepage",
+            field(detail.homepage.as_ref().map(|u| hyperlink(term, u))),
+        ),
+        ("License", field(detail.license.clone())),
+        (
+            "Authors",
+            field(Some(detail.authors.join(", ")).filter(|a| !a.is_empty())),
+        ),
+        (
+            "Tags",
+            field(Some(detail.tags.join(", ")).filter(|t| !t.is_empty())),
+        ),
+        (
+            "Source",
+            field(detail.source_url.as_ref().map(|u| u.to_string())),
+        ),
+    ] {
+        term.write_line(&format!("{label:>12}: {value}"))?;
+    }
+
+    if detail.dependencies.is_empty() {
+        term.write_line(&format!("{:>12}: -", "Dependencies"))?;
+    } else {
+        term.write_line(&format!("{:>12}:", "Dependencies"))?;
+        for dep in detail.dependencies.iter() {
+            let installed = dep
+                .installed
+                .as_ref()
+                .map(|v| format!(" (installed {v})"))
+                .unwrap_or_default();
+            term.write_line(&format!(
+                "{:>14}{} {}{installed}",
+                "", dep.name, dep.requirement
+            ))?;
+        }
+    }
+
+    // Once it's installed, the version that's installed is what matters.
+    if !detail.installed {
+        let versions: Vec<String> = detail
+            .available_versions
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        term.write_line(&format!("{:>12}: {}", "Available", versions.join(", ")))?;
+    }
+
+    Ok(())
+}
+
+// A link that modern terminals make clickable, using the OSC 8 escape, which
+// we only use when we're writing to a terminal that a person is looking at.
+fn hyperlink(term: &Term, url: &Url) -> String {
+    if term.features().is_attended() && term.features().colors_supported() {
+        format!("\x1b]8;;{url}\x1b\\{url}\x1b]8;;\x1b\\")
+    } else {
+        url.to_string()
+    }
+}
+
+fn print_table<const N: usize>(
+    term: &Term,
+    headers: [&str; N],
+    rows: &[[String; N]],
+) -> Result<()> {
+    let mut widths = headers.map(|h| h.len());
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            // Cells can be styled, which doesn't take up any space.
+            *width = (*width).max(measure_text_width(cell));
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| pad_str(cell, *width, Alignment::Left, None).into_owned())
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    term.write_line(&line(headers.to_vec()))?;
+    for row in rows.iter() {
+        term.write_line(&line(row.iter().map(|c| c.as_str()).collect()))?;
+    }
+
+    Ok(())
+}
+
+fn print_packages(term: &Term, packages: &[SearchResult], query: &str) -> Result<()> {
+    if packages.is_empty() {
+        term.write_line("No packages found")?;
+        return Ok(());
+    }
+
+    // Names are canonical, so they get matched against a canonical query.
+    let name_query = query.to_ascii_lowercase().replace('-', "_");
+    let rows: Vec<[String; 3]> = packages
+        .iter()
+        .map(|p| {
+            [
+                highlight(&p.name.to_string(), &name_query),
+                p.latest_version.to_string(),
+                p.description
+                    .as_deref()
+                    .map(|d| highlight(d, query))
+                    .unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+    print_table(term, ["Name", "Latest Version", "Description"], &rows)
+}
+
+// Style the first place that `query` appears in `text`, ignoring case.
+fn highlight(text: &str, query: &str) -> String {
+    let start = match text.to_ascii_lowercase().find(&query.to_ascii_lowercase()) {
+        Some(start) if !query.is_empty() => start,
+        _ => return text.to_string(),
+    };
+    let end = start + query.len();
+
+    format!(
+        "{}{}{}",
+        &text[..start],
+        style(&text[start..end]).bold().underlined(),
+        &text[end..]
+    )
+}
+
+// A path on the real filesystem, rooted at its parent, so that a directory can
+// be staged next to it and then renamed into place.
+fn physical_path(path: &Path) -> Result<VfsPath> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("'{}' has no name", path.display()))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => canonicalize(parent)?,
+        _ => current_dir()?,
+    };
+    let root: VfsPath = PhysicalFS::new(PathBuf::from(&parent)).into();
+
+    Ok(root.join(name.to_string_lossy())?)
+}
+
+fn check_message(err: &CheckError) -> String {
+    match err {
+        CheckError::Missing(name, version) => {
+            format!("{name} {version} is locked, but not installed")
+        }
+        CheckError::VersionMismatch {
+            name,
+            locked,
+            installed,
+        } => format!("{name} is locked at {locked}, but {installed} is installed"),
+        CheckError::NotLocked(name, version) => {
+            format!("{name} {version} is installed, but not locked")
+        }
+        CheckError::NotCached(name, version) => {
+            format!("{name} {version} is not in the package cache")
+        }
+        CheckError::DigestMismatch {
+            name,
+            version,
+            expected,
+            got,
+        } => {
+            format!("the cached archive for {name} {version} has digest {got}, expected {expected}")
+        }
+    }
+}
+
+fn version(version: &Option<semver::Version>) -> String {
+    version
+        .as_ref()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_string())
 }
 
 fn canonicalize<P: AsRef<Path>>(path: P) -> Result<Utf8PathBuf> {
@@ -0,0 +1,173 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::fmt;
+
+use anyhow::Result;
+use clap::ArgEnum;
+use serde::Serialize;
+
+use mqpkg::{
+    DBError, DependencyNode, InstallSummary, InstalledPackage, InstallerError, PackageChange,
+    PackageDetail, SearchResult, SolverError,
+};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ArgEnum)]
+pub(crate) enum OutputFormat {
+    Human,
+    Json,
+    Toml,
+}
+
+impl OutputFormat {
+    pub(crate) fn is_human(&self) -> bool {
+        *self == OutputFormat::Human
+    }
+}
+
+/// What a command emits when the output is meant to be read by a machine,
+/// rather than a person.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub(crate) enum CliOutput<'o> {
+    // TOML requires that plain values come before any tables, so warnings has
+    // to be first.
+    Changes {
+        warnings: Vec<String>,
+        installed: &'o [PackageChange],
+        upgraded: &'o [PackageChange],
+        downgraded: &'o [PackageChange],
+        removed: &'o [PackageChange],
+    },
+    Installed {
+        warnings: Vec<String>,
+        packages: &'o [InstalledPackage],
+    },
+    Tree {
+        warnings: Vec<String>,
+        root: &'o [DependencyNode],
+    },
+    Detail {
+        warnings: Vec<String>,
+        package: &'o PackageDetail,
+    },
+    Search {
+        warnings: Vec<String>,
+        total: usize,
+        packages: &'o [SearchResult],
+    },
+    Error {
+        error: String,
+        kind: ErrorKind,
+    },
+}
+
+impl<'o> CliOutput<'o> {
+    pub(crate) fn changes(summary: &'o InstallSummary, warnings: Vec<String>) -> CliOutput<'o> {
+        CliOutput::Changes {
+            warnings,
+            installed: &summary.diff.installed,
+            upgraded: &summary.diff.upgraded,
+            downgraded: &summary.diff.downgraded,
+            removed: &summary.diff.removed,
+        }
+    }
+
+    pub(crate) fn error(err: &anyhow::Error) -> CliOutput<'static> {
+        // Errors are often styled for a terminal, which nothing reading this
+        // wants to have to deal with.
+        CliOutput::Error {
+            error: console::strip_ansi_codes(&format!("{:#}", err)).into_owned(),
+            kind: ErrorKind::from_anyhow(err),
+        }
+    }
+
+    pub(crate) fn render(&self, format: OutputFormat) -> Result<String> {
+        // Human output is written out by each command itself, so it's never
+        // rendered from here, but if it were, JSON is the most readable.
+        Ok(match format {
+            OutputFormat::Json | OutputFormat::Human => serde_json::to_string_pretty(self)?,
+            OutputFormat::Toml => toml::to_string_pretty(self)?,
+        })
+    }
+}
+
+/// What kind of error something was, for whatever is reading our output to
+/// be able to act on.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ErrorKind {
+    Usage,
+    Resolver,
+    Repository,
+    Database,
+    Installer,
+    Error,
+}
+
+impl ErrorKind {
+    pub(crate) fn of(err: &InstallerError) -> ErrorKind {
+        match err {
+            InstallerError::ResolverError(_) => ErrorKind::Resolver,
+            InstallerError::RepositoryError(_) => ErrorKind::Repository,
+            InstallerError::DatabaseError(_) => ErrorKind::Database,
+            InstallerError::MissingProgressCallback(_)
+            | InstallerError::CorruptedArchive { .. }
+            | InstallerError::FileConflict { .. }
+            | InstallerError::ProtectedPath { .. }
+            | InstallerError::UnsupportedArchiveFormat { .. }
+            | InstallerError::FileSystemError(_)
+            | InstallerError::PackagesNotFound(_)
+            | InstallerError::LockedVersionUnavailable { .. }
+            | InstallerError::HookFailed { .. }
+            | InstallerError::PostInstallScriptFailed { .. }
+            | InstallerError::UninstallError(_)
+            | InstallerError::NoLockFile
+            | InstallerError::NoRepositories
+            | InstallerError::NotInstalled(_)
+            | InstallerError::SignatureVerificationFailed { .. }
+            | InstallerError::BlockedByAdvisory { .. }
+            | InstallerError::PinConflict { .. }
+            | InstallerError::ExtraConstraintConflict { .. } => ErrorKind::Installer,
+        }
+    }
+
+    fn from_anyhow(err: &anyhow::Error) -> ErrorKind {
+        if let Some(err) = err.downcast_ref::<HumanizedError>() {
+            err.kind
+        } else if let Some(err) = err.downcast_ref::<InstallerError>() {
+            ErrorKind::of(err)
+        } else if err.downcast_ref::<clap::Error>().is_some() {
+            ErrorKind::Usage
+        } else if err.downcast_ref::<SolverError>().is_some() {
+            ErrorKind::Resolver
+        } else if err.downcast_ref::<DBError>().is_some() {
+            ErrorKind::Database
+        } else {
+            ErrorKind::Error
+        }
+    }
+}
+
+/// An InstallerError that has been reworded for a person to read, which keeps
+/// what kind of error it was, since the rewording loses its type.
+#[derive(Debug)]
+pub(crate) struct HumanizedError {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl HumanizedError {
+    pub(crate) fn new(kind: ErrorKind, message: String) -> HumanizedError {
+        HumanizedError { kind, message }
+    }
+}
+
+impl fmt::Display for HumanizedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for HumanizedError {}
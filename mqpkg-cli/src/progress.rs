@@ -2,16 +2,20 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
-use indicatif::{ProgressBar, WeakProgressBar};
+use indicatif::{MultiProgress, ProgressBar, WeakProgressBar};
 use std::sync::{Arc, Mutex};
 
+// Every bar is drawn through a single MultiProgress, so that the bars for the
+// overall progress and the bars for each download can be shown at once.
 pub(crate) struct SuspendableBars {
+    multi: Arc<MultiProgress>,
     bars: Arc<Mutex<Vec<WeakProgressBar>>>,
 }
 
 impl Clone for SuspendableBars {
     fn clone(&self) -> SuspendableBars {
         SuspendableBars {
+            multi: self.multi.clone(),
             bars: self.bars.clone(),
         }
     }
@@ -20,11 +24,13 @@ impl Clone for SuspendableBars {
 impl SuspendableBars {
     pub(crate) fn new() -> SuspendableBars {
         SuspendableBars {
+            multi: Arc::new(MultiProgress::new()),
             bars: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
     pub(crate) fn with_bar(&self, bar: ProgressBar) -> ProgressBar {
+        let bar = self.multi.add(bar);
         self.bars.lock().unwrap().push(bar.downgrade());
         bar
     }
@@ -33,23 +39,25 @@ impl SuspendableBars {
         let mut bs = self.bars.lock().unwrap();
         bs.retain(|b| b.upgrade().is_some());
 
-        let mut ibar = bs.iter().filter(|bar| match bar.upgrade() {
-            Some(b) => !b.is_finished(),
-            None => false,
-        });
-        let wbar = ibar.next();
-
-        match wbar {
-            Some(wbar) => {
-                let nbars = ibar.count();
-                assert!(nbars == 0, "too many active bars");
-
-                match wbar.upgrade() {
-                    Some(bar) => bar.suspend(callback),
-                    None => (callback)(),
+        let active: Vec<ProgressBar> = bs
+            .iter()
+            .filter_map(|bar| bar.upgrade())
+            .filter(|bar| !bar.is_finished())
+            .collect();
+
+        match active.as_slice() {
+            [] => (callback)(),
+            [bar] => bar.suspend(callback),
+            // Suspending a single bar only clears that bar, so when there is
+            // more than one we have to clear all of them, and then redraw them
+            // once we're done.
+            bars => {
+                self.multi.clear().ok();
+                (callback)();
+                for bar in bars.iter() {
+                    bar.tick();
                 }
             }
-            None => (callback)(),
         }
     }
 }
@@ -0,0 +1,93 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use semver::{Version, VersionReq};
+
+use crate::types::PackageName;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    /// Reported to the user, but doesn't stop anything from being installed.
+    Warning,
+    /// Blocks the install, unless advisories are being ignored.
+    Critical,
+}
+
+#[derive(Debug, Clone)]
+pub struct Advisory {
+    pub id: String,
+    pub severity: Severity,
+    pub description: String,
+    /// The versions that the advisory has been fixed in.
+    pub patched_versions: VersionReq,
+}
+
+pub trait AdvisoryDb {
+    fn check(&self, package: &PackageName, version: &Version) -> Vec<Advisory>;
+}
+
+/// An AdvisoryDb backed by a local checkout of the RustSec advisory database,
+/// which matches packages by their name against the crates in it.
+#[cfg(feature = "rustsec")]
+pub struct RustsecDb {
+    db: rustsec::Database,
+}
+
+#[cfg(feature = "rustsec")]
+impl RustsecDb {
+    pub fn open(path: &std::path::Path) -> Result<RustsecDb, rustsec::Error> {
+        Ok(RustsecDb {
+            db: rustsec::Database::open(path)?,
+        })
+    }
+}
+
+#[cfg(feature = "rustsec")]
+impl AdvisoryDb for RustsecDb {
+    fn check(&self, package: &PackageName, version: &Version) -> Vec<Advisory> {
+        use rustsec::advisory::Severity as RustsecSeverity;
+        use rustsec::database::Query;
+
+        let name: rustsec::package::Name = match package.to_string().parse() {
+            Ok(name) => name,
+            Err(_) => return Vec::new(),
+        };
+        let query = Query::crate_scope().package_version(name, version.clone());
+
+        self.db
+            .query(&query)
+            .into_iter()
+            .map(|advisory| Advisory {
+                id: advisory.id().to_string(),
+                // Advisories without a CVSS score have no severity at all, and
+                // we don't want to block an install on something unrated.
+                severity: match advisory.severity() {
+                    Some(RustsecSeverity::High | RustsecSeverity::Critical) => Severity::Critical,
+                    _ => Severity::Warning,
+                },
+                description: advisory.title().to_string(),
+                patched_versions: patched_versions(advisory.versions.patched()),
+            })
+            .collect()
+    }
+}
+
+// RustSec lists every range of patched versions separately, and we only have
+// room for one, so we keep the newest, which is the one listed last. If there
+// aren't any, then no version is patched.
+#[cfg(feature = "rustsec")]
+fn patched_versions(patched: &[VersionReq]) -> VersionReq {
+    match patched.last() {
+        Some(req) => req.clone(),
+        None => VersionReq {
+            comparators: vec![semver::Comparator {
+                op: semver::Op::Less,
+                major: 0,
+                minor: Some(0),
+                patch: Some(0),
+                pre: semver::Prerelease::new("0").unwrap_or_default(),
+            }],
+        },
+    }
+}
@@ -0,0 +1,350 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Cursor, Read, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Component, Path};
+
+use flate2::read::GzDecoder;
+use tar::Archive as TarArchive;
+use url::Url;
+use vfs::VfsPath;
+use zip::ZipArchive;
+
+use crate::config::CONFIG_FILENAME;
+use crate::errors::InstallerError;
+use crate::logging::{trace, warn};
+use crate::pkgdb::PKGDB_DIR;
+use crate::types::{PackageName, MANIFEST_FILENAME};
+
+const LOGNAME: &str = "mqpkg::archive";
+
+type Result<T, E = InstallerError> = core::result::Result<T, E>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum ArchiveFormat {
+    Zip,
+    TarGz,
+    #[cfg(feature = "zstd")]
+    TarZst,
+}
+
+impl ArchiveFormat {
+    // We only look at the path of the url, since the query string or fragment
+    // might contain anything at all.
+    pub(crate) fn detect(url: &Url) -> Option<ArchiveFormat> {
+        let path = url.path().to_ascii_lowercase();
+        if path.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else {
+            #[cfg(feature = "zstd")]
+            if path.ends_with(".tar.zst") {
+                return Some(ArchiveFormat::TarZst);
+            }
+            None
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveFormat::Zip => write!(f, "zip"),
+            ArchiveFormat::TarGz => write!(f, "tar.gz"),
+            #[cfg(feature = "zstd")]
+            ArchiveFormat::TarZst => write!(f, "tar.zst"),
+        }
+    }
+}
+
+/// Extract the archive for `name` into `dest`, returning the path of every file
+/// relative to `dest`. Nothing is written if any of those paths already belongs
+/// to another package in `owners`, and everything that does get written is
+/// tracked by `extracted`, so that it can be undone.
+pub(crate) fn extract_archive(
+    name: &PackageName,
+    data: &[u8],
+    format: ArchiveFormat,
+    dest: &VfsPath,
+    owners: &HashMap<String, PackageName>,
+    extracted: &mut DownloadGuard,
+) -> Result<Vec<String>> {
+    let corrupted = |details: String| InstallerError::CorruptedArchive {
+        name: name.clone(),
+        details,
+    };
+
+    // We read the entire archive into memory before we write anything out, that
+    // way a corrupted or truncated archive is detected up front, rather than
+    // leaving a partially extracted package behind. The archive crates are also
+    // known to panic on some malformed inputs, so we treat a panic as corruption
+    // too.
+    trace!(target: LOGNAME, "reading {name} as a {format} archive");
+    let mut files = catch_unwind(AssertUnwindSafe(|| match format {
+        ArchiveFormat::Zip => read_zip(data),
+        ArchiveFormat::TarGz => read_tar(GzDecoder::new(data)),
+        #[cfg(feature = "zstd")]
+        ArchiveFormat::TarZst => zstd::stream::read::Decoder::new(data)
+            .map_err(|e| e.to_string())
+            .and_then(read_tar),
+    }))
+    .map_err(|panic| corrupted(panic_message(panic)))?
+    .map_err(corrupted)?;
+
+    // The manifest describes the package for us, it isn't something that gets
+    // installed.
+    files.retain(|(path, _)| path != MANIFEST_FILENAME);
+
+    // Packages get extracted into the root of the target, which is also where
+    // our own files live, so nothing is written if any of them would be
+    // replaced.
+    if let Some((path, _)) = files.iter().find(|(path, _)| is_protected(path)) {
+        return Err(InstallerError::ProtectedPath {
+            name: name.clone(),
+            path: path.clone(),
+        });
+    }
+
+    for (path, _) in files.iter() {
+        match owners.get(path) {
+            Some(owner) if owner != name => {
+                return Err(InstallerError::FileConflict {
+                    path: path.clone(),
+                    package: name.clone(),
+                    owner: owner.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    for (path, content) in files.iter() {
+        trace!(target: LOGNAME, "extracting {path:?} from {name}");
+        let target = dest.join(path)?;
+        if let Some(parent) = target.parent() {
+            parent.create_dir_all()?;
+        }
+        extracted.push(&target)?;
+        let mut file = target.create_file()?;
+        file.write_all(content).map_err(vfs::VfsError::from)?;
+    }
+
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
+
+/// Tracks the files that have been extracted, and when it is dropped, unless it
+/// has been committed first, removes them again, or puts back what they had
+/// replaced.
+#[derive(Debug, Default)]
+pub(crate) struct DownloadGuard {
+    files: Vec<(VfsPath, Option<Vec<u8>>)>,
+}
+
+impl DownloadGuard {
+    pub(crate) fn new() -> DownloadGuard {
+        DownloadGuard::default()
+    }
+
+    /// Track `file` before it gets written to. An upgrade overwrites the files
+    /// of the version it's replacing, which is still what the pkgdb says is
+    /// installed if we fail, so we hold on to whatever was there before.
+    pub(crate) fn push(&mut self, file: &VfsPath) -> Result<()> {
+        let previous = if file.is_file()? {
+            let mut content = Vec::new();
+            file.open_file()?
+                .read_to_end(&mut content)
+                .map_err(vfs::VfsError::from)?;
+            Some(content)
+        } else {
+            None
+        };
+        self.files.push((file.clone(), previous));
+
+        Ok(())
+    }
+
+    /// Keep every file that has been extracted.
+    pub(crate) fn commit(mut self) {
+        self.files.clear();
+    }
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        // We go in reverse, so that if the same file was written more than
+        // once, the last thing we put back is what was there to begin with.
+        for (file, previous) in self.files.iter().rev() {
+            let result = match previous {
+                Some(content) => {
+                    trace!(target: LOGNAME, "restoring {:?}", file.as_str());
+                    file.create_file()
+                        .and_then(|mut f| f.write_all(content).map_err(vfs::VfsError::from))
+                }
+                // We track files before they're written, so one that we failed
+                // to create won't be there to remove.
+                None if !file.exists().unwrap_or(false) => Ok(()),
+                None => {
+                    trace!(target: LOGNAME, "removing {:?}", file.as_str());
+                    file.remove_file()
+                }
+            };
+            if let Err(err) = result {
+                warn!(
+                    target: LOGNAME,
+                    "could not restore {:?}: {}",
+                    file.as_str(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+fn read_zip(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+    let mut files = Vec::new();
+
+    for idx in 0..archive.len() {
+        let mut file = archive.by_index(idx).map_err(|e| e.to_string())?;
+
+        // Empty folders are ignored, since directories get created as needed
+        // when we write out the files that they contain.
+        if file.is_dir() {
+            continue;
+        }
+
+        // The names inside of a zip file are arbitrary strings, so we make sure
+        // that they can't escape from the directory that we're extracting into.
+        let path = file
+            .enclosed_name()
+            .and_then(|p| p.to_str())
+            .map(|p| p.replace('\\', "/"))
+            .ok_or_else(|| format!("invalid path {:?}", file.name()))?;
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).map_err(|e| e.to_string())?;
+        files.push((path, content));
+    }
+
+    Ok(files)
+}
+
+fn read_tar<R: Read>(reader: R) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut archive = TarArchive::new(reader);
+    let mut files = Vec::new();
+
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+
+        // Like with zip files, directories get created as needed, and we don't
+        // install anything that isn't a regular file, such as symlinks.
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path().map_err(|e| e.to_string())?;
+        let path = enclosed_name(&path).ok_or_else(|| format!("invalid path {:?}", path))?;
+
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).map_err(|e| e.to_string())?;
+        files.push((path, content));
+    }
+
+    Ok(files)
+}
+
+// The tar crate has no equivalent to enclosed_name from the zip crate, so we
+// only accept paths that are made up entirely of normal components.
+fn enclosed_name(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+// Paths are compared ignoring case, since the target might be on a filesystem
+// that does too.
+fn is_protected(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    let first = path.split('/').next().unwrap_or_default();
+    first == PKGDB_DIR || path == CONFIG_FILENAME
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(msg) => msg.to_string(),
+            Err(_) => "archive extraction panicked".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vfs::MemoryFS;
+
+    use super::*;
+
+    fn write(path: &VfsPath, content: &[u8]) {
+        path.create_file().unwrap().write_all(content).unwrap();
+    }
+
+    fn read(path: &VfsPath) -> Vec<u8> {
+        let mut content = Vec::new();
+        path.open_file().unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn guard_restores_overwritten_files() {
+        let root: VfsPath = MemoryFS::new().into();
+        let existing = root.join("existing.txt").unwrap();
+        let added = root.join("added.txt").unwrap();
+        write(&existing, b"old");
+
+        let mut guard = DownloadGuard::new();
+        guard.push(&existing).unwrap();
+        write(&existing, b"new");
+        guard.push(&added).unwrap();
+        write(&added, b"new");
+        drop(guard);
+
+        assert_eq!(read(&existing), b"old");
+        assert!(!added.exists().unwrap());
+    }
+
+    #[test]
+    fn guard_keeps_committed_files() {
+        let root: VfsPath = MemoryFS::new().into();
+        let existing = root.join("existing.txt").unwrap();
+        let added = root.join("added.txt").unwrap();
+        write(&existing, b"old");
+
+        let mut guard = DownloadGuard::new();
+        guard.push(&existing).unwrap();
+        write(&existing, b"new");
+        guard.push(&added).unwrap();
+        write(&added, b"new");
+        guard.commit();
+
+        assert_eq!(read(&existing), b"new");
+        assert_eq!(read(&added), b"new");
+    }
+}
@@ -0,0 +1,287 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use vfs::VfsPath;
+
+use crate::logging::{trace, warn};
+use crate::types::{PackageManifest, PackageName};
+
+const LOGNAME: &str = "mqpkg::cache";
+
+const DIGEST_PREFIX_LEN: usize = 16;
+
+// Where each target that shares this cache records which archives it uses.
+const TARGETS_DIR: &str = "targets";
+
+/// The cached archive for a package, identified by the digest of the archive.
+pub(crate) type ArchiveKey = (PackageName, Version, String);
+
+// The archives that a single target uses, which garbage collecting any target
+// has to keep, since they all share the same cache.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct TargetUsage {
+    target: String,
+    archives: Vec<String>,
+}
+
+// The cache is purely an optimization, so nothing in here ever fails, anything
+// that goes wrong just gets logged and treated as if the archive wasn't cached.
+#[derive(Debug, Clone)]
+pub(crate) struct PackageCache {
+    root: VfsPath,
+}
+
+impl PackageCache {
+    pub(crate) fn new(root: VfsPath) -> PackageCache {
+        PackageCache { root }
+    }
+
+    pub(crate) fn contains(&self, name: &PackageName, version: &Version) -> bool {
+        let prefix = format!("{name}-{version}-");
+        match self.root.read_dir() {
+            Ok(mut entries) => entries.any(|e| {
+                let filename = e.filename();
+                filename.starts_with(&prefix) && filename.ends_with(".archive")
+            }),
+            Err(_) => false,
+        }
+    }
+
+    pub(crate) fn get(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        digest: &str,
+    ) -> Option<Vec<u8>> {
+        let path = self.path(name, version, digest)?;
+        let data = self.read(&path)?;
+
+        // The key only contains a prefix of the digest, and the file could have
+        // been modified since we wrote it, so we check the full digest again.
+        let got = format!("{:x}", Sha256::digest(&data));
+        if !got.eq_ignore_ascii_case(digest) {
+            warn!(
+                target: LOGNAME,
+                "cached archive {:?} does not match its digest, ignoring it",
+                path.as_str()
+            );
+            return None;
+        }
+
+        trace!(target: LOGNAME, "using cached archive {:?}", path.as_str());
+        Some(data)
+    }
+
+    /// The manifest from inside of the cached archive with `digest`, only
+    /// package archives have one, so anything else is treated as not cached.
+    pub(crate) fn manifest(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        digest: &str,
+    ) -> Option<PackageManifest> {
+        let data = self.get(name, version, digest)?;
+        match PackageManifest::from_reader(data.as_slice()) {
+            Ok(manifest) => Some(manifest),
+            Err(err) => {
+                trace!(target: LOGNAME, "no manifest for {name} {version}: {err}");
+                None
+            }
+        }
+    }
+
+    /// The digest of what is actually cached for the archive with `digest`,
+    /// which may not match it if the cached file has been modified.
+    pub(crate) fn cached_digest(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        digest: &str,
+    ) -> Option<String> {
+        let data = self.read(&self.path(name, version, digest)?)?;
+        Some(format!("{:x}", Sha256::digest(&data)))
+    }
+
+    pub(crate) fn put(&self, name: &PackageName, version: &Version, digest: &str, data: &[u8]) {
+        let path = match self.path(name, version, digest) {
+            Some(path) => path,
+            None => return,
+        };
+
+        trace!(target: LOGNAME, "caching archive at {:?}", path.as_str());
+        let result = self.root.create_dir_all().and_then(|_| {
+            path.create_file()?
+                .write_all(data)
+                .map_err(vfs::VfsError::from)
+        });
+        if let Err(err) = result {
+            warn!(target: LOGNAME, "could not cache {:?}: {}", path.as_str(), err);
+        }
+    }
+
+    /// Record that `target` uses the archives for `using`, replacing whatever it
+    /// used before, so that garbage collecting another target keeps them.
+    pub(crate) fn record_usage(&self, target: &str, using: &HashSet<ArchiveKey>) {
+        let path = match self.usage_path(target) {
+            Some(path) => path,
+            None => return,
+        };
+        let mut archives: Vec<String> = using
+            .iter()
+            .filter_map(|(name, version, digest)| self.filename(name, version, digest))
+            .collect();
+        archives.sort();
+        let usage = TargetUsage {
+            target: target.to_string(),
+            archives,
+        };
+
+        trace!(target: LOGNAME, "recording the archives used by {target:?}");
+        let result = path
+            .parent()
+            .map(|p| p.create_dir_all())
+            .transpose()
+            .and_then(|_| {
+                let data = serde_json::to_vec(&usage).unwrap_or_default();
+                path.create_file()?
+                    .write_all(&data)
+                    .map_err(vfs::VfsError::from)
+            });
+        if let Err(err) = result {
+            warn!(target: LOGNAME, "could not record cache usage {:?}: {}", path.as_str(), err);
+        }
+    }
+
+    /// Remove every file in the cache that isn't an archive that `target` uses,
+    /// according to `using`, or that any other target that still exists has
+    /// recorded that it uses, returning the name and size of each one that was
+    /// removed. A dry run only works out what would be removed.
+    pub(crate) fn gc(
+        &self,
+        target: &str,
+        using: &HashSet<ArchiveKey>,
+        dry_run: bool,
+    ) -> Vec<(String, u64)> {
+        if !dry_run {
+            self.record_usage(target, using);
+        }
+
+        let mut keep: HashSet<String> = using
+            .iter()
+            .filter_map(|(name, version, digest)| self.filename(name, version, digest))
+            .collect();
+        for usage in self.usages() {
+            if usage.target == target {
+                continue;
+            }
+            // A target that has been deleted doesn't need anything anymore.
+            if !Path::new(&usage.target).exists() {
+                trace!(target: LOGNAME, "{:?} no longer exists, ignoring it", usage.target);
+                if !dry_run {
+                    if let Some(path) = self.usage_path(&usage.target) {
+                        path.remove_file().ok();
+                    }
+                }
+                continue;
+            }
+            keep.extend(usage.archives);
+        }
+
+        let entries = match self.root.read_dir() {
+            Ok(entries) => entries,
+            Err(err) => {
+                trace!(target: LOGNAME, "nothing to collect from the cache: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let mut removed = Vec::new();
+        for path in entries {
+            let filename = path.filename();
+            if keep.contains(&filename) {
+                continue;
+            }
+            if !path.is_file().unwrap_or(false) {
+                continue;
+            }
+
+            let len = path.metadata().map(|m| m.len).unwrap_or(0);
+            if !dry_run {
+                if let Err(err) = path.remove_file() {
+                    warn!(target: LOGNAME, "could not remove {:?}: {}", path.as_str(), err);
+                    continue;
+                }
+            }
+            trace!(target: LOGNAME, "collected {:?}", path.as_str());
+            removed.push((filename, len));
+        }
+
+        removed
+    }
+
+    fn read(&self, path: &VfsPath) -> Option<Vec<u8>> {
+        if !path.is_file().unwrap_or(false) {
+            return None;
+        }
+
+        let mut data = Vec::new();
+        if let Err(err) = path
+            .open_file()
+            .and_then(|mut f| f.read_to_end(&mut data).map_err(vfs::VfsError::from))
+        {
+            warn!(target: LOGNAME, "could not read {:?}: {}", path.as_str(), err);
+            return None;
+        }
+
+        Some(data)
+    }
+
+    fn usages(&self) -> Vec<TargetUsage> {
+        let entries = match self.root.join(TARGETS_DIR).and_then(|d| d.read_dir()) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        entries
+            .filter_map(|path| {
+                let data = self.read(&path)?;
+                match serde_json::from_slice(&data) {
+                    Ok(usage) => Some(usage),
+                    Err(err) => {
+                        warn!(target: LOGNAME, "ignoring {:?}: {}", path.as_str(), err);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // Targets are named by a digest of their path, which keeps them unique
+    // without having to worry about what characters are in it.
+    fn usage_path(&self, target: &str) -> Option<VfsPath> {
+        let digest = format!("{:x}", Sha256::digest(target.as_bytes()));
+        self.root
+            .join(TARGETS_DIR)
+            .and_then(|d| d.join(format!("{}.json", &digest[..DIGEST_PREFIX_LEN])))
+            .ok()
+    }
+
+    fn filename(&self, name: &PackageName, version: &Version, digest: &str) -> Option<String> {
+        Some(self.path(name, version, digest)?.filename())
+    }
+
+    fn path(&self, name: &PackageName, version: &Version, digest: &str) -> Option<VfsPath> {
+        let prefix: String = digest.chars().take(DIGEST_PREFIX_LEN).collect();
+        self.root
+            .join(format!("{name}-{version}-{prefix}.archive"))
+            .ok()
+    }
+}
@@ -2,27 +2,116 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use camino::Utf8PathBuf;
-use log::info;
+use semver::{Version, VersionReq};
 use serde::Deserialize;
 use serde_with::{serde_as, DisplayFromStr, PickFirst};
+use serde_yaml::{Mapping, Value};
 use url::Url;
-use vfs::VfsPath;
+use vfs::{PhysicalFS, VfsError, VfsPath};
+use yaml_rust::parser::{Event, Parser};
+use yaml_rust::scanner::{Marker, ScanError, TScalarStyle, TokenType};
 
 use crate::errors::ConfigError;
+use crate::logging::{debug, info};
+use crate::paths;
+use crate::pkgdb::DEFAULT_MAX_HISTORY;
+use crate::platform::Platform;
+use crate::repository::DEFAULT_MAX_CONCURRENT_REQUESTS;
+use crate::resolver::DEFAULT_WARN_ITERATIONS;
+use crate::types::{PackageName, PackageSpecifier};
 
 const LOGNAME: &str = "mqpkg::config";
 
-const CONFIG_FILENAME: &str = "mqpkg.yml";
+pub(crate) const CONFIG_FILENAME: &str = "mqpkg.yml";
+
+const REDACTED: &str = "<redacted>";
+
+const DEFAULT_CACHE_TTL_SECONDS: u64 = 3600;
+
+// The one key whose values get combined across configuration files, rather
+// than the highest priority file replacing it.
+const REPOSITORIES_KEY: &str = "repositories";
+
+// Anywhere a repository can go, `!include path` can go instead, which is
+// replaced by the repositories from that file.
+const INCLUDE_TAG: &str = "!include";
 
 type Result<T, E = ConfigError> = core::result::Result<T, E>;
 
-#[derive(Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Deserialize, Clone, Eq, PartialEq, Hash)]
 pub(crate) struct Repository {
     pub(crate) name: String,
     pub(crate) url: Url,
+
+    // Tried in order, whenever we can't fetch the index from url.
+    #[serde(default)]
+    pub(crate) mirrors: Vec<Url>,
+
+    // How the index itself is compressed, for servers that serve a compressed
+    // index as is, rather than negotiating a Content-Encoding.
+    #[serde(default)]
+    pub(crate) compression: Option<CompressionAlgorithm>,
+
+    #[serde(default)]
+    pub(crate) report_stats: bool,
+    #[serde(default)]
+    pub(crate) stats_url: Option<Url>,
+
+    // Base64 encoded minisign public keys, if there are any then every package
+    // from this repository must be signed by at least one of them.
+    #[serde(default)]
+    pub(crate) trusted_keys: Vec<String>,
+
+    // The expected sha256 hex digest of the repository's index, which pins the
+    // repository to exactly that index, and together with a lock file, makes
+    // installs completely reproducible.
+    #[serde(default)]
+    pub(crate) index_digest: Option<String>,
+
+    // A base64 encoded Ed25519 public key, which the index itself must be
+    // signed with.
+    #[serde(default)]
+    pub(crate) signing_key: Option<String>,
+
+    // Credentials for private repositories, a token is sent as a bearer token,
+    // otherwise a username (and password) uses basic auth. If neither are set
+    // then we'll look in ~/.netrc for the repository's host.
+    #[serde(default)]
+    pub(crate) token: Option<String>,
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+    #[serde(default)]
+    pub(crate) password: Option<String>,
+}
+
+impl fmt::Debug for Repository {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let redacted = |v: &Option<String>| v.as_ref().map(|_| REDACTED);
+
+        f.debug_struct("Repository")
+            .field("name", &self.name)
+            .field("url", &self.url)
+            .field("mirrors", &self.mirrors)
+            .field("compression", &self.compression)
+            .field("report_stats", &self.report_stats)
+            .field("stats_url", &self.stats_url)
+            .field("trusted_keys", &self.trusted_keys)
+            .field("index_digest", &self.index_digest)
+            .field("signing_key", &self.signing_key)
+            .field("token", &redacted(&self.token))
+            .field("username", &self.username)
+            .field("password", &redacted(&self.password))
+            .finish()
+    }
 }
 
 impl FromStr for Repository {
@@ -32,15 +121,555 @@ impl FromStr for Repository {
         let name = s.to_string();
         let url = Url::from_str(s).map_err(|source| ConfigError::InvalidURL { source })?;
 
-        Ok(Repository { name, url })
+        Ok(Repository {
+            name,
+            url,
+            mirrors: Vec::new(),
+            compression: None,
+            report_stats: false,
+            stats_url: None,
+            trusted_keys: Vec::new(),
+            index_digest: None,
+            signing_key: None,
+            token: None,
+            username: None,
+            password: None,
+        })
+    }
+}
+
+/// Replace every `${VAR}` in `s` with the value of that environment variable.
+/// Values are substituted as is, so a value that itself contains a `${...}`
+/// isn't expanded any further.
+pub(crate) fn interpolate(s: &str) -> Result<String> {
+    let invalid = || ConfigError::InvalidEnvVarReference {
+        value: s.to_string(),
+    };
+
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(invalid)?;
+        let var = &after[..end];
+
+        // This also rules out nested references, like ${A${B}}, since
+        // variable names are only ever made up of these characters.
+        if var.is_empty() || !var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(invalid());
+        }
+
+        let value = std::env::var(var).map_err(|_| ConfigError::MissingEnvVar {
+            var: var.to_string(),
+        })?;
+        result.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+// Repository urls are interpolated before the configuration is deserialized,
+// since an error that goes through serde only keeps its message, and a missing
+// variable should be reported as one. A repository that's given as just a url
+// gets turned into a mapping, so that its name is still the url as written.
+fn interpolate_urls(data: &mut Value) -> Result<()> {
+    let repos = match data.get_mut(REPOSITORIES_KEY) {
+        Some(Value::Sequence(repos)) => repos,
+        _ => return Ok(()),
+    };
+    for repo in repos.iter_mut() {
+        match repo {
+            Value::String(url) => {
+                let mut map = Mapping::new();
+                map.insert(Value::from("name"), Value::from(url.as_str()));
+                map.insert(Value::from("url"), Value::from(interpolate(url)?));
+                *repo = Value::Mapping(map);
+            }
+            Value::Mapping(map) => {
+                if let Some(Value::String(url)) = map.get_mut(&Value::from("url")) {
+                    *url = interpolate(url)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CompressionAlgorithm {
+    Gzip,
+    Zstd,
+    Brotli,
+}
+
+impl fmt::Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompressionAlgorithm::Gzip => write!(f, "gzip"),
+            CompressionAlgorithm::Zstd => write!(f, "zstd"),
+            CompressionAlgorithm::Brotli => write!(f, "brotli"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct PlatformConfig {
+    pub(crate) target: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ResolverConfig {
+    #[serde(default = "default_warn_iterations")]
+    pub(crate) warn_iterations: u64,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> ResolverConfig {
+        ResolverConfig {
+            warn_iterations: DEFAULT_WARN_ITERATIONS,
+        }
+    }
+}
+
+fn default_warn_iterations() -> u64 {
+    DEFAULT_WARN_ITERATIONS
+}
+
+fn default_max_history() -> u32 {
+    DEFAULT_MAX_HISTORY
+}
+
+fn default_max_concurrent_requests() -> usize {
+    DEFAULT_MAX_CONCURRENT_REQUESTS
+}
+
+fn default_cache_ttl_seconds() -> Option<u64> {
+    Some(DEFAULT_CACHE_TTL_SECONDS)
+}
+
+// How we retry requests that fail for reasons that are likely to be transient,
+// each retry waits initial_delay_ms * backoff_multiplier ^ attempt, plus up to
+// jitter_factor of that again, so that clients don't all retry in lockstep.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct RetryConfig {
+    pub(crate) max_attempts: u32,
+    pub(crate) initial_delay_ms: u64,
+    pub(crate) backoff_multiplier: f64,
+    pub(crate) jitter_factor: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            initial_delay_ms: 500,
+            backoff_multiplier: 2.0,
+            jitter_factor: 0.1,
+        }
+    }
+}
+
+// The proxy that every request gets sent through, when one isn't configured
+// the HTTP_PROXY, HTTPS_PROXY, and NO_PROXY environment variables are used.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub(crate) struct ProxyConfig {
+    pub(crate) proxy: Option<Url>,
+    pub(crate) proxy_username: Option<String>,
+    pub(crate) proxy_password: Option<String>,
+}
+
+// The proxy url can have credentials embedded in it as well, so we don't
+// show any of it.
+impl fmt::Debug for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProxyConfig")
+            .field("proxy", &self.proxy.as_ref().map(|_| REDACTED))
+            .field("proxy_username", &self.proxy_username)
+            .field(
+                "proxy_password",
+                &self.proxy_password.as_ref().map(|_| REDACTED),
+            )
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Exclusion {
+    pub(crate) package: PackageName,
+    pub(crate) versions: Vec<Version>,
+}
+
+// Each hook is a command that gets run by the shell.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct HooksConfig {
+    pub(crate) pre_install: Option<String>,
+    pub(crate) post_install: Option<String>,
+    pub(crate) pre_uninstall: Option<String>,
+    pub(crate) post_uninstall: Option<String>,
+}
+
+/// The text of a configuration file, along with where in it each package was
+/// pinned, so that errors about those packages can point at their pins.
+#[derive(Debug)]
+pub struct ConfigSource {
+    name: String,
+    text: String,
+    pins: HashMap<PackageName, (usize, usize)>,
+}
+
+impl ConfigSource {
+    fn new(name: &str, text: String) -> ConfigSource {
+        ConfigSource {
+            name: name.to_string(),
+            pins: pin_spans(&text),
+            text,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The byte offset and length of where `package` was pinned, if it was.
+    pub fn span(&self, package: &PackageName) -> Option<(usize, usize)> {
+        self.pins.get(package).copied()
+    }
+}
+
+#[cfg(feature = "miette-errors")]
+impl miette::SourceCode for ConfigSource {
+    fn read_span<'a>(
+        &'a self,
+        span: &miette::SourceSpan,
+        context_lines_before: usize,
+        context_lines_after: usize,
+    ) -> Result<Box<dyn miette::SpanContents<'a> + 'a>, miette::MietteError> {
+        let contents = self
+            .text
+            .read_span(span, context_lines_before, context_lines_after)?;
+        Ok(Box::new(miette::MietteSpanContents::new_named(
+            self.name.clone(),
+            contents.data(),
+            *contents.span(),
+            contents.line(),
+            contents.column(),
+            contents.line_count(),
+        )))
     }
 }
 
+// We don't get any positions from serde_yaml, so we walk the events from the
+// parser that it is built on to find where each pin's key is. Any error here
+// gets reported when the configuration itself is parsed, so we just keep the
+// spans that we found before it.
+fn pin_spans(text: &str) -> HashMap<PackageName, (usize, usize)> {
+    let mut spans = HashMap::new();
+    let _ = find_pins(text, &mut spans);
+    spans
+}
+
+fn find_pins(
+    text: &str,
+    spans: &mut HashMap<PackageName, (usize, usize)>,
+) -> Result<(), ScanError> {
+    let mut parser = Parser::new(text.chars());
+    loop {
+        match parser.next()?.0 {
+            Event::StreamStart | Event::DocumentStart => {}
+            Event::MappingStart(_) => break,
+            _ => return Ok(()),
+        }
+    }
+
+    loop {
+        let key = match parser.next()?.0 {
+            Event::MappingEnd => return Ok(()),
+            Event::Scalar(key, ..) => key,
+            event => {
+                skip_node(&mut parser, event)?;
+                String::new()
+            }
+        };
+        let value = parser.next()?.0;
+        match value {
+            Event::MappingStart(_) if key == "pins" => loop {
+                let (event, mark) = parser.next()?;
+                match event {
+                    Event::MappingEnd => break,
+                    Event::Scalar(name, style, ..) => {
+                        let len = match style {
+                            TScalarStyle::Plain => name.len(),
+                            _ => name.len() + 2,
+                        };
+                        if let Ok(name) = name.parse() {
+                            spans.insert(name, (offset(text, mark), len));
+                        }
+                    }
+                    event => skip_node(&mut parser, event)?,
+                }
+                let value = parser.next()?.0;
+                skip_node(&mut parser, value)?;
+            },
+            value => skip_node(&mut parser, value)?,
+        }
+    }
+}
+
+// Where in the text of a configuration file a pin can be written, without
+// touching anything else in it.
+#[derive(Debug, PartialEq)]
+enum PinEdit {
+    // The existing value of the pin, as (start, end).
+    Replace(usize, usize),
+    // Before the first entry of the pins, as (offset, indentation).
+    Insert(usize, String),
+    // There aren't any pins yet, so they go at the end of the file.
+    Append,
+}
+
+// This only finds an edit for the block style mappings that we'd expect to
+// see in a configuration file, anything else gives None.
+fn pin_edit(text: &str, package: &PackageName) -> Result<Option<PinEdit>, ScanError> {
+    let mut parser = Parser::new(text.chars());
+    let mark = loop {
+        let (event, mark) = parser.next()?;
+        match event {
+            Event::StreamStart | Event::DocumentStart => {}
+            Event::MappingStart(_) => break mark,
+            _ => return Ok(None),
+        }
+    };
+    if mark.col() != 0 || text[offset(text, mark)..].starts_with('{') {
+        return Ok(None);
+    }
+
+    loop {
+        let key = match parser.next()?.0 {
+            Event::MappingEnd => return Ok(Some(PinEdit::Append)),
+            Event::Scalar(key, ..) => key,
+            event => {
+                skip_node(&mut parser, event)?;
+                String::new()
+            }
+        };
+        let (value, mark) = parser.next()?;
+        if key != "pins" {
+            skip_node(&mut parser, value)?;
+            continue;
+        }
+        if !matches!(value, Event::MappingStart(_)) || text[offset(text, mark)..].starts_with('{') {
+            return Ok(None);
+        }
+
+        let mut first = None;
+        loop {
+            let (event, mark) = parser.next()?;
+            let name = match event {
+                Event::MappingEnd => break,
+                Event::Scalar(name, ..) => name,
+                event => {
+                    skip_node(&mut parser, event)?;
+                    String::new()
+                }
+            };
+            first.get_or_insert(mark);
+
+            let (value, mark) = parser.next()?;
+            if name.parse().ok().as_ref() != Some(package) {
+                skip_node(&mut parser, value)?;
+                continue;
+            }
+            // We only replace the value when we can tell exactly where it
+            // ends, which we can't for anything with escapes in it.
+            let source = match value {
+                Event::Scalar(value, TScalarStyle::Plain, ..) => value,
+                Event::Scalar(value, TScalarStyle::SingleQuoted, ..) => {
+                    format!("'{}'", value.replace('\'', "''"))
+                }
+                Event::Scalar(value, TScalarStyle::DoubleQuoted, ..) => format!("\"{value}\""),
+                _ => return Ok(None),
+            };
+            let start = offset(text, mark);
+            return Ok(text[start..]
+                .starts_with(&source)
+                .then(|| PinEdit::Replace(start, start + source.len())));
+        }
+
+        let key = match first {
+            Some(mark) => offset(text, mark),
+            None => return Ok(None),
+        };
+        let line = text[..key].rfind('\n').map_or(0, |idx| idx + 1);
+        let indent = &text[line..key];
+        return Ok((indent.trim().is_empty()).then(|| PinEdit::Insert(line, indent.to_string())));
+    }
+}
+
+// The parser's marks count chars, rather than bytes.
+fn offset(text: &str, mark: Marker) -> usize {
+    text.char_indices()
+        .nth(mark.index())
+        .map_or(text.len(), |(offset, _)| offset)
+}
+
+// Consume the rest of the node that `event` starts.
+fn skip_node<T: Iterator<Item = char>>(
+    parser: &mut Parser<T>,
+    event: Event,
+) -> Result<(), ScanError> {
+    let mut depth = match event {
+        Event::SequenceStart(_) | Event::MappingStart(_) => 1,
+        _ => 0,
+    };
+    while depth > 0 {
+        match parser.next()?.0 {
+            Event::SequenceStart(_) | Event::MappingStart(_) => depth += 1,
+            Event::SequenceEnd | Event::MappingEnd => depth -= 1,
+            Event::StreamEnd => break,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Remove the line that package's pin is on, and when it's the last pin, the
+// line that starts the pins as well. Like pin_edit, this only works for the
+// block style mappings that we'd expect, anything else gives None.
+fn unpin_edit(text: &str, package: &PackageName, last: bool) -> Option<String> {
+    let (key, _) = *pin_spans(text).get(package)?;
+    let mut lines = vec![line_of(text, key)?];
+    if last {
+        let pins = text
+            .match_indices("pins:")
+            .map(|(idx, _)| idx)
+            .find(|idx| *idx == 0 || text[..*idx].ends_with('\n'))?;
+        lines.push(line_of(text, pins)?);
+    }
+    lines.sort_unstable();
+
+    let mut edited = String::with_capacity(text.len());
+    let mut rest = 0;
+    for (start, end) in lines {
+        edited.push_str(&text[rest..start]);
+        rest = end;
+    }
+    edited.push_str(&text[rest..]);
+
+    Some(edited)
+}
+
+// The whole of the line that offset is at the start of, as (start, end),
+// including its newline.
+fn line_of(text: &str, offset: usize) -> Option<(usize, usize)> {
+    let start = text[..offset].rfind('\n').map_or(0, |idx| idx + 1);
+    if !text[start..offset].trim().is_empty() {
+        return None;
+    }
+    let end = text[offset..]
+        .find('\n')
+        .map_or(text.len(), |idx| offset + idx + 1);
+
+    Some((start, end))
+}
+
+// Save a configuration file that we've edited. We only trust an edit to the
+// text if it parses to the same thing as data, otherwise we fall back to
+// writing out data as a whole, which loses any comments and formatting, and
+// writes any includes back as quoted strings.
+fn save_edited(filename: &VfsPath, data: &Mapping, edited: Option<String>) -> Result<()> {
+    let same = |text: &str| -> Option<bool> {
+        let edited = serde_json::to_value(parse_yaml(text).ok()?).ok()?;
+        Some(edited == serde_json::to_value(data).ok()?)
+    };
+    let edited = edited.filter(|text| same(text) == Some(true));
+
+    let mut file = filename
+        .create_file()
+        .map_err(|source| ConfigError::UnableToSave { source })?;
+    match edited {
+        Some(text) => file
+            .write_all(text.as_bytes())
+            .map_err(VfsError::from)
+            .map_err(|source| ConfigError::UnableToSave { source })?,
+        None => serde_yaml::to_writer(file, data)
+            .map_err(|source| ConfigError::InvalidConfig { source })?,
+    }
+
+    Ok(())
+}
+
 #[serde_with::serde_as]
 #[derive(Deserialize, Debug)]
 pub struct Config {
+    // This defaults to empty so that a user or system configuration file can
+    // set other things without having to list any repositories.
     #[serde_as(as = "Vec<PickFirst<(_, DisplayFromStr)>>")]
+    #[serde(default)]
     repositories: Vec<Repository>,
+
+    #[serde(default)]
+    platform: PlatformConfig,
+
+    #[serde(default)]
+    resolver: ResolverConfig,
+
+    #[serde(default)]
+    pins: HashMap<PackageName, VersionReq>,
+
+    // What the project requests, in the same form that `export` writes, so
+    // that an export can be pasted in as is.
+    #[serde(default)]
+    packages: Vec<PackageSpecifier>,
+
+    #[serde(default)]
+    cache_dir: Option<PathBuf>,
+
+    #[serde(default)]
+    exclusions: Vec<Exclusion>,
+
+    // The default of 0.0 means that there is no limit.
+    #[serde(default)]
+    http_requests_per_second: f64,
+
+    #[serde(default)]
+    hooks: Option<HooksConfig>,
+
+    #[serde(default)]
+    retry: RetryConfig,
+
+    // How long cached repository metadata is used for without asking the
+    // server about it at all, null means to always ask.
+    #[serde(default = "default_cache_ttl_seconds")]
+    cache_ttl_seconds: Option<u64>,
+
+    // How many transactions are kept in the pkgdb's history.
+    #[serde(default = "default_max_history")]
+    max_history: u32,
+
+    // How many HTTP requests can be in flight at once.
+    #[serde(default = "default_max_concurrent_requests")]
+    max_concurrent_requests: usize,
+
+    #[serde(flatten)]
+    proxy: ProxyConfig,
+
+    // Only the project's own configuration file has one of these, since that
+    // is the file that errors can point into.
+    #[serde(skip)]
+    source: Option<Arc<ConfigSource>>,
 }
 
 impl Config {
@@ -57,20 +686,236 @@ impl Config {
             "loading config from {:?}",
             filename.as_str()
         );
-        let file = filename
-            .open_file()
-            .map_err(|source| ConfigError::NoConfig { source })?;
-        let config: Config = serde_yaml::from_reader(file)
+        let data = read_config(&filename).map_err(|source| ConfigError::NoConfig { source })?;
+        let mut config = resolve_includes(&filename, &data, &mut HashSet::new())?;
+        interpolate_urls(&mut config)?;
+
+        let mut config: Config = serde_yaml::from_value(config)
             .map_err(|source| ConfigError::InvalidConfig { source })?;
+        config.source = Some(Arc::new(ConfigSource::new(filename.as_str(), data)));
 
         Ok(config)
     }
 
+    /// The system wide configuration file that load_merged uses.
+    pub fn system_config_path() -> PathBuf {
+        paths::system_config_file()
+    }
+
+    /// The per user configuration file that load_merged uses, if we can work
+    /// out where it should be.
+    pub fn user_config_path() -> Option<PathBuf> {
+        paths::user_config_file()
+    }
+
+    /// Load the configuration for root, layered on top of the user and system
+    /// configuration files.
+    pub fn load_merged(root: &VfsPath) -> Result<Config> {
+        let system = ConfigPath::Default(Config::system_config_path());
+        let user = Config::user_config_path().map(ConfigPath::Default);
+        Ok(Config::load_hierarchy(root, &system, user.as_ref())?.config)
+    }
+
+    /// Load the configuration for root, along with the given system and user
+    /// configuration files, either of which are skipped if they don't exist,
+    /// unless they were given explicitly.
+    ///
+    /// Repositories from every file are combined, with those from root first,
+    /// then the user's, then the system's, and a repository with the same name
+    /// as one that came before it is dropped. Anything else is taken from the
+    /// highest priority file that sets it, going root > user > system.
+    pub fn load_hierarchy(
+        root: &VfsPath,
+        system: &ConfigPath,
+        user: Option<&ConfigPath>,
+    ) -> Result<MergedConfig> {
+        let project = root
+            .join(CONFIG_FILENAME)
+            .map_err(|source| ConfigError::NoConfig { source })?;
+        info!(
+            target: LOGNAME,
+            "loading config from {:?}",
+            project.as_str()
+        );
+        let text = read_config(&project).map_err(|source| ConfigError::NoConfig { source })?;
+        let data = resolve_includes(&project, &text, &mut HashSet::new())?;
+
+        let mut layers = vec![(PathBuf::from(project.as_str()), data)];
+        for path in user.into_iter().chain([system]) {
+            if let Some(data) = read_optional(path)? {
+                layers.push((path.path().to_path_buf(), data));
+            }
+        }
+
+        let parse = |mut data: Value| -> Result<Config> {
+            interpolate_urls(&mut data)?;
+            serde_yaml::from_value(data).map_err(|source| ConfigError::InvalidConfig { source })
+        };
+
+        let mut merged = Value::Mapping(Mapping::new());
+        let mut sources = Vec::with_capacity(layers.len());
+        for (path, data) in layers.into_iter().rev() {
+            merged = merge_values(data.clone(), merged);
+            sources.push((path, parse(data)?));
+        }
+        sources.reverse();
+
+        let mut config = parse(merged)?;
+        config.source = Some(Arc::new(ConfigSource::new(project.as_str(), text)));
+        let mut seen = HashSet::new();
+        config
+            .repositories
+            .retain(|repo| seen.insert(repo.name.clone()));
+
+        let merged = MergedConfig { config, sources };
+        for repo in merged.config.repositories.iter() {
+            if let Some(path) = merged.source_of(&repo.name) {
+                debug!(
+                    target: LOGNAME,
+                    "using repository {:?} from {:?}", repo.name, path
+                );
+            }
+        }
+
+        Ok(merged)
+    }
+
+    /// Pin `package` to `requirement` in the configuration file within root.
+    /// Only the pin itself is edited, so comments and formatting are kept,
+    /// unless `pins` is written in a style that we can't edit in place, like
+    /// a flow mapping, in which case the whole file is written out again
+    /// without them.
+    pub fn pin(root: &VfsPath, package: &PackageName, requirement: &VersionReq) -> Result<()> {
+        let filename = root
+            .join(CONFIG_FILENAME)
+            .map_err(|source| ConfigError::NoConfig { source })?;
+        let content = read_config(&filename).map_err(|source| ConfigError::NoConfig { source })?;
+
+        // We edit the raw YAML rather than serializing a Config, so that we don't
+        // throw away anything in the file that we don't know about.
+        let mut data: Mapping = serde_yaml::from_value(parse_yaml(&content)?)
+            .map_err(|source| ConfigError::InvalidConfig { source })?;
+        let key = Value::from("pins");
+        if !matches!(data.get(&key), Some(Value::Mapping(_))) {
+            data.insert(key.clone(), Value::Mapping(Mapping::new()));
+        }
+        if let Some(Value::Mapping(pins)) = data.get_mut(&key) {
+            // A pin that's already there keeps its key as written.
+            let existing = pins
+                .iter()
+                .map(|(name, _)| name)
+                .find(|name| name.as_str().and_then(|n| n.parse().ok()).as_ref() == Some(package))
+                .cloned();
+            pins.insert(
+                existing.unwrap_or_else(|| Value::from(package.to_string())),
+                Value::from(requirement.to_string()),
+            );
+        }
+
+        // Where we can, we only edit the pin itself in the text, so that the
+        // comments and formatting in the rest of the file are kept.
+        let value = format!("\"{requirement}\"");
+        let edited = match pin_edit(&content, package).ok().flatten() {
+            Some(PinEdit::Replace(start, end)) => {
+                Some(format!("{}{value}{}", &content[..start], &content[end..]))
+            }
+            Some(PinEdit::Insert(offset, indent)) => Some(format!(
+                "{}{indent}{package}: {value}\n{}",
+                &content[..offset],
+                &content[offset..]
+            )),
+            Some(PinEdit::Append) => {
+                let newline = if content.is_empty() || content.ends_with('\n') {
+                    ""
+                } else {
+                    "\n"
+                };
+                Some(format!("{content}{newline}pins:\n  {package}: {value}\n"))
+            }
+            None => None,
+        };
+
+        info!(
+            target: LOGNAME,
+            "pinning {} to {} in {:?}",
+            package,
+            requirement,
+            filename.as_str()
+        );
+        save_edited(&filename, &data, edited)
+    }
+
+    /// Remove the pin for `package` from the configuration file within root,
+    /// returning whether there was one to remove. Just like with
+    /// [`Config::pin`], only the pin itself is edited where we can.
+    pub fn unpin(root: &VfsPath, package: &PackageName) -> Result<bool> {
+        let filename = root
+            .join(CONFIG_FILENAME)
+            .map_err(|source| ConfigError::NoConfig { source })?;
+        let content = read_config(&filename).map_err(|source| ConfigError::NoConfig { source })?;
+
+        let mut data: Mapping = serde_yaml::from_value(parse_yaml(&content)?)
+            .map_err(|source| ConfigError::InvalidConfig { source })?;
+        let key = Value::from("pins");
+        let remaining = match data.get_mut(&key) {
+            Some(Value::Mapping(pins)) => {
+                let existing = pins
+                    .iter()
+                    .map(|(name, _)| name)
+                    .find(|name| {
+                        name.as_str().and_then(|n| n.parse().ok()).as_ref() == Some(package)
+                    })
+                    .cloned();
+                match existing {
+                    Some(name) => {
+                        pins.remove(&name);
+                        pins.len()
+                    }
+                    None => return Ok(false),
+                }
+            }
+            _ => return Ok(false),
+        };
+        // An empty pins section would read back as null rather than as no
+        // pins, so the last pin takes the section with it.
+        if remaining == 0 {
+            data.remove(&key);
+        }
+
+        info!(
+            target: LOGNAME,
+            "unpinning {} in {:?}",
+            package,
+            filename.as_str()
+        );
+        let edited = unpin_edit(&content, package, remaining == 0);
+        save_edited(&filename, &data, edited)?;
+
+        Ok(true)
+    }
+
     pub fn find<P>(path: P) -> Result<Utf8PathBuf>
     where
         P: Into<Utf8PathBuf>,
     {
-        let mut path = path.into();
+        // Popping components off of a path is purely lexical, so we resolve any
+        // symlinks first, otherwise we'd walk up the parents of the symlink
+        // rather than the parents of where it actually points to.
+        let path = path.into();
+        let mut path = match dunce::canonicalize(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|p| Utf8PathBuf::try_from(p).map_err(|e| e.to_string()))
+        {
+            Ok(canonical) => canonical,
+            Err(err) => {
+                debug!(
+                    target: LOGNAME,
+                    "could not canonicalize {:?}, searching it as is: {}", path, err
+                );
+                path
+            }
+        };
+
         loop {
             path.push(CONFIG_FILENAME);
             if path.is_file() {
@@ -87,8 +932,682 @@ impl Config {
     }
 }
 
+/// A Config that was merged together from several configuration files, along
+/// with each of those files, from the highest priority to the lowest.
+#[derive(Debug)]
+pub struct MergedConfig {
+    pub config: Config,
+    pub sources: Vec<(PathBuf, Config)>,
+}
+
+impl MergedConfig {
+    /// The configuration file that the repository named name came from.
+    pub fn source_of(&self, name: &str) -> Option<&Path> {
+        self.sources
+            .iter()
+            .find(|(_, config)| config.repositories.iter().any(|r| r.name == name))
+            .map(|(path, _)| path.as_path())
+    }
+}
+
+/// Where to find one of the configuration files that root's is layered on top
+/// of.
+#[derive(Debug, Clone)]
+pub enum ConfigPath {
+    /// Where we look by default, which is skipped if it doesn't exist.
+    Default(PathBuf),
+    /// A file that was asked for explicitly, so it must exist.
+    Explicit(PathBuf),
+}
+
+impl ConfigPath {
+    pub fn path(&self) -> &Path {
+        match self {
+            ConfigPath::Default(path) | ConfigPath::Explicit(path) => path,
+        }
+    }
+}
+
+// Read a configuration file that may or may not exist, an empty file is the
+// same thing as one that doesn't set anything.
+fn read_optional(path: &ConfigPath) -> Result<Option<Value>> {
+    let (path, required) = match path {
+        ConfigPath::Default(path) => (path, false),
+        ConfigPath::Explicit(path) => (path, true),
+    };
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !required => {
+            debug!(target: LOGNAME, "skipping missing config {:?}", path);
+            return Ok(None);
+        }
+        Err(source) => {
+            return Err(ConfigError::UnreadableConfig {
+                path: path.to_path_buf(),
+                source,
+            })
+        }
+    };
+
+    info!(target: LOGNAME, "loading config from {:?}", path);
+
+    // Includes are loaded through the vfs, like everything else, rooted at the
+    // directory that this file is in.
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => {
+            return Err(ConfigError::UnreadableConfig {
+                path: path.to_path_buf(),
+                source: io::ErrorKind::InvalidInput.into(),
+            })
+        }
+    };
+    let root: VfsPath = PhysicalFS::new(dir.to_path_buf()).into();
+    let filename = root
+        .join(&name)
+        .map_err(|source| ConfigError::InvalidInclude {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+    match resolve_includes(&filename, &content, &mut HashSet::new())? {
+        Value::Null => Ok(None),
+        data => Ok(Some(data)),
+    }
+}
+
+fn read_config(filename: &VfsPath) -> Result<String, VfsError> {
+    let mut content = String::new();
+    filename
+        .open_file()?
+        .read_to_string(&mut content)
+        .map_err(VfsError::from)?;
+    Ok(content)
+}
+
+// YAML tags that we don't know about are dropped by serde_yaml, which leaves
+// just the path behind, so we find every scalar tagged `!include` with the
+// parser that serde_yaml is built on, and turn each of them into the string
+// "!include path" in what serde_yaml parsed.
+fn parse_yaml(content: &str) -> Result<Value> {
+    let mut data: Value =
+        serde_yaml::from_str(content).map_err(|source| ConfigError::InvalidConfig { source })?;
+
+    let mut includes = Vec::new();
+    // Anything that the parser fails on, serde_yaml already failed on.
+    let _ = find_includes(content, &mut includes);
+    for (path, target) in includes {
+        let node = path
+            .iter()
+            .try_fold(&mut data, |node, step| match (node, step) {
+                (Value::Sequence(items), Step::Index(idx)) => items.get_mut(*idx),
+                (Value::Mapping(map), Step::Key(key)) => map.get_mut(&Value::from(key.as_str())),
+                _ => None,
+            });
+        if let Some(node) = node {
+            *node = Value::from(format!("{INCLUDE_TAG} {target}"));
+        }
+    }
+
+    Ok(data)
+}
+
+// Where a node is within a document.
+#[derive(Clone)]
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+fn find_includes(content: &str, includes: &mut Vec<(Vec<Step>, String)>) -> Result<(), ScanError> {
+    let mut parser = Parser::new(content.chars());
+    loop {
+        match parser.next()?.0 {
+            Event::StreamStart | Event::DocumentStart => {}
+            Event::StreamEnd => return Ok(()),
+            event => return walk_includes(&mut parser, event, &mut Vec::new(), includes),
+        }
+    }
+}
+
+fn walk_includes<T: Iterator<Item = char>>(
+    parser: &mut Parser<T>,
+    event: Event,
+    path: &mut Vec<Step>,
+    includes: &mut Vec<(Vec<Step>, String)>,
+) -> Result<(), ScanError> {
+    match event {
+        Event::Scalar(value, _, _, Some(TokenType::Tag(handle, suffix)))
+            if format!("{handle}{suffix}") == INCLUDE_TAG =>
+        {
+            includes.push((path.clone(), value));
+        }
+        Event::SequenceStart(_) => {
+            for idx in 0.. {
+                let event = parser.next()?.0;
+                if event == Event::SequenceEnd {
+                    break;
+                }
+                path.push(Step::Index(idx));
+                walk_includes(parser, event, path, includes)?;
+                path.pop();
+            }
+        }
+        Event::MappingStart(_) => loop {
+            match parser.next()?.0 {
+                Event::MappingEnd => break,
+                Event::Scalar(key, ..) => {
+                    let value = parser.next()?.0;
+                    path.push(Step::Key(key));
+                    walk_includes(parser, value, path, includes)?;
+                    path.pop();
+                }
+                key => {
+                    skip_node(parser, key)?;
+                    let value = parser.next()?.0;
+                    skip_node(parser, value)?;
+                }
+            }
+        },
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// Parse the configuration file at filename, which has already been read into
+// content, replacing every include within its repositories with what that file
+// lists. Included files can be either just a list of repositories, or a whole
+// configuration file, in which case only its repositories are used.
+fn resolve_includes(
+    filename: &VfsPath,
+    content: &str,
+    in_progress: &mut HashSet<PathBuf>,
+) -> Result<Value> {
+    let path = PathBuf::from(filename.as_str());
+    if !in_progress.insert(path.clone()) {
+        return Err(ConfigError::CircularInclude { path });
+    }
+
+    let mut data = parse_yaml(content)?;
+    match &mut data {
+        Value::Mapping(map) => {
+            if let Some(repos) = map.get_mut(&Value::from(REPOSITORIES_KEY)) {
+                let value = std::mem::replace(repos, Value::Null);
+                *repos = expand_includes(filename, value, in_progress)?;
+            }
+        }
+        Value::Sequence(_) => {
+            data = expand_includes(filename, data, in_progress)?;
+        }
+        _ => {}
+    }
+
+    in_progress.remove(&path);
+    Ok(data)
+}
+
+fn expand_includes(
+    filename: &VfsPath,
+    value: Value,
+    in_progress: &mut HashSet<PathBuf>,
+) -> Result<Value> {
+    let include = |value: &Value| -> Option<String> {
+        let rest = value.as_str()?.strip_prefix(INCLUDE_TAG)?;
+        rest.starts_with(char::is_whitespace)
+            .then(|| rest.trim().to_string())
+    };
+
+    let items = match value {
+        Value::Sequence(items) => items,
+        value if include(&value).is_some() => vec![value],
+        value => return Ok(value),
+    };
+
+    let mut expanded = Vec::with_capacity(items.len());
+    for item in items {
+        let target = match include(&item) {
+            Some(target) => target,
+            None => {
+                expanded.push(item);
+                continue;
+            }
+        };
+
+        // Relative paths are relative to the file that includes them.
+        let included = filename
+            .parent()
+            .unwrap_or_else(|| filename.clone())
+            .join(&target)
+            .map_err(|source| ConfigError::InvalidInclude {
+                path: PathBuf::from(&target),
+                source,
+            })?;
+        debug!(
+            target: LOGNAME,
+            "including {:?} from {:?}",
+            included.as_str(),
+            filename.as_str()
+        );
+        let content = read_config(&included).map_err(|source| ConfigError::InvalidInclude {
+            path: PathBuf::from(included.as_str()),
+            source,
+        })?;
+
+        match resolve_includes(&included, &content, in_progress)? {
+            Value::Sequence(repos) => expanded.extend(repos),
+            Value::Mapping(mut map) => {
+                if let Some(Value::Sequence(repos)) = map.remove(&Value::from(REPOSITORIES_KEY)) {
+                    expanded.extend(repos);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Value::Sequence(expanded))
+}
+
+// Merge two configuration files, where anything set in higher wins over lower,
+// except for repositories which are combined. Nested mappings, like retry, are
+// merged key by key, so setting one part of them doesn't reset the rest.
+fn merge_values(higher: Value, lower: Value) -> Value {
+    fn merge(higher: Value, lower: Value, top: bool) -> Value {
+        match (higher, lower) {
+            (Value::Mapping(mut higher), Value::Mapping(lower)) => {
+                for (key, value) in lower {
+                    let combined = match (higher.remove(&key), value) {
+                        (None, value) => value,
+                        (Some(Value::Sequence(mut h)), Value::Sequence(l))
+                            if top && key.as_str() == Some(REPOSITORIES_KEY) =>
+                        {
+                            h.extend(l);
+                            Value::Sequence(h)
+                        }
+                        (Some(h), l) => merge(h, l, false),
+                    };
+                    higher.insert(key, combined);
+                }
+                Value::Mapping(higher)
+            }
+            (higher, _) => higher,
+        }
+    }
+
+    // A file with nothing in it doesn't override anything.
+    match higher {
+        Value::Null => lower,
+        higher => merge(higher, lower, true),
+    }
+}
+
 impl Config {
     pub(crate) fn repositories(&self) -> &[Repository] {
         &self.repositories
     }
+
+    pub(crate) fn resolver(&self) -> &ResolverConfig {
+        &self.resolver
+    }
+
+    pub(crate) fn pins(&self) -> &HashMap<PackageName, VersionReq> {
+        &self.pins
+    }
+
+    /// The packages that the project's configuration requests.
+    pub fn packages(&self) -> &[PackageSpecifier] {
+        &self.packages
+    }
+
+    /// Where this configuration was loaded from, if it was loaded from a file.
+    pub fn source(&self) -> Option<&Arc<ConfigSource>> {
+        self.source.as_ref()
+    }
+
+    pub(crate) fn exclusions(&self) -> HashMap<PackageName, HashSet<Version>> {
+        let mut exclusions = HashMap::<PackageName, HashSet<Version>>::new();
+        for exclusion in self.exclusions.iter() {
+            exclusions
+                .entry(exclusion.package.clone())
+                .or_default()
+                .extend(exclusion.versions.iter().cloned());
+        }
+        exclusions
+    }
+
+    pub(crate) fn http_requests_per_second(&self) -> f64 {
+        self.http_requests_per_second
+    }
+
+    pub(crate) fn retry(&self) -> &RetryConfig {
+        &self.retry
+    }
+
+    pub(crate) fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_seconds.unwrap_or(0))
+    }
+
+    pub(crate) fn max_history(&self) -> u32 {
+        self.max_history
+    }
+
+    pub(crate) fn max_concurrent_requests(&self) -> usize {
+        self.max_concurrent_requests
+    }
+
+    pub(crate) fn proxy(&self) -> &ProxyConfig {
+        &self.proxy
+    }
+
+    pub(crate) fn hooks(&self) -> Option<&HooksConfig> {
+        self.hooks.as_ref()
+    }
+
+    pub(crate) fn cache_dir(&self) -> PathBuf {
+        self.cache_dir.clone().unwrap_or_else(paths::cache_dir)
+    }
+
+    pub(crate) fn platform(&self) -> Platform {
+        match &self.platform.target {
+            Some(target) => Platform::from_triple(target),
+            None => Platform::current(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use vfs::MemoryFS;
+
+    use super::*;
+
+    fn load(text: &str) -> Result<Config> {
+        let root = VfsPath::new(MemoryFS::new());
+        root.join(CONFIG_FILENAME)
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(text.as_bytes())
+            .unwrap();
+        Config::load(&root)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn find_follows_symlinks() {
+        let tmp = std::env::temp_dir().join(format!("mqpkg-find-{}", std::process::id()));
+        let project = tmp.join("project");
+        let elsewhere = tmp.join("elsewhere");
+        std::fs::create_dir_all(project.join("sub")).unwrap();
+        std::fs::create_dir_all(&elsewhere).unwrap();
+        std::fs::write(project.join(CONFIG_FILENAME), "repositories: []\n").unwrap();
+        std::os::unix::fs::symlink(project.join("sub"), elsewhere.join("link")).unwrap();
+
+        let link = Utf8PathBuf::try_from(elsewhere.join("link")).unwrap();
+        let found = Config::find(link);
+        let expected = Utf8PathBuf::try_from(dunce::canonicalize(&project).unwrap()).unwrap();
+        std::fs::remove_dir_all(&tmp).unwrap();
+
+        assert_eq!(found.unwrap(), expected);
+    }
+
+    #[test]
+    fn interpolate_env_vars() {
+        std::env::set_var("MQPKG_TEST_HOST", "example.com");
+        std::env::set_var("MQPKG_TEST_REF", "${MQPKG_TEST_HOST}");
+
+        assert_eq!(
+            interpolate("https://${MQPKG_TEST_HOST}/index.json").unwrap(),
+            "https://example.com/index.json"
+        );
+        assert_eq!(
+            interpolate("https://example.com/index.json").unwrap(),
+            "https://example.com/index.json"
+        );
+        // Substituted values aren't expanded again.
+        assert_eq!(
+            interpolate("${MQPKG_TEST_REF}").unwrap(),
+            "${MQPKG_TEST_HOST}"
+        );
+    }
+
+    #[test]
+    fn interpolate_missing_env_var() {
+        assert!(matches!(
+            interpolate("https://${MQPKG_TEST_MISSING}/index.json"),
+            Err(ConfigError::MissingEnvVar { var }) if var == "MQPKG_TEST_MISSING"
+        ));
+    }
+
+    #[test]
+    fn interpolate_invalid_env_var_reference() {
+        for value in [
+            "https://${MQPKG_TEST_HOST/index.json",
+            "https://${}/index.json",
+            "https://${A${B}}/x",
+        ] {
+            assert!(matches!(
+                interpolate(value),
+                Err(ConfigError::InvalidEnvVarReference { value: v }) if v == value
+            ));
+        }
+    }
+
+    fn span<'t>(text: &'t str, package: &str) -> Option<&'t str> {
+        let spans = pin_spans(text);
+        let (offset, len) = spans.get(&package.parse().unwrap())?;
+        Some(&text[*offset..offset + len])
+    }
+
+    #[test]
+    fn pin_spans_block_style() {
+        let text = "repositories: []\npins:\n  # comment\n  foo: \"^1.0\"\n  'bar': \"2\"\n";
+        assert_eq!(span(text, "foo"), Some("foo"));
+        assert_eq!(span(text, "bar"), Some("'bar'"));
+    }
+
+    #[test]
+    fn pin_spans_flow_style() {
+        let text = "# café\npins: {foo: \"^1.0\", bar: {nested: 1}, baz: \"3\"}\n";
+        assert_eq!(span(text, "foo"), Some("foo"));
+        assert_eq!(span(text, "bar"), Some("bar"));
+        assert_eq!(span(text, "baz"), Some("baz"));
+    }
+
+    #[test]
+    fn pin_spans_only_top_level() {
+        let text = "hooks:\n  pins:\n    foo: \"1\"\npins:\n  bar: \"2\"\n";
+        assert_eq!(span(text, "foo"), None);
+        assert_eq!(span(text, "bar"), Some("bar"));
+    }
+
+    fn pin(text: &str, package: &str, requirement: &str) -> String {
+        let root = VfsPath::new(MemoryFS::new());
+        let filename = root.join(CONFIG_FILENAME).unwrap();
+        filename
+            .create_file()
+            .unwrap()
+            .write_all(text.as_bytes())
+            .unwrap();
+        Config::pin(
+            &root,
+            &package.parse().unwrap(),
+            &requirement.parse().unwrap(),
+        )
+        .unwrap();
+        read_config(&filename).unwrap()
+    }
+
+    #[test]
+    fn pin_replaces_existing_in_place() {
+        let text = "# repos\nrepositories: []\npins:\n  # why\n  Foo: '^1.0'  # old\n";
+        assert_eq!(
+            pin(text, "foo", "^2.0"),
+            "# repos\nrepositories: []\npins:\n  # why\n  Foo: \"^2.0\"  # old\n"
+        );
+    }
+
+    #[test]
+    fn pin_inserts_in_place() {
+        let text = "repositories: []\npins:\n    bar: \"1\" # keep\n";
+        assert_eq!(
+            pin(text, "foo", "^2.0"),
+            "repositories: []\npins:\n    foo: \"^2.0\"\n    bar: \"1\" # keep\n"
+        );
+    }
+
+    #[test]
+    fn pin_appends_pins() {
+        let text = "# comment\nrepositories: []";
+        assert_eq!(
+            pin(text, "foo", "^2.0"),
+            "# comment\nrepositories: []\npins:\n  foo: \"^2.0\"\n"
+        );
+    }
+
+    #[test]
+    fn pin_rewrites_flow_pins() {
+        let text = "# comment\npins: {bar: \"1\"}\n";
+        let data: Value = serde_yaml::from_str(&pin(text, "foo", "^2.0")).unwrap();
+        assert_eq!(data["pins"]["bar"], Value::from("1"));
+        assert_eq!(data["pins"]["foo"], Value::from("^2.0"));
+    }
+
+    fn unpin(text: &str, package: &str) -> (bool, String) {
+        let root = VfsPath::new(MemoryFS::new());
+        let filename = root.join(CONFIG_FILENAME).unwrap();
+        filename
+            .create_file()
+            .unwrap()
+            .write_all(text.as_bytes())
+            .unwrap();
+        let removed = Config::unpin(&root, &package.parse().unwrap()).unwrap();
+        (removed, read_config(&filename).unwrap())
+    }
+
+    #[test]
+    fn unpin_removes_in_place() {
+        let text = "repositories: []\npins:\n  # why\n  foo: \"^1.0\"  # old\n  bar: \"1\"\n";
+        assert_eq!(
+            unpin(text, "foo"),
+            (
+                true,
+                "repositories: []\npins:\n  # why\n  bar: \"1\"\n".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn unpin_removes_last_pin_with_pins() {
+        let text = "# repos\nrepositories: []\npins:\n  Foo: '^1.0'\n";
+        assert_eq!(
+            unpin(text, "foo"),
+            (true, "# repos\nrepositories: []\n".to_string())
+        );
+    }
+
+    #[test]
+    fn unpin_without_pin() {
+        let text = "# repos\nrepositories: []\npins:\n  bar: \"1\"\n";
+        assert_eq!(unpin(text, "foo"), (false, text.to_string()));
+    }
+
+    #[test]
+    fn explicit_config_must_exist() {
+        let root = VfsPath::new(MemoryFS::new());
+        root.join(CONFIG_FILENAME)
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(b"repositories: []\n")
+            .unwrap();
+        let missing = std::env::temp_dir()
+            .join("mqpkg-test-missing")
+            .join(CONFIG_FILENAME);
+        let system = ConfigPath::Default(missing.clone());
+
+        let user = ConfigPath::Default(missing.clone());
+        let merged = Config::load_hierarchy(&root, &system, Some(&user)).unwrap();
+        assert_eq!(merged.sources.len(), 1);
+
+        let user = ConfigPath::Explicit(missing.clone());
+        assert!(matches!(
+            Config::load_hierarchy(&root, &system, Some(&user)),
+            Err(ConfigError::UnreadableConfig { path, .. }) if path == missing
+        ));
+    }
+
+    #[test]
+    fn tagged_includes() {
+        let data =
+            parse_yaml("hooks: !include hooks.yml\nrepositories:\n- !include repos.yml\n").unwrap();
+        assert_eq!(data["hooks"], Value::from("!include hooks.yml"));
+        assert_eq!(data["repositories"][0], Value::from("!include repos.yml"));
+    }
+
+    #[test]
+    fn includes_in_block_scalars_and_flow_mappings() {
+        let text = "description: |\n  !include notes.yml\nhooks: {a: !include x.yml, b: c}\n";
+        let data = parse_yaml(text).unwrap();
+        assert_eq!(data["description"], Value::from("!include notes.yml\n"));
+        assert_eq!(data["hooks"]["a"], Value::from("!include x.yml"));
+        assert_eq!(data["hooks"]["b"], Value::from("c"));
+    }
+
+    #[test]
+    fn packages_read_as_exported() {
+        let exported: Vec<PackageSpecifier> =
+            vec!["bar".parse().unwrap(), "foo[a]>=1.0.0".parse().unwrap()];
+        #[derive(serde::Serialize)]
+        struct Export<'a> {
+            packages: &'a [PackageSpecifier],
+        }
+        let text = serde_yaml::to_string(&Export {
+            packages: &exported,
+        })
+        .unwrap();
+
+        let config: Config = serde_yaml::from_str(&text).unwrap();
+        assert_eq!(config.packages(), exported.as_slice());
+    }
+
+    #[test]
+    fn env_vars_in_urls() {
+        std::env::set_var("MQPKG_TEST_HOST", "example.com");
+        let config = load(
+            "repositories:\n\
+             - https://${MQPKG_TEST_HOST}/a.json\n\
+             - name: b\n  url: https://${MQPKG_TEST_HOST}/b.json\n",
+        )
+        .unwrap();
+
+        let repos = config.repositories();
+        assert_eq!(repos[0].name, "https://${MQPKG_TEST_HOST}/a.json");
+        assert_eq!(repos[0].url.as_str(), "https://example.com/a.json");
+        assert_eq!(repos[1].url.as_str(), "https://example.com/b.json");
+    }
+
+    #[test]
+    fn missing_env_var_in_url() {
+        for text in [
+            "repositories:\n- https://${MQPKG_TEST_MISSING}/index.json\n",
+            "repositories:\n- name: a\n  url: https://${MQPKG_TEST_MISSING}/index.json\n",
+        ] {
+            assert!(matches!(
+                load(text),
+                Err(ConfigError::MissingEnvVar { var }) if var == "MQPKG_TEST_MISSING"
+            ));
+        }
+    }
+
+    #[test]
+    fn invalid_env_var_reference_in_url() {
+        let result = load("repositories:\n- https://${MQPKG_TEST_HOST/index.json\n");
+        assert!(matches!(
+            result,
+            Err(ConfigError::InvalidEnvVarReference { .. })
+        ));
+    }
 }
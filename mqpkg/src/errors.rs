@@ -17,6 +17,111 @@ pub enum InstallerError {
 
     #[error("error attempting to resolve dependencies")]
     ResolverError(#[from] SolverError),
+
+    #[error("{0} must be set when any other progress callbacks are")]
+    MissingProgressCallback(&'static str),
+
+    #[error("the archive for {name} is corrupted: {details}")]
+    CorruptedArchive { name: PackageName, details: String },
+
+    #[error("{package} and {owner} both install {path}")]
+    FileConflict {
+        path: String,
+        package: PackageName,
+        owner: PackageName,
+    },
+
+    #[error("the archive for {name} contains {path}, which is reserved for mqpkg itself")]
+    ProtectedPath { name: PackageName, path: String },
+
+    #[error("the archive for {package} at {url} is not a supported format")]
+    UnsupportedArchiveFormat {
+        package: PackageName,
+        url: Box<url::Url>,
+    },
+
+    #[error("could not write installed files")]
+    FileSystemError(#[from] vfs::VfsError),
+
+    #[error(
+        "could not find any packages named:{}",
+        .0.iter().map(|name| format!("\n    {name}")).collect::<String>()
+    )]
+    PackagesNotFound(Vec<PackageName>),
+
+    #[error("the locked version of {name} ({version}) is not available from any repository")]
+    LockedVersionUnavailable {
+        name: PackageName,
+        version: semver::Version,
+    },
+
+    #[error("the {hook} hook failed with exit code {exit_code}: {stderr}")]
+    HookFailed {
+        hook: String,
+        exit_code: i32,
+        stderr: String,
+    },
+
+    #[error(
+        "post install script for {package} failed with exit code {exit_code}: {script}\n{stderr}"
+    )]
+    PostInstallScriptFailed {
+        package: PackageName,
+        script: String,
+        exit_code: i32,
+        stderr: String,
+    },
+
+    #[error(transparent)]
+    UninstallError(#[from] UninstallError),
+
+    #[error("there is no lock file")]
+    NoLockFile,
+
+    #[error("no repositories are configured")]
+    NoRepositories,
+
+    #[error("{0} is not installed")]
+    NotInstalled(PackageName),
+
+    #[error("no valid signature from a trusted key for {package}")]
+    SignatureVerificationFailed { package: PackageName },
+
+    #[error("{package} {version} has a critical security advisory ({id})")]
+    BlockedByAdvisory {
+        package: PackageName,
+        version: semver::Version,
+        id: String,
+    },
+
+    #[error("{package} is pinned to {pin}, which conflicts with the requested {requested}")]
+    PinConflict {
+        package: PackageName,
+        pin: semver::VersionReq,
+        requested: semver::VersionReq,
+    },
+
+    #[error("the extra constraint {extra} on {name} conflicts with the requested {existing}")]
+    ExtraConstraintConflict {
+        name: PackageName,
+        existing: semver::VersionReq,
+        extra: semver::VersionReq,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum UninstallError {
+    #[error("{0} was not requested, so it can't be uninstalled")]
+    NotRequested(PackageName),
+
+    #[error(
+        "{package} is still required by {}",
+        .required_by.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    StillRequired {
+        package: PackageName,
+        required_by: Vec<PackageName>,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -24,11 +129,25 @@ pub enum PackageNameError {
     #[error("names must have at least one character")]
     TooShort,
 
-    #[error("names must begin with an alpha character")]
-    NoStartingAlpha { name: String, character: String },
+    #[error("package name '{name}': names must begin with an alpha character, found '{character}' at position {position}")]
+    NoStartingAlpha {
+        name: String,
+        character: String,
+        position: usize,
+        /// Where the character is within the name, in bytes.
+        offset: usize,
+        length: usize,
+    },
 
-    #[error("names must contain only alphanumeric characters")]
-    InvalidCharacter { name: String, character: String },
+    #[error("package name '{name}': names must contain only alphanumeric characters, hyphens, underscores, or dots between other characters, found '{character}' at position {position}")]
+    InvalidCharacter {
+        name: String,
+        character: String,
+        position: usize,
+        /// Where the character is within the name, in bytes.
+        offset: usize,
+        length: usize,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -39,8 +158,29 @@ pub enum PackageSpecifierError {
     #[error(transparent)]
     InvalidPackageName(#[from] PackageNameError),
 
-    #[error(transparent)]
-    InvalidVersionRequirement(#[from] semver::Error),
+    #[error("invalid version requirement '{requirement}': {source}")]
+    InvalidVersionRequirement {
+        requirement: String,
+        source: semver::Error,
+    },
+
+    #[error("version requirement '{requirement}' matches everything, leave it off instead")]
+    EmptyVersionRequirement { requirement: String },
+
+    #[error("invalid extras '{extras}', extras must be a comma separated list of names in []")]
+    InvalidExtras { extras: String },
+}
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("could not read the package archive")]
+    IoError(#[from] std::io::Error),
+
+    #[error("the package archive does not contain a manifest.json")]
+    Missing,
+
+    #[error("could not parse manifest.json")]
+    Deserialize(#[from] serde_json::Error),
 }
 
 #[derive(Error, Debug)]
@@ -51,14 +191,38 @@ pub enum ConfigError {
     #[error("invalid configuration")]
     InvalidConfig { source: serde_yaml::Error },
 
+    #[error("could not read configuration file {path:?}")]
+    UnreadableConfig {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
     #[error("invalid url")]
     InvalidURL { source: url::ParseError },
 
+    #[error("could not include {path:?}")]
+    InvalidInclude {
+        path: std::path::PathBuf,
+        source: vfs::VfsError,
+    },
+
+    #[error("{path:?} includes itself")]
+    CircularInclude { path: std::path::PathBuf },
+
+    #[error("unable to save configuration file")]
+    UnableToSave { source: vfs::VfsError },
+
     #[error("unable to traverse directory")]
     DirectoryTraversalError { source: vfs::VfsError },
 
     #[error("unable to locate a valid directory")]
     NoTargetDirectoryFound,
+
+    #[error("environment variable {var} is not set")]
+    MissingEnvVar { var: String },
+
+    #[error("invalid environment variable reference in '{value}'")]
+    InvalidEnvVarReference { value: String },
 }
 
 #[derive(Error, Debug)]
@@ -75,11 +239,51 @@ pub enum DBError {
     #[error("could not parse state.yml")]
     InvalidState { source: serde_yaml::Error },
 
+    #[error("state.yml is version {found}, which needs at least version {minimum} to use")]
+    IncompatibleState { found: u32, minimum: u32 },
+
+    #[error("could not convert the pkgdb to or from JSON")]
+    InvalidExport { source: serde_json::Error },
+
+    #[error("unsupported export schema version: {version:?}")]
+    UnsupportedExport { version: Option<serde_json::Value> },
+
+    #[error("could not parse mqpkg.lock")]
+    InvalidLockFile { source: serde_yaml::Error },
+
+    #[error("could not read or write the transaction history")]
+    InvalidHistory { source: serde_json::Error },
+
+    #[error("there is no transaction {0} in the history")]
+    UnknownTransaction(u64),
+
     #[error("could not initiate transaction")]
     TransactionError(#[from] TransactionError),
 
     #[error("no transaction")]
     NoTransaction,
+
+    #[error("the pkgdb was opened read only")]
+    ReadOnly,
+
+    #[error("{0} has not been requested")]
+    PackageNotFound(PackageName),
+
+    #[error("cannot pin {name} to {pin}, it has been requested as {conflicts_with}")]
+    PinConflict {
+        name: PackageName,
+        pin: semver::Version,
+        conflicts_with: semver::VersionReq,
+    },
+
+    #[error("{0} has not been pinned")]
+    NotPinned(PackageName),
+
+    #[error("a backup already exists at {0}")]
+    BackupExists(String),
+
+    #[error("{0} is not a pkgdb backup, it has no state.yml")]
+    InvalidBackup(String),
 }
 
 #[derive(Error, Debug)]
@@ -92,6 +296,65 @@ pub enum RepositoryError {
 
     #[error("could not access local file")]
     IoError(#[from] std::io::Error),
+
+    #[error("could not access cached metadata")]
+    CacheError(#[from] vfs::VfsError),
+
+    #[error("could not decompress repository metadata: {0}")]
+    DecompressionError(String),
+
+    #[error("no repository metadata has been loaded")]
+    NotLoaded,
+
+    #[error("{url} is not available while offline")]
+    Offline { url: Box<url::Url> },
+
+    #[error("no release of {name} {version} is available")]
+    UnknownRelease {
+        name: PackageName,
+        version: semver::Version,
+    },
+
+    #[error("no download urls for {name} {version}")]
+    NoDownloadUrls {
+        name: PackageName,
+        version: semver::Version,
+    },
+
+    #[error("digest mismatch for {url}, expected {expected} but got {got}")]
+    DigestMismatch {
+        url: Box<url::Url>,
+        expected: String,
+        got: String,
+    },
+
+    #[error("could not fetch signature from {url}")]
+    SignatureFetchFailed { url: Box<url::Url> },
+
+    #[error("the index at {url} is not signed by its signing key")]
+    InvalidSignature { url: Box<url::Url> },
+
+    #[error("the index at {url} is format version {version}, which isn't supported")]
+    UnsupportedIndexVersion { url: Box<url::Url>, version: u32 },
+
+    #[error("{url} is not a valid local file url")]
+    InvalidFileUrl { url: Box<url::Url> },
+
+    #[error("the dependencies at {url} are not hosted by the repository at {repository}")]
+    UntrustedDependenciesUrl {
+        url: Box<url::Url>,
+        repository: Box<url::Url>,
+    },
+
+    #[error("the dependencies at {url} have no digest, but the index they come from is verified")]
+    UnverifiedDependencies { url: Box<url::Url> },
+
+    #[error(
+        "could not fetch {} repositories: {}",
+        .0.len(),
+        .0.iter().map(|(url, err)| format!("{url} ({err})")).collect::<Vec<_>>().join(", ")
+    )]
+    MultipleFetchErrors(Vec<(url::Url, RepositoryError)>),
 }
 
 #[derive(Error, Debug)]
@@ -122,8 +385,62 @@ pub enum SolverError {
     #[error("{0}")]
     Failure(String),
 
+    #[error("resolving dependencies was cancelled")]
+    Cancelled,
+
+    #[error("resolving dependencies timed out after {}s", .elapsed.as_secs())]
+    Timeout { elapsed: std::time::Duration },
+
     // These errors shouldn't actually be possible, because our implementation
     // of our dependency provider makes sure of that.
     #[error("impossible error")]
     Impossible,
 }
+
+#[cfg(feature = "miette-errors")]
+impl miette::Diagnostic for PackageNameError {
+    fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+        match self {
+            PackageNameError::TooShort => None,
+            PackageNameError::NoStartingAlpha { name, .. }
+            | PackageNameError::InvalidCharacter { name, .. } => Some(name),
+        }
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = miette::LabeledSpan> + '_>> {
+        let (label, offset, length) = match self {
+            PackageNameError::TooShort => return None,
+            PackageNameError::NoStartingAlpha { offset, length, .. } => {
+                ("expected a letter", offset, length)
+            }
+            PackageNameError::InvalidCharacter { offset, length, .. } => {
+                ("not allowed here", offset, length)
+            }
+        };
+
+        Some(Box::new(std::iter::once(miette::LabeledSpan::new(
+            Some(label.to_string()),
+            *offset,
+            *length,
+        ))))
+    }
+}
+
+// The only problems with a specifier that we can point at are in its name, so
+// we show just the name, along with where in it the problem is.
+#[cfg(feature = "miette-errors")]
+impl miette::Diagnostic for PackageSpecifierError {
+    fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+        match self {
+            PackageSpecifierError::InvalidPackageName(err) => err.source_code(),
+            _ => None,
+        }
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = miette::LabeledSpan> + '_>> {
+        match self {
+            PackageSpecifierError::InvalidPackageName(err) => err.labels(),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::process::{Command, Stdio};
+
+use crate::config::HooksConfig;
+use crate::errors::InstallerError;
+use crate::logging::{info, trace};
+use crate::types::PackageName;
+
+const LOGNAME: &str = "mqpkg::hooks";
+
+type Result<T, E = InstallerError> = core::result::Result<T, E>;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Hook {
+    PreInstall,
+    PostInstall,
+    PreUninstall,
+    PostUninstall,
+}
+
+impl Hook {
+    fn name(&self) -> &'static str {
+        match self {
+            Hook::PreInstall => "pre_install",
+            Hook::PostInstall => "post_install",
+            Hook::PreUninstall => "pre_uninstall",
+            Hook::PostUninstall => "post_uninstall",
+        }
+    }
+
+    fn command<'c>(&self, config: &'c HooksConfig) -> Option<&'c str> {
+        match self {
+            Hook::PreInstall => config.pre_install.as_deref(),
+            Hook::PostInstall => config.post_install.as_deref(),
+            Hook::PreUninstall => config.pre_uninstall.as_deref(),
+            Hook::PostUninstall => config.post_uninstall.as_deref(),
+        }
+    }
+}
+
+/// Run `hook` if it has been configured, passing along what we're doing in
+/// the environment.
+pub(crate) fn run(
+    hook: Hook,
+    config: Option<&HooksConfig>,
+    target: &str,
+    operation: &str,
+    packages: &[&PackageName],
+) -> Result<()> {
+    let command = match config.and_then(|c| hook.command(c)) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    info!(target: LOGNAME, "running {} hook: {}", hook.name(), command);
+    let packages: Vec<String> = packages.iter().map(|p| p.to_string()).collect();
+    let failed = |exit_code: i32, stderr: String| InstallerError::HookFailed {
+        hook: hook.name().to_string(),
+        exit_code,
+        stderr,
+    };
+
+    // We let the hook write to our stdout, but we capture stderr so that we
+    // can report it if the hook fails.
+    let output = shell(command)
+        .env("MQPKG_TARGET", target)
+        .env("MQPKG_PACKAGES", packages.join(" "))
+        .env("MQPKG_OPERATION", operation)
+        .stdin(Stdio::null())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| failed(-1, e.to_string()))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if !output.status.success() {
+        // There's no exit code when the hook is killed by a signal.
+        return Err(failed(output.status.code().unwrap_or(-1), stderr));
+    }
+
+    if !stderr.is_empty() {
+        trace!(target: LOGNAME, "{} hook stderr: {}", hook.name(), stderr);
+    }
+
+    Ok(())
+}
+
+/// Run one of the post install scripts for `package` from within `dir`, and
+/// return everything that it wrote to stdout and stderr.
+pub(crate) fn run_script(package: &PackageName, script: &str, dir: &str) -> Result<String> {
+    info!(
+        target: LOGNAME,
+        "running post install script for {}: {}",
+        package,
+        script
+    );
+    let failed = |exit_code: i32, stderr: String| InstallerError::PostInstallScriptFailed {
+        package: package.clone(),
+        script: script.to_string(),
+        exit_code,
+        stderr,
+    };
+
+    let output = shell(script)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| failed(-1, e.to_string()))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(failed(
+            output.status.code().unwrap_or(-1),
+            stderr.trim().to_string(),
+        ));
+    }
+
+    Ok(format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        stderr
+    ))
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
@@ -3,82 +3,445 @@
 // for complete details.
 
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use console::{style, Emoji};
-use semver::VersionReq;
-use vfs::VfsPath;
+use semver::{Op, Version, VersionReq};
+use url::Url;
+use vfs::{PhysicalFS, VfsPath};
 
+use crate::advisory::Severity;
+use crate::archive::{ArchiveFormat, DownloadGuard};
+use crate::cache::{ArchiveKey, PackageCache};
+use crate::errors::RepositoryError;
+use crate::hooks::Hook;
+use crate::logging::{debug, info, trace, warn};
 use crate::pkgdb::transaction;
 use crate::progress::Progress;
 use crate::repository::Repository;
-use crate::resolver::Solver;
-use crate::types::{PackageName, Packages};
+use crate::resolver::{CandidateTrait, Requirement, Solver, SolverContext};
+use crate::summary::millis;
+use crate::types::{version_req, Package, Packages, WithSource};
 
-pub use crate::config::Config;
-pub use crate::errors::{InstallerError, SolverError};
-pub use crate::types::PackageSpecifier;
+#[cfg(feature = "rustsec")]
+pub use crate::advisory::RustsecDb;
+pub use crate::advisory::{Advisory, AdvisoryDb, Severity as AdvisorySeverity};
+pub use crate::config::{Config, ConfigPath, ConfigSource, MergedConfig};
+pub use crate::errors::{DBError, InstallerError, ManifestError, SolverError, UninstallError};
+pub use crate::paths::cache_dir;
+pub use crate::pkgdb::{
+    Database, InstalledEntry, LockFile, LockedPackage, MemoryPackageStore, Operation,
+    PackageRequest, PackageStore, Snapshot, Transaction, TransactionLogEntry, TransactionManager,
+};
+pub use crate::repository::check_repository;
+pub use crate::resolver::{CancelHandle, ConflictReport, Incompatibility, RequiredBy};
+pub use crate::summary::{
+    CheckError, DependencyChain, DependencyEdge, GcSummary, InstallPlan, InstallSummary,
+    InstallTimings, InstalledPackage, OutdatedPackage, PackageChange, PackageDependency,
+    PackageDetail, PlannedPackage, SearchResult, SolutionDiff, UpgradePlan,
+};
+pub use crate::types::{
+    DependencyNode, DependencyTree, PackageManifest, PackageName, PackageSpecifier, SolverSolution,
+};
 
 pub(crate) mod progress;
 pub(crate) mod types;
 
+mod advisory;
+mod archive;
+mod cache;
 mod config;
 mod errors;
+mod hooks;
+mod logging;
+mod paths;
 mod pkgdb;
+mod platform;
 mod repository;
 mod resolver;
+mod summary;
 
 static OFFICE_PAPER: Emoji<'_, '_> = Emoji("📄 ", "");
 static LOOKING_GLASS: Emoji<'_, '_> = Emoji("🔍 ", "");
+static CLIPBOARD: Emoji<'_, '_> = Emoji("📋 ", "");
+static PACKAGE: Emoji<'_, '_> = Emoji("📦 ", "");
+
+const LOGNAME: &str = "mqpkg";
 
 type Result<T, E = InstallerError> = core::result::Result<T, E>;
 
-pub struct Installer<'p, T> {
+pub struct Installer<'p, T, S: PackageStore = Database> {
     config: config::Config,
-    db: pkgdb::Database,
+    fs: VfsPath,
+    db: S,
     progress: Progress<'p, T>,
     console: Option<Box<dyn Fn(&str) + 'p>>,
+    report_stats: bool,
+    cache: Option<PackageCache>,
+    exclusions: HashMap<PackageName, HashSet<Version>>,
+    extra_constraints: HashMap<PackageName, VersionReq>,
+    fail_fast: bool,
+    advisory_db: Option<Box<dyn AdvisoryDb + 'p>>,
+    ignore_advisories: bool,
+    run_scripts: bool,
+    dry_run: bool,
+    use_proxy: bool,
+    offline: bool,
+    cache_ttl: Duration,
+    max_concurrent_requests: usize,
+    cancel: CancelHandle,
+    solver_timeout: Option<Duration>,
+    target: String,
 }
 
 impl<'p, T> Installer<'p, T> {
+    #[deprecated(note = "use Installer::builder instead")]
     pub fn new(config: config::Config, fs: VfsPath, rid: &str) -> Result<Installer<T>> {
-        // We're using MD5 here because it's short and fast, we're not using
-        // this in a security sensitive aspect.
-        let id = format!("{:x}", md5::compute(rid));
-        let db = pkgdb::Database::new(fs, id)?;
+        InstallerBuilder::new(config, fs, rid).build()
+    }
 
-        Ok(Installer {
-            config,
-            db,
-            progress: Progress::new(),
-            console: None,
-        })
+    /// Start building an Installer, see `InstallerBuilder::new`.
+    pub fn builder(config: config::Config, fs: VfsPath, rid: &str) -> InstallerBuilder<'p, T> {
+        InstallerBuilder::new(config, fs, rid)
+    }
+
+    /// Copy the pkgdb to `dest`, see `Database::backup`.
+    pub fn backup(&self, dest: &VfsPath) -> Result<()> {
+        Ok(self.db.backup(dest)?)
+    }
+
+    /// Replace the pkgdb with the backup at `src`, see `Database::restore`.
+    pub fn restore(&self, src: &VfsPath) -> Result<()> {
+        Ok(self.db.restore(src)?)
+    }
+
+    /// Export the whole pkgdb as JSON, see `Database::export_json`.
+    pub fn export_json(&self) -> Result<serde_json::Value> {
+        Ok(self.db.export_json()?)
     }
 
+    /// Replace the whole pkgdb with one that was exported with `export_json`,
+    /// see `Database::import_json`.
+    pub fn import_json(&mut self, json: &serde_json::Value) -> Result<()> {
+        Ok(self.db.import_json(json)?)
+    }
+
+    /// Every transaction that has changed the pkgdb, see `Database::history`.
+    pub fn history(&self) -> Result<Vec<TransactionLogEntry>> {
+        Ok(self.db.history()?)
+    }
+
+    /// Undo the transaction `to_id`, and everything after it, by going back to
+    /// what had been requested and pinned before it, and then resolving and
+    /// installing that.
+    pub fn rollback(&mut self, to_id: u64) -> Result<InstallSummary> {
+        let (mut summary, committing) = transaction!(self.db, commit: !self.dry_run, {
+            self.db.replay(to_id)?;
+            let requested = self.db.requested()?;
+            (self.run(requested)?, Instant::now())
+        });
+        summary.timings.commit_ms = millis(committing.elapsed());
+
+        Ok(summary)
+    }
+}
+
+impl<'p, T, S: PackageStore> Installer<'p, T, S> {
+    #[deprecated(note = "use InstallerBuilder::console instead")]
     pub fn with_console(&mut self, cb: impl Fn(&str) + 'p) {
         self.console = Some(Box::new(cb))
     }
 
+    #[deprecated(note = "use InstallerBuilder::progress_start instead")]
     pub fn with_progress_start(&mut self, cb: impl FnMut(u64) -> T + 'p) {
         self.progress.with_progress_start(Box::new(cb))
     }
 
+    #[deprecated(note = "use InstallerBuilder::progress_spinner instead")]
     pub fn with_progress_spinner(&mut self, cb: impl FnMut(&'static str) -> T + 'p) {
         self.progress.with_progress_spinner(Box::new(cb))
     }
 
+    #[deprecated(note = "use InstallerBuilder::progress_update instead")]
     pub fn with_progress_update(&mut self, cb: impl FnMut(&T, u64) + 'p) {
         self.progress.with_progress_update(Box::new(cb))
     }
 
+    #[deprecated(note = "use InstallerBuilder::progress_finish instead")]
     pub fn with_progress_finish(&mut self, cb: impl FnMut(&T) + 'p) {
         self.progress.with_progress_finish(Box::new(cb))
     }
+
+    fn create(builder: InstallerBuilder<'p, T>, db: S) -> Result<Installer<'p, T, S>> {
+        let InstallerBuilder {
+            config,
+            fs,
+            rid,
+            progress,
+            console,
+            report_stats,
+            use_cache,
+            fail_fast,
+            ignore_advisories,
+            run_scripts,
+            dry_run,
+            use_proxy,
+            offline,
+            solver_timeout,
+            extra_constraints,
+        } = builder;
+
+        // The cache is shared between every target, so it lives on the real
+        // filesystem rather than in our fs. If we can't create it, we just carry
+        // on without one, since it's only an optimization.
+        let cache = if use_cache {
+            let dir = config.cache_dir();
+            match std::fs::create_dir_all(&dir) {
+                Ok(()) => Some(PackageCache::new(PhysicalFS::new(dir).into())),
+                Err(err) => {
+                    warn!(target: LOGNAME, "could not create cache {:?}: {}", dir, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let cache_ttl = config.cache_ttl();
+        let max_concurrent_requests = config.max_concurrent_requests();
+        Ok(Installer {
+            config,
+            fs,
+            db,
+            progress,
+            console,
+            report_stats,
+            cache,
+            exclusions: HashMap::new(),
+            extra_constraints,
+            fail_fast,
+            advisory_db: None,
+            ignore_advisories,
+            run_scripts,
+            dry_run,
+            use_proxy,
+            offline,
+            cache_ttl,
+            max_concurrent_requests,
+            cancel: CancelHandle::default(),
+            solver_timeout,
+            target: rid,
+        })
+    }
 }
 
-impl<'p, T> Installer<'p, T> {
-    pub fn install(&mut self, packages: &[PackageSpecifier]) -> Result<()> {
-        transaction!(self.db, {
+pub struct InstallerBuilder<'p, T> {
+    config: config::Config,
+    fs: VfsPath,
+    rid: String,
+    progress: Progress<'p, T>,
+    console: Option<Box<dyn Fn(&str) + 'p>>,
+    report_stats: bool,
+    use_cache: bool,
+    fail_fast: bool,
+    ignore_advisories: bool,
+    run_scripts: bool,
+    dry_run: bool,
+    use_proxy: bool,
+    offline: bool,
+    solver_timeout: Option<Duration>,
+    extra_constraints: HashMap<PackageName, VersionReq>,
+}
+
+impl<'p, T> InstallerBuilder<'p, T> {
+    /// The `fs` can be any vfs backend, such as a `MemoryFS` or an `OverlayFS`,
+    /// and both the pkgdb and all extracted packages are written to it. Note
+    /// that `file://` repository and package urls are still read from the real
+    /// filesystem, and the package cache (see `cache_dir`) lives there too.
+    pub fn new(config: config::Config, fs: VfsPath, rid: &str) -> InstallerBuilder<'p, T> {
+        InstallerBuilder {
+            config,
+            fs,
+            rid: rid.to_string(),
+            progress: Progress::new(),
+            console: None,
+            report_stats: true,
+            use_cache: true,
+            fail_fast: false,
+            ignore_advisories: false,
+            run_scripts: false,
+            dry_run: false,
+            use_proxy: true,
+            offline: false,
+            solver_timeout: None,
+            extra_constraints: HashMap::new(),
+        }
+    }
+
+    pub fn console(mut self, cb: impl Fn(&str) + 'p) -> InstallerBuilder<'p, T> {
+        self.console = Some(Box::new(cb));
+        self
+    }
+
+    pub fn progress_start(mut self, cb: impl FnMut(u64) -> T + 'p) -> InstallerBuilder<'p, T> {
+        self.progress.with_progress_start(cb);
+        self
+    }
+
+    pub fn progress_spinner(
+        mut self,
+        cb: impl FnMut(&'static str) -> T + 'p,
+    ) -> InstallerBuilder<'p, T> {
+        self.progress.with_progress_spinner(cb);
+        self
+    }
+
+    pub fn progress_update(mut self, cb: impl FnMut(&T, u64) + 'p) -> InstallerBuilder<'p, T> {
+        self.progress.with_progress_update(cb);
+        self
+    }
+
+    pub fn progress_finish(mut self, cb: impl FnMut(&T) + 'p) -> InstallerBuilder<'p, T> {
+        self.progress.with_progress_finish(cb);
+        self
+    }
+
+    /// Called to create a bar for each package that gets downloaded, with the
+    /// size of the download in bytes, or 0 if that isn't known.
+    pub fn download_start(
+        mut self,
+        cb: impl FnMut(u64, &str) -> T + 'p,
+    ) -> InstallerBuilder<'p, T> {
+        self.progress.with_download_start(cb);
+        self
+    }
+
+    pub fn download_update(mut self, cb: impl FnMut(&T, u64) + 'p) -> InstallerBuilder<'p, T> {
+        self.progress.with_download_update(cb);
+        self
+    }
+
+    pub fn download_finish(mut self, cb: impl FnMut(&T) + 'p) -> InstallerBuilder<'p, T> {
+        self.progress.with_download_finish(cb);
+        self
+    }
+
+    pub fn report_stats(mut self, enabled: bool) -> InstallerBuilder<'p, T> {
+        self.report_stats = enabled;
+        self
+    }
+
+    pub fn use_cache(mut self, enabled: bool) -> InstallerBuilder<'p, T> {
+        self.use_cache = enabled;
+        self
+    }
+
+    /// Fail if any repository can't be fetched, rather than carrying on with
+    /// the repositories that could be.
+    pub fn fail_fast(mut self, enabled: bool) -> InstallerBuilder<'p, T> {
+        self.fail_fast = enabled;
+        self
+    }
+
+    /// Install packages even if they have critical security advisories.
+    pub fn ignore_advisories(mut self, enabled: bool) -> InstallerBuilder<'p, T> {
+        self.ignore_advisories = enabled;
+        self
+    }
+
+    /// Run the post install scripts that packages declare, which are run from
+    /// within the target directory. They can run anything at all, so they're
+    /// only run when this is enabled.
+    pub fn run_scripts(mut self, enabled: bool) -> InstallerBuilder<'p, T> {
+        self.run_scripts = enabled;
+        self
+    }
+
+    /// Resolve and plan everything as normal, but stop short of changing
+    /// anything, so that we can see what would happen.
+    pub fn dry_run(mut self, enabled: bool) -> InstallerBuilder<'p, T> {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// Send requests through the configured proxy, or the one from the
+    /// environment, when disabled no proxy is used at all.
+    pub fn use_proxy(mut self, enabled: bool) -> InstallerBuilder<'p, T> {
+        self.use_proxy = enabled;
+        self
+    }
+
+    /// Never touch the network, repository metadata has to have been cached by
+    /// an earlier fetch, and packages have to be in the package cache.
+    pub fn offline(mut self, enabled: bool) -> InstallerBuilder<'p, T> {
+        self.offline = enabled;
+        self
+    }
+
+    /// Give up on resolving dependencies if it takes longer than `timeout`,
+    /// by default resolving takes as long as it takes.
+    pub fn solver_timeout(mut self, timeout: Duration) -> InstallerBuilder<'p, T> {
+        self.solver_timeout = Some(timeout);
+        self
+    }
+
+    /// Add requirements that only apply to what this Installer resolves, without
+    /// ever being saved as having been requested. Like pins, a constraint only
+    /// narrows down a package that ends up being installed anyway, it never
+    /// causes a package to be installed.
+    pub fn extra_constraints(
+        mut self,
+        constraints: &[PackageSpecifier],
+    ) -> InstallerBuilder<'p, T> {
+        for constraint in constraints.iter() {
+            self.extra_constraints
+                .entry(constraint.name.clone())
+                .and_modify(|req| {
+                    req.comparators
+                        .extend(constraint.version.comparators.iter().cloned())
+                })
+                .or_insert_with(|| constraint.version.clone());
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<Installer<'p, T>> {
+        // We're using MD5 here because it's short and fast, we're not using
+        // this in a security sensitive aspect.
+        let id = format!("{:x}", md5::compute(&self.rid));
+        if !self.dry_run {
+            Database::initialize(&self.fs)?;
+        }
+        let db = Database::new(self.fs.clone(), id)?
+            .with_max_history(self.config.max_history())
+            .with_read_only(self.dry_run);
+
+        self.build_with_store(db)
+    }
+
+    /// Build an Installer that keeps track of its state in `store`, rather than
+    /// in the pkgdb inside of the target directory.
+    pub fn build_with_store<S: PackageStore>(self, store: S) -> Result<Installer<'p, T, S>> {
+        if let Some(name) = self.progress.missing() {
+            return Err(InstallerError::MissingProgressCallback(name));
+        }
+
+        Installer::create(self, store)
+    }
+}
+
+impl<'p, T, S: PackageStore> Installer<'p, T, S> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub fn install(&mut self, packages: &[PackageSpecifier]) -> Result<InstallSummary> {
+        let names: Vec<&PackageName> = packages.iter().map(|p| &p.name).collect();
+        self.hook(Hook::PreInstall, "install", &names)?;
+
+        // Our transaction body returns the moment it finished, so we can time how
+        // long it takes to commit everything.
+        let (mut summary, committing) = transaction!(self.db, commit: !self.dry_run, {
+            self.db.describe(Operation::Install);
             // Add all of the packages being requested to the set of all requested packages.
             for package in packages {
                 self.db.add(package)?;
@@ -87,56 +450,1968 @@ impl<'p, T> Installer<'p, T> {
             // Get all of the requested packages, we need this to ensure that this install
             // doesn't invalidate any of the version requirements of the already requested
             // packages.
-            let mut requested = HashMap::new();
-            for req in self.db.requested()?.values() {
-                requested.insert(req.name.clone(), req.version.clone());
+            let requested = self.db.requested()?;
+
+            // If nothing has changed since we last resolved, then the lock file
+            // already has the answer, and using it keeps repeated installs the
+            // same, even if something newer has been released since. The lock
+            // file knows nothing about any extra constraints though, so those
+            // always need resolving.
+            let summary = match LockFile::load(&self.fs)? {
+                Some(lock)
+                    if self.extra_constraints.is_empty()
+                        && lock.is_current(&self.with_pinned(requested.clone())?) =>
+                {
+                    info!(target: LOGNAME, "requested packages unchanged, using lock file");
+                    self.run_locked(&lock)?
+                }
+                _ => self.run(requested)?,
+            };
+
+            (summary, Instant::now())
+        });
+        summary.timings.commit_ms = millis(committing.elapsed());
+
+        self.hook(Hook::PostInstall, "install", &names)?;
+
+        Ok(summary)
+    }
+
+    /// What installing `packages` would change, without changing anything,
+    /// not even what has been requested.
+    pub fn diff(&mut self, packages: &[PackageSpecifier]) -> Result<InstallPlan> {
+        let (mut requested, mut extras, installed) = transaction!(self.db, commit: false, {
+            (
+                self.db.requested()?,
+                self.db.requested_extras()?,
+                self.db.list_installed()?,
+            )
+        });
+
+        // These get merged the same way that adding them to the pkgdb would,
+        // and only then do the pins get added on top.
+        for package in packages {
+            requested.insert(package.name.clone(), package.version.clone());
+            if package.extras.is_empty() {
+                extras.remove(&package.name);
+            } else {
+                extras.insert(package.name.clone(), package.extras.clone());
+            }
+        }
+
+        let requested = transaction!(self.db, commit: false, { self.with_pinned(requested)? });
+
+        let repository = self.repository()?;
+        check_exists(&repository, &requested)?;
+        let (solution, _) =
+            self.resolve(&repository, requested, &extras, &installed, &HashMap::new())?;
+
+        Ok(solution.diff().install_plan())
+    }
+
+    /// Resolve `packages` on their own, as if nothing had ever been requested
+    /// or installed, without reading or writing anything in the pkgdb, not
+    /// even the cached repository metadata kept there. Every package in the
+    /// solution is one that would be added.
+    pub fn resolve_only(&self, packages: &[PackageSpecifier]) -> Result<SolverSolution> {
+        let mut requested = HashMap::new();
+        let mut extras = HashMap::new();
+        for package in packages {
+            requested.insert(package.name.clone(), package.version.clone());
+            if !package.extras.is_empty() {
+                extras.insert(package.name.clone(), package.extras.clone());
+            }
+        }
+
+        let repository = self.repository_with(self.offline, false)?;
+        check_exists(&repository, &requested)?;
+        let (solution, _) = self.resolve(
+            &repository,
+            requested,
+            &extras,
+            &HashMap::new(),
+            &HashMap::new(),
+        )?;
+
+        Ok(solution)
+    }
+
+    /// Install exactly the packages in `lock`, without resolving anything.
+    pub fn install_locked(&mut self, lock: &LockFile) -> Result<InstallSummary> {
+        let names: Vec<&PackageName> = lock.packages.iter().map(|p| &p.name).collect();
+        self.hook(Hook::PreInstall, "install", &names)?;
+
+        let (mut summary, committing) = transaction!(self.db, commit: !self.dry_run, {
+            self.db.describe(Operation::Install);
+            (self.run_locked(lock)?, Instant::now())
+        });
+        summary.timings.commit_ms = millis(committing.elapsed());
+
+        self.hook(Hook::PostInstall, "install", &names)?;
+
+        Ok(summary)
+    }
+
+    pub fn update(&mut self, packages: &[PackageName]) -> Result<InstallSummary> {
+        let names: Vec<&PackageName> = packages.iter().collect();
+        self.hook(Hook::PreInstall, "update", &names)?;
+
+        let (mut summary, committing) = transaction!(self.db, commit: !self.dry_run, {
+            self.db.describe(Operation::Update);
+            let installed = self.db.list_installed()?;
+
+            // We can only update packages that we've actually got installed, since
+            // we need to know what version they're currently at.
+            for package in packages {
+                if !installed.contains_key(package) {
+                    return Err(InstallerError::NotInstalled(package.clone()));
+                }
+            }
+
+            let explicit = self.db.requested()?;
+            let extras = self.db.requested_extras()?;
+            let mut requested = explicit.clone();
+
+            // Every package that we're not updating is held at exactly the version
+            // that it's currently installed at, but this is only for this resolve,
+            // we don't want to persist these.
+            for (name, version) in installed.iter() {
+                if !packages.contains(name) {
+                    requested.insert(name.clone(), version_req(Op::Exact, version));
+                }
+            }
+
+            // The packages that we are updating have their requirement loosened to
+            // allow anything at least as new as what we have now. If they were
+            // explicitly requested, then that loosened requirement gets persisted.
+            for package in packages {
+                let version = version_req(Op::GreaterEq, &installed[package]);
+                if explicit.contains_key(package) {
+                    self.db.add(&PackageSpecifier {
+                        name: package.clone(),
+                        version: version.clone(),
+                        extras: extras.get(package).cloned().unwrap_or_default(),
+                    })?;
+                }
+                requested.insert(package.clone(), version);
+            }
+
+            (self.run(requested)?, Instant::now())
+        });
+        summary.timings.commit_ms = millis(committing.elapsed());
+
+        self.hook(Hook::PostInstall, "update", &names)?;
+
+        Ok(summary)
+    }
+
+    /// Upgrade `packages`, or everything that has been requested if `None`, to
+    /// the newest versions available. Unlike installing, nothing that is
+    /// already installed is downgraded unless there's no other way to resolve.
+    pub fn upgrade(&mut self, packages: Option<&[PackageSpecifier]>) -> Result<InstallSummary> {
+        self.upgrade_with(packages, false)
+    }
+
+    /// Upgrade just like `upgrade`, except that what was requested for each
+    /// package being upgraded is loosened to anything at least as new as what
+    /// is installed now, so that it can move on to a new major version. This
+    /// only applies where that isn't given a new requirement, and can't lift
+    /// the requirements that other packages have on it. Anything that does move
+    /// on is requested as `^new_version` from then on.
+    pub fn upgrade_major(
+        &mut self,
+        packages: Option<&[PackageSpecifier]>,
+    ) -> Result<InstallSummary> {
+        self.upgrade_with(packages, true)
+    }
+
+    fn upgrade_with(
+        &mut self,
+        packages: Option<&[PackageSpecifier]>,
+        major: bool,
+    ) -> Result<InstallSummary> {
+        let (mut summary, committing) = transaction!(self.db, commit: !self.dry_run, {
+            self.db.describe(Operation::Upgrade);
+            let installed = self.db.list_installed()?;
+            let mut requested = self.db.requested()?;
+
+            let targets: Vec<PackageName> = match packages {
+                Some(packages) => {
+                    for package in packages {
+                        if !installed.contains_key(&package.name) {
+                            return Err(InstallerError::NotInstalled(package.name.clone()));
+                        }
+
+                        // An upgrade can also tighten what was requested, which
+                        // we persist just like installing would.
+                        if package.version != VersionReq::STAR {
+                            self.db.add(package)?;
+                            requested.insert(package.name.clone(), package.version.clone());
+                        }
+                    }
+                    packages.iter().map(|p| p.name.clone()).collect()
+                }
+                None => installed.keys().cloned().collect(),
+            };
+
+            // Loosening a requirement only applies to this resolve, since
+            // persisting it would leave the package without any upper bound
+            // from then on.
+            let mut loosened = HashMap::new();
+            if major {
+                let explicit: Vec<&PackageName> = packages
+                    .unwrap_or_default()
+                    .iter()
+                    .filter(|p| p.version != VersionReq::STAR)
+                    .map(|p| &p.name)
+                    .collect();
+                for name in targets.iter().filter(|n| !explicit.contains(n)) {
+                    let version = match installed.get(name) {
+                        Some(version) => version,
+                        None => continue,
+                    };
+                    if let Some(req) = requested.get_mut(name) {
+                        let original = std::mem::replace(req, version_req(Op::GreaterEq, version));
+                        loosened.insert(name.clone(), original);
+                    }
+                }
+            }
+
+            // Anything that isn't being upgraded is held at exactly the version it
+            // is at now, while everything being upgraded can only go up. Neither
+            // of these are persisted, they only apply to this resolve.
+            let mut floors = HashMap::new();
+            for (name, version) in installed.iter() {
+                if targets.contains(name) {
+                    floors.insert(name.clone(), version_req(Op::GreaterEq, version));
+                } else {
+                    floors.insert(name.clone(), version_req(Op::Exact, version));
+                }
+            }
+
+            let summary = self.run_checked(requested, &floors, |_, _| Ok(()), upgrade_message)?;
+
+            // Anything that ended up outside of what was requested for it has
+            // moved on to a new major version, so what gets persisted is the
+            // usual requirement for that, otherwise the very next install would
+            // take us right back to the old one.
+            let upgraded = self.db.list_installed()?;
+            let extras = self.db.requested_extras()?;
+            let mut persisted = false;
+            for (name, original) in loosened.into_iter() {
+                match upgraded.get(&name) {
+                    Some(version) if !original.matches(version) => {
+                        self.db.add(&PackageSpecifier {
+                            version: version_req(Op::Caret, version),
+                            extras: extras.get(&name).cloned().unwrap_or_default(),
+                            name,
+                        })?;
+                        persisted = true;
+                    }
+                    _ => {}
+                }
+            }
+
+            // The lock file was written for what was requested before that, but
+            // it still has exactly the packages that the new requests resolve to.
+            if persisted {
+                if let Some(mut lock) = LockFile::load(&self.fs)? {
+                    let requested = self.db.requested()?;
+                    lock.requested = self.with_pinned(requested)?.into_iter().collect();
+                    lock.save(&self.fs)?;
+                }
+            }
+
+            (summary, Instant::now())
+        });
+        summary.timings.commit_ms = millis(committing.elapsed());
+
+        Ok(summary)
+    }
+
+    /// Remove `packages` from the requested packages, along with anything that
+    /// was only installed because something depended on them.
+    pub fn uninstall(&mut self, packages: &[PackageName]) -> Result<InstallSummary> {
+        let names: Vec<&PackageName> = packages.iter().collect();
+        self.hook(Hook::PreUninstall, "uninstall", &names)?;
+
+        let (mut summary, committing) = transaction!(self.db, commit: !self.dry_run, {
+            self.db.describe(Operation::Uninstall);
+            let mut requested = self.db.requested()?;
+
+            // We can only uninstall what was requested, anything else is either
+            // not installed, or is only there as a dependency.
+            for package in packages {
+                requested
+                    .remove(package)
+                    .ok_or_else(|| UninstallError::NotRequested(package.clone()))?;
+                self.db.remove(package)?;
+            }
+
+            // If anything that is still requested depends on one of the packages
+            // being uninstalled, then removing it isn't going to do anything, so
+            // we refuse rather than silently leaving it installed.
+            let roots: Vec<PackageName> = requested.keys().cloned().collect();
+            let check = |repository: &Repository, solution: &SolverSolution| {
+                for package in packages {
+                    if solution.contains(package) {
+                        return Err(UninstallError::StillRequired {
+                            package: package.clone(),
+                            required_by: dependents(repository, solution, &roots, package),
+                        }
+                        .into());
+                    }
+                }
+                Ok(())
+            };
+            let summary = self.run_checked(requested, &HashMap::new(), check, plan_message)?;
+
+            (summary, Instant::now())
+        });
+        summary.timings.commit_ms = millis(committing.elapsed());
+
+        self.hook(Hook::PostUninstall, "uninstall", &names)?;
+
+        Ok(summary)
+    }
+
+    /// Compare what is installed, and what is in the package cache, against the
+    /// lock file. This only reads what has already been committed, so it never
+    /// waits on another Installer.
+    pub fn check(&self) -> Result<Vec<CheckError>> {
+        let lock = LockFile::load(&self.fs)?.ok_or(InstallerError::NoLockFile)?;
+        let installed: HashMap<PackageName, Version> = self
+            .db
+            .snapshot()?
+            .installed
+            .into_iter()
+            .map(|(name, entry)| (name, entry.version))
+            .collect();
+
+        let mut errors = Vec::new();
+        for locked in lock.packages.iter() {
+            match installed.get(&locked.name) {
+                None => errors.push(CheckError::Missing(
+                    locked.name.clone(),
+                    locked.version.clone(),
+                )),
+                Some(version) if version != &locked.version => {
+                    errors.push(CheckError::VersionMismatch {
+                        name: locked.name.clone(),
+                        locked: locked.version.clone(),
+                        installed: version.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+
+            if let Some(err) = self.check_cached(locked) {
+                errors.push(err);
+            }
+        }
+
+        let mut unlocked: Vec<(&PackageName, &Version)> = installed
+            .iter()
+            .filter(|(name, _)| !lock.packages.iter().any(|l| &l.name == *name))
+            .collect();
+        unlocked.sort();
+        for (name, version) in unlocked {
+            errors.push(CheckError::NotLocked(name.clone(), version.clone()));
+        }
+
+        Ok(errors)
+    }
+
+    fn check_cached(&self, locked: &LockedPackage) -> Option<CheckError> {
+        // Without a cache there's nothing that we can check.
+        let cache = self.cache.as_ref()?;
+        let (name, version) = (&locked.name, &locked.version);
+
+        let got = match &locked.digest {
+            Some(expected) => match cache.cached_digest(name, version, expected) {
+                Some(got) if got.eq_ignore_ascii_case(expected) => return None,
+                Some(got) => got,
+                None => return Some(CheckError::NotCached(name.clone(), version.clone())),
+            },
+            None if cache.contains(name, version) => return None,
+            None => return Some(CheckError::NotCached(name.clone(), version.clone())),
+        };
+
+        Some(CheckError::DigestMismatch {
+            name: name.clone(),
+            version: version.clone(),
+            expected: locked.digest.clone().unwrap_or_default(),
+            got,
+        })
+    }
+
+    /// Explain why `package` is installed, by following everything that depends
+    /// on it back to the packages that were requested. Every path is included,
+    /// but each package along them is only looked at once.
+    pub fn why(&mut self, package: &PackageName) -> Result<DependencyChain> {
+        let (installed, requested) = transaction!(self.db, commit: false, {
+            (self.db.list_installed()?, self.db.requested()?)
+        });
+
+        if !installed.contains_key(package) {
+            return Err(InstallerError::NotInstalled(package.clone()));
+        }
+
+        // The pkgdb doesn't record dependencies, so we get them from the
+        // repository metadata for the versions that we have installed.
+        let repository = self.repository()?;
+        let mut dependents = HashMap::<PackageName, Vec<(PackageName, VersionReq)>>::new();
+        for (name, version) in installed.iter() {
+            let deps = match repository.package(name, version) {
+                Some(pkg) => repository.dependencies(&pkg).unwrap_or_default(),
+                None => {
+                    warn!(
+                        target: LOGNAME,
+                        "{name} {version} is no longer available, its dependencies are unknown"
+                    );
+                    continue;
+                }
+            };
+
+            for (dep, req) in deps.into_iter() {
+                if installed.contains_key(&dep) {
+                    dependents.entry(dep).or_default().push((name.clone(), req));
+                }
+            }
+        }
+        // Rather than following every path separately, which grows
+        // exponentially when dependencies are shared, we only record what
+        // requires each package the first time we reach it, which also keeps
+        // us from going around dependency cycles.
+        let mut chain = DependencyChain {
+            package: package.clone(),
+            required_by: BTreeMap::new(),
+            requested: BTreeMap::new(),
+        };
+        let mut queue = VecDeque::from([package.clone()]);
+        while let Some(name) = queue.pop_front() {
+            if chain.required_by.contains_key(&name) {
+                continue;
+            }
+            if let Some(req) = requested.get(&name) {
+                chain.requested.insert(name.clone(), req.clone());
+            }
+
+            let mut edges: Vec<DependencyEdge> = dependents
+                .remove(&name)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(requirer, constraint)| DependencyEdge {
+                    requirer,
+                    constraint,
+                })
+                .collect();
+            edges.sort_by(|l, r| l.requirer.cmp(&r.requirer));
+            queue.extend(edges.iter().map(|edge| edge.requirer.clone()));
+            chain.required_by.insert(name, edges);
+        }
+
+        Ok(chain)
+    }
+
+    /// Explain why no version of `package` that satisfies its requirement would
+    /// be selected alongside everything that has been requested, without
+    /// actually changing anything.
+    pub fn why_not(&mut self, package: &PackageSpecifier) -> Result<String> {
+        let (requested, mut extras) = transaction!(self.db, commit: false, {
+            let requested = self.db.requested()?;
+            (self.with_pinned(requested)?, self.db.requested_extras()?)
+        });
+        if !package.extras.is_empty() {
+            let wanted = extras.entry(package.name.clone()).or_default();
+            wanted.extend(package.extras.iter().cloned());
+            wanted.sort();
+            wanted.dedup();
+        }
+        let requested = self.apply_pins(requested)?;
+        let exclusions = self.exclusions();
+        let repository = self.repository()?;
+
+        let _resolving = self.cancel.resolving();
+        let spinner = self.progress.spinner("Resolving dependencies");
+        let solver = Solver::new(&repository)
+            .with_pins(&self.pins())
+            .with_extras(&extras)
+            .with_warn_iterations(self.config.resolver().warn_iterations);
+        let explanation = solver.why_not(
+            requested,
+            &exclusions,
+            &package.name,
+            &Requirement::new(package.version.clone()),
+            SolverContext::new(|| spinner.update(1)).with_cancel_handle(&self.cancel),
+        );
+        spinner.finish();
+
+        Ok(explanation?)
+    }
+
+    /// Everything that is installed, laid out as the tree of dependencies that
+    /// starts at each requested package.
+    pub fn tree(&self) -> Result<DependencyTree> {
+        self.tree_from(&self.repository()?)
+    }
+
+    /// Like `tree`, except that this only uses repository metadata that has
+    /// already been cached, so it never touches the network. Anything we don't
+    /// have metadata for is shown without any dependencies.
+    pub fn cached_tree(&self) -> Result<DependencyTree> {
+        // Having nothing cached for a repository isn't an error here, it just
+        // means that we don't have any metadata from it, but anything else
+        // that goes wrong still is.
+        let (repository, result) = self
+            .unfetched_repository(true, true)?
+            .fetch_partial(self.config.repositories(), || {});
+        for (_, err) in result.failed {
+            if !matches!(err, RepositoryError::Offline { .. }) {
+                return Err(err.into());
+            }
+        }
+
+        self.tree_from(&repository)
+    }
+
+    fn tree_from(&self, repository: &Repository) -> Result<DependencyTree> {
+        let Snapshot {
+            requested,
+            installed,
+        } = self.db.snapshot()?;
+
+        // Like with why, the dependencies come from the repository metadata for
+        // the versions that we have installed.
+        let mut dependencies = HashMap::<PackageName, Vec<PackageName>>::new();
+        for (name, entry) in installed.iter() {
+            let mut deps: Vec<PackageName> = match repository.package(name, &entry.version) {
+                Some(pkg) => repository
+                    .dependencies(&pkg)
+                    .map(|deps| deps.keys().cloned().collect())
+                    .unwrap_or_default(),
+                None => {
+                    warn!(
+                        target: LOGNAME,
+                        "{name} {} is no longer available, its dependencies are unknown",
+                        entry.version
+                    );
+                    continue;
+                }
+            };
+            deps.retain(|dep| installed.contains_key(dep));
+            deps.sort();
+            dependencies.insert(name.clone(), deps);
+        }
+
+        let versions: HashMap<PackageName, Version> = installed
+            .into_iter()
+            .map(|(name, entry)| (name, entry.version))
+            .collect();
+        let mut roots: Vec<&PackageName> = requested
+            .keys()
+            .filter(|name| versions.contains_key(*name))
+            .collect();
+        roots.sort();
+
+        Ok(DependencyTree {
+            root: roots
+                .into_iter()
+                .map(|name| dependency_node(name, &versions, &dependencies, &mut Vec::new()))
+                .collect(),
+        })
+    }
+
+    /// Everything that is installed, sorted by name. This only reads what has
+    /// already been committed, so it never waits on another Installer.
+    pub fn list(&self) -> Result<Vec<InstalledPackage>> {
+        let Snapshot {
+            requested,
+            installed,
+        } = self.db.snapshot()?;
+
+        let mut packages: Vec<InstalledPackage> = installed
+            .into_iter()
+            .map(|(name, entry)| InstalledPackage {
+                requested_version: requested.get(&name).cloned(),
+                resolved_version: entry.version,
+                source_name: entry.source,
+                install_time: entry.installed_at,
+                name,
+            })
+            .collect();
+        packages.sort_by(|l, r| l.name.cmp(&r.name));
+
+        Ok(packages)
+    }
+
+    /// Every installed package that has a newer version available, which still
+    /// satisfies what it was requested with, sorted by name. Packages that were
+    /// only installed as a dependency can move to any newer version.
+    pub fn outdated(&self) -> Result<Vec<OutdatedPackage>> {
+        let Snapshot {
+            requested,
+            installed,
+        } = self.db.snapshot()?;
+        let repository = self.repository()?;
+
+        let mut outdated = Vec::new();
+        for (name, entry) in installed.into_iter() {
+            let req = requested.get(&name).cloned().unwrap_or(VersionReq::STAR);
+
+            // Candidates are in the order of our repositories, so only taking a
+            // version when it's strictly newer means ties go to the first one.
+            let mut newest: Option<(Version, Package)> = None;
+            for candidate in repository.candidates(&name) {
+                let version = Version::from(candidate.version());
+                if version <= entry.version || !req.matches(&version) {
+                    continue;
+                }
+                if matches!(&newest, Some((current, _)) if &version <= current) {
+                    continue;
+                }
+
+                let package =
+                    Package::new(name.clone(), version.clone(), candidate.source().clone());
+                newest = Some((version, package));
+            }
+
+            if let Some((available, package)) = newest {
+                outdated.push(OutdatedPackage {
+                    source: repository
+                        .source_name(&package)
+                        .unwrap_or_default()
+                        .to_string(),
+                    name,
+                    installed: entry.version,
+                    available,
+                });
+            }
+        }
+        outdated.sort_by(|l, r| l.name.cmp(&r.name));
+
+        Ok(outdated)
+    }
+
+    /// The details of `name`, as it is installed, or for the newest version
+    /// that is available if it isn't installed, or None if it's neither.
+    pub fn show(&self, name: &PackageName) -> Result<Option<PackageDetail>> {
+        let Snapshot {
+            requested,
+            installed,
+        } = self.db.snapshot()?;
+
+        let repository = self.repository()?;
+        let mut available: Vec<Version> = repository
+            .candidates(name)
+            .iter()
+            .map(|c| Version::from(c.version()))
+            .collect();
+        available.sort_by(|l, r| r.cmp(l));
+        available.dedup();
+
+        let entry = installed.get(name);
+        let version = match (entry, available.first()) {
+            (Some(entry), _) => entry.version.clone(),
+            (None, Some(newest)) => newest.clone(),
+            (None, None) => return Ok(None),
+        };
+
+        let mut detail = PackageDetail {
+            name: name.clone(),
+            version: version.clone(),
+            installed: entry.is_some(),
+            install_time: entry.and_then(|e| e.installed_at),
+            requested_constraint: requested.get(name).cloned(),
+            dependencies: Vec::new(),
+            available_versions: available,
+            source_url: None,
+            description: None,
+            homepage: None,
+            license: None,
+            authors: Vec::new(),
+            tags: Vec::new(),
+        };
+
+        match repository.package(name, &version) {
+            Some(package) => {
+                let mut dependencies: Vec<PackageDependency> = repository
+                    .dependencies(&package)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(name, requirement)| PackageDependency {
+                        installed: installed.get(&name).map(|e| e.version.clone()),
+                        name,
+                        requirement,
+                    })
+                    .collect();
+                dependencies.sort_by(|l, r| l.name.cmp(&r.name));
+
+                detail.dependencies = dependencies;
+                detail.source_url = repository.source_url(&package).cloned();
+                detail.description = repository.description(&package).map(String::from);
+                detail.homepage = repository.homepage(&package).cloned();
+                detail.license = repository.license(&package).map(String::from);
+                detail.authors = repository.authors(&package).to_vec();
+                detail.tags = repository.tags(&package).to_vec();
+            }
+            None => warn!(
+                target: LOGNAME,
+                "{name} {} is no longer available, its details are unknown",
+                version
+            ),
+        }
+
+        Ok(Some(detail))
+    }
+
+    /// Find every available package whose name or description contains
+    /// `query`, with the best matches first.
+    pub fn search(&mut self, query: &str) -> Result<Vec<SearchResult>> {
+        // With nothing to fetch, every search would just find nothing, which
+        // looks a lot like the package doesn't exist.
+        if self.config.repositories().is_empty() {
+            return Err(InstallerError::NoRepositories);
+        }
+
+        Ok(self.repository()?.search(query)?)
+    }
+
+    /// Remove every cached archive that isn't for a package in the lock file,
+    /// or that is installed, along with the cached metadata for any repository
+    /// that is no longer configured. A dry run only reports what it would
+    /// remove.
+    ///
+    /// The archive cache is shared between every target, so archives that any
+    /// other target has recorded that it uses are kept too. Targets only record
+    /// what they use when they install something, so a target that hasn't
+    /// installed anything since this was recorded can still lose archives,
+    /// which only means that they would be downloaded again.
+    pub fn gc(&self) -> Result<GcSummary> {
+        let mut keep: HashSet<ArchiveKey> = self
+            .db
+            .snapshot()?
+            .installed
+            .into_iter()
+            .filter_map(|(name, entry)| Some((name, entry.version, entry.digest?)))
+            .collect();
+        if let Some(lock) = LockFile::load(&self.fs)? {
+            keep.extend(
+                lock.packages
+                    .into_iter()
+                    .filter_map(|p| Some((p.name, p.version, p.digest?))),
+            );
+        }
+
+        let mut summary = GcSummary::default();
+        if let Some(cache) = &self.cache {
+            let dir = self.config.cache_dir();
+            for (filename, len) in cache.gc(&self.target, &keep, self.dry_run) {
+                summary.removed_files.push(dir.join(filename));
+                summary.bytes_freed += len;
+            }
+        }
+
+        let urls: HashSet<Url> = self
+            .config
+            .repositories()
+            .iter()
+            .flat_map(|r| std::iter::once(&r.url).chain(r.mirrors.iter()))
+            .cloned()
+            .collect();
+        let target = PathBuf::from(&self.target);
+        let pruned =
+            repository::prune_repo_cache(pkgdb::pkgdb_path(&self.fs)?, &urls, self.dry_run);
+        for (path, len) in pruned {
+            summary
+                .removed_files
+                .push(target.join(path.trim_start_matches('/')));
+            summary.bytes_freed += len;
+        }
+
+        info!(
+            target: LOGNAME,
+            "{} {} file(s) from the caches",
+            if self.dry_run { "would remove" } else { "removed" },
+            summary.removed_files.len()
+        );
+
+        Ok(summary)
+    }
+
+    /// Everything that has been requested, sorted by name, in a form that can
+    /// be shared and then given to `import`.
+    pub fn export(&mut self) -> Result<Vec<PackageSpecifier>> {
+        let (requested, extras) = transaction!(self.db, commit: false, {
+            (self.db.requested()?, self.db.requested_extras()?)
+        });
+
+        let mut specifiers: Vec<PackageSpecifier> = requested
+            .into_iter()
+            .map(|(name, version)| PackageSpecifier {
+                extras: extras.get(&name).cloned().unwrap_or_default(),
+                name,
+                version,
+            })
+            .collect();
+        specifiers.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(specifiers)
+    }
+
+    /// The exact version of every package in the lock file, sorted by name, so
+    /// that the same set of packages can be installed again somewhere else.
+    /// Without a lock file, we resolve what has been requested instead.
+    pub fn freeze(&mut self) -> Result<Vec<PackageSpecifier>> {
+        let mut versions: Vec<(PackageName, Version)> = match LockFile::load(&self.fs)? {
+            Some(lock) => lock
+                .packages
+                .into_iter()
+                .map(|p| (p.name, p.version))
+                .collect(),
+            None => {
+                let (requested, extras, installed) = transaction!(self.db, commit: false, {
+                    let requested = self.db.requested()?;
+                    (
+                        self.with_pinned(requested)?,
+                        self.db.requested_extras()?,
+                        self.db.list_installed()?,
+                    )
+                });
+
+                let repository = self.repository()?;
+                check_exists(&repository, &requested)?;
+                let (solution, _) =
+                    self.resolve(&repository, requested, &extras, &installed, &HashMap::new())?;
+                solution
+                    .iter()
+                    .map(|(name, package)| (name.clone(), package.version().clone()))
+                    .collect()
+            }
+        };
+        versions.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(versions
+            .into_iter()
+            .map(|(name, version)| PackageSpecifier {
+                name,
+                version: version_req(Op::Exact, &version),
+                extras: Vec::new(),
+            })
+            .collect())
+    }
+
+    /// Replace everything that has been requested with `specifiers`, and then
+    /// resolve and install that. If anything fails, then nothing is changed,
+    /// not even what has been requested.
+    pub fn import(&mut self, specifiers: &[PackageSpecifier]) -> Result<InstallSummary> {
+        let names: Vec<&PackageName> = specifiers.iter().map(|p| &p.name).collect();
+        self.hook(Hook::PreInstall, "import", &names)?;
+
+        let (mut summary, committing) = transaction!(self.db, commit: !self.dry_run, {
+            self.db.describe(Operation::Import);
+            for name in self.db.requested()?.keys() {
+                self.db.remove(name)?;
             }
+            for package in specifiers {
+                self.db.add(package)?;
+            }
+
+            let requested = self.db.requested()?;
+            (self.run(requested)?, Instant::now())
+        });
+        summary.timings.commit_ms = millis(committing.elapsed());
 
-            // Grab our repository, and pre-emptively fetch all of the data
-            let repository = self.repository()?;
-            self.console(step(1, 2, OFFICE_PAPER, "Fetched package metadata"));
+        self.hook(Hook::PostInstall, "import", &names)?;
 
-            // Resolve all of our requirements to a full set of packages that we should install
-            let _solution = self.resolve(repository, requested)?;
-            self.console(step(2, 2, LOOKING_GLASS, "Resolved dependencies"));
+        Ok(summary)
+    }
+
+    /// Require `package` at exactly `version` from now on, until it is unpinned.
+    /// This only changes the pkgdb, nothing is installed until the next time
+    /// that we resolve.
+    pub fn pin(&mut self, package: &PackageName, version: &Version) -> Result<()> {
+        transaction!(self.db, commit: !self.dry_run, {
+            self.db.describe(Operation::Pin);
+            self.db.pin(package, version)?
         });
+        Ok(())
+    }
 
+    /// Stop requiring `package` at the version that it was pinned to.
+    pub fn unpin(&mut self, package: &PackageName) -> Result<()> {
+        transaction!(self.db, commit: !self.dry_run, {
+            self.db.describe(Operation::Unpin);
+            self.db.unpin(package)?
+        });
         Ok(())
     }
+
+    /// Use cached repository metadata for up to `ttl` after it was fetched,
+    /// rather than for however long the configuration says to. A `ttl` of zero
+    /// means that we always check with the server.
+    pub fn with_cache_ttl(&mut self, ttl: Duration) {
+        self.cache_ttl = ttl;
+    }
+
+    /// Never have more than `max` HTTP requests in flight at once, rather than
+    /// however many the configuration allows. A `max` of 1 means that
+    /// everything is fetched one at a time.
+    pub fn with_max_concurrent_requests(&mut self, max: usize) {
+        self.max_concurrent_requests = max;
+    }
+
+    /// Exclude specific versions of packages from being resolved, on top of any
+    /// exclusions in the configuration.
+    pub fn with_exclusions(&mut self, exclusions: HashMap<PackageName, HashSet<Version>>) {
+        for (package, versions) in exclusions.into_iter() {
+            self.exclusions.entry(package).or_default().extend(versions);
+        }
+    }
+
+    /// A handle that cancels resolving dependencies, which can be used from
+    /// another thread, such as a signal handler, while this Installer is busy.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Check every resolved package against `db` before anything is installed.
+    pub fn with_advisory_db(&mut self, db: impl AdvisoryDb + 'p) {
+        self.advisory_db = Some(Box::new(db));
+    }
+
+    pub fn is_package_cached(&self, package: &PackageName, version: &Version) -> bool {
+        match &self.cache {
+            Some(cache) => cache.contains(package, version),
+            None => false,
+        }
+    }
 }
 
-impl<'p, T> Installer<'p, T> {
-    fn console<S: AsRef<str>>(&self, msg: S) {
+impl<'p, T, S: PackageStore> Installer<'p, T, S> {
+    fn console<M: AsRef<str>>(&self, msg: M) {
         if let Some(cb) = &self.console {
             (cb)(msg.as_ref());
         }
     }
 
+    fn hook(&self, hook: Hook, operation: &str, packages: &[&PackageName]) -> Result<()> {
+        // Hooks are expected to have side effects, so a dry run can't run them.
+        if self.dry_run {
+            return Ok(());
+        }
+
+        hooks::run(hook, self.config.hooks(), &self.target, operation, packages)
+    }
+
+    fn run(&mut self, requested: HashMap<PackageName, VersionReq>) -> Result<InstallSummary> {
+        self.run_checked(requested, &HashMap::new(), |_, _| Ok(()), plan_message)
+    }
+
+    // Like run, except that `check` gets a chance to reject the solution after
+    // we've resolved it, but before anything has actually been changed. Any
+    // `floors` are preferred, but are dropped if nothing satisfies them.
+    fn run_checked(
+        &mut self,
+        requested: HashMap<PackageName, VersionReq>,
+        floors: &HashMap<PackageName, VersionReq>,
+        check: impl FnOnce(&Repository, &SolverSolution) -> Result<()>,
+        describe: fn(&SolutionDiff) -> String,
+    ) -> Result<InstallSummary> {
+        let mut timings = InstallTimings::default();
+        let mut steps = StepCounter::new(4);
+        let requested = self.with_pinned(requested)?;
+
+        // Grab our repository, and pre-emptively fetch all of the data
+        let start = Instant::now();
+        let repository = self.repository()?;
+        timings.fetch_metadata_ms = millis(start.elapsed());
+        self.console(steps.next(OFFICE_PAPER, "Fetched package metadata"));
+
+        // The resolver can't tell the difference between a package that doesn't
+        // exist and one that has no versions that satisfy our requirements, so
+        // we check for the former up front to give a clearer error.
+        check_exists(&repository, &requested)?;
+
+        // Resolve all of our requirements to a full set of packages that we should install
+        let start = Instant::now();
+        let extras = self.db.requested_extras()?;
+        let installed = self.db.list_installed()?;
+        let (solution, resolver_iterations) =
+            self.resolve(&repository, requested, &extras, &installed, floors)?;
+        timings.resolve_ms = millis(start.elapsed());
+        debug!(
+            target: LOGNAME,
+            "resolved {} packages, {} added, {} changed, {} removed, {} unchanged",
+            solution.package_count(),
+            solution.diff().installed.len(),
+            solution.diff().upgraded.len() + solution.diff().downgraded.len(),
+            solution.diff().removed.len(),
+            solution.diff().unchanged.len()
+        );
+        self.console(steps.next(LOOKING_GLASS, "Resolved dependencies"));
+        check(&repository, &solution)?;
+
+        // Check for any security advisories before we actually install anything.
+        self.check_advisories(&solution)?;
+
+        // Figure out what is actually going to change from what we have now.
+        let diff = self.plan(&solution)?;
+        self.console(steps.next(CLIPBOARD, &describe(&diff)));
+        if self.dry_run {
+            return Ok(InstallSummary {
+                diff,
+                timings,
+                resolver_iterations,
+            });
+        }
+
+        // Download and extract all of the packages that have changed.
+        self.apply(&repository, &solution, &diff, &mut timings)?;
+        self.write_lock(&repository, &solution)?;
+        self.console(steps.next(PACKAGE, "Installed packages"));
+
+        Ok(InstallSummary {
+            diff,
+            timings,
+            resolver_iterations,
+        })
+    }
+
+    fn run_locked(&mut self, lock: &LockFile) -> Result<InstallSummary> {
+        let mut timings = InstallTimings::default();
+        let mut steps = StepCounter::new(3);
+
+        // We still need our repository, since that's where we download from.
+        let start = Instant::now();
+        let repository = self.repository()?;
+        timings.fetch_metadata_ms = millis(start.elapsed());
+        self.console(steps.next(OFFICE_PAPER, "Fetched package metadata"));
+
+        // The lock file is already a full set of packages, so we just need to
+        // find where each of them is available from, rather than resolving.
+        let mut packages = Packages::new();
+        for locked in lock.packages.iter() {
+            let package = repository
+                .package(&locked.name, &locked.version)
+                .ok_or_else(|| InstallerError::LockedVersionUnavailable {
+                    name: locked.name.clone(),
+                    version: locked.version.clone(),
+                })?;
+            packages.insert(locked.name.clone(), package);
+        }
+        let solution = SolverSolution::new(packages, &self.db.list_installed()?);
+
+        self.check_advisories(&solution)?;
+
+        let diff = self.plan(&solution)?;
+        self.console(steps.next(CLIPBOARD, &plan_message(&diff)));
+        if self.dry_run {
+            return Ok(InstallSummary {
+                diff,
+                timings,
+                resolver_iterations: 0,
+            });
+        }
+
+        self.apply(&repository, &solution, &diff, &mut timings)?;
+        self.console(steps.next(PACKAGE, "Installed packages"));
+
+        Ok(InstallSummary {
+            diff,
+            timings,
+            resolver_iterations: 0,
+        })
+    }
+
+    fn write_lock(&mut self, repository: &Repository, solution: &SolverSolution) -> Result<()> {
+        // This runs after everything has been installed, so the pkgdb knows
+        // where each package was actually downloaded from.
+        let requested = self.db.requested()?;
+        let urls = self.db.source_urls()?;
+        let lock = LockFile {
+            requested: self.with_pinned(requested)?.into_iter().collect(),
+            packages: solution
+                .iter()
+                .map(|(_, package)| LockedPackage {
+                    name: package.name().clone(),
+                    version: package.version().clone(),
+                    source_url: urls.get(package.name()).cloned(),
+                    digest: repository.digest(package).map(|d| d.to_string()),
+                })
+                .collect(),
+        };
+
+        Ok(lock.save(&self.fs)?)
+    }
+
     fn repository(&self) -> Result<Repository> {
+        self.repository_with(self.offline, true)
+    }
+
+    fn repository_with(&self, offline: bool, use_repo_cache: bool) -> Result<Repository> {
+        let repository = self.unfetched_repository(offline, use_repo_cache)?;
         let bar = self
             .progress
             .bar(self.config.repositories().len().try_into().unwrap());
-        let repository = Repository::new()?.fetch(self.config.repositories(), || bar.update(1))?;
+        let repository = if self.fail_fast {
+            match repository.fetch_parallel(self.config.repositories(), || bar.update(1)) {
+                Ok(repository) => repository,
+                Err(err) => {
+                    bar.finish();
+                    return Err(err.into());
+                }
+            }
+        } else {
+            repository.fetch(self.config.repositories(), || bar.update(1))?
+        };
         bar.finish();
 
         Ok(repository)
     }
 
+    // A Repository that is set up the way that our configuration says, but
+    // that hasn't fetched anything yet.
+    fn unfetched_repository(&self, offline: bool, use_repo_cache: bool) -> Result<Repository> {
+        let proxy = self.use_proxy.then(|| self.config.proxy());
+        let mut repository = Repository::new(self.config.platform(), proxy)?
+            .with_rate_limit(self.config.http_requests_per_second())
+            .with_retry(self.config.retry().clone())
+            .with_max_concurrent_requests(self.max_concurrent_requests)
+            .with_offline(offline)
+            .with_fail_fast(self.fail_fast);
+        // The metadata cache gets written to as we fetch, which a dry run
+        // isn't allowed to do, but it can still read from it.
+        if use_repo_cache {
+            let root = pkgdb::pkgdb_path(&self.fs)?;
+            repository = if self.dry_run {
+                repository.with_read_only_repo_cache(root, self.cache_ttl)
+            } else {
+                repository.with_repo_cache(root, self.cache_ttl)
+            };
+        }
+        if let Some(cache) = &self.cache {
+            repository = repository.with_package_cache(cache.clone());
+        }
+
+        Ok(repository)
+    }
+
     fn resolve(
         &self,
-        repository: Repository,
+        repository: &Repository,
         requested: HashMap<PackageName, VersionReq>,
-    ) -> Result<Packages> {
+        extras: &HashMap<PackageName, Vec<String>>,
+        installed: &HashMap<PackageName, Version>,
+        floors: &HashMap<PackageName, VersionReq>,
+    ) -> Result<(SolverSolution, u64)> {
+        let requested = self.apply_pins(requested)?;
+        let exclusions = self.exclusions();
+
+        // Floors work just like pins, in that they only apply to a package if
+        // it ends up in the solution at all.
+        let mut pins = self.pins();
+        for (package, floor) in floors.iter() {
+            pins.entry(package.clone())
+                .or_insert_with(|| VersionReq {
+                    comparators: vec![],
+                })
+                .comparators
+                .extend(floor.comparators.iter().cloned());
+        }
+
+        let _resolving = self.cancel.resolving();
         let spinner = self.progress.spinner("Resolving dependencies");
-        let solver = Solver::new(repository);
-        let solution = solver.resolve(requested, || spinner.update(1))?;
+        let solver = Solver::new(repository)
+            .with_pins(&pins)
+            .with_extras(extras)
+            .with_installed(installed)
+            .with_warn_iterations(self.config.resolver().warn_iterations);
+        let context = || SolverContext::new(|| spinner.update(1)).with_cancel_handle(&self.cancel);
+        let run = |solver: &Solver, requested| match self.solver_timeout {
+            Some(timeout) => {
+                solver.resolve_with_timeout(requested, &exclusions, timeout, context())
+            }
+            None => solver.resolve_excluding(requested, &exclusions, context()),
+        };
+        let mut solution = run(&solver, requested.clone());
+        let mut iterations = solver.iterations();
+
+        if matches!(solution, Err(SolverError::NoSolution(..))) && !floors.is_empty() {
+            warn!(
+                target: LOGNAME,
+                "could not resolve without downgrading an installed package, trying again \
+                 without keeping installed versions"
+            );
+            let solver = Solver::new(repository)
+                .with_pins(&self.pins())
+                .with_extras(extras)
+                .with_installed(installed)
+                .with_warn_iterations(self.config.resolver().warn_iterations);
+            solution = run(&solver, requested);
+            iterations += solver.iterations();
+        }
         spinner.finish();
 
-        Ok(solution)
+        Ok((solution?, iterations))
+    }
+
+    fn check_advisories(&self, solution: &SolverSolution) -> Result<()> {
+        let db = match &self.advisory_db {
+            Some(db) => db,
+            None => return Ok(()),
+        };
+
+        // We report every advisory we find, even once we know that we're going
+        // to block the install, so that they can all be dealt with at once.
+        let mut blocked = None;
+        for (_, package) in solution.iter() {
+            for advisory in db.check(package.name(), package.version()) {
+                let label = match advisory.severity {
+                    Severity::Critical => style("critical").red().bold(),
+                    Severity::Warning => style("warning").yellow().bold(),
+                };
+                self.console(format!(
+                    "{label}: {} {} is affected by {}: {}",
+                    package.name(),
+                    package.version(),
+                    advisory.id,
+                    advisory.description
+                ));
+
+                if advisory.severity == Severity::Critical
+                    && !self.ignore_advisories
+                    && blocked.is_none()
+                {
+                    blocked = Some(InstallerError::BlockedByAdvisory {
+                        package: package.name().clone(),
+                        version: package.version().clone(),
+                        id: advisory.id.clone(),
+                    });
+                }
+            }
+        }
+
+        match blocked {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // Everything that has been pinned in the pkgdb is required at exactly the
+    // version it's pinned to, on top of whatever `requested` requires of it.
+    fn with_pinned(
+        &mut self,
+        mut requested: HashMap<PackageName, VersionReq>,
+    ) -> Result<HashMap<PackageName, VersionReq>> {
+        for (name, version) in self.db.pinned()?.into_iter() {
+            let pin = version_req(Op::Exact, &version);
+            requested
+                .entry(name)
+                .and_modify(|req| req.comparators.extend(pin.comparators.iter().cloned()))
+                .or_insert(pin);
+        }
+
+        Ok(requested)
+    }
+
+    // The exclusions from the configuration, along with any that were added to
+    // this Installer.
+    fn exclusions(&self) -> HashMap<PackageName, HashSet<Version>> {
+        let mut exclusions = self.config.exclusions();
+        for (package, versions) in self.exclusions.iter() {
+            exclusions
+                .entry(package.clone())
+                .or_default()
+                .extend(versions.iter().cloned());
+        }
+
+        exclusions
+    }
+
+    // Our pins, along with any extra constraints, which the solver only applies
+    // to packages that end up in the solution.
+    fn pins(&self) -> HashMap<PackageName, VersionReq> {
+        let mut pins = self.config.pins().clone();
+        for (package, extra) in self.extra_constraints.iter() {
+            pins.entry(package.clone())
+                .or_insert_with(|| VersionReq {
+                    comparators: vec![],
+                })
+                .comparators
+                .extend(extra.comparators.iter().cloned());
+        }
+
+        pins
+    }
+
+    fn apply_pins(
+        &self,
+        mut requested: HashMap<PackageName, VersionReq>,
+    ) -> Result<HashMap<PackageName, VersionReq>> {
+        // Extra constraints get passed to the solver along with our pins, but
+        // one that can't be satisfied alongside a request is never going to
+        // resolve, so just like with pins we check for that up front.
+        for (package, extra) in self.extra_constraints.iter() {
+            if let Some(req) = requested.get_mut(package) {
+                if Requirement::new(req.clone()).is_disjoint(&Requirement::new(extra.clone())) {
+                    return Err(InstallerError::ExtraConstraintConflict {
+                        name: package.clone(),
+                        existing: req.clone(),
+                        extra: extra.clone(),
+                    });
+                }
+                req.comparators.extend(extra.comparators.iter().cloned());
+            }
+        }
+
+        // Pins get layered on top of whatever was requested, but a pin that
+        // can't be satisfied alongside the request is never going to resolve,
+        // so we bail out early with a better error than the solver would give.
+        for (package, pin) in self.config.pins().iter() {
+            if let Some(req) = requested.get_mut(package) {
+                if Requirement::new(req.clone()).is_disjoint(&Requirement::new(pin.clone())) {
+                    return Err(InstallerError::PinConflict {
+                        package: package.clone(),
+                        pin: pin.clone(),
+                        requested: req.clone(),
+                    });
+                }
+                req.comparators.extend(pin.comparators.iter().cloned());
+            }
+        }
+
+        Ok(requested)
+    }
+
+    fn plan(&mut self, solution: &SolverSolution) -> Result<SolutionDiff> {
+        let diff = solution.diff().clone();
+
+        for change in diff.installed.iter() {
+            info!(
+                target: LOGNAME,
+                "installing {} {}",
+                change.name,
+                plan_version(&change.to)
+            );
+        }
+        for change in diff.upgraded.iter() {
+            info!(
+                target: LOGNAME,
+                "upgrading {} from {} to {}",
+                change.name,
+                plan_version(&change.from),
+                plan_version(&change.to)
+            );
+        }
+        // A downgrade is rarely what anyone wants, so we make sure that these
+        // get noticed, even though we're still going to do them.
+        for change in diff.downgraded.iter() {
+            warn!(
+                target: LOGNAME,
+                "downgrading {} from {} to {}",
+                change.name,
+                plan_version(&change.from),
+                plan_version(&change.to)
+            );
+        }
+        for change in diff.removed.iter() {
+            info!(
+                target: LOGNAME,
+                "removing {} {}",
+                change.name,
+                plan_version(&change.from)
+            );
+        }
+
+        Ok(diff)
+    }
+
+    fn apply(
+        &mut self,
+        repository: &Repository,
+        solution: &SolverSolution,
+        diff: &SolutionDiff,
+        timings: &mut InstallTimings,
+    ) -> Result<()> {
+        // The files of packages that are staying exactly as they are can't be
+        // replaced by anything else, but everything that is being upgraded or
+        // removed is giving its files up.
+        let mut owners = HashMap::new();
+        for (name, files) in self.db.installed_files()?.into_iter() {
+            if solution.contains(&name) && !diff.is_changed(&name) {
+                owners.extend(files.into_iter().map(|f| (f, name.clone())));
+            }
+        }
+        let (files, urls) = self.download_packages(repository, solution, diff, owners, timings)?;
+
+        // Now that everything new is in place, anything that was installed by a
+        // package that has been removed, or replaced by another version, and
+        // that nothing has just installed over, isn't wanted anymore.
+        let kept: HashSet<&String> = files.values().flatten().collect();
+        for (name, previous) in self.db.installed_files()?.iter() {
+            if solution.contains(name) && !diff.is_changed(name) {
+                continue;
+            }
+            for file in previous.iter().filter(|f| !kept.contains(f)) {
+                self.remove_file(file);
+            }
+        }
+
+        for change in diff.removed.iter() {
+            self.db.mark_removed(&change.name)?;
+        }
+        for (_, package) in solution.iter() {
+            self.db.mark_installed(
+                package.name(),
+                package.version(),
+                repository.source_name(package),
+                urls.get(package.name()),
+                repository.digest(package),
+                files.get(package.name()).map(|f| f.as_slice()),
+            )?;
+        }
+
+        // Other targets share our cache, so we let them know what we're using
+        // before their gc can remove it.
+        if let Some(cache) = &self.cache {
+            let using: HashSet<ArchiveKey> = solution
+                .iter()
+                .filter_map(|(_, p)| {
+                    let digest = repository.digest(p)?;
+                    Some((p.name().clone(), p.version().clone(), digest.to_string()))
+                })
+                .collect();
+            cache.record_usage(&self.target, &using);
+        }
+
+        Ok(())
+    }
+
+    fn download_packages(
+        &self,
+        repository: &Repository,
+        solution: &SolverSolution,
+        diff: &SolutionDiff,
+        mut owners: HashMap<String, PackageName>,
+        timings: &mut InstallTimings,
+    ) -> Result<(HashMap<PackageName, Vec<String>>, HashMap<PackageName, Url>)> {
+        // Packages that are already installed at the version we resolved to
+        // don't need to be downloaded again. The solution only has a single
+        // entry per package, no matter how many things depend on it, so each
+        // package only counts once towards our progress.
+        let packages: Vec<&Package> = solution
+            .iter()
+            .map(|(_, p)| p)
+            .filter(|p| diff.is_changed(p.name()))
+            .collect();
+
+        // If any package fails, then we remove everything that we've extracted
+        // so far, rather than leaving some of our packages half installed.
+        // Each package also claims its own files as it goes, so that two
+        // packages in the same solution can't install the same file either.
+        let mut extracted = DownloadGuard::new();
+        let mut installed = HashMap::new();
+        let mut urls = HashMap::new();
+        let bar = self.progress.bar(packages.len().try_into().unwrap());
+        for package in packages {
+            let result = self
+                .download_package(repository, package, &owners, &mut extracted, timings)
+                .and_then(|downloaded| {
+                    self.post_install(repository, package)?;
+                    Ok(downloaded)
+                });
+            match result {
+                Ok((files, url)) => {
+                    owners.extend(files.iter().map(|f| (f.clone(), package.name().clone())));
+                    installed.insert(package.name().clone(), files);
+                    if let Some(url) = url {
+                        urls.insert(package.name().clone(), url);
+                    }
+                }
+                Err(err) => {
+                    bar.finish();
+                    return Err(err);
+                }
+            }
+            bar.update(1);
+        }
+        bar.finish();
+        extracted.commit();
+
+        Ok((installed, urls))
+    }
+
+    // Remove one of the files that a package installed, along with any of its
+    // parent directories that are left empty. A file that is already gone is
+    // exactly what we wanted, and anything that can't be removed is left
+    // behind rather than failing the whole install over it.
+    fn remove_file(&self, file: &str) {
+        let result = || -> Result<()> {
+            let path = self.fs.join(file)?;
+            if path.is_file()? {
+                trace!(target: LOGNAME, "removing {:?}", file);
+                path.remove_file()?;
+            }
+
+            // The root of the target is the only path without a name.
+            let mut parent = path.parent();
+            while let Some(dir) = parent.filter(|d| !d.filename().is_empty()) {
+                if !dir.exists()? || dir.read_dir()?.next().is_some() {
+                    break;
+                }
+                dir.remove_dir()?;
+                parent = dir.parent();
+            }
+            Ok(())
+        }();
+
+        if let Err(err) = result {
+            warn!(target: LOGNAME, "could not remove {:?}: {}", file, err);
+        }
+    }
+
+    fn post_install(&self, repository: &Repository, package: &Package) -> Result<()> {
+        let scripts = repository.post_install(package);
+        if !self.run_scripts {
+            if !scripts.is_empty() {
+                warn!(
+                    target: LOGNAME,
+                    "not running the post install scripts for {}, since scripts aren't enabled",
+                    package.name()
+                );
+            }
+            return Ok(());
+        }
+
+        for script in scripts.iter() {
+            let output = hooks::run_script(package.name(), script, &self.target)?;
+            for line in output.lines() {
+                self.console(line);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn download_package(
+        &self,
+        repository: &Repository,
+        package: &Package,
+        owners: &HashMap<String, PackageName>,
+        extracted: &mut DownloadGuard,
+        timings: &mut InstallTimings,
+    ) -> Result<(Vec<String>, Option<Url>)> {
+        let mut retried = false;
+        let downloaded = loop {
+            // If we're retrying, then the cached archive might be the thing that
+            // is corrupted, so we always go back to the repository.
+            let start = Instant::now();
+            let (format, data, url) = self.fetch_package(repository, package, !retried)?;
+            timings.download_ms += millis(start.elapsed());
+
+            let start = Instant::now();
+            let result = archive::extract_archive(
+                package.name(),
+                &data,
+                format,
+                &self.fs,
+                owners,
+                extracted,
+            );
+            timings.extract_ms += millis(start.elapsed());
+
+            match result {
+                Ok(files) => {
+                    debug!(
+                        target: LOGNAME,
+                        "extracted {} files for {}",
+                        files.len(),
+                        package.name()
+                    );
+                    break (files, url);
+                }
+                Err(InstallerError::CorruptedArchive { name, details }) if !retried => {
+                    warn!(
+                        target: LOGNAME,
+                        "could not extract the archive for {name} ({details}), downloading it \
+                         again"
+                    );
+                    retried = true;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        if self.report_stats {
+            repository.report_download(package);
+        }
+
+        Ok(downloaded)
+    }
+
+    fn fetch_package(
+        &self,
+        repository: &Repository,
+        package: &Package,
+        use_cache: bool,
+    ) -> Result<(ArchiveFormat, Vec<u8>, Option<Url>)> {
+        // We can only cache archives that have a digest, since that's what we
+        // use to make sure a cached archive is still the one we expect.
+        let cache = match (&self.cache, repository.digest(package)) {
+            (Some(cache), Some(digest)) => Some((cache, digest)),
+            _ => None,
+        };
+
+        // The cache doesn't record which url an archive came from, but all of
+        // the urls for a release are expected to be the same archive, so we
+        // can take the format from any of them. We don't claim that it came
+        // from any one of them though, so there's no url for a cached archive.
+        let cached_format = repository
+            .urls(package)
+            .iter()
+            .find_map(ArchiveFormat::detect);
+        if let Some((cache, digest)) = cache.filter(|_| use_cache) {
+            if let Some(format) = cached_format {
+                if let Some(data) = cache.get(package.name(), package.version(), digest) {
+                    return Ok((format, data, None));
+                }
+            }
+        }
+
+        // We don't know how big the download is until it has started, so the
+        // bar is only created once we get our first chunk of it.
+        let mut bar = None;
+        let result = repository.download(package, &mut |total, delta| {
+            bar.get_or_insert_with(|| self.progress.download(total, &package.name().to_string()))
+                .update(delta)
+        });
+        if let Some(bar) = bar {
+            bar.finish();
+        }
+        let (url, data) = result?;
+        let format = ArchiveFormat::detect(&url).ok_or_else(|| {
+            InstallerError::UnsupportedArchiveFormat {
+                package: package.name().clone(),
+                url: Box::new(url.clone()),
+            }
+        })?;
+        if !repository.verify_signature(package, &url, &data)? {
+            return Err(InstallerError::SignatureVerificationFailed {
+                package: package.name().clone(),
+            });
+        }
+
+        if let Some((cache, digest)) = cache {
+            cache.put(package.name(), package.version(), digest, &data);
+        }
+
+        Ok((format, data, Some(url)))
+    }
+}
+
+fn check_exists(
+    repository: &Repository,
+    requested: &HashMap<PackageName, VersionReq>,
+) -> Result<()> {
+    let mut missing: Vec<PackageName> = requested
+        .keys()
+        .filter(|name| repository.candidates(name).is_empty())
+        .cloned()
+        .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        missing.sort();
+        Err(InstallerError::PackagesNotFound(missing))
     }
 }
 
-fn step(n: u8, t: u8, emoji: Emoji, msg: &str) -> String {
-    let prefix = style(format!("[{n}/{t}]")).bold().dim();
-    format!("{prefix} {emoji}{msg}")
+// Which of the `roots` depend on `package`, either directly or through some
+// other package in the solution.
+fn dependents(
+    repository: &Repository,
+    solution: &SolverSolution,
+    roots: &[PackageName],
+    package: &PackageName,
+) -> Vec<PackageName> {
+    let mut dependents: Vec<PackageName> = roots
+        .iter()
+        .filter(|root| {
+            let mut seen = HashSet::new();
+            let mut stack = vec![(*root).clone()];
+            while let Some(name) = stack.pop() {
+                if !seen.insert(name.clone()) {
+                    continue;
+                }
+                if let Some(deps) = solution.get(&name).and_then(|p| repository.dependencies(p)) {
+                    if deps.contains_key(package) {
+                        return true;
+                    }
+                    stack.extend(deps.keys().cloned());
+                }
+            }
+            false
+        })
+        .cloned()
+        .collect();
+
+    dependents.sort();
+    dependents
+}
+
+fn dependency_node(
+    package: &PackageName,
+    versions: &HashMap<PackageName, Version>,
+    dependencies: &HashMap<PackageName, Vec<PackageName>>,
+    path: &mut Vec<PackageName>,
+) -> DependencyNode {
+    let cycle = path.contains(package);
+    let mut node = DependencyNode {
+        name: package.clone(),
+        version: versions[package].clone(),
+        deps: Vec::new(),
+        cycle,
+    };
+
+    if !cycle {
+        path.push(package.clone());
+        node.deps = dependencies
+            .get(package)
+            .map(|deps| {
+                deps.iter()
+                    .map(|dep| dependency_node(dep, versions, dependencies, path))
+                    .collect()
+            })
+            .unwrap_or_default();
+        path.pop();
+    }
+
+    node
+}
+
+fn plan_message(diff: &SolutionDiff) -> String {
+    let mut msg = format!(
+        "{} to install, {} to upgrade, {} to remove",
+        diff.installed.len(),
+        diff.upgraded.len(),
+        diff.removed.len()
+    );
+    if !diff.downgraded.is_empty() {
+        msg.push_str(&format!(", {} to downgrade", diff.downgraded.len()));
+    }
+    msg
+}
+
+fn upgrade_message(diff: &SolutionDiff) -> String {
+    match diff.upgraded.len() {
+        1 => "Upgrading 1 package".to_string(),
+        n => format!("Upgrading {n} packages"),
+    }
+}
+
+fn plan_version(version: &Option<Version>) -> String {
+    version
+        .as_ref()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+struct StepCounter {
+    current: u8,
+    total: u8,
+}
+
+impl StepCounter {
+    fn new(total: u8) -> StepCounter {
+        StepCounter { current: 0, total }
+    }
+
+    fn next(&mut self, emoji: Emoji, msg: &str) -> String {
+        self.current += 1;
+        debug_assert!(self.current <= self.total, "more steps than expected");
+
+        let prefix = style(format!("[{}/{}]", self.current, self.total))
+            .bold()
+            .dim();
+        format!("{prefix} {emoji}{msg}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use url::Url;
+    use vfs::MemoryFS;
+    use zip::write::{FileOptions, ZipWriter};
+
+    use super::*;
+
+    // A target that only lives in memory, with `config` as its mqpkg.yml.
+    fn target(config: &str) -> VfsPath {
+        let fs: VfsPath = MemoryFS::new().into();
+        fs.join(Config::filename())
+            .unwrap()
+            .create_file()
+            .unwrap()
+            .write_all(config.as_bytes())
+            .unwrap();
+        fs
+    }
+
+    fn builder<'p>(config: &str) -> InstallerBuilder<'p, ()> {
+        let fs = target(config);
+        let config = Config::load(&fs).unwrap();
+
+        InstallerBuilder::new(config, fs, "/test").use_cache(false)
+    }
+
+    fn requested(reqs: &[(&str, &str)]) -> HashMap<PackageName, VersionReq> {
+        reqs.iter()
+            .map(|(name, req)| (name.parse().unwrap(), VersionReq::parse(req).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_full_install_in_memory() {
+        // Only the target goes through the vfs, file:// repositories, and the
+        // packages in them, are always read from the real filesystem.
+        let repo = std::env::temp_dir().join(format!("mqpkg-install-{}", std::process::id()));
+        std::fs::create_dir_all(&repo).unwrap();
+
+        let mut archive = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        archive
+            .start_file("share/foo/hello.txt", FileOptions::default())
+            .unwrap();
+        archive.write_all(b"hello\n").unwrap();
+        let archive = archive.finish().unwrap().into_inner();
+        std::fs::write(repo.join("foo-1.0.0.zip"), archive).unwrap();
+
+        let archive_url = Url::from_file_path(repo.join("foo-1.0.0.zip")).unwrap();
+        let index = serde_json::json!({
+            "meta": {"name": "test"},
+            "packages": {"foo": {"1.0.0": {"urls": [archive_url], "digests": {}}}},
+        });
+        std::fs::write(repo.join("index.json"), index.to_string()).unwrap();
+        let index_url = Url::from_file_path(repo.join("index.json")).unwrap();
+
+        let fs = target(&format!("repositories:\n- {index_url}\n"));
+        let config = Config::load(&fs).unwrap();
+
+        let mut installer = InstallerBuilder::<()>::new(config, fs.clone(), "/test")
+            .report_stats(false)
+            .use_cache(false)
+            .build()
+            .unwrap();
+        let result = installer.install(&["foo".parse().unwrap()]);
+        std::fs::remove_dir_all(&repo).unwrap();
+
+        let summary = result.unwrap();
+        assert_eq!(summary.diff.installed.len(), 1);
+
+        let mut installed = String::new();
+        fs.join("share/foo/hello.txt")
+            .unwrap()
+            .open_file()
+            .unwrap()
+            .read_to_string(&mut installed)
+            .unwrap();
+        assert_eq!(installed, "hello\n");
+    }
+
+    #[test]
+    fn panic_mid_install_releases_lock() {
+        let rid = format!("/mqpkg-test-panic-{}", std::process::id());
+        let fs = target("repositories: []\n");
+        let config = Config::load(&fs).unwrap();
+        let mut installer = InstallerBuilder::<()>::new(config, fs, &rid)
+            .console(|_| panic!("panicked mid install"))
+            .report_stats(false)
+            .use_cache(false)
+            .build()
+            .unwrap();
+
+        let result = catch_unwind(AssertUnwindSafe(|| installer.install(&[])));
+        assert!(result.is_err());
+
+        // The pkgdb's lock is shared by everything on the system using the same
+        // id, so if the panic had left it held, this would block forever.
+        let id = format!("{:x}", md5::compute(&rid));
+        let txnm = TransactionManager::new(&id).unwrap();
+        txnm.begin().unwrap();
+    }
+
+    #[test]
+    fn extra_constraint_conflicting_with_request() {
+        let installer = builder("repositories: []\n")
+            .extra_constraints(&["foo<2".parse().unwrap()])
+            .build_with_store(MemoryPackageStore::new())
+            .unwrap();
+
+        let err = installer
+            .apply_pins(requested(&[("foo", ">=2")]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InstallerError::ExtraConstraintConflict { ref name, .. } if name.to_string() == "foo"
+        ));
+    }
+
+    #[test]
+    fn extra_constraint_narrows_request() {
+        let installer = builder("repositories: []\n")
+            .extra_constraints(&["foo<2".parse().unwrap()])
+            .build_with_store(MemoryPackageStore::new())
+            .unwrap();
+
+        let pinned = installer.apply_pins(requested(&[("foo", ">=1")])).unwrap();
+        let foo = &pinned[&"foo".parse::<PackageName>().unwrap()];
+        assert!(foo.matches(&Version::new(1, 5, 0)));
+        assert!(!foo.matches(&Version::new(2, 0, 0)));
+    }
+
+    #[test]
+    fn extra_constraint_does_not_request() {
+        let installer = builder("repositories: []\n")
+            .extra_constraints(&["foo<2".parse().unwrap()])
+            .build_with_store(MemoryPackageStore::new())
+            .unwrap();
+
+        let pinned = installer.apply_pins(requested(&[("bar", "*")])).unwrap();
+        let foo: PackageName = "foo".parse().unwrap();
+        assert!(!pinned.contains_key(&foo));
+        assert!(installer.pins().contains_key(&foo));
+    }
+
+    #[test]
+    fn dry_run_does_not_initialize_pkgdb() {
+        let builder = builder("repositories: []\n").dry_run(true);
+        let fs = builder.fs.clone();
+        let mut installer = builder.build().unwrap();
+
+        installer
+            .pin(&"foo".parse().unwrap(), &Version::new(1, 0, 0))
+            .unwrap();
+        assert!(!Database::is_initialized(&fs));
+    }
+
+    #[test]
+    fn remove_file_removes_empty_directories() {
+        let builder = builder("repositories: []\n");
+        let fs = builder.fs.clone();
+        let installer = builder.build_with_store(MemoryPackageStore::new()).unwrap();
+        fs.join("share/foo").unwrap().create_dir_all().unwrap();
+        fs.join("share/foo/a.txt").unwrap().create_file().unwrap();
+        fs.join("share/b.txt").unwrap().create_file().unwrap();
+
+        installer.remove_file("share/foo/a.txt");
+        installer.remove_file("share/foo/missing.txt");
+
+        assert!(!fs.join("share/foo").unwrap().exists().unwrap());
+        assert!(fs.join("share/b.txt").unwrap().exists().unwrap());
+        assert!(fs.join(Config::filename()).unwrap().exists().unwrap());
+    }
 }
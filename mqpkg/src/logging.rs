@@ -0,0 +1,16 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+// Everything logs through these re-exports rather than using log directly, so
+// that the tracing feature can swap in tracing's macros, which accept the same
+// `target: LOGNAME, "..."` syntax that we use everywhere. There's no tracing
+// equivalent of log_enabled!, but with tracing's log feature enabled, tracing
+// also emits log records, so it still gives a useful answer.
+#[cfg(feature = "tracing")]
+pub(crate) use log::log_enabled;
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, info, trace, warn};
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log::{debug, info, log_enabled, trace, warn};
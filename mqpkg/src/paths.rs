@@ -0,0 +1,62 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::env;
+use std::path::PathBuf;
+
+use crate::config::CONFIG_FILENAME;
+
+const CACHE_DIR_ENV: &str = "MQPKG_CACHE_DIR";
+
+const SYSTEM_CONFIG_DIR: &str = "/etc/mqpkg";
+
+/// The directory that mqpkg caches things in by default, following the
+/// conventions of the current platform. This can be overridden by setting
+/// `MQPKG_CACHE_DIR`.
+pub fn cache_dir() -> PathBuf {
+    if let Some(dir) = env_path(CACHE_DIR_ENV) {
+        return dir;
+    }
+
+    platform_cache_dir().unwrap_or_else(|| env::temp_dir().join("mqpkg"))
+}
+
+#[cfg(target_os = "windows")]
+fn platform_cache_dir() -> Option<PathBuf> {
+    env_path("LOCALAPPDATA").map(|d| d.join("mqpkg").join("cache"))
+}
+
+#[cfg(target_os = "macos")]
+fn platform_cache_dir() -> Option<PathBuf> {
+    env_path("HOME").map(|d| d.join("Library").join("Caches").join("mqpkg"))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn platform_cache_dir() -> Option<PathBuf> {
+    env_path("XDG_CACHE_HOME")
+        .or_else(|| env_path("HOME").map(|d| d.join(".cache")))
+        .map(|d| d.join("mqpkg"))
+}
+
+/// The system wide configuration file, which every target directory on this
+/// machine inherits from.
+pub(crate) fn system_config_file() -> PathBuf {
+    PathBuf::from(SYSTEM_CONFIG_DIR).join(CONFIG_FILENAME)
+}
+
+/// The configuration file for the current user, which is None when we can't
+/// tell where their home directory is.
+pub(crate) fn user_config_file() -> Option<PathBuf> {
+    env_path("XDG_CONFIG_HOME")
+        .or_else(|| env_path("HOME").map(|d| d.join(".config")))
+        .map(|d| d.join("mqpkg").join(CONFIG_FILENAME))
+}
+
+// An environment variable that is set, but empty, is treated the same as if it
+// wasn't set at all.
+fn env_path(key: &str) -> Option<PathBuf> {
+    env::var_os(key)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
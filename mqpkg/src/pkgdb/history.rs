@@ -0,0 +1,139 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use vfs::VfsPath;
+
+use crate::errors::DBError;
+use crate::logging::trace;
+use crate::pkgdb::{ensure_dir, pkgdb_path, Snapshot, State};
+
+const LOGNAME: &str = "mqpkg::pkgdb::history";
+
+const HISTORY_FILE: &str = "txlog.jsonl";
+
+type Result<T, E = DBError> = core::result::Result<T, E>;
+
+/// What a transaction in the history was doing when it was committed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Install,
+    Update,
+    Upgrade,
+    Uninstall,
+    Import,
+    Pin,
+    Unpin,
+    /// Going back to how things were before the transaction with this id.
+    Rollback(u64),
+    /// Anything that didn't say what it was doing.
+    Unknown,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Install => write!(f, "install"),
+            Operation::Update => write!(f, "update"),
+            Operation::Upgrade => write!(f, "upgrade"),
+            Operation::Uninstall => write!(f, "uninstall"),
+            Operation::Import => write!(f, "import"),
+            Operation::Pin => write!(f, "pin"),
+            Operation::Unpin => write!(f, "unpin"),
+            Operation::Rollback(id) => write!(f, "rollback to {}", id),
+            Operation::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A transaction that changed the pkgdb, along with the state from before and
+/// after it was committed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransactionLogEntry {
+    pub id: u64,
+    pub timestamp: SystemTime,
+    pub operation: Operation,
+    pub(super) before: State,
+    pub(super) after: State,
+}
+
+impl TransactionLogEntry {
+    pub fn before(&self) -> Snapshot {
+        self.before.snapshot()
+    }
+
+    pub fn after(&self) -> Snapshot {
+        self.after.snapshot()
+    }
+}
+
+/// Load every transaction in the history of the pkgdb within `fs`, oldest
+/// first.
+pub(super) fn load(fs: &VfsPath) -> Result<Vec<TransactionLogEntry>> {
+    let filename = history_path(fs)?;
+    if !filename.is_file()? {
+        return Ok(Vec::new());
+    }
+
+    trace!(
+        target: LOGNAME,
+        "loading history from {:?}",
+        filename.as_str()
+    );
+    let mut entries = Vec::new();
+    for line in BufReader::new(filename.open_file()?).lines() {
+        let line = line.map_err(|e| DBError::PathUnavailable(e.into()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(
+            serde_json::from_str(&line).map_err(|source| DBError::InvalidHistory { source })?,
+        );
+    }
+
+    Ok(entries)
+}
+
+/// Add a transaction to the end of the history, dropping the oldest entries
+/// so that there are never more than `max_history` of them.
+pub(super) fn append(
+    fs: &VfsPath,
+    operation: Operation,
+    before: State,
+    after: State,
+    max_history: u32,
+) -> Result<()> {
+    let mut entries = load(fs)?;
+    let id = entries.last().map(|e| e.id + 1).unwrap_or(1);
+    trace!(target: LOGNAME, "recording transaction {} ({})", id, operation);
+    entries.push(TransactionLogEntry {
+        id,
+        timestamp: SystemTime::now(),
+        operation,
+        before,
+        after,
+    });
+
+    let excess = entries.len().saturating_sub(max_history as usize);
+    entries.drain(..excess);
+
+    ensure_dir(&pkgdb_path(fs)?)?;
+    let mut file = history_path(fs)?.create_file()?;
+    for entry in entries.iter() {
+        let line =
+            serde_json::to_string(entry).map_err(|source| DBError::InvalidHistory { source })?;
+        writeln!(file, "{}", line).map_err(|e| DBError::PathUnavailable(e.into()))?;
+    }
+
+    Ok(())
+}
+
+fn history_path(fs: &VfsPath) -> Result<VfsPath> {
+    Ok(pkgdb_path(fs)?.join(HISTORY_FILE)?)
+}
@@ -0,0 +1,122 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::collections::{BTreeMap, HashMap};
+
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use vfs::VfsPath;
+
+use crate::errors::DBError;
+use crate::logging::trace;
+use crate::pkgdb::{ensure_dir, pkgdb_path, sibling};
+use crate::types::PackageName;
+
+const LOGNAME: &str = "mqpkg::pkgdb::lock";
+
+const LOCK_FILE: &str = "mqpkg.lock";
+
+type Result<T, E = DBError> = core::result::Result<T, E>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct LockedPackage {
+    pub name: PackageName,
+    pub version: Version,
+    #[serde(default)]
+    pub source_url: Option<Url>,
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+/// The exact set of packages to install, which can be installed as is without
+/// needing to resolve anything.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct LockFile {
+    /// What had been requested when this lock file was written, if that has
+    /// changed since, then this lock file no longer reflects what was asked
+    /// for.
+    pub requested: BTreeMap<PackageName, VersionReq>,
+    pub packages: Vec<LockedPackage>,
+}
+
+impl LockFile {
+    /// Whether this lock file was written for exactly `requested`.
+    pub fn is_current(&self, requested: &HashMap<PackageName, VersionReq>) -> bool {
+        self.requested.len() == requested.len()
+            && requested
+                .iter()
+                .all(|(name, req)| self.requested.get(name) == Some(req))
+    }
+
+    /// Load the lock file from the pkgdb within `fs`, if there is one.
+    pub fn load(fs: &VfsPath) -> Result<Option<LockFile>> {
+        let filename = lock_path(fs)?;
+        if !filename.is_file()? {
+            trace!(target: LOGNAME, "no lock file at {:?}", filename.as_str());
+            return Ok(None);
+        }
+
+        trace!(
+            target: LOGNAME,
+            "loading lock file from {:?}",
+            filename.as_str()
+        );
+        let lock = serde_yaml::from_reader(filename.open_file()?)
+            .map_err(|source| DBError::InvalidLockFile { source })?;
+
+        Ok(Some(lock))
+    }
+
+    /// Save the lock file to the pkgdb within `fs`. It's written next to where
+    /// it goes first, and then moved into place, so that a partially written
+    /// lock file is never left behind.
+    pub fn save(&self, fs: &VfsPath) -> Result<()> {
+        ensure_dir(&pkgdb_path(fs)?)?;
+
+        let filename = lock_path(fs)?;
+        let partial = sibling(&filename, "partial")?;
+        trace!(target: LOGNAME, "saving lock file to {:?}", filename.as_str());
+        let file = partial.create_file()?;
+        serde_yaml::to_writer(file, self).map_err(|source| DBError::InvalidLockFile { source })?;
+        if filename.exists()? {
+            filename.remove_file()?;
+        }
+        partial.move_file(&filename)?;
+        Ok(())
+    }
+}
+
+fn lock_path(fs: &VfsPath) -> Result<VfsPath> {
+    Ok(pkgdb_path(fs)?.join(LOCK_FILE)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use vfs::MemoryFS;
+
+    use super::*;
+
+    #[test]
+    fn save_replaces_lock_file() {
+        let fs: VfsPath = MemoryFS::new().into();
+        let mut lock = LockFile::default();
+        lock.save(&fs).unwrap();
+
+        lock.requested
+            .insert("foo".parse().unwrap(), VersionReq::parse("^1").unwrap());
+        lock.save(&fs).unwrap();
+
+        let loaded = LockFile::load(&fs).unwrap().unwrap();
+        assert_eq!(loaded.requested, lock.requested);
+        let names: Vec<String> = pkgdb_path(&fs)
+            .unwrap()
+            .read_dir()
+            .unwrap()
+            .map(|p| p.filename())
+            .collect();
+        assert_eq!(names, vec![LOCK_FILE.to_string()]);
+    }
+}
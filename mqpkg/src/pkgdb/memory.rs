@@ -0,0 +1,133 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::collections::HashMap;
+use std::mem::drop;
+use std::sync::{Arc, Mutex};
+
+use semver::{Version, VersionReq};
+use url::Url;
+
+use crate::errors::DBError;
+use crate::pkgdb::store::PackageStore;
+use crate::pkgdb::transactions::{Transaction, TransactionManager};
+use crate::pkgdb::{Operation, Snapshot, State};
+use crate::types::{PackageName, PackageSpecifier};
+
+type Result<T, E = DBError> = core::result::Result<T, E>;
+
+/// A PackageStore that only ever lives in memory, which is useful for testing,
+/// or for using an Installer without persisting anything.
+#[derive(Debug, Default)]
+pub struct MemoryPackageStore {
+    lock: Arc<Mutex<()>>,
+    committed: State,
+    // Changes are made to a copy of our state, which only replaces the real
+    // state when the transaction is committed, so a transaction that fails
+    // part way through doesn't leave anything behind.
+    pending: Option<State>,
+}
+
+impl MemoryPackageStore {
+    pub fn new() -> MemoryPackageStore {
+        MemoryPackageStore::default()
+    }
+
+    fn state(&mut self) -> Result<&mut State> {
+        self.pending.as_mut().ok_or(DBError::NoTransaction)
+    }
+}
+
+impl PackageStore for MemoryPackageStore {
+    fn transaction(&self) -> Result<TransactionManager> {
+        Ok(TransactionManager::in_process(self.lock.clone()))
+    }
+
+    fn begin<'r>(&mut self, txnm: &'r TransactionManager) -> Result<Transaction<'r>> {
+        let txn = txnm.begin()?;
+        self.pending = Some(self.committed.clone());
+        Ok(txn)
+    }
+
+    fn commit(&mut self, txn: Transaction<'_>) -> Result<()> {
+        if let Some(state) = self.pending.take() {
+            self.committed = state;
+        }
+        drop(txn);
+
+        Ok(())
+    }
+
+    fn rollback(&mut self, txn: Transaction<'_>) {
+        self.pending = None;
+        drop(txn);
+    }
+
+    // We have nowhere to keep a history, so there's nothing to describe.
+    fn describe(&mut self, _operation: Operation) {}
+
+    fn add(&mut self, package: &PackageSpecifier) -> Result<()> {
+        self.state()?.add(package);
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &PackageName) -> Result<()> {
+        self.state()?.remove(name)
+    }
+
+    fn requested(&mut self) -> Result<HashMap<PackageName, VersionReq>> {
+        Ok(self.state()?.requested())
+    }
+
+    fn requested_extras(&mut self) -> Result<HashMap<PackageName, Vec<String>>> {
+        Ok(self.state()?.requested_extras())
+    }
+
+    fn pin(&mut self, name: &PackageName, version: &Version) -> Result<()> {
+        self.state()?.pin(name, version)
+    }
+
+    fn unpin(&mut self, name: &PackageName) -> Result<()> {
+        self.state()?.unpin(name)
+    }
+
+    fn pinned(&mut self) -> Result<HashMap<PackageName, Version>> {
+        Ok(self.state()?.pinned.clone())
+    }
+
+    fn list_installed(&mut self) -> Result<HashMap<PackageName, Version>> {
+        Ok(self.state()?.list_installed())
+    }
+
+    fn mark_installed(
+        &mut self,
+        name: &PackageName,
+        version: &Version,
+        source: Option<&str>,
+        source_url: Option<&Url>,
+        digest: Option<&str>,
+        files: Option<&[String]>,
+    ) -> Result<()> {
+        self.state()?
+            .mark_installed(name, version, source, source_url, digest, files);
+        Ok(())
+    }
+
+    fn installed_files(&mut self) -> Result<HashMap<PackageName, Vec<String>>> {
+        Ok(self.state()?.installed_files())
+    }
+
+    fn source_urls(&mut self) -> Result<HashMap<PackageName, Url>> {
+        Ok(self.state()?.source_urls())
+    }
+
+    fn mark_removed(&mut self, name: &PackageName) -> Result<()> {
+        self.state()?.mark_removed(name);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Ok(self.committed.snapshot())
+    }
+}
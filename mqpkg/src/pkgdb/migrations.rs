@@ -0,0 +1,142 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::collections::HashMap;
+
+use semver::Version;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::errors::DBError;
+use crate::logging::info;
+use crate::pkgdb::{InstalledEntry, LockFile, PackageRequest, State};
+use crate::types::PackageName;
+
+const LOGNAME: &str = "mqpkg::pkgdb::migrations";
+
+// Version 3 added pins, and version 4 added where each installed package came
+// from, which anything older would drop when writing it back. Version 5 writes
+// requested packages as specifiers, which nothing older can read at all.
+pub(super) const CURRENT_VERSION: u32 = 5;
+pub(super) const MIN_COMPATIBLE_VERSION: u32 = 5;
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+pub(super) const MIN_COMPATIBLE_VERSION_KEY: &str = "min_compatible_version";
+
+type Result<T, E = DBError> = core::result::Result<T, E>;
+
+/// The state as it was before it was versioned.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct StateV1 {
+    requested: HashMap<PackageName, PackageRequest>,
+    installed: HashMap<PackageName, InstalledEntryV1>,
+}
+
+#[derive(Deserialize)]
+struct InstalledEntryV1 {
+    version: Version,
+}
+
+/// Turn the raw contents of a state file, from any version that we know how
+/// to read, into the current State. The lock file is only loaded when a state
+/// from before we tracked what was installed needs it.
+pub(super) fn load<F>(data: Value, lock: F) -> Result<State>
+where
+    F: FnOnce() -> Result<Option<LockFile>>,
+{
+    let found = version(&data, SCHEMA_VERSION_KEY).unwrap_or(1);
+    let minimum = version(&data, MIN_COMPATIBLE_VERSION_KEY).unwrap_or(found);
+
+    // A state from a newer version is fine for us to use, as long as whatever
+    // wrote it says that it's still compatible with us, otherwise writing it
+    // back out would lose whatever we don't understand.
+    if minimum > CURRENT_VERSION {
+        return Err(DBError::IncompatibleState { found, minimum });
+    }
+
+    match found {
+        1 => {
+            info!(
+                target: LOGNAME,
+                "migrating state from version 1 to {}", CURRENT_VERSION
+            );
+            Ok(migrate_to_current(parse::<StateV1>(data)?, lock)?)
+        }
+        _ => parse(data),
+    }
+}
+
+fn migrate_to_current<F>(old: StateV1, lock: F) -> Result<State>
+where
+    F: FnOnce() -> Result<Option<LockFile>>,
+{
+    let mut installed: HashMap<PackageName, InstalledEntry> = old
+        .installed
+        .into_iter()
+        .map(|(name, entry)| {
+            (
+                name,
+                InstalledEntry {
+                    version: entry.version,
+                    source: None,
+                    source_url: None,
+                    digest: None,
+                    installed_at: None,
+                    files: Vec::new(),
+                },
+            )
+        })
+        .collect();
+
+    // The earliest states didn't record what was installed at all, but if the
+    // lock file was written for what they requested, then it's what got
+    // installed. Otherwise we leave it empty, and the next install will treat
+    // everything as new.
+    if installed.is_empty() && !old.requested.is_empty() {
+        let requested = old
+            .requested
+            .values()
+            .map(|req| (req.name.clone(), req.version.clone()))
+            .collect();
+        match lock()? {
+            Some(lock) if lock.is_current(&requested) => {
+                info!(
+                    target: LOGNAME,
+                    "marking {} package(s) from the lock file as installed",
+                    lock.packages.len()
+                );
+                installed.extend(lock.packages.into_iter().map(|p| {
+                    (
+                        p.name,
+                        InstalledEntry {
+                            version: p.version,
+                            source: None,
+                            source_url: p.source_url,
+                            digest: p.digest,
+                            installed_at: None,
+                            files: Vec::new(),
+                        },
+                    )
+                }));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(State {
+        requested: old.requested,
+        installed,
+        ..Default::default()
+    })
+}
+
+fn parse<T: DeserializeOwned>(data: Value) -> Result<T> {
+    serde_yaml::from_value(data).map_err(|source| DBError::InvalidState { source })
+}
+
+fn version(data: &Value, key: &str) -> Option<u32> {
+    data.get(key)?.as_u64()?.try_into().ok()
+}
@@ -5,35 +5,208 @@
 use std::collections::HashMap;
 use std::default::Default;
 use std::mem::drop;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-use log::trace;
-use semver::VersionReq;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
-use vfs::VfsPath;
-
-use crate::errors::DBError;
-use crate::pkgdb::transactions::{Transaction, TransactionManager};
+use url::Url;
+use vfs::{MemoryFS, VfsPath};
+
+use crate::errors::{DBError, PackageSpecifierError};
+use crate::logging::trace;
+pub use crate::pkgdb::history::{Operation, TransactionLogEntry};
+pub use crate::pkgdb::lock::{LockFile, LockedPackage};
+pub use crate::pkgdb::memory::MemoryPackageStore;
+pub use crate::pkgdb::store::PackageStore;
+pub use crate::pkgdb::transactions::{Transaction, TransactionManager};
 use crate::types::{PackageName, PackageSpecifier};
 
+mod history;
+mod lock;
+mod memory;
+mod migrations;
+mod store;
 mod transactions;
 
 const LOGNAME: &str = "mqpkg::pkgdb";
 
-const PKGDB_DIR: &str = "pkgdb";
+pub(crate) const PKGDB_DIR: &str = "pkgdb";
 const STATE_FILE: &str = "state.yml";
 
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const EXPORT_SCHEMA_VERSION: u64 = 1;
+
+pub(crate) const DEFAULT_MAX_HISTORY: u32 = 50;
+
 type Result<T, E = DBError> = core::result::Result<T, E>;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub(crate) struct PackageRequest {
-    pub(crate) name: PackageName,
-    pub(crate) version: VersionReq,
+macro_rules! transaction {
+    ($db:expr, $body:block) => {{
+        transaction!($db, commit: true, $body)
+    }};
+
+    // When `commit` is false, a body that succeeds is still rolled back, which
+    // lets us do everything short of actually persisting it, while holding the
+    // lock the whole time.
+    ($db:expr, commit: $commit:expr, $body:block) => {{
+        let __txnm = $db.transaction()?;
+        let __txn = $db.begin(&__txnm)?;
+
+        // The body runs inside of a closure so that an early return from it
+        // still comes back through here, where we can roll back the
+        // transaction rather than leaving whatever it had changed behind. A
+        // panic gets caught for the same reason, and then carries on once the
+        // transaction has been rolled back and its lock released.
+        let __result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| Ok($body)));
+
+        match __result {
+            Ok(Ok(value)) if $commit => {
+                $db.commit(__txn)?;
+                value
+            }
+            Ok(Ok(value)) => {
+                $db.rollback(__txn);
+                value
+            }
+            Ok(Err(err)) => {
+                $db.rollback(__txn);
+                return Err(err);
+            }
+            Err(panic) => {
+                $db.rollback(__txn);
+                std::panic::resume_unwind(panic);
+            }
+        }
+    }};
+
+    ($db:expr, $body:expr) => {{
+        transaction!($db, { $body })
+    }};
+}
+
+pub(crate) use transaction;
+
+/// A package that has been requested, which is written out the same way that
+/// a PackageSpecifier is displayed, like `name[extra]>=1.0.0`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "PackageRequestRepr", into = "String")]
+pub struct PackageRequest {
+    pub name: PackageName,
+    pub version: VersionReq,
+    pub extras: Vec<String>,
+}
+
+impl PackageRequest {
+    fn specifier(&self) -> PackageSpecifier {
+        PackageSpecifier {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            extras: self.extras.clone(),
+        }
+    }
+}
+
+// Requests were written out field by field before schema version 5, and both
+// ways are still read, so that older states don't need a migration.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PackageRequestRepr {
+    Specifier(String),
+    Fields {
+        name: PackageName,
+        version: VersionReq,
+        #[serde(default)]
+        extras: Vec<String>,
+    },
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+impl TryFrom<PackageRequestRepr> for PackageRequest {
+    type Error = PackageSpecifierError;
+
+    fn try_from(repr: PackageRequestRepr) -> Result<PackageRequest, Self::Error> {
+        match repr {
+            PackageRequestRepr::Specifier(s) => {
+                let specifier: PackageSpecifier = s.parse()?;
+                Ok(PackageRequest {
+                    name: specifier.name,
+                    version: specifier.version,
+                    extras: specifier.extras,
+                })
+            }
+            PackageRequestRepr::Fields {
+                name,
+                version,
+                extras,
+            } => Ok(PackageRequest {
+                name,
+                version,
+                extras,
+            }),
+        }
+    }
+}
+
+impl From<PackageRequest> for String {
+    fn from(request: PackageRequest) -> String {
+        request.specifier().to_string()
+    }
+}
+
+/// What we know about a package that is installed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InstalledEntry {
+    pub version: Version,
+    /// The name of the repository that the package was installed from.
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Where the package itself was downloaded from.
+    #[serde(default)]
+    pub source_url: Option<Url>,
+    /// The sha256 hex digest of the package that was installed.
+    #[serde(default)]
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub installed_at: Option<SystemTime>,
+    /// Every file that was extracted for the package, relative to the root of
+    /// the target.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+}
+
+/// Everything in a PackageStore, as it was when it was read.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub requested: HashMap<PackageName, VersionReq>,
+    pub installed: HashMap<PackageName, InstalledEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(default)]
 struct State {
+    /// The version of the schema that this state was written with, state files
+    /// from before we started versioning them are version 1.
+    schema_version: u32,
+    /// The oldest schema version that can safely read, and write back, this
+    /// state without losing anything.
+    min_compatible_version: u32,
     requested: HashMap<PackageName, PackageRequest>,
+    installed: HashMap<PackageName, InstalledEntry>,
+    /// Packages that are always required at exactly this version, whatever
+    /// else has been requested.
+    pinned: HashMap<PackageName, Version>,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State {
+            schema_version: migrations::CURRENT_VERSION,
+            min_compatible_version: migrations::MIN_COMPATIBLE_VERSION,
+            requested: HashMap::new(),
+            installed: HashMap::new(),
+            pinned: HashMap::new(),
+        }
+    }
 }
 
 impl State {
@@ -45,8 +218,9 @@ impl State {
             filename.as_str()
         );
         let state: State = if filename.is_file()? {
-            serde_yaml::from_reader(filename.open_file()?)
-                .map_err(|source| DBError::InvalidState { source })?
+            let data = serde_yaml::from_reader(filename.open_file()?)
+                .map_err(|source| DBError::InvalidState { source })?;
+            migrations::load(data, || LockFile::load(fs))?
         } else {
             trace!(target: LOGNAME, "could not find state, using default");
             State {
@@ -60,46 +234,327 @@ impl State {
     fn save(&self, fs: &VfsPath) -> Result<()> {
         ensure_dir(&pkgdb_path(fs)?)?;
 
+        // Whatever version we loaded, we always write the current one.
+        let state = State {
+            schema_version: migrations::CURRENT_VERSION,
+            min_compatible_version: migrations::MIN_COMPATIBLE_VERSION,
+            ..self.clone()
+        };
+
         let filename = state_path(fs)?;
         trace!(target: LOGNAME, "saving state to {:?}", filename.as_str());
         let file = filename.create_file()?;
-        serde_yaml::to_writer(file, self).map_err(|source| DBError::InvalidState { source })?;
+        serde_yaml::to_writer(file, &state).map_err(|source| DBError::InvalidState { source })?;
+        Ok(())
+    }
+
+    fn add(&mut self, package: &PackageSpecifier) {
+        trace!(
+            target: LOGNAME,
+            "adding {}({}) to requested packages",
+            package.name,
+            package.version
+        );
+        self.requested.insert(
+            package.name.clone(),
+            PackageRequest {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                extras: package.extras.clone(),
+            },
+        );
+    }
+
+    fn remove(&mut self, name: &PackageName) -> Result<()> {
+        trace!(target: LOGNAME, "removing {} from requested packages", name);
+        match self.requested.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(DBError::PackageNotFound(name.clone())),
+        }
+    }
+
+    fn requested(&self) -> HashMap<PackageName, VersionReq> {
+        self.requested
+            .values()
+            .map(|req| (req.name.clone(), req.version.clone()))
+            .collect()
+    }
+
+    fn requested_extras(&self) -> HashMap<PackageName, Vec<String>> {
+        self.requested
+            .values()
+            .filter(|req| !req.extras.is_empty())
+            .map(|req| (req.name.clone(), req.extras.clone()))
+            .collect()
+    }
+
+    fn pin(&mut self, name: &PackageName, version: &Version) -> Result<()> {
+        if let Some(req) = self.requested.get(name) {
+            if !req.version.matches(version) {
+                return Err(DBError::PinConflict {
+                    name: name.clone(),
+                    pin: version.clone(),
+                    conflicts_with: req.version.clone(),
+                });
+            }
+        }
+
+        trace!(target: LOGNAME, "pinning {}({})", name, version);
+        self.pinned.insert(name.clone(), version.clone());
         Ok(())
     }
+
+    fn unpin(&mut self, name: &PackageName) -> Result<()> {
+        trace!(target: LOGNAME, "unpinning {}", name);
+        match self.pinned.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(DBError::NotPinned(name.clone())),
+        }
+    }
+
+    fn list_installed(&self) -> HashMap<PackageName, Version> {
+        self.installed
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.version.clone()))
+            .collect()
+    }
+
+    fn mark_installed(
+        &mut self,
+        name: &PackageName,
+        version: &Version,
+        source: Option<&str>,
+        source_url: Option<&Url>,
+        digest: Option<&str>,
+        files: Option<&[String]>,
+    ) {
+        trace!(
+            target: LOGNAME,
+            "marking {}({}) as installed",
+            name,
+            version
+        );
+
+        // Everything in a solution gets marked as installed, whether it changed
+        // or not, so we have to keep the original install time for anything
+        // that hasn't.
+        let installed_at = match self.installed.get(name) {
+            Some(entry) if &entry.version == version => entry.installed_at,
+            _ => Some(SystemTime::now()),
+        };
+        let files = match (files, self.installed.get(name)) {
+            (Some(files), _) => files.to_vec(),
+            (None, Some(entry)) => entry.files.clone(),
+            (None, None) => Vec::new(),
+        };
+        let source_url = match (source_url, self.installed.get(name)) {
+            (Some(url), _) => Some(url.clone()),
+            (None, Some(entry)) if &entry.version == version => entry.source_url.clone(),
+            (None, _) => None,
+        };
+        self.installed.insert(
+            name.clone(),
+            InstalledEntry {
+                version: version.clone(),
+                source: source.map(|s| s.to_string()),
+                source_url,
+                digest: digest.map(|d| d.to_string()),
+                installed_at,
+                files,
+            },
+        );
+    }
+
+    fn installed_files(&self) -> HashMap<PackageName, Vec<String>> {
+        self.installed
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.files.clone()))
+            .collect()
+    }
+
+    fn source_urls(&self) -> HashMap<PackageName, Url> {
+        self.installed
+            .iter()
+            .filter_map(|(name, entry)| Some((name.clone(), entry.source_url.clone()?)))
+            .collect()
+    }
+
+    fn mark_removed(&mut self, name: &PackageName) {
+        trace!(target: LOGNAME, "marking {} as removed", name);
+        self.installed.remove(name);
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            requested: self.requested(),
+            installed: self.installed.clone(),
+        }
+    }
+}
+
+enum DatabaseBackend {
+    FileSystem(VfsPath),
+    Memory { fs: VfsPath, lock: Arc<Mutex<()>> },
 }
 
-pub(crate) struct Database {
+pub struct Database {
     id: String,
-    fs: VfsPath,
+    backend: DatabaseBackend,
     state: Option<State>,
+    operation: Option<Operation>,
+    max_history: u32,
+    read_only: bool,
 }
 
 impl Database {
     pub(crate) fn new(fs: VfsPath, id: String) -> Result<Database> {
         Ok(Database {
             id,
-            fs,
+            backend: DatabaseBackend::FileSystem(fs),
+            state: None,
+            operation: None,
+            max_history: DEFAULT_MAX_HISTORY,
+            read_only: false,
+        })
+    }
+
+    /// Refuse to write anything to the pkgdb, transactions can still be made,
+    /// but committing one is an error.
+    pub(crate) fn with_read_only(mut self, read_only: bool) -> Database {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Keep at most `max_history` transactions in the history, dropping the
+    /// oldest ones once there are more than that.
+    pub(crate) fn with_max_history(mut self, max_history: u32) -> Database {
+        self.max_history = max_history;
+        self
+    }
+
+    /// Create the pkgdb within `fs`, with an empty state, unless it already
+    /// exists.
+    pub fn initialize(fs: &VfsPath) -> Result<()> {
+        if !Database::is_initialized(fs) {
+            trace!(target: LOGNAME, "initializing pkgdb");
+            State::default().save(fs)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the pkgdb has been set up within `fs` at all, which is distinct
+    /// from it having been set up but having nothing in it.
+    pub fn is_initialized(fs: &VfsPath) -> bool {
+        state_path(fs)
+            .and_then(|p| Ok(p.is_file()?))
+            .unwrap_or(false)
+    }
+
+    /// Export the whole pkgdb as JSON, in a format that is versioned
+    /// separately from state.yml, so it can be relied on to move a pkgdb
+    /// between machines.
+    pub fn export_json(&self) -> Result<serde_json::Value> {
+        // Like a snapshot, this is whatever was last committed.
+        let state = State::load(self.fs())?;
+        let mut json =
+            serde_json::to_value(&state).map_err(|source| DBError::InvalidExport { source })?;
+
+        // The export is versioned on its own, so the state's own versioning
+        // doesn't belong in it.
+        if let serde_json::Value::Object(map) = &mut json {
+            map.remove(migrations::MIN_COMPATIBLE_VERSION_KEY);
+            map.insert(
+                SCHEMA_VERSION_KEY.to_string(),
+                serde_json::Value::from(EXPORT_SCHEMA_VERSION),
+            );
+        }
+
+        Ok(json)
+    }
+
+    /// Replace the whole pkgdb with one that was previously exported with
+    /// `export_json`, as a single transaction, so it shows up in the history
+    /// and can be rolled back like anything else.
+    pub fn import_json(&mut self, json: &serde_json::Value) -> Result<()> {
+        self.writable()?;
+        let mut json = json.clone();
+        let version = match &mut json {
+            serde_json::Value::Object(map) => map.remove(SCHEMA_VERSION_KEY),
+            _ => None,
+        };
+
+        match version.as_ref().and_then(|v| v.as_u64()) {
+            Some(EXPORT_SCHEMA_VERSION) => {}
+            _ => return Err(DBError::UnsupportedExport { version }),
+        }
+
+        let imported: State =
+            serde_json::from_value(json).map_err(|source| DBError::InvalidExport { source })?;
+
+        trace!(target: LOGNAME, "importing pkgdb");
+        transaction!(self, commit: true, {
+            self.describe(Operation::Import);
+            *self.state()? = imported;
+        });
+
+        Ok(())
+    }
+
+    // Used to exercise the Database without touching the filesystem, or
+    // the system wide named locks, which so far only the tests do.
+    #[allow(dead_code)]
+    pub(crate) fn new_in_memory(id: String) -> Result<Database> {
+        Ok(Database {
+            id,
+            backend: DatabaseBackend::Memory {
+                fs: MemoryFS::new().into(),
+                lock: Arc::new(Mutex::new(())),
+            },
             state: None,
+            operation: None,
+            max_history: DEFAULT_MAX_HISTORY,
+            read_only: false,
         })
     }
+}
 
-    pub(crate) fn transaction(&self) -> Result<TransactionManager> {
-        Ok(TransactionManager::new(&self.id)?)
+impl PackageStore for Database {
+    fn transaction(&self) -> Result<TransactionManager> {
+        Ok(match &self.backend {
+            DatabaseBackend::FileSystem(_) => TransactionManager::new(&self.id)?,
+            DatabaseBackend::Memory { lock, .. } => TransactionManager::in_process(lock.clone()),
+        })
     }
 
-    pub(crate) fn begin<'r>(&mut self, txnm: &'r TransactionManager) -> Result<Transaction<'r>> {
+    fn begin<'r>(&mut self, txnm: &'r TransactionManager) -> Result<Transaction<'r>> {
         let txn = txnm.begin()?;
         trace!(target: LOGNAME, "begin transaction");
+
+        // If a previous transaction was abandoned without being committed or
+        // rolled back, then whatever state it left behind is stale.
+        self.state = None;
+        self.operation = None;
+
         Ok(txn)
     }
 
-    pub(crate) fn commit(&mut self, txn: Transaction) -> Result<()> {
+    fn commit(&mut self, txn: Transaction<'_>) -> Result<()> {
+        self.writable()?;
         trace!(target: LOGNAME, "commit transaction");
-        let fs = self.fs.clone();
+        let fs = self.fs().clone();
 
         // Save all our various pieces of data that we've built up in our
-        // transaction.
-        self.state()?.save(&fs)?;
+        // transaction, and if that changed anything, record it in the history
+        // along with what it was before, which is still what's on disk until
+        // we've saved.
+        let operation = self.operation.take().unwrap_or(Operation::Unknown);
+        let after = self.state()?.clone();
+        let before = State::load(&fs)?;
+        after.save(&fs)?;
+        if before != after {
+            history::append(&fs, operation, before, after, self.max_history)?;
+        }
         self.state = None;
 
         // Drop our transaction, which unlocks everything, and ensures that
@@ -112,44 +567,228 @@ impl Database {
         Ok(())
     }
 
-    pub(crate) fn add(&mut self, package: &PackageSpecifier) -> Result<()> {
-        let state = self.state()?;
-        trace!(
-            target: LOGNAME,
-            "adding {}({}) to requested packages",
-            package.name,
-            package.version
-        );
-        state.requested.insert(
-            package.name.clone(),
-            PackageRequest {
-                name: package.name.clone(),
-                version: package.version.clone(),
-            },
-        );
+    fn rollback(&mut self, txn: Transaction<'_>) {
+        trace!(target: LOGNAME, "rollback transaction");
+        self.state = None;
+        self.operation = None;
+        drop(txn);
+    }
+
+    fn describe(&mut self, operation: Operation) {
+        self.operation = Some(operation);
+    }
+
+    fn add(&mut self, package: &PackageSpecifier) -> Result<()> {
+        self.state()?.add(package);
         Ok(())
     }
 
-    pub(crate) fn requested(&mut self) -> Result<&HashMap<PackageName, PackageRequest>> {
-        Ok(&self.state()?.requested)
+    fn remove(&mut self, name: &PackageName) -> Result<()> {
+        self.state()?.remove(name)
+    }
+
+    fn requested(&mut self) -> Result<HashMap<PackageName, VersionReq>> {
+        Ok(self.state()?.requested())
+    }
+
+    fn requested_extras(&mut self) -> Result<HashMap<PackageName, Vec<String>>> {
+        Ok(self.state()?.requested_extras())
+    }
+
+    fn pin(&mut self, name: &PackageName, version: &Version) -> Result<()> {
+        self.state()?.pin(name, version)
+    }
+
+    fn unpin(&mut self, name: &PackageName) -> Result<()> {
+        self.state()?.unpin(name)
+    }
+
+    fn pinned(&mut self) -> Result<HashMap<PackageName, Version>> {
+        Ok(self.state()?.pinned.clone())
+    }
+
+    fn list_installed(&mut self) -> Result<HashMap<PackageName, Version>> {
+        Ok(self.state()?.list_installed())
+    }
+
+    fn mark_installed(
+        &mut self,
+        name: &PackageName,
+        version: &Version,
+        source: Option<&str>,
+        source_url: Option<&Url>,
+        digest: Option<&str>,
+        files: Option<&[String]>,
+    ) -> Result<()> {
+        self.state()?
+            .mark_installed(name, version, source, source_url, digest, files);
+        Ok(())
+    }
+
+    fn installed_files(&mut self) -> Result<HashMap<PackageName, Vec<String>>> {
+        Ok(self.state()?.installed_files())
+    }
+
+    fn source_urls(&mut self) -> Result<HashMap<PackageName, Url>> {
+        Ok(self.state()?.source_urls())
+    }
+
+    fn mark_removed(&mut self, name: &PackageName) -> Result<()> {
+        self.state()?.mark_removed(name);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        // We don't hold the lock here, so we read whatever was last committed,
+        // rather than anything that an open transaction has changed.
+        Ok(State::load(self.fs())?.snapshot())
     }
 }
 
 impl Database {
+    /// Copy the whole pkgdb to `dest`, which must not already exist. The copy
+    /// is made next to `dest` and then renamed into place, so that `dest` is
+    /// never left with a partial backup in it.
+    pub fn backup(&self, dest: &VfsPath) -> Result<()> {
+        if dest.exists()? {
+            return Err(DBError::BackupExists(dest.as_str().to_string()));
+        }
+
+        let txnm = self.transaction()?;
+        let txn = txnm.begin()?;
+
+        trace!(target: LOGNAME, "backing up pkgdb to {:?}", dest.as_str());
+        let partial = sibling(dest, "partial")?;
+        if partial.exists()? {
+            partial.remove_dir_all()?;
+        }
+        pkgdb_path(self.fs())?.copy_dir(&partial)?;
+        partial.move_dir(dest)?;
+
+        drop(txn);
+        Ok(())
+    }
+
+    /// Replace the pkgdb with a backup that was made with `backup`, as long as
+    /// its state is one that we're able to use.
+    pub fn restore(&self, src: &VfsPath) -> Result<()> {
+        self.writable()?;
+        let state = src.join(STATE_FILE)?;
+        if !state.is_file()? {
+            return Err(DBError::InvalidBackup(src.as_str().to_string()));
+        }
+        let data = serde_yaml::from_reader(state.open_file()?)
+            .map_err(|source| DBError::InvalidState { source })?;
+        // A backup is only ever checked here, it gets migrated, with its own
+        // lock file, whenever it's loaded after being restored.
+        migrations::load(data, || Ok(None))?;
+
+        let txnm = self.transaction()?;
+        let txn = txnm.begin()?;
+
+        // We copy the backup in alongside the current pkgdb first, so that the
+        // only thing left to do once we start replacing it is a pair of renames.
+        trace!(target: LOGNAME, "restoring pkgdb from {:?}", src.as_str());
+        let pkgdb = pkgdb_path(self.fs())?;
+        let (restoring, previous) = (sibling(&pkgdb, "restoring")?, sibling(&pkgdb, "previous")?);
+        for path in [&restoring, &previous] {
+            if path.exists()? {
+                path.remove_dir_all()?;
+            }
+        }
+        src.copy_dir(&restoring)?;
+        if pkgdb.exists()? {
+            pkgdb.move_dir(&previous)?;
+        }
+        restoring.move_dir(&pkgdb)?;
+        if previous.exists()? {
+            previous.remove_dir_all()?;
+        }
+
+        drop(txn);
+        Ok(())
+    }
+
+    /// Everything that has been requested, for making many changes to it at
+    /// once, which like everything else, can only be done within a transaction.
+    pub fn requested_mut(&mut self) -> Result<&mut HashMap<PackageName, PackageRequest>> {
+        Ok(&mut self.state()?.requested)
+    }
+
+    /// Every transaction that has changed the pkgdb, oldest first, going back
+    /// as far as the history is kept for.
+    pub fn history(&self) -> Result<Vec<TransactionLogEntry>> {
+        history::load(self.fs())
+    }
+
+    /// Go back to what had been requested and pinned before the transaction
+    /// `to_id`, which is itself recorded in the history.
+    ///
+    /// What is installed is left alone, since nothing has been changed on disk,
+    /// so it's up to the next resolve to make that match again.
+    pub fn rollback_to(&mut self, to_id: u64) -> Result<()> {
+        let txnm = self.transaction()?;
+        let txn = self.begin(&txnm)?;
+        if let Err(err) = self.replay(to_id) {
+            self.rollback(txn);
+            return Err(err);
+        }
+        self.commit(txn)
+    }
+
+    // Restore what had been requested and pinned before `to_id` into the open
+    // transaction.
+    pub(crate) fn replay(&mut self, to_id: u64) -> Result<()> {
+        let entry = self
+            .history()?
+            .into_iter()
+            .find(|e| e.id == to_id)
+            .ok_or(DBError::UnknownTransaction(to_id))?;
+
+        trace!(target: LOGNAME, "rolling back to before transaction {}", to_id);
+        let state = self.state()?;
+        state.requested = entry.before.requested;
+        state.pinned = entry.before.pinned;
+        self.describe(Operation::Rollback(to_id));
+
+        Ok(())
+    }
+
+    /// Everything that is installed, along with where it was installed from,
+    /// which like everything else, can only be read within a transaction.
+    pub fn installed(&mut self) -> Result<&HashMap<PackageName, InstalledEntry>> {
+        Ok(&self.state()?.installed)
+    }
+
+    fn fs(&self) -> &VfsPath {
+        match &self.backend {
+            DatabaseBackend::FileSystem(fs) => fs,
+            DatabaseBackend::Memory { fs, .. } => fs,
+        }
+    }
+
+    fn writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(DBError::ReadOnly);
+        }
+
+        Ok(())
+    }
+
     fn in_transaction(&self) -> Result<bool> {
         Ok(self.transaction()?.is_active()?)
     }
 
     fn state(&mut self) -> Result<&mut State> {
         if self.in_transaction()? && self.state.is_none() {
-            self.state = Some(State::load(&self.fs)?);
+            self.state = Some(State::load(self.fs())?);
         }
 
         self.state.as_mut().ok_or(DBError::NoTransaction)
     }
 }
 
-fn pkgdb_path(fs: &VfsPath) -> Result<VfsPath> {
+pub(crate) fn pkgdb_path(fs: &VfsPath) -> Result<VfsPath> {
     Ok(fs.join(PKGDB_DIR)?)
 }
 
@@ -157,6 +796,14 @@ fn state_path(fs: &VfsPath) -> Result<VfsPath> {
     Ok(pkgdb_path(fs)?.join(STATE_FILE)?)
 }
 
+// A hidden path next to `path`, for staging changes to it.
+fn sibling(path: &VfsPath, suffix: &str) -> Result<VfsPath> {
+    let parent = path.parent().ok_or_else(|| vfs::VfsError::InvalidPath {
+        path: path.as_str().to_string(),
+    })?;
+    Ok(parent.join(format!(".{}.{}", path.filename(), suffix))?)
+}
+
 fn ensure_dir(path: &VfsPath) -> Result<()> {
     if !path.is_dir()? {
         path.create_dir()?;
@@ -165,20 +812,98 @@ fn ensure_dir(path: &VfsPath) -> Result<()> {
     Ok(())
 }
 
-macro_rules! transaction {
-    ($db:expr, $body:block) => {{
-        let __txnm = $db.transaction()?;
-        let __txn = $db.begin(&__txnm)?;
-        let __result = $body;
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
 
-        $db.commit(__txn)?;
+    use super::*;
 
-        __result
-    }};
+    fn add(store: &mut MemoryPackageStore, name: &str, panics: bool) -> Result<()> {
+        transaction!(store, {
+            store.add(&PackageSpecifier::any(name.parse().unwrap()))?;
+            if panics {
+                panic!("panicked mid transaction");
+            }
+        });
+        Ok(())
+    }
 
-    ($db:expr, $body:expr) => {{
-        transaction!($db, { $body })
-    }};
-}
+    #[test]
+    fn source_url_kept_for_same_version() {
+        let name: PackageName = "foo".parse().unwrap();
+        let url: Url = "https://example.com/foo-1.0.0.tar.gz".parse().unwrap();
+        let mut state = State::default();
 
-pub(crate) use transaction;
+        state.mark_installed(&name, &Version::new(1, 0, 0), None, Some(&url), None, None);
+        state.mark_installed(&name, &Version::new(1, 0, 0), None, None, None, None);
+        assert_eq!(state.source_urls().get(&name), Some(&url));
+
+        state.mark_installed(&name, &Version::new(2, 0, 0), None, None, None, None);
+        assert_eq!(state.source_urls().get(&name), None);
+    }
+
+    #[test]
+    fn panic_releases_transaction() {
+        let mut store = MemoryPackageStore::new();
+
+        let result = catch_unwind(AssertUnwindSafe(|| add(&mut store, "foo", true)));
+        assert!(result.is_err());
+        assert!(!store.transaction().unwrap().is_active().unwrap());
+
+        // If the lock hadn't been released, this would block forever.
+        add(&mut store, "bar", false).unwrap();
+    }
+
+    #[test]
+    fn panic_rolls_back_transaction() -> Result<()> {
+        let mut store = MemoryPackageStore::new();
+
+        let result = catch_unwind(AssertUnwindSafe(|| add(&mut store, "foo", true)));
+        assert!(result.is_err());
+        add(&mut store, "bar", false)?;
+
+        transaction!(store, {
+            let requested = store.requested()?;
+            assert!(!requested.contains_key(&"foo".parse::<PackageName>().unwrap()));
+            assert!(requested.contains_key(&"bar".parse::<PackageName>().unwrap()));
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn import_is_a_transaction() {
+        let mut db = Database::new_in_memory("mqpkg-test-import-json".to_string()).unwrap();
+        let exported = serde_json::json!({
+            "schema_version": EXPORT_SCHEMA_VERSION,
+            "requested": {"foo": "foo>=1.0.0"},
+        });
+
+        db.import_json(&exported).unwrap();
+
+        let requested = db.snapshot().unwrap().requested;
+        assert!(requested.contains_key(&"foo".parse::<PackageName>().unwrap()));
+        let history = db.history().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].operation, Operation::Import);
+        assert_eq!(
+            db.export_json().unwrap()["requested"],
+            exported["requested"]
+        );
+    }
+
+    #[test]
+    fn read_only_refuses_to_commit() {
+        let mut db = Database::new_in_memory("mqpkg-test-read-only".to_string())
+            .unwrap()
+            .with_read_only(true);
+
+        let result: Result<()> = (|| {
+            transaction!(db, {
+                db.add(&PackageSpecifier::any("foo".parse().unwrap()))?;
+            });
+            Ok(())
+        })();
+        assert!(matches!(result, Err(DBError::ReadOnly)));
+        assert!(!Database::is_initialized(db.fs()));
+    }
+}
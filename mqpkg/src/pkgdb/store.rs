@@ -0,0 +1,91 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::collections::HashMap;
+
+use semver::{Version, VersionReq};
+use url::Url;
+
+use crate::errors::DBError;
+use crate::pkgdb::transactions::{Transaction, TransactionManager};
+use crate::pkgdb::{Operation, Snapshot};
+use crate::types::{PackageName, PackageSpecifier};
+
+type Result<T, E = DBError> = core::result::Result<T, E>;
+
+/// Where an Installer keeps track of what has been requested and installed.
+///
+/// Everything other than starting a transaction is only ever called while the
+/// transaction is open, and nothing is expected to persist until it has been
+/// committed.
+pub trait PackageStore: Send {
+    /// The lock that guards this store, which every transaction holds for as
+    /// long as it is open.
+    fn transaction(&self) -> Result<TransactionManager>;
+
+    fn begin<'r>(&mut self, txnm: &'r TransactionManager) -> Result<Transaction<'r>> {
+        Ok(txnm.begin()?)
+    }
+
+    /// Persist everything that was changed during the transaction, and then
+    /// release it.
+    fn commit(&mut self, txn: Transaction<'_>) -> Result<()>;
+
+    /// Throw away everything that was changed during the transaction, and then
+    /// release it.
+    fn rollback(&mut self, txn: Transaction<'_>);
+
+    /// Say what the open transaction is doing, for the history of a store
+    /// that keeps one.
+    fn describe(&mut self, operation: Operation);
+
+    fn add(&mut self, package: &PackageSpecifier) -> Result<()>;
+
+    /// Remove `name` from what has been requested, which is an error if it
+    /// wasn't requested to begin with.
+    fn remove(&mut self, name: &PackageName) -> Result<()>;
+
+    fn requested(&mut self) -> Result<HashMap<PackageName, VersionReq>>;
+
+    /// The extras that each requested package was requested with, packages
+    /// without any are left out.
+    fn requested_extras(&mut self) -> Result<HashMap<PackageName, Vec<String>>>;
+
+    /// Always require `name` at exactly `version`, which is an error if that
+    /// version doesn't satisfy what `name` has been requested with.
+    fn pin(&mut self, name: &PackageName, version: &Version) -> Result<()>;
+
+    /// Stop pinning `name`, which is an error if it wasn't pinned.
+    fn unpin(&mut self, name: &PackageName) -> Result<()>;
+
+    fn pinned(&mut self) -> Result<HashMap<PackageName, Version>>;
+
+    fn list_installed(&mut self) -> Result<HashMap<PackageName, Version>>;
+
+    /// Record `name` as installed at `version`, along with the files that were
+    /// extracted for it, where None keeps whatever files were already recorded.
+    /// Likewise, a `source_url` of None keeps the one already recorded, as long
+    /// as it was for the same version.
+    fn mark_installed(
+        &mut self,
+        name: &PackageName,
+        version: &Version,
+        source: Option<&str>,
+        source_url: Option<&Url>,
+        digest: Option<&str>,
+        files: Option<&[String]>,
+    ) -> Result<()>;
+
+    /// The files that were extracted for each installed package.
+    fn installed_files(&mut self) -> Result<HashMap<PackageName, Vec<String>>>;
+
+    /// Where each installed package was downloaded from, for the ones that we
+    /// know that for.
+    fn source_urls(&mut self) -> Result<HashMap<PackageName, Url>>;
+
+    fn mark_removed(&mut self, name: &PackageName) -> Result<()>;
+
+    /// Read everything that has been committed, without needing a transaction.
+    fn snapshot(&self) -> Result<Snapshot>;
+}
@@ -2,6 +2,8 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+
 use named_lock::{Error as NLError, NamedLock, NamedLockGuard};
 
 use crate::errors::TransactionError;
@@ -9,29 +11,67 @@ use crate::errors::TransactionError;
 type Result<T, E = TransactionError> = core::result::Result<T, E>;
 
 #[derive(Debug)]
-pub(crate) struct TransactionManager {
-    lock: NamedLock,
+enum Lock {
+    Named(NamedLock),
+    InProcess(Arc<Mutex<()>>),
+}
+
+#[derive(Debug)]
+enum Guard<'r> {
+    Named { _guard: NamedLockGuard<'r> },
+    InProcess { _guard: MutexGuard<'r, ()> },
+}
+
+#[derive(Debug)]
+pub struct TransactionManager {
+    lock: Lock,
 }
 
 impl TransactionManager {
-    pub(super) fn new(id: &str) -> Result<TransactionManager> {
+    /// A lock that is shared by everything on the system using the same id.
+    pub fn new(id: &str) -> Result<TransactionManager> {
         Ok(TransactionManager {
-            lock: NamedLock::create(&format!("mqpkg.{}", id))?,
+            lock: Lock::Named(NamedLock::create(&format!("mqpkg.{}", id))?),
         })
     }
 
-    pub(super) fn begin(&self) -> Result<Transaction> {
-        Ok(Transaction {
-            _guard: self.lock.lock()?,
-        })
+    // An in process lock is only shared with the things that have a clone of
+    // the same mutex, which is fine when there's nothing outside of this
+    // process that could possibly be accessing the same pkgdb.
+    pub fn in_process(lock: Arc<Mutex<()>>) -> TransactionManager {
+        TransactionManager {
+            lock: Lock::InProcess(lock),
+        }
+    }
+
+    pub fn begin(&self) -> Result<Transaction> {
+        let guard = match &self.lock {
+            Lock::Named(lock) => Guard::Named {
+                _guard: lock.lock()?,
+            },
+            // A poisoned lock just means that something panicked while in a
+            // transaction, which doesn't mean the lock itself is unusable.
+            Lock::InProcess(lock) => Guard::InProcess {
+                _guard: lock.lock().unwrap_or_else(|e| e.into_inner()),
+            },
+        };
+
+        Ok(Transaction { _guard: guard })
     }
 
     pub(super) fn is_active(&self) -> Result<bool> {
-        match self.lock.try_lock() {
-            Ok(_) => Ok(false),
-            Err(e) => match e {
-                NLError::WouldBlock => Ok(true),
-                e => Err(TransactionError::LockError(e)),
+        match &self.lock {
+            Lock::Named(lock) => match lock.try_lock() {
+                Ok(_) => Ok(false),
+                Err(e) => match e {
+                    NLError::WouldBlock => Ok(true),
+                    e => Err(TransactionError::LockError(e)),
+                },
+            },
+            Lock::InProcess(lock) => match lock.try_lock() {
+                Ok(_) => Ok(false),
+                Err(TryLockError::WouldBlock) => Ok(true),
+                Err(TryLockError::Poisoned(_)) => Ok(false),
             },
         }
     }
@@ -39,5 +79,5 @@ impl TransactionManager {
 
 #[derive(Debug)]
 pub struct Transaction<'r> {
-    _guard: NamedLockGuard<'r>,
+    _guard: Guard<'r>,
 }
@@ -0,0 +1,59 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::env::consts::{ARCH, OS};
+use std::fmt;
+
+// These are the operating systems that std::env::consts::OS can report, which
+// we use to pick the OS component out of a target triple, since its position
+// within the triple depends on whether the triple has a vendor or not.
+const KNOWN_OS: &[&str] = &[
+    "linux",
+    "macos",
+    "ios",
+    "freebsd",
+    "dragonfly",
+    "netbsd",
+    "openbsd",
+    "solaris",
+    "android",
+    "windows",
+];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Platform {
+    arch: String,
+    os: String,
+}
+
+impl Platform {
+    pub(crate) fn current() -> Platform {
+        Platform {
+            arch: ARCH.to_string(),
+            os: OS.to_string(),
+        }
+    }
+
+    pub(crate) fn from_triple(triple: &str) -> Platform {
+        let mut parts = triple.split('-');
+        let arch = parts.next().unwrap_or_default().to_string();
+        let os = parts
+            .map(|p| if p == "darwin" { "macos" } else { p })
+            .find(|p| KNOWN_OS.contains(p))
+            .unwrap_or_default()
+            .to_string();
+
+        Platform { arch, os }
+    }
+
+    pub(crate) fn matches(&self, triple: &str) -> bool {
+        Platform::from_triple(triple) == *self
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.arch, self.os)
+    }
+}
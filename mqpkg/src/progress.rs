@@ -10,6 +10,11 @@ struct ProgressInternal<'p, T> {
     bar: Option<Box<dyn FnMut(u64) -> T + 'p>>,
     update: Option<Box<dyn FnMut(&T, u64) + 'p>>,
     finish: Option<Box<dyn FnMut(&T) + 'p>>,
+    // Downloads get their own callbacks, since there's one bar per file, and
+    // it's measured in bytes rather than steps.
+    download_start: Option<Box<dyn FnMut(u64, &str) -> T + 'p>>,
+    download_update: Option<Box<dyn FnMut(&T, u64) + 'p>>,
+    download_finish: Option<Box<dyn FnMut(&T) + 'p>>,
 }
 
 impl<'p, T> fmt::Debug for ProgressInternal<'p, T> {
@@ -27,17 +32,61 @@ impl<'p, T> ProgressInternal<'p, T> {
         self.spinner.as_mut().map(|cb| (cb)(msg))
     }
 
-    fn update(&mut self, bar: &T, delta: u64) {
-        if let Some(cb) = &mut self.update {
+    fn download(&mut self, len: u64, name: &str) -> Option<T> {
+        self.download_start.as_mut().map(|cb| (cb)(len, name))
+    }
+
+    fn update(&mut self, bar: &T, delta: u64, download: bool) {
+        let cb = if download {
+            &mut self.download_update
+        } else {
+            &mut self.update
+        };
+        if let Some(cb) = cb {
             (cb)(bar, delta);
         }
     }
 
-    fn finish(&mut self, bar: &T) {
-        if let Some(cb) = &mut self.finish {
+    fn finish(&mut self, bar: &T, download: bool) {
+        let cb = if download {
+            &mut self.download_finish
+        } else {
+            &mut self.finish
+        };
+        if let Some(cb) = cb {
             (cb)(bar);
         }
     }
+
+    fn missing(&self) -> Option<&'static str> {
+        let progress = [
+            ("progress_start", self.bar.is_some()),
+            ("progress_spinner", self.spinner.is_some()),
+            ("progress_update", self.update.is_some()),
+            ("progress_finish", self.finish.is_some()),
+        ];
+        let download = [
+            ("download_start", self.download_start.is_some()),
+            ("download_update", self.download_update.is_some()),
+            ("download_finish", self.download_finish.is_some()),
+        ];
+
+        missing(&progress).or_else(|| missing(&download))
+    }
+}
+
+fn missing(callbacks: &[(&'static str, bool)]) -> Option<&'static str> {
+    // Either none of our callbacks have been set, in which case progress
+    // is just disabled, or all of them have to be, otherwise we'd end up
+    // with bars that get created but never updated or finished.
+    if callbacks.iter().any(|(_, set)| *set) {
+        callbacks
+            .iter()
+            .find(|(_, set)| !*set)
+            .map(|(name, _)| *name)
+    } else {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -61,6 +110,9 @@ impl<'p, T> Progress<'p, T> {
                 update: None,
                 finish: None,
                 spinner: None,
+                download_start: None,
+                download_update: None,
+                download_finish: None,
             })),
         }
     }
@@ -84,6 +136,25 @@ impl<'p, T> Progress<'p, T> {
         let mut internal = self.internal.lock().unwrap();
         internal.finish = Some(Box::new(cb))
     }
+
+    pub(crate) fn with_download_start(&mut self, cb: impl FnMut(u64, &str) -> T + 'p) {
+        let mut internal = self.internal.lock().unwrap();
+        internal.download_start = Some(Box::new(cb))
+    }
+
+    pub(crate) fn with_download_update(&mut self, cb: impl FnMut(&T, u64) + 'p) {
+        let mut internal = self.internal.lock().unwrap();
+        internal.download_update = Some(Box::new(cb))
+    }
+
+    pub(crate) fn with_download_finish(&mut self, cb: impl FnMut(&T) + 'p) {
+        let mut internal = self.internal.lock().unwrap();
+        internal.download_finish = Some(Box::new(cb))
+    }
+
+    pub(crate) fn missing(&self) -> Option<&'static str> {
+        self.internal.lock().unwrap().missing()
+    }
 }
 
 impl<'p, T> Progress<'p, T> {
@@ -94,11 +165,17 @@ impl<'p, T> Progress<'p, T> {
     pub(crate) fn spinner(&self, msg: &'static str) -> ProgressBar<'p, T> {
         ProgressBar::new_spinner(self.internal.clone(), msg)
     }
+
+    /// A bar for downloading a single file of `len` bytes, for `name`.
+    pub(crate) fn download(&self, len: u64, name: &str) -> ProgressBar<'p, T> {
+        ProgressBar::new_download(self.internal.clone(), len, name)
+    }
 }
 
 pub(crate) struct ProgressBar<'p, T> {
     bar: Option<Box<T>>,
     internal: Arc<Mutex<ProgressInternal<'p, T>>>,
+    download: bool,
 }
 
 impl<'p, T> ProgressBar<'p, T> {
@@ -108,7 +185,11 @@ impl<'p, T> ProgressBar<'p, T> {
 
         drop(lock);
 
-        ProgressBar { internal, bar }
+        ProgressBar {
+            internal,
+            bar,
+            download: false,
+        }
     }
 
     fn new_spinner(
@@ -120,20 +201,41 @@ impl<'p, T> ProgressBar<'p, T> {
 
         drop(lock);
 
-        ProgressBar { internal, bar }
+        ProgressBar {
+            internal,
+            bar,
+            download: false,
+        }
+    }
+
+    fn new_download(
+        internal: Arc<Mutex<ProgressInternal<'p, T>>>,
+        len: u64,
+        name: &str,
+    ) -> ProgressBar<'p, T> {
+        let mut lock = internal.lock().unwrap();
+        let bar = lock.download(len, name).map(Box::new);
+
+        drop(lock);
+
+        ProgressBar {
+            internal,
+            bar,
+            download: true,
+        }
     }
 
     pub(crate) fn update(&self, delta: u64) {
         if let Some(bar) = &self.bar {
             let mut internal = self.internal.lock().unwrap();
-            internal.update(&**bar, delta);
+            internal.update(&**bar, delta, self.download);
         }
     }
 
     pub(crate) fn finish(&self) {
         if let Some(bar) = &self.bar {
             let mut internal = self.internal.lock().unwrap();
-            internal.finish(&**bar);
+            internal.finish(&**bar, self.download);
         }
     }
 }
@@ -2,118 +2,2120 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Cursor, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use ed25519_dalek::{PublicKey as SigningKey, Signature, Verifier};
+use flate2::read::GzDecoder;
 use indexmap::IndexMap;
-use log::info;
-use reqwest::blocking::Client as HTTPClient;
+use minisign::{PublicKey, SignatureBox};
+use reqwest::blocking::{Client as HTTPClient, RequestBuilder, Response};
+use reqwest::header::{
+    HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED,
+};
+use reqwest::StatusCode;
 use semver::{Version, VersionReq};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use url::Url;
+use vfs::VfsPath;
 
-use crate::config;
-use crate::errors::RepositoryError;
-use crate::resolver::{Candidate, StaticDependencies};
-use crate::types::{PackageName, Source};
+use crate::cache::PackageCache;
+use crate::config::{self, CompressionAlgorithm};
+use crate::errors::{InstallerError, RepositoryError};
+use crate::logging::{debug, info, trace, warn};
+use crate::platform::Platform;
+use crate::resolver::{
+    Candidate, Dependencies as CandidateDependencies, Name, Requirement, StaticDependencies,
+};
+use crate::summary::{RepositoryReport, SearchResult};
+use crate::types::{Package, PackageName, Source, WithSource};
 
 const LOGNAME: &str = "mqpkg::repository";
 
+const REPO_CACHE_FILE: &str = "repo_cache.json";
+const REPO_CACHE_DIR: &str = "repo_cache";
+
+// The zstd frame magic number, 0xFD2FB528, as it appears on disk.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+pub(crate) const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
+
+// Reporting stats never holds up an install, but we don't want to exit with a
+// report still being sent either, so we wait for them at the end, for at most
+// this long each.
+const STATS_TIMEOUT: Duration = Duration::from_secs(5);
+
 type Result<T, E = RepositoryError> = core::result::Result<T, E>;
 
-#[derive(Deserialize, Debug)]
-struct MetaData {
-    #[serde(rename = "name")]
-    _name: String,
+#[derive(Deserialize, Debug)]
+struct MetaData {
+    #[serde(rename = "name")]
+    _name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Release {
+    #[serde(default)]
+    dependencies: HashMap<PackageName, VersionReq>,
+    // Some repositories serve the dependencies of each release on their own,
+    // rather than in the index, in which case this is where they are.
+    #[serde(default)]
+    dependencies_url: Option<Url>,
+    #[serde(default)]
+    dependencies_digests: HashMap<String, String>,
+    // Named groups of dependencies that are only wanted when asked for.
+    #[serde(default)]
+    optional_dependencies: HashMap<String, HashMap<PackageName, VersionReq>>,
+    #[serde(default)]
+    platform: Option<Vec<String>>,
+    urls: Vec<Url>,
+    digests: HashMap<String, String>,
+    #[serde(default)]
+    post_install: Option<Vec<String>>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    homepage: Option<Url>,
+    #[serde(default)]
+    license: Option<String>,
+}
+
+// The dependencies of a single release, as they are served from its
+// dependencies_url.
+#[derive(Deserialize, Debug, Clone, Default)]
+struct ReleaseDependencies {
+    #[serde(default)]
+    dependencies: HashMap<PackageName, VersionReq>,
+    #[serde(default)]
+    optional_dependencies: HashMap<String, HashMap<PackageName, VersionReq>>,
+}
+
+type RemoteDependencyCache =
+    Arc<Mutex<HashMap<(PackageName, Version), Option<ReleaseDependencies>>>>;
+
+// Dependencies that are served separately from the index, which are only ever
+// fetched once something actually asks for them, so that we don't download the
+// dependencies of every version of a package just to resolve one of them.
+#[derive(Debug, Clone)]
+struct RemoteDependencies {
+    name: PackageName,
+    version: Version,
+    url: Url,
+    digest: Option<String>,
+    repo: config::Repository,
+    fetcher: Fetcher,
+    fetched: RemoteDependencyCache,
+}
+
+impl RemoteDependencies {
+    // The cache isn't locked while we fetch, so that one slow fetch doesn't
+    // hold up everything else, which means that two things asking for the same
+    // dependencies at once can both fetch them, but only the first one counts.
+    fn fetch(&self) -> Option<ReleaseDependencies> {
+        let key = (self.name.clone(), self.version.clone());
+        if let Some(fetched) = self.fetched.lock().unwrap().get(&key) {
+            return fetched.clone();
+        }
+
+        trace!(
+            target: LOGNAME,
+            "fetching dependencies of {} {} from {}",
+            self.name,
+            self.version,
+            self.url
+        );
+        let fetched =
+            match self
+                .fetcher
+                .fetch_dependencies(&self.repo, &self.url, self.digest.as_deref())
+            {
+                Ok(dependencies) => Some(dependencies),
+                Err(err) => {
+                    warn!(
+                        target: LOGNAME,
+                        "could not fetch the dependencies of {} {}: {}",
+                        self.name,
+                        self.version,
+                        err
+                    );
+                    None
+                }
+            };
+
+        self.fetched
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert(fetched)
+            .clone()
+    }
+
+    fn resolved(&self) -> Option<StaticDependencies> {
+        let fetched = self.fetch()?;
+        Some(
+            StaticDependencies::new(fetched.dependencies)
+                .with_optional(fetched.optional_dependencies),
+        )
+    }
+}
+
+// When the dependencies couldn't be fetched, the solver treats them as
+// unknown, which means that it won't ever pick this version.
+impl CandidateDependencies for RemoteDependencies {
+    fn get(&self) -> Option<HashMap<Name, Requirement>> {
+        self.resolved()?.get()
+    }
+
+    fn get_with_extras(&self, extras: &[String]) -> Option<HashMap<Name, Requirement>> {
+        self.resolved()?.get_with_extras(extras)
+    }
+}
+
+// Details about a package as a whole, rather than any one release of it.
+#[derive(Deserialize, Debug, Default)]
+struct PackageMeta {
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    homepage: Option<Url>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RepoIndex {
+    #[serde(rename = "meta")]
+    _meta: MetaData,
+    packages: HashMap<PackageName, HashMap<Version, Release>>,
+}
+
+// Version 2 only adds to version 1, and anything that only understands version
+// 1 ignores what it doesn't know about, so a version 2 index can be served to
+// either.
+#[derive(Deserialize, Debug)]
+struct RepoIndexV2 {
+    #[serde(rename = "meta")]
+    _meta: MetaData,
+    packages: HashMap<PackageName, HashMap<Version, Release>>,
+    #[serde(default)]
+    package_meta: HashMap<PackageName, PackageMeta>,
+}
+
+// Every format of the index that we understand ends up as the same RepoData,
+// so that nothing past fetching it has to care which one it was.
+trait IntoRepoData {
+    fn into_repo_data(self, fetched_from: Url) -> RepoData;
+}
+
+impl IntoRepoData for RepoIndex {
+    fn into_repo_data(self, fetched_from: Url) -> RepoData {
+        RepoData {
+            fetched_from,
+            packages: self.packages,
+            meta: HashMap::new(),
+        }
+    }
+}
+
+impl IntoRepoData for RepoIndexV2 {
+    fn into_repo_data(self, fetched_from: Url) -> RepoData {
+        RepoData {
+            fetched_from,
+            packages: self.packages,
+            meta: self.package_meta,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RepoData {
+    // Either the repository's url, or whichever of its mirrors we actually
+    // managed to fetch the index from.
+    fetched_from: Url,
+    packages: HashMap<PackageName, HashMap<Version, Release>>,
+    // Only indexes from version 2 onwards have any of this.
+    meta: HashMap<PackageName, PackageMeta>,
+}
+
+// A token bucket, which allows short bursts of up to a second's worth of
+// requests, but otherwise holds us to the configured rate.
+#[derive(Debug)]
+struct RateLimiter {
+    tokens: Mutex<f64>,
+    last_refill: Mutex<Instant>,
+    rate: f64,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> RateLimiter {
+        RateLimiter {
+            tokens: Mutex::new(rate.max(1.0)),
+            last_refill: Mutex::new(Instant::now()),
+            rate,
+        }
+    }
+
+    fn unlimited() -> RateLimiter {
+        RateLimiter::new(0.0)
+    }
+
+    fn acquire(&self) {
+        // Anything that isn't a positive rate means that we're not limited.
+        if self.rate.is_nan() || self.rate <= 0.0 {
+            return;
+        }
+
+        loop {
+            let wait = {
+                let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+                let mut last_refill = self.last_refill.lock().unwrap_or_else(|e| e.into_inner());
+
+                let now = Instant::now();
+                let elapsed = now.duration_since(*last_refill).as_secs_f64();
+                *tokens = (*tokens + elapsed * self.rate).min(self.rate.max(1.0));
+                *last_refill = now;
+
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return;
+                }
+
+                Duration::from_secs_f64((1.0 - *tokens) / self.rate)
+            };
+
+            trace!(target: LOGNAME, "rate limited, waiting {:?}", wait);
+            thread::sleep(wait);
+        }
+    }
+}
+
+// Counts the requests that are in flight, for the requests that aren't already
+// limited by how many threads are making them.
+#[derive(Debug)]
+struct RequestSlots {
+    max: usize,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl RequestSlots {
+    fn new(max: usize) -> RequestSlots {
+        RequestSlots {
+            max: max.max(1),
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> RequestSlot<'_> {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        while *used >= self.max {
+            used = self.freed.wait(used).unwrap_or_else(|e| e.into_inner());
+        }
+        *used += 1;
+
+        RequestSlot { slots: self }
+    }
+}
+
+struct RequestSlot<'s> {
+    slots: &'s RequestSlots,
+}
+
+impl Drop for RequestSlot<'_> {
+    fn drop(&mut self) {
+        *self.slots.used.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+// Everything that making a request needs, which is shared with anything that
+// still has to fetch something once the repositories have been fetched.
+#[derive(Debug, Clone)]
+struct Fetcher {
+    client: HTTPClient,
+    rate_limiter: Arc<RateLimiter>,
+    retry: config::RetryConfig,
+    offline: bool,
+    repo_cache: Option<Arc<RepoCache>>,
+    slots: Arc<RequestSlots>,
+}
+
+impl Fetcher {
+    /// Fetch `url` from `repo`, using what we've cached for it where we can,
+    /// and decoding it with `fallback` if the server didn't say how it was
+    /// encoded. Nothing gets cached unless `parse`, which is also where it
+    /// gets verified, accepts it.
+    fn fetch_cached<T>(
+        &self,
+        repo: &config::Repository,
+        url: &Url,
+        fallback: Option<CompressionAlgorithm>,
+        parse: impl Fn(&[u8]) -> Result<T>,
+    ) -> Result<T> {
+        let cached = self.repo_cache.as_ref().and_then(|c| c.get(url));
+        if self.offline {
+            let (_, data) = cached.ok_or_else(|| RepositoryError::Offline {
+                url: Box::new(url.clone()),
+            })?;
+            trace!(target: LOGNAME, "offline, using cached {}", url);
+            return parse(&data);
+        }
+        if let (Some(cache), Some((entry, data))) = (&self.repo_cache, &cached) {
+            if entry.is_fresh(cache.ttl) {
+                trace!(target: LOGNAME, "{} was fetched recently, using cache", url);
+                return parse(data);
+            }
+        }
+
+        let mut request = self
+            .request(url, Some(repo))
+            .header(ACCEPT_ENCODING, accept_encoding());
+        if let Some((entry, _)) = &cached {
+            if let Some(etag) = &entry.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = self.send(request)?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let (Some(cache), Some((_, data))) = (&self.repo_cache, cached) {
+                trace!(target: LOGNAME, "{} not modified, using cache", url);
+                let parsed = parse(&data)?;
+                cache.touch(url);
+                return Ok(parsed);
+            }
+        }
+
+        let response = response.error_for_status()?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let encoding = content_encoding(&response);
+
+        // We verify before caching, so that nothing we've cached is something
+        // that we wouldn't have accepted in the first place.
+        let mut data = response.bytes()?.to_vec();
+        if let Some(algorithm) = encoding.or_else(|| expected_encoding(fallback, &data)) {
+            data = decompress(algorithm, &data)?;
+        }
+        let parsed = parse(&data)?;
+        if let Some(cache) = &self.repo_cache {
+            cache.update(url, etag, last_modified, &data);
+        }
+
+        Ok(parsed)
+    }
+
+    /// Send `request`, retrying anything that failed in a way that might work
+    /// if we try again.
+    fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let mut attempt = 1;
+        loop {
+            // Only requests with a streaming body can't be cloned, and we
+            // never make those, but if we did, they just don't get retried.
+            let current = match request.try_clone() {
+                Some(current) => current,
+                None => {
+                    self.rate_limiter.acquire();
+                    return Ok(request.send()?);
+                }
+            };
+
+            self.rate_limiter.acquire();
+            let result = current.send();
+            let retryable = match &result {
+                Ok(response) => {
+                    response.status() == StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error()
+                }
+                Err(err) => err.is_timeout() || err.is_connect(),
+            };
+            if !retryable || attempt >= self.retry.max_attempts {
+                return Ok(result?);
+            }
+
+            let delay = self.retry_delay(attempt);
+            let reason = match &result {
+                Ok(response) => response.status().to_string(),
+                Err(err) => err.to_string(),
+            };
+            warn!(
+                target: LOGNAME,
+                "request failed ({}), retrying in {}ms (attempt {} of {})",
+                reason,
+                delay.as_millis(),
+                attempt + 1,
+                self.retry.max_attempts
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt - 1).unwrap_or(i32::MAX);
+        let delay =
+            self.retry.initial_delay_ms as f64 * self.retry.backoff_multiplier.powi(exponent);
+        let jitter = delay * self.retry.jitter_factor * rand::random::<f64>();
+        Duration::from_secs_f64((delay + jitter).max(0.0) / 1000.0)
+    }
+
+    /// Start a request for `url`, authenticated with the credentials for `repo`
+    /// if it's hosted by that repository.
+    fn request(&self, url: &Url, repo: Option<&config::Repository>) -> RequestBuilder {
+        authorize(self.client.get(url.clone()), url, repo)
+    }
+
+    /// Fetch the dependencies of a release from `url`, which has to be hosted
+    /// by `repo`, since that is all that vouches for them.
+    fn fetch_dependencies(
+        &self,
+        repo: &config::Repository,
+        url: &Url,
+        digest: Option<&str>,
+    ) -> Result<ReleaseDependencies> {
+        let hosted = std::iter::once(&repo.url)
+            .chain(repo.mirrors.iter())
+            .any(|r| same_origin(r, url));
+        if !hosted {
+            return Err(RepositoryError::UntrustedDependenciesUrl {
+                url: Box::new(url.clone()),
+                repository: Box::new(repo.url.clone()),
+            });
+        }
+
+        // The index only vouches for the url, so when the index itself has to
+        // be verified, the dependencies need a digest of their own.
+        if digest.is_none() && (repo.signing_key.is_some() || repo.index_digest.is_some()) {
+            return Err(RepositoryError::UnverifiedDependencies {
+                url: Box::new(url.clone()),
+            });
+        }
+        let parse = |data: &[u8]| -> Result<ReleaseDependencies> {
+            check_digest(url, digest, data)?;
+            Ok(serde_json::from_slice(data)?)
+        };
+
+        match url.scheme() {
+            "file" => parse(&std::fs::read(file_path(url)?)?),
+            _ => {
+                let _slot = self.slots.acquire();
+                let dependencies = self.fetch_cached(repo, url, None, parse)?;
+                if let Some(cache) = &self.repo_cache {
+                    cache.save();
+                }
+                Ok(dependencies)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedEntry {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    data_path: String,
+    // When the server last gave us, or confirmed, this metadata.
+    #[serde(default)]
+    fetched_at: Option<SystemTime>,
+}
+
+impl CachedEntry {
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        match self.fetched_at.map(|at| at.elapsed()) {
+            Some(Ok(age)) => age < ttl,
+            // A time in the future means the clock has changed, so we can't
+            // tell how old this is.
+            _ => false,
+        }
+    }
+}
+
+// Remembers the validators that servers gave us for each repository's metadata,
+// along with the metadata itself, so that we can ask the server whether it has
+// changed rather than downloading it again. Like the package cache, this is
+// purely an optimization, so failures are logged and otherwise ignored.
+#[derive(Debug)]
+struct RepoCache {
+    root: VfsPath,
+    // Anything fetched within this long ago is used without asking the server.
+    ttl: Duration,
+    // Used as normal, except that nothing ever gets written back.
+    read_only: bool,
+    entries: Mutex<HashMap<Url, CachedEntry>>,
+}
+
+impl RepoCache {
+    fn load(root: VfsPath, ttl: Duration, read_only: bool) -> RepoCache {
+        let entries = root
+            .join(REPO_CACHE_FILE)
+            .ok()
+            .filter(|p| p.is_file().unwrap_or(false))
+            .and_then(|p| match p.open_file() {
+                Ok(file) => match serde_json::from_reader(file) {
+                    Ok(entries) => Some(entries),
+                    Err(err) => {
+                        warn!(target: LOGNAME, "ignoring invalid {}: {}", REPO_CACHE_FILE, err);
+                        None
+                    }
+                },
+                Err(err) => {
+                    warn!(target: LOGNAME, "could not read {}: {}", REPO_CACHE_FILE, err);
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        RepoCache {
+            root,
+            ttl,
+            read_only,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    // The server told us that what we have is still current, so it's fresh
+    // again.
+    fn touch(&self, url: &Url) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(url) {
+            entry.fetched_at = Some(SystemTime::now());
+        }
+    }
+
+    // We only return an entry if we still have the data it refers to, since an
+    // entry without any data isn't something we can use to skip a download.
+    fn get(&self, url: &Url) -> Option<(CachedEntry, Vec<u8>)> {
+        let entry = self.entries.lock().unwrap().get(url).cloned()?;
+        let path = self.root.join(&entry.data_path).ok()?;
+        match Repository::load_cache(&path) {
+            Ok(data) => Some((entry, data)),
+            Err(err) => {
+                trace!(
+                    target: LOGNAME,
+                    "could not load cached metadata for {}: {}",
+                    url,
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    fn update(&self, url: &Url, etag: Option<String>, last_modified: Option<String>, data: &[u8]) {
+        // There's no point caching anything if the server isn't going to give
+        // us any way to tell if it has changed, and we won't use it unasked.
+        if self.read_only || etag.is_none() && last_modified.is_none() && self.ttl.is_zero() {
+            return;
+        }
+
+        let digest = format!("{:x}", Sha256::digest(url.as_str().as_bytes()));
+        // load_cache doesn't go by the extension, this is just to tell people
+        // poking around in the cache what they're looking at.
+        let extension = if cfg!(feature = "zstd") {
+            "json.zst"
+        } else {
+            "json"
+        };
+        let data_path = format!("{}/{}.{}", REPO_CACHE_DIR, &digest[..16], extension);
+        let result = self
+            .root
+            .join(REPO_CACHE_DIR)
+            .and_then(|dir| dir.create_dir_all())
+            .map_err(RepositoryError::from)
+            .and_then(|_| Repository::save_cache(&self.root.join(&data_path)?, data));
+        if let Err(err) = result {
+            warn!(target: LOGNAME, "could not cache metadata for {}: {}", url, err);
+            return;
+        }
+
+        self.entries.lock().unwrap().insert(
+            url.clone(),
+            CachedEntry {
+                etag,
+                last_modified,
+                data_path,
+                fetched_at: Some(SystemTime::now()),
+            },
+        );
+    }
+
+    // Forget every entry that isn't for one of `urls`, or for dependencies that
+    // one of their cached indexes refers to, and remove any cached metadata
+    // that no entry refers to anymore.
+    fn prune(&self, urls: &HashSet<Url>, dry_run: bool) -> Vec<(String, u64)> {
+        // Dependencies that are served separately from the index get cached
+        // right alongside it, and are just as likely to be needed again.
+        let mut keep_urls = urls.clone();
+        for url in urls.iter() {
+            if let Some((_, data)) = self.get(url) {
+                keep_urls.extend(dependencies_urls(&data));
+            }
+        }
+
+        let referenced: HashSet<String> = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|url, _| {
+                let keep = keep_urls.contains(url);
+                if !keep {
+                    trace!(target: LOGNAME, "forgetting cached metadata for {}", url);
+                }
+                keep
+            });
+            entries.values().map(|e| e.data_path.clone()).collect()
+        };
+
+        let dir = match self.root.join(REPO_CACHE_DIR) {
+            Ok(dir) => dir,
+            Err(_) => return Vec::new(),
+        };
+        let mut removed = Vec::new();
+        for path in dir.read_dir().into_iter().flatten() {
+            let data_path = format!("{}/{}", REPO_CACHE_DIR, path.filename());
+            if referenced.contains(&data_path) || !path.is_file().unwrap_or(false) {
+                continue;
+            }
+
+            let len = path.metadata().map(|m| m.len).unwrap_or(0);
+            if !dry_run {
+                if let Err(err) = path.remove_file() {
+                    warn!(target: LOGNAME, "could not remove {:?}: {}", path.as_str(), err);
+                    continue;
+                }
+            }
+            removed.push((path.as_str().to_string(), len));
+        }
+
+        if !dry_run {
+            self.save();
+        }
+
+        removed
+    }
+
+    // We write to a temporary file first and then move it into place, so that
+    // we never leave a partially written cache behind.
+    fn save(&self) {
+        if self.read_only {
+            return;
+        }
+
+        let result = || -> Result<()> {
+            let entries = self.entries.lock().unwrap();
+            let tmp = self.root.join(format!("{}.tmp", REPO_CACHE_FILE))?;
+            let dest = self.root.join(REPO_CACHE_FILE)?;
+
+            self.root.create_dir_all()?;
+            serde_json::to_writer(tmp.create_file()?, &*entries)?;
+            if dest.exists()? {
+                dest.remove_file()?;
+            }
+            tmp.move_file(&dest)?;
+            Ok(())
+        }();
+
+        if let Err(err) = result {
+            warn!(target: LOGNAME, "could not save {}: {}", REPO_CACHE_FILE, err);
+        }
+    }
+}
+
+// Every dependencies_url in an index. We only look at the JSON itself, rather
+// than parsing the index properly, since all we want is what it points at.
+fn dependencies_urls(index: &[u8]) -> Vec<Url> {
+    let index: serde_json::Value = match serde_json::from_slice(index) {
+        Ok(index) => index,
+        Err(_) => return Vec::new(),
+    };
+    index
+        .get("packages")
+        .and_then(|packages| packages.as_object())
+        .into_iter()
+        .flat_map(|packages| packages.values())
+        .filter_map(|releases| releases.as_object())
+        .flat_map(|releases| releases.values())
+        .filter_map(|release| release.get("dependencies_url")?.as_str()?.parse().ok())
+        .collect()
+}
+
+/// Remove the cached metadata within `root` for every repository that isn't
+/// at one of `urls`, returning the path and size of each file that was, or for
+/// a dry run, would be, removed.
+pub(crate) fn prune_repo_cache(
+    root: VfsPath,
+    urls: &HashSet<Url>,
+    dry_run: bool,
+) -> Vec<(String, u64)> {
+    RepoCache::load(root, Duration::ZERO, dry_run).prune(urls, dry_run)
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FetchResult {
+    pub(crate) successful: Vec<String>,
+    pub(crate) failed: Vec<(String, RepositoryError)>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Repository {
+    fetcher: Fetcher,
+    platform: Platform,
+    fail_fast: bool,
+    max_concurrent_requests: usize,
+    package_cache: Option<PackageCache>,
+    // Reading a manifest means reading the whole archive, so we only ever want
+    // to do that once for each package.
+    manifests: Mutex<HashMap<(PackageName, Version), Option<Dependencies>>>,
+    remote_dependencies: RemoteDependencyCache,
+    stats: StatsReports,
+    data: IndexMap<config::Repository, RepoData>,
+}
+
+// The stats reports that are still being sent, which get waited on when the
+// Repository goes away.
+#[derive(Debug, Default)]
+struct StatsReports(Mutex<Vec<thread::JoinHandle<()>>>);
+
+impl Drop for StatsReports {
+    fn drop(&mut self) {
+        let reports = match self.0.get_mut() {
+            Ok(reports) => reports,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for report in reports.drain(..) {
+            report.join().ok();
+        }
+    }
+}
+
+type Dependencies = HashMap<PackageName, VersionReq>;
+
+impl Repository {
+    /// Passing no proxy config disables proxying entirely, even if one has
+    /// been set in the environment.
+    pub(crate) fn new(
+        platform: Platform,
+        proxy: Option<&config::ProxyConfig>,
+    ) -> Result<Repository> {
+        let mut builder = HTTPClient::builder().gzip(true);
+        builder = match proxy {
+            Some(config::ProxyConfig {
+                proxy: Some(url),
+                proxy_username,
+                proxy_password,
+            }) => {
+                let mut proxy = reqwest::Proxy::all(url.clone())?;
+                if let Some(username) = proxy_username {
+                    proxy = proxy.basic_auth(username, proxy_password.as_deref().unwrap_or(""));
+                }
+                builder.proxy(proxy)
+            }
+            // reqwest already picks up any proxy from the environment.
+            Some(_) => builder,
+            None => builder.no_proxy(),
+        };
+        let client = builder.build()?;
+        let data = IndexMap::<config::Repository, RepoData>::new();
+
+        Ok(Repository {
+            fetcher: Fetcher {
+                client,
+                rate_limiter: Arc::new(RateLimiter::unlimited()),
+                retry: config::RetryConfig::default(),
+                offline: false,
+                repo_cache: None,
+                slots: Arc::new(RequestSlots::new(DEFAULT_MAX_CONCURRENT_REQUESTS)),
+            },
+            platform,
+            fail_fast: false,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            package_cache: None,
+            manifests: Mutex::new(HashMap::new()),
+            remote_dependencies: RemoteDependencyCache::default(),
+            stats: StatsReports::default(),
+            data,
+        })
+    }
+
+    /// Use `index` as if it had been fetched for `repo`.
+    #[cfg(test)]
+    pub(crate) fn with_index(mut self, repo: &str, index: serde_json::Value) -> Repository {
+        let repo: config::Repository = repo.parse().unwrap();
+        let index: RepoIndex = serde_json::from_value(index).unwrap();
+        self.data
+            .insert(repo.clone(), index.into_repo_data(repo.url));
+        self
+    }
+
+    pub(crate) fn with_rate_limit(mut self, requests_per_second: f64) -> Repository {
+        self.fetcher.rate_limiter = Arc::new(RateLimiter::new(requests_per_second));
+        self
+    }
+
+    /// Never have more than `max` requests in flight at once, where a `max` of
+    /// 0 or 1 means that everything is fetched one at a time.
+    pub(crate) fn with_max_concurrent_requests(mut self, max: usize) -> Repository {
+        self.max_concurrent_requests = max;
+        self.fetcher.slots = Arc::new(RequestSlots::new(max));
+        self
+    }
+
+    pub(crate) fn with_retry(mut self, retry: config::RetryConfig) -> Repository {
+        self.fetcher.retry = retry;
+        self
+    }
+
+    /// Never make any network requests, so metadata can only come from the
+    /// repo cache, and packages can only come from `file://` urls.
+    pub(crate) fn with_offline(mut self, offline: bool) -> Repository {
+        self.fetcher.offline = offline;
+        self
+    }
+
+    /// Stop fetching at the first repository that fails, rather than trying
+    /// every one of them first.
+    pub(crate) fn with_fail_fast(mut self, fail_fast: bool) -> Repository {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Cache repository metadata within the pkgdb at `root`, which is used as
+    /// is for up to `ttl` after it was fetched, and after that only downloaded
+    /// again if the server says that it has changed.
+    pub(crate) fn with_repo_cache(mut self, root: VfsPath, ttl: Duration) -> Repository {
+        self.fetcher.repo_cache = Some(Arc::new(RepoCache::load(root, ttl, false)));
+        self
+    }
+
+    /// Use the metadata cached in `root` just like `with_repo_cache` does,
+    /// except that nothing that gets fetched is ever written back to it.
+    pub(crate) fn with_read_only_repo_cache(mut self, root: VfsPath, ttl: Duration) -> Repository {
+        self.fetcher.repo_cache = Some(Arc::new(RepoCache::load(root, ttl, true)));
+        self
+    }
+
+    /// Prefer the manifests inside of any package archives that are already in
+    /// `cache` over the repository metadata.
+    pub(crate) fn with_package_cache(mut self, cache: PackageCache) -> Repository {
+        self.package_cache = Some(cache);
+        self
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub(crate) fn fetch(
+        self,
+        repos: &[config::Repository],
+        callback: impl Fn(),
+    ) -> Result<Repository> {
+        let (repository, result) = self.fetch_partial(repos, callback);
+
+        // We only consider fetching to have failed if we weren't able to get
+        // anything at all, otherwise one repository being down would make every
+        // package from every other repository unavailable too.
+        if result.successful.is_empty() {
+            if let Some((_, err)) = result.failed.into_iter().next() {
+                return Err(err);
+            }
+        } else {
+            for (name, err) in result.failed.iter() {
+                warn!(target: LOGNAME, "could not fetch {}: {}", name, err);
+            }
+        }
+
+        Ok(repository)
+    }
+
+    pub(crate) fn fetch_partial(
+        mut self,
+        repos: &[config::Repository],
+        callback: impl Fn(),
+    ) -> (Repository, FetchResult) {
+        let mut result = FetchResult::default();
+
+        info!(target: LOGNAME, "fetching package metadata");
+        let mut fetched = self.fetch_all(repos, callback, false);
+
+        // Our repositories are fetched in parallel, so they finish in whatever
+        // order they finish in, but the order they're declared in determines
+        // their priority, so that's the order we insert them in.
+        fetched.sort_by_key(|(idx, _)| *idx);
+        for (idx, fetched) in fetched.into_iter() {
+            let repo = &repos[idx];
+            let name = if !repo.name.is_empty() {
+                repo.name.clone()
+            } else {
+                repo.url.to_string()
+            };
+
+            match fetched {
+                Ok(data) => {
+                    if data.fetched_from != repo.url {
+                        info!(target: LOGNAME, "fetched {} from {}", name, data.fetched_from);
+                    }
+                    self.data.insert(repo.clone(), data);
+                    result.successful.push(name);
+                }
+                Err(err) => result.failed.push((name, err)),
+            }
+        }
+
+        if let Some(cache) = &self.fetcher.repo_cache {
+            cache.save();
+        }
+
+        (self, result)
+    }
+
+    /// Fetch every repository in `repos`, failing if any of them can't be
+    /// fetched, with every error that we got. Unless we're failing fast, that's
+    /// only once all of them have been tried, otherwise we stop at the first
+    /// one that fails.
+    pub(crate) fn fetch_parallel(
+        mut self,
+        repos: &[config::Repository],
+        callback: impl Fn(),
+    ) -> Result<Repository> {
+        let mut fetched = self.fetch_all(repos, callback, self.fail_fast);
+        fetched.sort_by_key(|(idx, _)| *idx);
+
+        // Whatever we did fetch is still worth caching, even if we're failing.
+        let mut errors = Vec::new();
+        for (idx, fetched) in fetched.into_iter() {
+            match fetched {
+                Ok(data) => {
+                    self.data.insert(repos[idx].clone(), data);
+                }
+                Err(err) => errors.push((repos[idx].url.clone(), err)),
+            }
+        }
+
+        if let Some(cache) = &self.fetcher.repo_cache {
+            cache.save();
+        }
+
+        if errors.is_empty() {
+            Ok(self)
+        } else {
+            Err(RepositoryError::MultipleFetchErrors(errors))
+        }
+    }
+
+    // Fetches our repositories on up to max_concurrent_requests threads at a
+    // time, returning the results along with the index of the repository that
+    // they are for. The callback is only ever called from this thread, once per
+    // repository as each one finishes. With stop_on_error, the first error is
+    // the last result, and nothing else gets started after it.
+    fn fetch_all(
+        &self,
+        repos: &[config::Repository],
+        callback: impl Fn(),
+        stop_on_error: bool,
+    ) -> Vec<(usize, Result<RepoData>)> {
+        // With no concurrency at all, there's no point in having threads, and
+        // doing everything in order makes what happened much easier to follow.
+        if self.max_concurrent_requests <= 1 {
+            let mut fetched = Vec::new();
+            for (idx, repo) in repos.iter().enumerate() {
+                let result = self.fetch_repository(repo);
+                (callback)();
+                let failed = result.is_err();
+                fetched.push((idx, result));
+                if failed && stop_on_error {
+                    break;
+                }
+            }
+            return fetched;
+        }
+
+        // Each worker takes the next repository that nobody has started on yet,
+        // until there aren't any left, or we've been told to stop.
+        let next = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..self.max_concurrent_requests.min(repos.len()) {
+                let tx = tx.clone();
+                let (next, stop) = (&next, &stop);
+                scope.spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        let idx = next.fetch_add(1, Ordering::SeqCst);
+                        let repo = match repos.get(idx) {
+                            Some(repo) => repo,
+                            None => break,
+                        };
+                        // Once we've stopped, nobody is listening anymore, and
+                        // whatever this fetched just gets thrown away.
+                        tx.send((idx, self.fetch_repository(repo))).ok();
+                    }
+                });
+            }
+            drop(tx);
+
+            // Requests that are already in flight can't be interrupted, so the
+            // scope still waits for them, but nothing new gets started.
+            let mut fetched = Vec::new();
+            for (idx, result) in rx.iter() {
+                (callback)();
+                let failed = result.is_err();
+                fetched.push((idx, result));
+                if failed && stop_on_error {
+                    stop.store(true, Ordering::SeqCst);
+                    break;
+                }
+            }
+            drop(rx);
+
+            fetched
+        })
+    }
+
+    pub(crate) fn candidates<P: AsRef<PackageName>>(&self, package: P) -> Vec<Candidate> {
+        let mut candidates = Vec::<Candidate>::new();
+
+        // Repositories are preferred in the order that they were defined in, no
+        // matter what versions they have, so that a repository can override a
+        // package from one after it, even with an older version. Because
+        // self.data is an IndexMap, that's already the order we iterate in, so
+        // we only need to sort the versions within each repository, newest
+        // first.
+        for (idx, (repo, data)) in self.data.iter().enumerate() {
+            if let Some(packages) = data.packages.get(package.as_ref()) {
+                let mut releases: Vec<_> = packages.iter().collect();
+                releases.sort_by(|(l, _), (r, _)| r.cmp(l));
+                for (version, release) in releases.into_iter() {
+                    if !self.is_compatible(release) {
+                        trace!(
+                            target: LOGNAME,
+                            "skipping {}({}), not available for {}",
+                            package.as_ref(),
+                            version,
+                            self.platform
+                        );
+                        continue;
+                    }
+
+                    let dependencies =
+                        self.candidate_dependencies(package.as_ref(), version, repo, release);
+                    candidates.push(Candidate::new(
+                        version,
+                        Box::new(RepositorySource::new(
+                            u64::try_from(idx).unwrap(),
+                            repo.clone(),
+                        )),
+                        dependencies,
+                    ));
+                }
+            }
+        }
+
+        candidates
+    }
+
+    /// Every package whose name or description contains `query`, with the
+    /// latest version that is available from the first repository to have any
+    /// compatible release of it, sorted by how well it matched.
+    pub(crate) fn search(&self, query: &str) -> Result<Vec<SearchResult>> {
+        if self.data.is_empty() {
+            return Err(RepositoryError::NotLoaded);
+        }
+
+        // Names are always canonical, so the query needs to be as well, but
+        // descriptions are free form so they're only compared ignoring case.
+        let name_query = query.to_ascii_lowercase().replace('-', "_");
+        let description_query = query.to_lowercase();
+
+        let mut found = HashMap::<&PackageName, SearchResult>::new();
+        for (_, data) in self.data.iter() {
+            for (name, releases) in data.packages.iter() {
+                if found.contains_key(name) {
+                    continue;
+                }
+
+                let latest = releases
+                    .iter()
+                    .filter(|(_, release)| self.is_compatible(release))
+                    .max_by(|(l, _), (r, _)| l.cmp(r));
+                let (version, release) = match latest {
+                    Some(latest) => latest,
+                    None => continue,
+                };
+
+                // The package's own metadata describes every release of it, so
+                // it's preferred over whatever the latest release says.
+                let meta = data.meta.get(name);
+                let description = meta
+                    .map(|m| m.description.as_str())
+                    .filter(|d| !d.is_empty())
+                    .or(release.description.as_deref());
+                let tags = meta.map(|m| m.tags.as_slice()).unwrap_or_default();
+                let score = if name.canonical() == name_query {
+                    1.0
+                } else if name.canonical().starts_with(&name_query) {
+                    0.75
+                } else if name.canonical().contains(&name_query) {
+                    0.5
+                } else if description
+                    .map(|d| d.to_lowercase().contains(&description_query))
+                    .unwrap_or(false)
+                    || tags.iter().any(|t| t.to_lowercase() == description_query)
+                {
+                    0.25
+                } else {
+                    continue;
+                };
+
+                found.insert(
+                    name,
+                    SearchResult {
+                        name: name.clone(),
+                        latest_version: version.clone(),
+                        description: description.map(String::from),
+                        score,
+                    },
+                );
+            }
+        }
+
+        let mut results: Vec<SearchResult> = found.into_values().collect();
+        results.sort_by(|l, r| {
+            r.score
+                .total_cmp(&l.score)
+                .then_with(|| l.name.cmp(&r.name))
+        });
+
+        Ok(results)
+    }
+
+    /// Find a specific version of a package, from the first repository that
+    /// has a release of it that is compatible with our platform.
+    pub(crate) fn package(&self, name: &PackageName, version: &Version) -> Option<Package> {
+        self.data
+            .iter()
+            .enumerate()
+            .find(|(_, (_, data))| {
+                data.packages
+                    .get(name)
+                    .and_then(|p| p.get(version))
+                    .map(|r| self.is_compatible(r))
+                    .unwrap_or(false)
+            })
+            .map(|(idx, (repo, _))| {
+                Package::new(
+                    name.clone(),
+                    version.clone(),
+                    Box::new(RepositorySource::new(
+                        u64::try_from(idx).unwrap(),
+                        repo.clone(),
+                    )),
+                )
+            })
+    }
+
+    /// What `package` depends on, which comes from the manifest in its archive
+    /// if we already have that cached, since that is what actually gets
+    /// installed, and otherwise from the repository metadata. This is always
+    /// the same as what the solver was given for it.
+    pub(crate) fn dependencies(&self, package: &Package) -> Option<Dependencies> {
+        let release = self.release(package)?;
+        let (name, version) = (package.name(), package.version());
+        if let Some(dependencies) = self.manifest_dependencies(name, version, release) {
+            return Some(dependencies);
+        }
+
+        let (repo, _) = self.source(package)?;
+        match self.remote_dependencies(name, version, repo, release) {
+            Some(remote) => Some(remote.fetch()?.dependencies),
+            None => Some(release.dependencies.clone()),
+        }
+    }
+
+    // What the solver gets for a release's dependencies, which has to be
+    // decided the same way as `dependencies` does, so that what we resolve and
+    // what we say about it afterwards always agree. Manifests don't have any
+    // optional dependencies, so those always come from the repository metadata.
+    fn candidate_dependencies(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        repo: &config::Repository,
+        release: &Release,
+    ) -> Box<dyn CandidateDependencies + Sync + Send> {
+        if let Some(dependencies) = self.manifest_dependencies(name, version, release) {
+            return Box::new(
+                StaticDependencies::new(dependencies)
+                    .with_optional(release.optional_dependencies.clone()),
+            );
+        }
+
+        match self.remote_dependencies(name, version, repo, release) {
+            Some(remote) => Box::new(remote),
+            None => Box::new(
+                StaticDependencies::new(release.dependencies.clone())
+                    .with_optional(release.optional_dependencies.clone()),
+            ),
+        }
+    }
+
+    fn remote_dependencies(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        repo: &config::Repository,
+        release: &Release,
+    ) -> Option<RemoteDependencies> {
+        Some(RemoteDependencies {
+            name: name.clone(),
+            version: version.clone(),
+            url: release.dependencies_url.clone()?,
+            digest: release.dependencies_digests.get("sha256").cloned(),
+            repo: repo.clone(),
+            fetcher: self.fetcher.clone(),
+            fetched: self.remote_dependencies.clone(),
+        })
+    }
+
+    fn manifest_dependencies(
+        &self,
+        name: &PackageName,
+        version: &Version,
+        release: &Release,
+    ) -> Option<Dependencies> {
+        let cache = self.package_cache.as_ref()?;
+        let digest = release.digests.get("sha256")?;
+
+        let mut manifests = self.manifests.lock().unwrap();
+        let key = (name.clone(), version.clone());
+        manifests
+            .entry(key)
+            .or_insert_with(|| {
+                let manifest = cache.manifest(name, version, digest)?;
+                if &manifest.name != name || &manifest.version != version {
+                    warn!(
+                        target: LOGNAME,
+                        "the manifest for {name} {version} is for {} {}, ignoring it",
+                        manifest.name,
+                        manifest.version
+                    );
+                    return None;
+                }
+                // When the dependencies are served on their own, there's nothing
+                // in the index to compare against.
+                if release.dependencies_url.is_none()
+                    && manifest.dependencies != release.dependencies
+                {
+                    warn!(
+                        target: LOGNAME,
+                        "the dependencies of {name} {version} in its manifest differ from the \
+                         repository metadata, using the manifest"
+                    );
+                }
+
+                Some(manifest.dependencies)
+            })
+            .clone()
+    }
+
+    /// The commands to run after this package has been extracted.
+    pub(crate) fn post_install(&self, package: &Package) -> &[String] {
+        self.release(package)
+            .and_then(|r| r.post_install.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Download `package`, calling `progress` with the total size of the file
+    /// (or 0 if that isn't known) and how many more bytes were just read.
+    pub(crate) fn download(
+        &self,
+        package: &Package,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<(Url, Vec<u8>)> {
+        let release = self
+            .release(package)
+            .ok_or_else(|| RepositoryError::UnknownRelease {
+                name: package.name().clone(),
+                version: package.version().clone(),
+            })?;
+
+        // We try each of the urls in order, and the first one that we can
+        // successfully fetch wins.
+        let mut error = None;
+        for url in release.urls.iter() {
+            info!(target: LOGNAME, "downloading {}", url);
+            let repo = self.source(package).map(|(repo, _)| repo);
+            match self
+                .fetch_url(url, repo, progress)
+                .and_then(|d| verify(url, release, d))
+            {
+                Ok(data) => return Ok((url.clone(), data)),
+                Err(err) => error = Some(err),
+            }
+        }
+
+        Err(error.unwrap_or_else(|| RepositoryError::NoDownloadUrls {
+            name: package.name().clone(),
+            version: package.version().clone(),
+        }))
+    }
+
+    pub(crate) fn digest(&self, package: &Package) -> Option<&str> {
+        self.release(package)?
+            .digests
+            .get("sha256")
+            .map(|d| d.as_str())
+    }
+
+    pub(crate) fn verify_signature(
+        &self,
+        package: &Package,
+        url: &Url,
+        data: &[u8],
+    ) -> Result<bool> {
+        // Repositories without any trusted keys don't require signatures, so
+        // there's nothing for us to check.
+        let repo = match self.source(package) {
+            Some((repo, _)) if !repo.trusted_keys.is_empty() => repo,
+            _ => return Ok(true),
+        };
+        let keys: Vec<PublicKey> = repo
+            .trusted_keys
+            .iter()
+            .filter_map(|key| match PublicKey::from_base64(key) {
+                Ok(key) => Some(key),
+                Err(err) => {
+                    warn!(target: LOGNAME, "ignoring invalid trusted key {key:?}: {err}");
+                    None
+                }
+            })
+            .collect();
+
+        let signature = self.fetch_signature(url, repo)?;
+        let signature = match String::from_utf8(signature)
+            .ok()
+            .and_then(|s| SignatureBox::from_string(&s).ok())
+        {
+            Some(signature) => signature,
+            None => {
+                trace!(target: LOGNAME, "invalid signature for {}", url);
+                return Ok(false);
+            }
+        };
+
+        Ok(keys.iter().any(|key| {
+            minisign::verify(key, &signature, Cursor::new(data), true, false, false).is_ok()
+        }))
+    }
+
+    pub(crate) fn report_download(&self, package: &Package) {
+        // Being offline means not making any requests at all, and that includes
+        // reporting stats.
+        if self.fetcher.offline {
+            return;
+        }
+
+        let repo = match self.source(package) {
+            Some((repo, _)) if repo.report_stats => repo,
+            _ => return,
+        };
+        let url = match stats_url(repo) {
+            Some(url) if matches!(url.scheme(), "http" | "https") => url,
+            _ => return,
+        };
+
+        let client = self.fetcher.client.clone();
+        let body = json!({
+            "package": package.name(),
+            "version": package.version(),
+            "success": true,
+        });
+
+        // Reporting stats should never block, or cause a failure in, the actual
+        // install, so it happens in the background, and is only waited on, for
+        // a bounded time, once we're done with the Repository.
+        trace!(target: LOGNAME, "reporting download of {} to {}", package, url);
+        let report = thread::spawn(move || {
+            let result = client
+                .post(url.clone())
+                .timeout(STATS_TIMEOUT)
+                .json(&body)
+                .send()
+                .and_then(|r| r.error_for_status());
+            if let Err(err) = result {
+                trace!(target: LOGNAME, "could not report stats to {}: {}", url, err);
+            }
+        });
+        if let Ok(mut reports) = self.stats.0.lock() {
+            reports.push(report);
+        }
+    }
+
+    fn source(&self, package: &Package) -> Option<(&config::Repository, &RepoData)> {
+        // The discriminator of a RepositorySource is the index of the repository
+        // that the package came from, so that's where we look it up.
+        let idx = usize::try_from(package.source().discriminator()).ok()?;
+        self.data.get_index(idx)
+    }
+
+    pub(crate) fn description(&self, package: &Package) -> Option<&str> {
+        match self.meta(package) {
+            Some(meta) if !meta.description.is_empty() => Some(meta.description.as_str()),
+            _ => self.release(package)?.description.as_deref(),
+        }
+    }
+
+    pub(crate) fn homepage(&self, package: &Package) -> Option<&Url> {
+        match self.meta(package).and_then(|m| m.homepage.as_ref()) {
+            Some(homepage) => Some(homepage),
+            None => self.release(package)?.homepage.as_ref(),
+        }
+    }
+
+    pub(crate) fn tags(&self, package: &Package) -> &[String] {
+        self.meta(package)
+            .map(|m| m.tags.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn authors(&self, package: &Package) -> &[String] {
+        self.meta(package)
+            .map(|m| m.authors.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn license(&self, package: &Package) -> Option<&str> {
+        self.release(package)?.license.as_deref()
+    }
+
+    /// The url of the repository that `package` came from.
+    pub(crate) fn source_url(&self, package: &Package) -> Option<&Url> {
+        self.source(package).map(|(repo, _)| &repo.url)
+    }
+
+    /// The name of the repository that `package` came from.
+    pub(crate) fn source_name(&self, package: &Package) -> Option<&str> {
+        let (repo, _) = self.source(package)?;
+        if repo.name.is_empty() {
+            Some(repo.url.as_str())
+        } else {
+            Some(repo.name.as_str())
+        }
+    }
+
+    pub(crate) fn urls(&self, package: &Package) -> &[Url] {
+        self.release(package)
+            .map(|r| r.urls.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn meta(&self, package: &Package) -> Option<&PackageMeta> {
+        let (_, data) = self.source(package)?;
+        data.meta.get(package.name())
+    }
+
+    fn release(&self, package: &Package) -> Option<&Release> {
+        let (_, data) = self.source(package)?;
+        data.packages.get(package.name())?.get(package.version())
+    }
+
+    // Tries the repository's url, then each of its mirrors in turn, and if
+    // none of them work, the error we report is the one from the url itself.
+    fn fetch_repository(&self, repo: &config::Repository) -> Result<RepoData> {
+        let mut primary_err = None;
+        for url in std::iter::once(&repo.url).chain(repo.mirrors.iter()) {
+            match self.fetch_repository_from(repo, url) {
+                Ok(data) => return Ok(data),
+                Err(err) => {
+                    if !repo.mirrors.is_empty() {
+                        warn!(target: LOGNAME, "could not fetch {}: {}", url, err);
+                    }
+                    primary_err.get_or_insert(err);
+                }
+            }
+        }
+
+        // We always try at least the repository's url, so there's always an
+        // error if we got here.
+        Err(primary_err.unwrap())
+    }
+
+    fn fetch_repository_from(&self, repo: &config::Repository, url: &Url) -> Result<RepoData> {
+        match url.scheme() {
+            "file" => {
+                let mut data = std::fs::read(file_path(url)?)?;
+                if let Some(algorithm) = file_compression(url).or(repo.compression) {
+                    data = decompress(algorithm, &data)?;
+                }
+                load_index(repo, url, &data)
+            }
+            _ => self
+                .fetcher
+                .fetch_cached(repo, url, repo.compression, |data| {
+                    load_index(repo, url, data)
+                }),
+        }
+    }
+
+    /// Fetch and parse the index at `url` on its own, without any of the
+    /// caching, retrying, or rate limiting that fetching a repository that we
+    /// have configured gets.
+    pub(crate) fn fetch_one(url: &Url, client: &HTTPClient) -> Result<RepoData> {
+        let (mut data, encoding) = match url.scheme() {
+            "file" => (std::fs::read(file_path(url)?)?, file_compression(url)),
+            _ => {
+                let response = client
+                    .get(url.clone())
+                    .header(ACCEPT_ENCODING, accept_encoding())
+                    .send()?
+                    .error_for_status()?;
+                let encoding = content_encoding(&response);
+                let data = response.bytes()?.to_vec();
+                let encoding = encoding.or_else(|| expected_encoding(file_compression(url), &data));
+                (data, encoding)
+            }
+        };
+        if let Some(algorithm) = encoding {
+            data = decompress(algorithm, &data)?;
+        }
+
+        parse_index(url, serde_json::from_slice(&data)?)
+    }
+
+    fn fetch_url(
+        &self,
+        url: &Url,
+        repo: Option<&config::Repository>,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<Vec<u8>> {
+        if url.scheme() == "file" {
+            let data = std::fs::read(file_path(url)?)?;
+            let len = u64::try_from(data.len()).unwrap_or(u64::MAX);
+            (progress)(len, len);
+            return Ok(data);
+        }
+        if self.fetcher.offline {
+            return Err(RepositoryError::Offline {
+                url: Box::new(url.clone()),
+            });
+        }
+
+        let mut response = self
+            .fetcher
+            .send(self.fetcher.request(url, repo))?
+            .error_for_status()?;
+        let total = response.content_length().unwrap_or(0);
+
+        // We read the body ourselves, rather than all at once, so that we can
+        // report progress as we go.
+        let mut data = Vec::with_capacity(usize::try_from(total).unwrap_or_default());
+        let mut buf = vec![0; DOWNLOAD_CHUNK_SIZE];
+        loop {
+            let read = response.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..read]);
+            (progress)(total, u64::try_from(read).unwrap_or_default());
+        }
+
+        Ok(data)
+    }
+
+    fn fetch_signature(&self, url: &Url, repo: &config::Repository) -> Result<Vec<u8>> {
+        let mut sig_url = url.clone();
+        sig_url.set_path(&format!("{}.minisig", url.path()));
+
+        info!(target: LOGNAME, "downloading {}", sig_url);
+        match self.fetch_url(&sig_url, Some(repo), &mut |_, _| {}) {
+            Err(RepositoryError::HTTPError(err)) if err.status() == Some(StatusCode::NOT_FOUND) => {
+                Err(RepositoryError::SignatureFetchFailed {
+                    url: Box::new(sig_url),
+                })
+            }
+            Err(RepositoryError::IoError(err)) if err.kind() == ErrorKind::NotFound => {
+                Err(RepositoryError::SignatureFetchFailed {
+                    url: Box::new(sig_url),
+                })
+            }
+            result => result,
+        }
+    }
+
+    fn is_compatible(&self, release: &Release) -> bool {
+        // Releases that don't declare any platforms are assumed to work
+        // everywhere.
+        match &release.platform {
+            Some(platforms) => platforms.iter().any(|p| self.platform.matches(p)),
+            None => true,
+        }
+    }
+
+    // Repository metadata is mostly JSON, which compresses very well, so any
+    // metadata that gets cached on disk is written compressed, when we're
+    // built with zstd.
+    fn save_cache(path: &VfsPath, data: &[u8]) -> Result<()> {
+        #[cfg(feature = "zstd")]
+        let compressed = zstd::encode_all(data, ZSTD_LEVEL)?;
+        #[cfg(not(feature = "zstd"))]
+        let compressed = data.to_vec();
+        trace!(
+            target: LOGNAME,
+            "caching metadata at {:?} ({} bytes, {} compressed)",
+            path.as_str(),
+            data.len(),
+            compressed.len()
+        );
+
+        path.create_file()
+            .and_then(|mut f| f.write_all(&compressed).map_err(vfs::VfsError::from))?;
+        Ok(())
+    }
+
+    // Cache files that were written before we compressed them are still read,
+    // so we sniff for the zstd magic number rather than assuming.
+    fn load_cache(path: &VfsPath) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        path.open_file()
+            .and_then(|mut f| f.read_to_end(&mut data).map_err(vfs::VfsError::from))?;
+
+        if data.starts_with(&ZSTD_MAGIC) {
+            decompress(CompressionAlgorithm::Zstd, &data)
+        } else {
+            trace!(
+                target: LOGNAME,
+                "{:?} is not compressed, reading as is",
+                path.as_str()
+            );
+            Ok(data)
+        }
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct Release {
-    #[serde(default)]
-    dependencies: HashMap<PackageName, VersionReq>,
-    #[serde(rename = "urls")]
-    _urls: Vec<Url>,
-    #[serde(rename = "digests")]
-    _digests: HashMap<String, String>,
+fn accept_encoding() -> String {
+    let mut encodings = Vec::new();
+    if cfg!(feature = "zstd") {
+        encodings.push("zstd");
+    }
+    if cfg!(feature = "brotli") {
+        encodings.push("br");
+    }
+    encodings.push("gzip");
+
+    encodings.join(", ")
 }
 
-#[derive(Deserialize, Debug)]
-struct RepoData {
-    #[serde(rename = "meta")]
-    _meta: MetaData,
-    packages: HashMap<PackageName, HashMap<Version, Release>>,
+// We only ever send credentials to the server that they were configured for,
+// packages are often served from somewhere else entirely, and the same host
+// over plain http, or on another port, isn't the same server.
+fn authorize(
+    request: RequestBuilder,
+    url: &Url,
+    repo: Option<&config::Repository>,
+) -> RequestBuilder {
+    match repo {
+        Some(repo) if same_origin(&repo.url, url) => match Credentials::for_repository(repo) {
+            Some(credentials) => credentials.authorize(request),
+            None => request,
+        },
+        _ => request,
+    }
 }
 
-#[derive(Debug)]
-pub(crate) struct Repository {
-    client: HTTPClient,
-    data: IndexMap<config::Repository, RepoData>,
+// The stats endpoint lives underneath the repository's url, unless it has been
+// configured to be somewhere else. Url::join replaces the last segment of a
+// path that doesn't end with a slash, so we have to add one first.
+fn stats_url(repo: &config::Repository) -> Option<Url> {
+    if let Some(url) = &repo.stats_url {
+        return Some(url.clone());
+    }
+
+    let mut base = repo.url.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    base.join("stats").ok()
 }
 
-impl Repository {
-    pub(crate) fn new() -> Result<Repository> {
-        let client = HTTPClient::builder().gzip(true).build()?;
-        let data = IndexMap::<config::Repository, RepoData>::new();
+// Whether `a` and `b` are served by the same server, in the same way.
+fn same_origin(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme()
+        && a.host_str() == b.host_str()
+        && a.port_or_known_default() == b.port_or_known_default()
+}
+
+fn file_path(url: &Url) -> Result<PathBuf> {
+    url.to_file_path()
+        .map_err(|_| RepositoryError::InvalidFileUrl {
+            url: Box::new(url.clone()),
+        })
+}
+
+// Verify the index that we fetched from `url` for `repo`, and then parse it,
+// the signature is over the index as a JSON value, rather than its exact
+// bytes, so it's only ever parsed once, as a value, for both of those.
+fn load_index(repo: &config::Repository, url: &Url, data: &[u8]) -> Result<RepoData> {
+    check_digest(url, repo.index_digest.as_deref(), data)?;
 
-        Ok(Repository { client, data })
+    let index: serde_json::Value = serde_json::from_slice(data)?;
+    match &repo.signing_key {
+        Some(key) => verify_index_signature(key, url, &index)?,
+        None => debug!(target: LOGNAME, "{} has no signing key, not verifying it", url),
     }
 
-    pub(crate) fn fetch(
-        mut self,
-        repos: &[config::Repository],
-        callback: impl Fn(),
-    ) -> Result<Repository> {
-        info!(target: LOGNAME, "fetching package metadata");
-        for repo in repos.iter() {
-            let data: RepoData = match repo.url.scheme() {
-                "file" => {
-                    let file = File::open(repo.url.to_file_path().unwrap())?;
-                    let reader = BufReader::new(file);
+    parse_index(url, index)
+}
 
-                    serde_json::from_reader(reader)?
-                }
-                _ => self
-                    .client
-                    .get(repo.url.clone())
-                    .send()?
-                    .error_for_status()?
-                    .json()?,
-            };
-            self.data.insert(repo.clone(), data);
-            (callback)();
+// Indexes from before there was more than one format don't say which one
+// they're in, and are version 1.
+fn format_version(index: &serde_json::Value) -> Result<u32> {
+    match index.get("format_version") {
+        Some(version) => Ok(u32::deserialize(version)?),
+        None => Ok(1),
+    }
+}
+
+fn parse_index(url: &Url, index: serde_json::Value) -> Result<RepoData> {
+    let format_version = format_version(&index)?;
+    trace!(
+        target: LOGNAME,
+        "{} is index format version {}",
+        url,
+        format_version
+    );
+    match format_version {
+        1 => Ok(serde_json::from_value::<RepoIndex>(index)?.into_repo_data(url.clone())),
+        2 => Ok(serde_json::from_value::<RepoIndexV2>(index)?.into_repo_data(url.clone())),
+        version => Err(RepositoryError::UnsupportedIndexVersion {
+            url: Box::new(url.clone()),
+            version,
+        }),
+    }
+}
+
+/// Fetch the index at `url`, and check that it's one that we're able to use,
+/// without needing a configuration, or anything else, to do it.
+pub fn check_repository(url: &Url) -> core::result::Result<RepositoryReport, InstallerError> {
+    let client = HTTPClient::builder()
+        .gzip(true)
+        .build()
+        .map_err(RepositoryError::from)?;
+    let data = Repository::fetch_one(url, &client)?;
+
+    Ok(RepositoryReport {
+        url: url.clone(),
+        packages: data.packages.len(),
+        releases: data.packages.values().map(|r| r.len()).sum(),
+    })
+}
+
+// reqwest takes care of gzip for us, and removes the Content-Encoding when it
+// does, so any encoding that's left is one that we have to decode ourselves.
+fn content_encoding(response: &Response) -> Option<CompressionAlgorithm> {
+    match response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some("zstd") => Some(CompressionAlgorithm::Zstd),
+        Some("br") => Some(CompressionAlgorithm::Brotli),
+        _ => None,
+    }
+}
+
+// reqwest decodes gzip itself, and drops the Content-Encoding when it does, so
+// when we were only expecting gzip, rather than told about it, what we got may
+// already have been decoded by the time that we see it.
+fn expected_encoding(
+    expected: Option<CompressionAlgorithm>,
+    data: &[u8],
+) -> Option<CompressionAlgorithm> {
+    expected.filter(|algorithm| {
+        *algorithm != CompressionAlgorithm::Gzip || data.starts_with(&GZIP_MAGIC)
+    })
+}
+
+fn file_compression(url: &Url) -> Option<CompressionAlgorithm> {
+    let path = url.path().to_ascii_lowercase();
+    if path.ends_with(".json.zst") {
+        Some(CompressionAlgorithm::Zstd)
+    } else if path.ends_with(".json.br") {
+        Some(CompressionAlgorithm::Brotli)
+    } else if path.ends_with(".json.gz") {
+        Some(CompressionAlgorithm::Gzip)
+    } else {
+        None
+    }
+}
+
+fn decompress(algorithm: CompressionAlgorithm, data: &[u8]) -> Result<Vec<u8>> {
+    let failed = |err: std::io::Error| RepositoryError::DecompressionError(err.to_string());
+    let mut decoded = Vec::new();
+    match algorithm {
+        CompressionAlgorithm::Gzip => {
+            GzDecoder::new(data)
+                .read_to_end(&mut decoded)
+                .map_err(failed)?;
+        }
+        #[cfg(feature = "zstd")]
+        CompressionAlgorithm::Zstd => {
+            decoded = zstd::decode_all(data).map_err(failed)?;
+        }
+        #[cfg(feature = "brotli")]
+        CompressionAlgorithm::Brotli => {
+            brotli::Decompressor::new(data, 4096)
+                .read_to_end(&mut decoded)
+                .map_err(failed)?;
+        }
+        #[allow(unreachable_patterns)]
+        algorithm => {
+            return Err(RepositoryError::DecompressionError(format!(
+                "mqpkg was built without support for {algorithm}"
+            )))
+        }
+    }
+
+    Ok(decoded)
+}
+
+fn verify_index_signature(key: &str, url: &Url, index: &serde_json::Value) -> Result<()> {
+    let invalid = || RepositoryError::InvalidSignature {
+        url: Box::new(url.clone()),
+    };
+
+    // A base64 encoded Ed25519 signature over the canonical JSON of packages,
+    // and from version 2 onwards, over package_meta as well, since that gets
+    // shown to users too.
+    let packages = index.get("packages").ok_or_else(invalid)?;
+    let signature = index
+        .get("signature")
+        .and_then(|s| s.as_str())
+        .and_then(|s| base64::decode(s).ok())
+        .and_then(|s| Signature::try_from(s.as_slice()).ok())
+        .ok_or_else(invalid)?;
+
+    let key = match base64::decode(key).map(|k| SigningKey::from_bytes(&k)) {
+        Ok(Ok(key)) => key,
+        _ => {
+            warn!(target: LOGNAME, "the signing key for {} is not a valid key", url);
+            return Err(invalid());
         }
+    };
 
-        Ok(self)
+    // Both of them get signed as a single object, keyed by where they came
+    // from, which is what canonical_json would produce for that object.
+    let mut message = String::new();
+    if format_version(index)? >= 2 {
+        let empty = serde_json::Value::Object(serde_json::Map::new());
+        message.push_str("{\"package_meta\":");
+        canonical_json(index.get("package_meta").unwrap_or(&empty), &mut message);
+        message.push_str(",\"packages\":");
+        canonical_json(packages, &mut message);
+        message.push('}');
+    } else {
+        canonical_json(packages, &mut message);
     }
+    key.verify(message.as_bytes(), &signature)
+        .map_err(|_| invalid())
+}
 
-    pub(crate) fn candidates<P: AsRef<PackageName>>(&self, package: P) -> Vec<Candidate> {
-        let mut candidates = Vec::<Candidate>::new();
+// Objects have their keys sorted, and there is no whitespace anywhere, so two
+// indexes with the same packages always have the same canonical JSON.
+fn canonical_json(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
 
-        // Because our underlying type of self.data is an IndexMap, this will ensure
-        // that our Vec is sorted by the order our repositories were defined in, however
-        // the list of versions within that is not sorted, so we'll need to resort
-        // the full list later.
-        for (idx, (repo, data)) in self.data.iter().enumerate() {
-            if let Some(packages) = data.packages.get(package.as_ref()) {
-                for (version, release) in packages.iter() {
-                    candidates.push(Candidate::new(
-                        version,
-                        Box::new(RepositorySource::new(
-                            u64::try_from(idx).unwrap(),
-                            repo.clone(),
-                        )),
-                        Box::new(StaticDependencies::new(release.dependencies.clone())),
-                    ));
+            out.push('{');
+            for (idx, key) in keys.into_iter().enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::Value::from(key.as_str()).to_string());
+                out.push(':');
+                canonical_json(&map[key], out);
+            }
+            out.push('}');
+        }
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (idx, item) in items.iter().enumerate() {
+                if idx > 0 {
+                    out.push(',');
                 }
+                canonical_json(item, out);
             }
+            out.push(']');
         }
+        other => out.push_str(&other.to_string()),
+    }
+}
 
-        candidates
+fn verify(url: &Url, release: &Release, data: Vec<u8>) -> Result<Vec<u8>> {
+    check_digest(
+        url,
+        release.digests.get("sha256").map(|d| d.as_str()),
+        &data,
+    )?;
+    Ok(data)
+}
+
+fn check_digest(url: &Url, expected: Option<&str>, data: &[u8]) -> Result<()> {
+    match expected {
+        Some(expected) => {
+            let got = format!("{:x}", Sha256::digest(data));
+            if !got.eq_ignore_ascii_case(expected) {
+                return Err(RepositoryError::DigestMismatch {
+                    url: Box::new(url.clone()),
+                    expected: expected.to_string(),
+                    got,
+                });
+            }
+        }
+        None => trace!(target: LOGNAME, "no sha256 digest for {}, skipping", url),
+    }
+
+    Ok(())
+}
+
+enum Credentials {
+    Bearer(String),
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+}
+
+impl Credentials {
+    /// The credentials configured for `repo`, falling back to whatever is in
+    /// the user's netrc file for its host.
+    fn for_repository(repo: &config::Repository) -> Option<Credentials> {
+        if let Some(token) = &repo.token {
+            return Some(Credentials::Bearer(token.clone()));
+        }
+        if let Some(username) = &repo.username {
+            return Some(Credentials::Basic {
+                username: username.clone(),
+                password: repo.password.clone(),
+            });
+        }
+
+        Credentials::from_netrc(repo.url.host_str()?)
+    }
+
+    fn from_netrc(host: &str) -> Option<Credentials> {
+        let netrc = NETRC.get_or_init(NetrcFile::load);
+        let (username, password) = netrc.hosts.get(host).or(netrc.default.as_ref())?;
+
+        trace!(target: LOGNAME, "using credentials for {} from netrc", host);
+        Some(Credentials::Basic {
+            username: username.clone(),
+            password: password.clone(),
+        })
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Credentials::Bearer(token) => request.bearer_auth(token),
+            Credentials::Basic { username, password } => {
+                request.basic_auth(username, password.as_ref())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::Bearer(_) => f.debug_tuple("Bearer").field(&"<redacted>").finish(),
+            Credentials::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
+// The user's netrc file is only read once, the first time that we need any
+// credentials from it, rather than for every request.
+static NETRC: OnceLock<NetrcFile> = OnceLock::new();
+
+// The login and password for each host in a netrc file, and for any other host.
+#[derive(Debug, Default)]
+struct NetrcFile {
+    hosts: HashMap<String, (String, Option<String>)>,
+    default: Option<(String, Option<String>)>,
+}
+
+impl NetrcFile {
+    fn load() -> NetrcFile {
+        let path = match netrc_path() {
+            Some(path) => path,
+            None => return NetrcFile::default(),
+        };
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return NetrcFile::default(),
+        };
+        let netrc = match netrc::Netrc::parse(BufReader::new(file)) {
+            Ok(netrc) => netrc,
+            Err(err) => {
+                warn!(target: LOGNAME, "could not parse {}: {:?}", path.display(), err);
+                return NetrcFile::default();
+            }
+        };
+
+        // Like everything else that reads netrc files, the first entry for a
+        // host is the one that counts.
+        let mut hosts = HashMap::new();
+        for (host, machine) in netrc.hosts.into_iter() {
+            hosts
+                .entry(host)
+                .or_insert((machine.login, machine.password));
+        }
+
+        NetrcFile {
+            hosts,
+            default: netrc.default.map(|m| (m.login, m.password)),
+        }
     }
 }
 
+fn netrc_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("NETRC") {
+        return Some(PathBuf::from(path));
+    }
+
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    let name = if cfg!(windows) { "_netrc" } else { ".netrc" };
+    Some(PathBuf::from(home).join(name))
+}
+
 #[derive(Debug, Clone)]
 struct RepositorySource {
     repository_id: u64,
@@ -149,3 +2151,207 @@ impl Source for RepositorySource {
         self.repository_id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use vfs::MemoryFS;
+
+    use super::*;
+
+    fn fetcher() -> Fetcher {
+        Repository::new(Platform::current(), None).unwrap().fetcher
+    }
+
+    fn repo(url: &str) -> config::Repository {
+        url.parse().unwrap()
+    }
+
+    fn fetch_dependencies(repo: &config::Repository, url: &str) -> Result<ReleaseDependencies> {
+        fetcher().fetch_dependencies(repo, &url.parse().unwrap(), None)
+    }
+
+    #[test]
+    fn dependencies_from_another_host() {
+        let repo = repo("https://example.com/index.json");
+        let err = fetch_dependencies(&repo, "https://example.org/deps.json").unwrap_err();
+        assert!(matches!(
+            err,
+            RepositoryError::UntrustedDependenciesUrl { .. }
+        ));
+    }
+
+    #[test]
+    fn dependencies_from_another_port() {
+        let repo = repo("https://example.com/index.json");
+        let err = fetch_dependencies(&repo, "https://example.com:8443/deps.json").unwrap_err();
+        assert!(matches!(
+            err,
+            RepositoryError::UntrustedDependenciesUrl { .. }
+        ));
+    }
+
+    #[test]
+    fn dependencies_from_local_file() {
+        let repo = repo("https://example.com/index.json");
+        let err = fetch_dependencies(&repo, "file:///etc/passwd").unwrap_err();
+        assert!(matches!(
+            err,
+            RepositoryError::UntrustedDependenciesUrl { .. }
+        ));
+    }
+
+    #[test]
+    fn dependencies_from_invalid_file_url() {
+        let repo = repo("file://host/index.json");
+        let err = fetch_dependencies(&repo, "file://host/deps.json").unwrap_err();
+        assert!(matches!(err, RepositoryError::InvalidFileUrl { .. }));
+    }
+
+    fn is_authorized(repo: &config::Repository, url: &str) -> bool {
+        let url: Url = url.parse().unwrap();
+        authorize(HTTPClient::new().get(url.clone()), &url, Some(repo))
+            .build()
+            .unwrap()
+            .headers()
+            .contains_key(reqwest::header::AUTHORIZATION)
+    }
+
+    #[test]
+    fn credentials_only_sent_to_repository() {
+        let mut repo = repo("https://example.com/index.json");
+        repo.token = Some("secret".to_string());
+
+        assert!(is_authorized(&repo, "https://example.com/packages/foo.zip"));
+        assert!(is_authorized(
+            &repo,
+            "https://example.com:443/packages/foo.zip"
+        ));
+        assert!(!is_authorized(&repo, "http://example.com/packages/foo.zip"));
+        assert!(!is_authorized(
+            &repo,
+            "https://example.com:8443/packages/foo.zip"
+        ));
+        assert!(!is_authorized(
+            &repo,
+            "https://example.org/packages/foo.zip"
+        ));
+    }
+
+    fn signed(mut index: serde_json::Value, message: &str) -> (String, serde_json::Value) {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = SigningKey::from(&secret);
+        let signature =
+            ed25519_dalek::ExpandedSecretKey::from(&secret).sign(message.as_bytes(), &public);
+        index["signature"] = json!(base64::encode(signature.to_bytes()));
+
+        (base64::encode(public.to_bytes()), index)
+    }
+
+    #[test]
+    fn signature_covers_package_meta() {
+        let url: Url = "https://example.com/index.json".parse().unwrap();
+        let (key, mut index) = signed(
+            json!({
+                "format_version": 2,
+                "packages": {},
+                "package_meta": {"foo": {"homepage": "https://example.com/"}},
+            }),
+            r#"{"package_meta":{"foo":{"homepage":"https://example.com/"}},"packages":{}}"#,
+        );
+        verify_index_signature(&key, &url, &index).unwrap();
+
+        index["package_meta"]["foo"]["homepage"] = json!("https://example.org/");
+        let err = verify_index_signature(&key, &url, &index).unwrap_err();
+        assert!(matches!(err, RepositoryError::InvalidSignature { .. }));
+    }
+
+    #[test]
+    fn signature_of_version_1_index() {
+        let url: Url = "https://example.com/index.json".parse().unwrap();
+        let (key, index) = signed(json!({"packages": {}}), "{}");
+        verify_index_signature(&key, &url, &index).unwrap();
+    }
+
+    #[test]
+    fn dependencies_without_digest_from_signed_index() {
+        let mut repo = repo("https://example.com/index.json");
+        repo.signing_key = Some("key".to_string());
+        let err = fetch_dependencies(&repo, "https://example.com/deps.json").unwrap_err();
+        assert!(matches!(
+            err,
+            RepositoryError::UnverifiedDependencies { .. }
+        ));
+    }
+
+    #[test]
+    fn read_only_repo_cache_writes_nothing() {
+        let root: VfsPath = MemoryFS::new().into();
+        let cache = RepoCache::load(root.clone(), Duration::from_secs(60), true);
+        let url: Url = "https://example.com/index.json".parse().unwrap();
+
+        cache.update(&url, Some("\"abc\"".to_string()), None, b"{}");
+        cache.save();
+
+        assert!(cache.get(&url).is_none());
+        assert!(root.read_dir().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn prune_keeps_dependencies_of_cached_indexes() {
+        let root: VfsPath = MemoryFS::new().into();
+        let cache = RepoCache::load(root, Duration::from_secs(60), false);
+        let index: Url = "https://example.com/index.json".parse().unwrap();
+        let deps: Url = "https://example.com/foo-1.0.0.json".parse().unwrap();
+        let stale: Url = "https://example.com/old/index.json".parse().unwrap();
+
+        let data = json!({
+            "meta": {"name": "test"},
+            "packages": {"foo": {"1.0.0": {
+                "urls": ["https://example.com/foo-1.0.0.zip"],
+                "digests": {},
+                "dependencies_url": deps,
+            }}},
+        });
+        cache.update(&index, None, None, data.to_string().as_bytes());
+        cache.update(&deps, None, None, b"{}");
+        cache.update(&stale, None, None, b"{}");
+
+        let removed = cache.prune(&HashSet::from([index.clone()]), false);
+        assert_eq!(removed.len(), 1);
+        assert!(cache.get(&index).is_some());
+        assert!(cache.get(&deps).is_some());
+        assert!(cache.get(&stale).is_none());
+    }
+
+    #[test]
+    fn stats_url_keeps_last_segment() {
+        let url = stats_url(&repo("https://example.com/repo")).unwrap();
+        assert_eq!(url.as_str(), "https://example.com/repo/stats");
+
+        let url = stats_url(&repo("https://example.com/repo/")).unwrap();
+        assert_eq!(url.as_str(), "https://example.com/repo/stats");
+    }
+
+    #[test]
+    fn fetch_one_rejects_invalid_file_url() {
+        let url = Url::parse("file://example.com/index.json").unwrap();
+        let result = Repository::fetch_one(&url, &HTTPClient::new());
+        assert!(matches!(
+            result,
+            Err(RepositoryError::InvalidFileUrl { .. })
+        ));
+    }
+
+    #[test]
+    fn expected_gzip_already_decoded() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(b"{}").unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let gzip = Some(CompressionAlgorithm::Gzip);
+        assert_eq!(expected_encoding(gzip, &gzipped), gzip);
+        assert_eq!(expected_encoding(gzip, b"{}"), None);
+        let brotli = Some(CompressionAlgorithm::Brotli);
+        assert_eq!(expected_encoding(brotli, b"{}"), brotli);
+    }
+}
@@ -2,14 +2,20 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
+use std::collections::BTreeMap;
 use std::fmt;
+#[cfg(feature = "miette-errors")]
+use std::sync::Arc;
 
 use pubgrub::error::PubGrubError;
-use pubgrub::report::{DefaultStringReporter, Reporter};
+use pubgrub::report::{DefaultStringReporter, DerivationTree, External, Reporter};
 
+#[cfg(feature = "miette-errors")]
+use crate::config::ConfigSource;
 use crate::errors::SolverError;
 use crate::resolver::pubgrub::{Candidate, DerivedResult, VersionSet};
 use crate::resolver::types::Name;
+use crate::types::PackageName;
 
 impl SolverError {
     pub(super) fn from_pubgrub(err: PubGrubError<Name, VersionSet<Candidate>>) -> Self {
@@ -31,7 +37,11 @@ impl SolverError {
             PubGrubError::Failure(s) => SolverError::Failure(s),
             PubGrubError::ErrorRetrievingDependencies { .. } => SolverError::Impossible,
             PubGrubError::ErrorChoosingPackageVersion(_) => SolverError::Impossible,
-            PubGrubError::ErrorInShouldCancel(_) => SolverError::Impossible,
+            // The only error we ever return from should_cancel is our own.
+            PubGrubError::ErrorInShouldCancel(err) => match err.downcast::<SolverError>() {
+                Ok(err) => *err,
+                Err(_) => SolverError::Impossible,
+            },
         }
     }
 
@@ -39,6 +49,203 @@ impl SolverError {
         HumanizedNoSolutionError {
             msg: msg.into(),
             dt,
+            #[cfg(feature = "miette-errors")]
+            related: Vec::new(),
+        }
+    }
+
+    /// Group everything that went into there being no solution by the package
+    /// that couldn't be satisfied. This only covers the incompatibilities that
+    /// can be pinned on specific packages, so when the report comes back empty
+    /// the full derivation from `humanized` is the only explanation we have.
+    pub fn explain(dt: &DerivedResult) -> ConflictReport {
+        let mut required: BTreeMap<PackageName, Vec<RequiredBy>> = BTreeMap::new();
+        let mut incompatibilities = Vec::new();
+        let mut remaining = vec![dt];
+
+        while let Some(tree) = remaining.pop() {
+            match tree {
+                DerivationTree::Derived(derived) => {
+                    remaining.push(&derived.cause2);
+                    remaining.push(&derived.cause1);
+                }
+                DerivationTree::External(External::FromDependencyOf(
+                    package,
+                    versions,
+                    dependency,
+                    requirement,
+                )) => {
+                    let by = RequiredBy {
+                        package: (!package.is_root()).then(|| package.clone().into()),
+                        versions: versions.to_string(),
+                        requirement: requirement.to_string(),
+                    };
+                    let entry = required.entry(dependency.clone().into()).or_default();
+                    if !entry.contains(&by) {
+                        entry.push(by);
+                    }
+                }
+                DerivationTree::External(External::NoVersions(package, versions)) => {
+                    let incompatibility = Incompatibility::NoVersions {
+                        package: package.clone().into(),
+                        versions: versions.to_string(),
+                    };
+                    if !incompatibilities.contains(&incompatibility) {
+                        incompatibilities.push(incompatibility);
+                    }
+                }
+                DerivationTree::External(External::UnavailableDependencies(package, versions)) => {
+                    let incompatibility = Incompatibility::UnavailableDependencies {
+                        package: package.clone().into(),
+                        versions: versions.to_string(),
+                    };
+                    if !incompatibilities.contains(&incompatibility) {
+                        incompatibilities.push(incompatibility);
+                    }
+                }
+                DerivationTree::External(External::NotRoot(..)) => {}
+            }
+        }
+
+        // A package that is only required one way is just a link in the chain
+        // that led to a conflict, rather than a conflict itself.
+        let conflicts = required
+            .into_iter()
+            .filter(|(_, by)| by.iter().any(|b| b.requirement != by[0].requirement))
+            .map(|(package, required_by)| Incompatibility::Conflict {
+                package,
+                required_by,
+            });
+
+        ConflictReport {
+            incompatibilities: conflicts.chain(incompatibilities).collect(),
+        }
+    }
+}
+
+/// The incompatibilities that left us without a solution, see
+/// `SolverError::explain`.
+#[derive(Debug, Clone)]
+pub struct ConflictReport {
+    pub incompatibilities: Vec<Incompatibility>,
+}
+
+impl ConflictReport {
+    pub fn is_empty(&self) -> bool {
+        self.incompatibilities.is_empty()
+    }
+}
+
+impl fmt::Display for ConflictReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for incompatibility in self.incompatibilities.iter() {
+            writeln!(f, "{}", incompatibility)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incompatibility {
+    /// Every package that requires `package`, where at least two of them
+    /// disagree on which versions of it are acceptable.
+    Conflict {
+        package: PackageName,
+        required_by: Vec<RequiredBy>,
+    },
+    /// None of the versions of `package` that were asked for exist.
+    NoVersions {
+        package: PackageName,
+        versions: String,
+    },
+    /// We couldn't find out what these versions of `package` depend on.
+    UnavailableDependencies {
+        package: PackageName,
+        versions: String,
+    },
+}
+
+impl Incompatibility {
+    /// The package that this incompatibility is about.
+    pub fn package(&self) -> &PackageName {
+        match self {
+            Incompatibility::Conflict { package, .. } => package,
+            Incompatibility::NoVersions { package, .. } => package,
+            Incompatibility::UnavailableDependencies { package, .. } => package,
+        }
+    }
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatibility::Conflict {
+                package,
+                required_by,
+            } => {
+                let (first, rest) = required_by
+                    .split_first()
+                    .expect("conflicts are never empty");
+                let others = rest.iter().filter(|o| o.requirement != first.requirement);
+                for (idx, other) in others.enumerate() {
+                    if idx > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(
+                        f,
+                        "{} {} {} {}, but {} {} {} {}",
+                        first,
+                        first.verb(),
+                        package,
+                        first.requirement,
+                        other,
+                        other.verb(),
+                        package,
+                        other.requirement
+                    )?;
+                }
+
+                Ok(())
+            }
+            Incompatibility::NoVersions { package, versions } => {
+                write!(f, "no versions of {} match {}", package, versions)
+            }
+            Incompatibility::UnavailableDependencies { package, versions } => {
+                write!(
+                    f,
+                    "the dependencies of {} {} could not be determined",
+                    package, versions
+                )
+            }
+        }
+    }
+}
+
+/// Something that requires a package, either another package, or whatever was
+/// requested to be installed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequiredBy {
+    /// None when it's what was requested.
+    pub package: Option<PackageName>,
+    pub versions: String,
+    pub requirement: String,
+}
+
+impl RequiredBy {
+    pub fn verb(&self) -> &'static str {
+        match self.package {
+            Some(_) => "requires",
+            None => "require",
+        }
+    }
+}
+
+impl fmt::Display for RequiredBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.package {
+            Some(package) => write!(f, "{} {}", package, self.versions),
+            None => write!(f, "the requested packages"),
         }
     }
 }
@@ -47,6 +254,29 @@ impl SolverError {
 pub struct HumanizedNoSolutionError {
     msg: String,
     dt: DerivedResult,
+    #[cfg(feature = "miette-errors")]
+    related: Vec<RelatedIncompatibility>,
+}
+
+#[cfg(feature = "miette-errors")]
+impl HumanizedNoSolutionError {
+    /// Link each of the incompatibilities to where its package was pinned in
+    /// `source`, for any that were pinned there.
+    pub fn with_source(mut self, source: Option<Arc<ConfigSource>>) -> HumanizedNoSolutionError {
+        self.related = match source {
+            Some(source) => SolverError::explain(&self.dt)
+                .incompatibilities
+                .into_iter()
+                .map(|incompatibility| RelatedIncompatibility {
+                    span: source.span(incompatibility.package()),
+                    message: incompatibility.to_string(),
+                    source: Arc::clone(&source),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        self
+    }
 }
 
 impl fmt::Display for HumanizedNoSolutionError {
@@ -63,3 +293,65 @@ impl std::error::Error for HumanizedNoSolutionError {
         None
     }
 }
+
+#[cfg(feature = "miette-errors")]
+impl miette::Diagnostic for HumanizedNoSolutionError {
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn miette::Diagnostic> + 'a>> {
+        if self.related.is_empty() {
+            return None;
+        }
+
+        Some(Box::new(
+            self.related.iter().map(|r| r as &dyn miette::Diagnostic),
+        ))
+    }
+}
+
+#[cfg(feature = "miette-errors")]
+impl miette::Diagnostic for SolverError {
+    fn code<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        match self {
+            SolverError::NoSolution(..) => Some(Box::new("mqpkg::resolver::no_solution")),
+            SolverError::Cancelled => Some(Box::new("mqpkg::resolver::cancelled")),
+            SolverError::Timeout { .. } => Some(Box::new("mqpkg::resolver::timeout")),
+            _ => None,
+        }
+    }
+}
+
+/// One of the incompatibilities behind a HumanizedNoSolutionError, pointing at
+/// where its package was pinned, if it was.
+#[cfg(feature = "miette-errors")]
+#[derive(Debug)]
+struct RelatedIncompatibility {
+    message: String,
+    source: Arc<ConfigSource>,
+    span: Option<(usize, usize)>,
+}
+
+#[cfg(feature = "miette-errors")]
+impl fmt::Display for RelatedIncompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[cfg(feature = "miette-errors")]
+impl std::error::Error for RelatedIncompatibility {}
+
+#[cfg(feature = "miette-errors")]
+impl miette::Diagnostic for RelatedIncompatibility {
+    fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+        self.span
+            .map(|_| self.source.as_ref() as &dyn miette::SourceCode)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = miette::LabeledSpan> + '_>> {
+        let (offset, len) = self.span?;
+        Some(Box::new(std::iter::once(miette::LabeledSpan::new(
+            Some("pinned here".to_string()),
+            offset,
+            len,
+        ))))
+    }
+}
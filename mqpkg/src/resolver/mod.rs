@@ -2,17 +2,23 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use ::pubgrub::solver::resolve;
-use log::{info, log_enabled, trace};
 
 use crate::errors::SolverError;
+use crate::logging::{info, log_enabled, trace, warn};
 use crate::repository::Repository;
-pub(crate) use crate::resolver::pubgrub::{Candidate, DerivedResult};
-use crate::resolver::pubgrub::{CandidateTrait, RepositoryProvider};
-pub(crate) use crate::resolver::types::{Name, Requirement, StaticDependencies};
-use crate::types::{Package, Packages, WithSource};
+pub use crate::resolver::errors::{ConflictReport, Incompatibility, RequiredBy};
+use crate::resolver::pubgrub::RepositoryProvider;
+pub(crate) use crate::resolver::pubgrub::{Candidate, CandidateTrait, DerivedResult};
+pub(crate) use crate::resolver::types::{Dependencies, Name, Requirement, StaticDependencies};
+use crate::types::{Package, PackageName, Packages, SolverSolution, WithSource};
 
 mod errors;
 mod pubgrub;
@@ -20,39 +26,344 @@ mod types;
 
 const LOGNAME: &str = "mqpkg::resolver";
 
-pub(crate) struct Solver {
-    repository: Repository,
+pub(crate) const DEFAULT_WARN_ITERATIONS: u64 = 10_000;
+
+/// Lets a resolve that is in progress be cancelled from another thread, the
+/// solver notices the next time that it ticks, and fails with
+/// `SolverError::Cancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+    resolving: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Whether dependencies are being resolved right now, which is the only
+    /// time that cancelling does anything.
+    pub fn is_resolving(&self) -> bool {
+        self.resolving.load(Ordering::Relaxed)
+    }
+
+    /// Mark that dependencies are being resolved until the returned guard is
+    /// dropped. Anything that was cancelled before this was called doesn't
+    /// count against it.
+    pub(crate) fn resolving(&self) -> ResolvingGuard {
+        self.cancelled.store(false, Ordering::Relaxed);
+        self.resolving.store(true, Ordering::Relaxed);
+        ResolvingGuard(Arc::clone(&self.resolving))
+    }
+}
+
+pub(crate) struct ResolvingGuard(Arc<AtomicBool>);
+
+impl Drop for ResolvingGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+// Everything that the solver needs from whoever is running it while it runs.
+pub(crate) struct SolverContext<'c> {
+    callback: Box<dyn Fn() + 'c>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl<'c> SolverContext<'c> {
+    pub(crate) fn new(callback: impl Fn() + 'c) -> SolverContext<'c> {
+        SolverContext {
+            callback: Box::new(callback),
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn with_cancel_handle(mut self, handle: &CancelHandle) -> SolverContext<'c> {
+        self.cancel = Arc::clone(&handle.cancelled);
+        self
+    }
+
+    pub(in crate::resolver) fn tick(&self) -> Result<(), SolverError> {
+        (self.callback)();
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(SolverError::Cancelled);
+        }
+
+        Ok(())
+    }
 }
 
-impl Solver {
-    pub(crate) fn new(repository: Repository) -> Solver {
-        Solver { repository }
+pub(crate) struct Solver<'r> {
+    repository: &'r Repository,
+    pins: HashMap<Name, Requirement>,
+    extras: HashMap<Name, Vec<String>>,
+    installed: HashMap<PackageName, semver::Version>,
+    warn_iterations: u64,
+    backtrack_count: Arc<AtomicU64>,
+}
+
+impl<'r> Solver<'r> {
+    pub(crate) fn new(repository: &'r Repository) -> Solver<'r> {
+        Solver {
+            repository,
+            pins: HashMap::new(),
+            extras: HashMap::new(),
+            installed: HashMap::new(),
+            warn_iterations: DEFAULT_WARN_ITERATIONS,
+            backtrack_count: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub(crate) fn with_pins<N: Into<Name> + Clone, R: Into<Requirement> + Clone>(
+        mut self,
+        pins: &HashMap<N, R>,
+    ) -> Solver<'r> {
+        self.pins = pins
+            .iter()
+            .map(|(p, r)| (p.clone().into(), r.clone().into()))
+            .collect();
+        self
+    }
+
+    /// Include the named groups of optional dependencies for each requested
+    /// package, these only apply to it as it was requested, and not to
+    /// anywhere else that the same package is depended on, or resolved.
+    pub(crate) fn with_extras<N: Into<Name> + Clone>(
+        mut self,
+        extras: &HashMap<N, Vec<String>>,
+    ) -> Solver<'r> {
+        self.extras = extras
+            .iter()
+            .map(|(p, e)| (p.clone().into(), e.clone()))
+            .collect();
+        self
+    }
+
+    /// What is currently installed, which solutions are compared against.
+    pub(crate) fn with_installed(
+        mut self,
+        installed: &HashMap<PackageName, semver::Version>,
+    ) -> Solver<'r> {
+        self.installed = installed.clone();
+        self
+    }
+
+    pub(crate) fn with_warn_iterations(mut self, iterations: u64) -> Solver<'r> {
+        self.warn_iterations = iterations;
+        self
     }
 
+    /// The number of iterations the solver took during the last call to resolve.
+    pub(crate) fn iterations(&self) -> u64 {
+        self.backtrack_count.load(Ordering::Relaxed)
+    }
+
+    /// Resolve without excluding anything, which only the tests need.
+    #[cfg(test)]
     pub(crate) fn resolve<N: Into<Name> + Clone, R: Into<Requirement> + Clone>(
         &self,
         reqs: HashMap<N, R>,
-        callback: impl Fn(),
-    ) -> Result<Packages, SolverError> {
+        context: SolverContext,
+    ) -> Result<SolverSolution, SolverError> {
+        self.resolve_excluding(
+            reqs,
+            &HashMap::<Name, HashSet<semver::Version>>::new(),
+            context,
+        )
+    }
+
+    /// Resolve just like resolve_excluding, except that the solver runs on its
+    /// own thread, and if it hasn't finished within `timeout` it's cancelled,
+    /// and we fail with `SolverError::Timeout`. The context's callback is still
+    /// called from this thread, each time that the solver ticks.
+    ///
+    /// The solver only notices that it has been cancelled the next time that
+    /// it ticks, so this can take a little longer than `timeout` to return.
+    pub(crate) fn resolve_with_timeout<
+        N: Into<Name> + Clone + Send,
+        R: Into<Requirement> + Clone + Send,
+        E: Into<Name> + Clone + Sync,
+    >(
+        &self,
+        reqs: HashMap<N, R>,
+        excludes: &HashMap<E, HashSet<semver::Version>>,
+        timeout: Duration,
+        context: SolverContext,
+    ) -> Result<SolverSolution, SolverError> {
+        enum Message {
+            Tick,
+            Done(Result<SolverSolution, SolverError>),
+        }
+
+        // The solver thread gets a cancel flag of its own, rather than sharing
+        // the one from our context, so that timing out doesn't leave whoever
+        // gave us that context cancelled for every resolve after this one.
+        let cancel = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+
+        thread::scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+            let solver_cancel = Arc::clone(&cancel);
+            scope.spawn(move || {
+                let ticks = tx.clone();
+                let inner = SolverContext {
+                    // If we've stopped listening, then we've already given up on
+                    // this resolve, so there's nobody to tell about a tick.
+                    callback: Box::new(move || {
+                        ticks.send(Message::Tick).ok();
+                    }),
+                    cancel: solver_cancel,
+                };
+                tx.send(Message::Done(self.resolve_excluding(reqs, excludes, inner)))
+                    .ok();
+            });
+
+            loop {
+                match rx.recv_timeout(timeout.saturating_sub(started.elapsed())) {
+                    Ok(Message::Tick) => {
+                        if let Err(err) = context.tick() {
+                            cancel.store(true, Ordering::Relaxed);
+                            return Err(err);
+                        }
+                    }
+                    Ok(Message::Done(result)) => return result,
+                    Err(RecvTimeoutError::Timeout) => {
+                        cancel.store(true, Ordering::Relaxed);
+                        let elapsed = started.elapsed();
+                        warn!(
+                            target: LOGNAME,
+                            "gave up resolving dependencies after {:?}", elapsed
+                        );
+                        return Err(SolverError::Timeout { elapsed });
+                    }
+                    // The solver thread only goes away without telling us how
+                    // it went if it panicked, and the scope is going to carry
+                    // that panic on to our caller anyways.
+                    Err(RecvTimeoutError::Disconnected) => return Err(SolverError::Impossible),
+                }
+            }
+        })
+    }
+
+    /// Explain why nothing that satisfies `req` gets selected for `name`, by
+    /// resolving `reqs` with `name` required to satisfy `req` as well, and
+    /// describing whatever stops that from resolving.
+    pub(crate) fn why_not<
+        N: Into<Name> + Clone,
+        R: Into<Requirement> + Clone,
+        E: Into<Name> + Clone,
+    >(
+        &self,
+        reqs: HashMap<N, R>,
+        excludes: &HashMap<E, HashSet<semver::Version>>,
+        name: &PackageName,
+        req: &Requirement,
+        context: SolverContext,
+    ) -> Result<String, SolverError> {
+        let mut reqs: HashMap<Name, Requirement> = reqs
+            .into_iter()
+            .map(|(p, r)| (p.into(), r.into()))
+            .collect();
+        let demanded = match reqs.get(&name.clone().into()) {
+            Some(existing) => existing.and(req),
+            None => req.clone(),
+        };
+        reqs.insert(name.clone().into(), demanded);
+
+        match self.resolve_excluding(reqs, excludes, context) {
+            Ok(_) => Ok(format!("Package {name}{req} is satisfiable")),
+            Err(SolverError::NoSolution(mut dt)) => {
+                dt.collapse_no_versions();
+                let msg = format!("Package {name}{req} cannot be selected");
+                Ok(SolverError::humanized(msg, *dt).to_string())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Resolve as normal, except that the excluded versions of a package are
+    /// treated as if they don't exist at all.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    pub(crate) fn resolve_excluding<
+        N: Into<Name> + Clone,
+        R: Into<Requirement> + Clone,
+        E: Into<Name> + Clone,
+    >(
+        &self,
+        reqs: HashMap<N, R>,
+        excludes: &HashMap<E, HashSet<semver::Version>>,
+        context: SolverContext,
+    ) -> Result<SolverSolution, SolverError> {
+        // Extras belong to whoever asked for them, which here is only ever what
+        // was requested, so they're dependencies of the root, rather than
+        // something that applies to the package anywhere else it appears.
+        let mut reqs: HashMap<Name, Requirement> = reqs
+            .into_iter()
+            .map(|(p, r)| (p.into(), r.into()))
+            .collect();
+        let with_extras: Vec<(Name, Requirement)> = reqs
+            .iter()
+            .filter_map(|(name, req)| Some((self.extras.get(name)?, name, req)))
+            .flat_map(|(extras, name, req)| {
+                extras.iter().map(move |extra| {
+                    let package: PackageName = name.clone().into();
+                    (Name::with_extra(package, extra), req.clone())
+                })
+            })
+            .collect();
+        reqs.extend(with_extras);
+
         let package = Name::root();
         let version = Candidate::root(reqs.clone());
 
+        let excludes: HashMap<Name, HashSet<semver::Version>> = excludes
+            .iter()
+            .map(|(p, v)| (p.clone().into(), v.clone()))
+            .collect();
+
+        self.backtrack_count.store(0, Ordering::Relaxed);
         let resolver = RepositoryProvider::new(
-            &self.repository,
-            reqs.into_iter()
-                .map(|(p, r)| (p.into(), r.into()))
-                .collect(),
-            Box::new(callback),
+            self.repository,
+            reqs,
+            &self.pins,
+            &excludes,
+            context,
+            Arc::clone(&self.backtrack_count),
         );
 
         info!(target: LOGNAME, "resolving requested packages");
 
-        let result = resolve(&resolver, package, version).map_err(SolverError::from_pubgrub)?;
+        let result = resolve(&resolver, package, version);
+        resolver.log_cache_stats();
+
+        // We want to log this regardless of whether we found a solution or not,
+        // since a failed resolve can be just as slow as a successful one.
+        let iterations = self.iterations();
+        info!(
+            target: LOGNAME,
+            "solver called {} times (indicative of backtracking)", iterations
+        );
+        if iterations > self.warn_iterations {
+            warn!(
+                target: LOGNAME,
+                "resolving dependencies took {} iterations, simplifying your version \
+                 constraints may make this faster",
+                iterations
+            );
+        }
+
+        let result = result.map_err(SolverError::from_pubgrub)?;
         let packages: Packages = result
             .into_iter()
-            // Filter out the root package from our results since nothing but this
-            // module should even be aware it exists.
-            .filter(|(p, _)| !p.is_root())
+            // Filter out the root package, and the extras, from our results since
+            // nothing but this module should even be aware they exist.
+            .filter(|(p, _)| !p.is_root() && p.extra().is_none())
             // Turn our (Name, Candidate) into (PackageName, Package)
             .map(|(p, c)| {
                 (
@@ -69,6 +380,151 @@ impl Solver {
             }
         }
 
-        Ok(packages)
+        Ok(SolverSolution::new(packages, &self.installed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::platform::Platform;
+
+    fn release(dependencies: serde_json::Value, optional: serde_json::Value) -> serde_json::Value {
+        json!({
+            "urls": ["https://example.com/package.zip"],
+            "digests": {},
+            "dependencies": dependencies,
+            "optional_dependencies": optional,
+        })
+    }
+
+    fn repository(packages: serde_json::Value) -> Repository {
+        Repository::new(Platform::current(), None)
+            .unwrap()
+            .with_index(
+                "https://example.com/index.json",
+                json!({"meta": {"name": "test"}, "packages": packages}),
+            )
+    }
+
+    fn requested(names: &[&str]) -> HashMap<PackageName, semver::VersionReq> {
+        names
+            .iter()
+            .map(|name| (name.parse().unwrap(), semver::VersionReq::STAR))
+            .collect()
+    }
+
+    fn resolved(solver: &Solver, names: &[&str]) -> Vec<String> {
+        solver
+            .resolve(requested(names), SolverContext::new(|| {}))
+            .unwrap()
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn excluded_versions_are_never_selected() {
+        let repository = repository(json!({
+            "app": {"1.0.0": release(json!({"dep": "*"}), json!({}))},
+            "dep": {
+                "1.0.0": release(json!({}), json!({})),
+                "1.1.0": release(json!({}), json!({})),
+            },
+        }));
+        let solver = Solver::new(&repository);
+        let dep: PackageName = "dep".parse().unwrap();
+        let exclude = |versions: &[&str]| {
+            let versions: HashSet<semver::Version> =
+                versions.iter().map(|v| v.parse().unwrap()).collect();
+            HashMap::from([(dep.clone(), versions)])
+        };
+
+        let solution = solver
+            .resolve_excluding(
+                requested(&["app"]),
+                &exclude(&["1.1.0"]),
+                SolverContext::new(|| {}),
+            )
+            .unwrap();
+        assert_eq!(solution.get(&dep).unwrap().version().to_string(), "1.0.0");
+
+        // Excluding the only versions left for a dependency leaves nothing to
+        // select, rather than falling back to an excluded one.
+        let result = solver.resolve_excluding(
+            requested(&["app"]),
+            &exclude(&["1.0.0", "1.1.0"]),
+            SolverContext::new(|| {}),
+        );
+        assert!(matches!(result, Err(SolverError::NoSolution(_))));
+    }
+
+    #[test]
+    fn extras_add_their_dependencies() {
+        let repository = repository(json!({
+            "foo": {"1.0.0": release(json!({}), json!({"a": {"bar": "*"}}))},
+            "bar": {"1.0.0": release(json!({}), json!({}))},
+        }));
+        let extras = HashMap::from([("foo".parse::<PackageName>().unwrap(), vec!["a".into()])]);
+
+        let with_extras = Solver::new(&repository).with_extras(&extras);
+        assert_eq!(resolved(&with_extras, &["foo"]), ["bar", "foo"]);
+
+        // They only apply to the Solver that was given them.
+        let solver = Solver::new(&repository);
+        assert_eq!(resolved(&solver, &["foo"]), ["foo"]);
+    }
+
+    #[test]
+    fn extras_only_apply_as_requested() {
+        let repository = repository(json!({
+            "foo": {"1.0.0": release(json!({}), json!({"a": {"bar": "*"}}))},
+            "bar": {"1.0.0": release(json!({}), json!({}))},
+            "baz": {"1.0.0": release(json!({"foo": "*"}), json!({}))},
+        }));
+        let extras = HashMap::from([("foo".parse::<PackageName>().unwrap(), vec!["a".into()])]);
+
+        // The extra was asked for, but only for foo as it was requested, not
+        // for foo as something else depends on it.
+        let with_extras = Solver::new(&repository).with_extras(&extras);
+        assert_eq!(resolved(&with_extras, &["baz"]), ["baz", "foo"]);
+    }
+
+    #[test]
+    fn earlier_repository_preferred_over_newer_version() {
+        let index = |packages: serde_json::Value| {
+            json!({
+                "meta": {"name": "test"},
+                "packages": {"foo": packages},
+            })
+        };
+        let repository = Repository::new(Platform::current(), None)
+            .unwrap()
+            .with_index(
+                "https://private.example.com/index.json",
+                index(json!({"1.0.0": release(json!({}), json!({}))})),
+            )
+            .with_index(
+                "https://example.com/index.json",
+                index(json!({
+                    "1.5.0": release(json!({}), json!({})),
+                    "2.0.0": release(json!({}), json!({})),
+                })),
+            );
+
+        let name: PackageName = "foo".parse().unwrap();
+        let versions: Vec<String> = repository
+            .candidates(&name)
+            .iter()
+            .map(|c| semver::Version::from(c.version()).to_string())
+            .collect();
+        assert_eq!(versions, ["1.0.0", "2.0.0", "1.5.0"]);
+
+        let solution = Solver::new(&repository)
+            .resolve(requested(&["foo"]), SolverContext::new(|| {}))
+            .unwrap();
+        assert_eq!(solution.get(&name).unwrap().version().to_string(), "1.0.0");
     }
 }
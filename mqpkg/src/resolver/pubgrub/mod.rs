@@ -5,9 +5,8 @@
 pub(crate) use crate::resolver::pubgrub::candidate::Candidate;
 pub(super) use crate::resolver::pubgrub::provider::RepositoryProvider;
 pub(crate) use crate::resolver::pubgrub::types::DerivedResult;
-pub(super) use crate::resolver::pubgrub::versionset::{
-    Candidate as CandidateTrait, CandidateVersion, VersionSet,
-};
+pub(crate) use crate::resolver::pubgrub::versionset::Candidate as CandidateTrait;
+pub(super) use crate::resolver::pubgrub::versionset::{CandidateVersion, VersionSet};
 
 mod candidate;
 mod provider;
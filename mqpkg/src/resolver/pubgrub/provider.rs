@@ -3,20 +3,26 @@
 // for complete details.
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use ::pubgrub::solver::{
     choose_package_with_fewest_versions, Dependencies as PDependencies, DependencyProvider,
 };
 use ::pubgrub::type_aliases::DependencyConstraints;
-use log::{log_enabled, trace};
+use ::pubgrub::version_set::VersionSet as BaseVersionSet;
 
+use crate::logging::{debug, log_enabled, trace};
 use crate::repository::Repository;
 pub(crate) use crate::resolver::pubgrub::Candidate;
-use crate::resolver::pubgrub::VersionSet;
+use crate::resolver::pubgrub::{CandidateTrait, VersionSet};
+use crate::resolver::types::Version;
 use crate::resolver::types::WithDependencies;
 pub(crate) use crate::resolver::types::{Name, Requirement};
+use crate::resolver::SolverContext;
 
 const LOGNAME: &str = "mqpkg::resolver";
 
@@ -29,22 +35,54 @@ const LOGNAME: &str = "mqpkg::resolver";
 pub(in crate::resolver) struct RepositoryProvider<'r, 'c> {
     repository: &'r Repository,
     requested: HashMap<Name, Requirement>,
-    callback: Box<dyn Fn() + 'c>,
+    pins: HashMap<Name, VersionSet<Candidate>>,
+    excludes: &'r HashMap<Name, HashSet<semver::Version>>,
+    context: SolverContext<'c>,
+    backtrack_count: Arc<AtomicU64>,
+    // PubGrub asks for the dependencies of the same candidate again every time
+    // it backtracks past it, and working them out can mean merging extras, so
+    // we only ever do that once per candidate.
+    dep_cache: RefCell<HashMap<(Name, Version), PDependencies<Name, VersionSet<Candidate>>>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
 }
 
 impl<'r, 'c> RepositoryProvider<'r, 'c> {
     pub(in crate::resolver) fn new(
         repository: &'r Repository,
         requested: HashMap<Name, Requirement>,
-        callback: Box<dyn Fn() + 'c>,
+        pins: &HashMap<Name, Requirement>,
+        excludes: &'r HashMap<Name, HashSet<semver::Version>>,
+        context: SolverContext<'c>,
+        backtrack_count: Arc<AtomicU64>,
     ) -> RepositoryProvider<'r, 'c> {
         RepositoryProvider {
             repository,
             requested,
-            callback,
+            pins: pins.iter().map(|(n, r)| (n.clone(), r.into())).collect(),
+            excludes,
+            context,
+            backtrack_count,
+            dep_cache: RefCell::new(HashMap::new()),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
         }
     }
 
+    pub(in crate::resolver) fn log_cache_stats(&self) {
+        let (hits, misses) = (self.hits.get(), self.misses.get());
+        let rate = match hits + misses {
+            0 => 0.0,
+            total => hits as f64 / total as f64 * 100.0,
+        };
+        debug!(
+            target: LOGNAME,
+            "dependency cache: {} hits, {} misses ({:.1}% hit rate)", hits, misses, rate
+        );
+    }
+
+    // The repository gives us its candidates in the order that they should be
+    // tried in, which isn't necessarily newest first, so they're never sorted.
     fn list_versions(&self, package: &Name) -> std::vec::IntoIter<Candidate> {
         let mut candidates = if package.is_root() {
             vec![Candidate::root(self.requested.clone())]
@@ -52,7 +90,21 @@ impl<'r, 'c> RepositoryProvider<'r, 'c> {
             self.repository.candidates(package)
         };
 
-        candidates.sort_by(|l, r| l.cmp(r).reverse());
+        // Pins apply to every package, not just the ones that were requested, so
+        // any candidate that falls outside of a pin is never even considered.
+        // An extra can only ever be the same version as its package, so the
+        // same goes for it.
+        let base = package.base();
+        if let Some(pin) = self.pins.get(&base) {
+            candidates.retain(|c| pin.contains(c));
+        }
+
+        if let Some(excluded) = self.excludes.get(&base) {
+            candidates.retain(|c| {
+                let version: semver::Version = c.version().into();
+                !excluded.contains(&version)
+            });
+        }
 
         if log_enabled!(log::Level::Trace) && !package.is_root() {
             let versions_str: Vec<String> = candidates.iter().map(|v| v.to_string()).collect();
@@ -70,10 +122,14 @@ impl<'r, 'c> RepositoryProvider<'r, 'c> {
 
 impl<'r, 'c> DependencyProvider<Name, VersionSet<Candidate>> for RepositoryProvider<'r, 'c> {
     fn should_cancel(&self) -> Result<(), Box<dyn std::error::Error>> {
-        (self.callback)();
-        Ok(())
+        // PubGrub calls this once per iteration of its main loop, so how many
+        // times it gets called is a decent proxy for how much backtracking the
+        // solver had to do.
+        self.backtrack_count.fetch_add(1, Ordering::Relaxed);
+        Ok(self.context.tick()?)
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     fn choose_package_version<P: Borrow<Name>, U: Borrow<VersionSet<Candidate>>>(
         &self,
         potential_packages: impl Iterator<Item = (P, U)>,
@@ -102,14 +158,41 @@ impl<'r, 'c> DependencyProvider<Name, VersionSet<Candidate>> for RepositoryProvi
         package: &Name,
         candidate: &Candidate,
     ) -> Result<PDependencies<Name, VersionSet<Candidate>>, Box<dyn std::error::Error>> {
-        match candidate.dependencies().get() {
+        let key = (package.clone(), candidate.version().clone());
+        if let Some(dependencies) = self.dep_cache.borrow().get(&key) {
+            self.hits.set(self.hits.get() + 1);
+            return Ok(dependencies.clone());
+        }
+        self.misses.set(self.misses.get() + 1);
+
+        let dependencies = self.find_dependencies(package, candidate);
+        self.dep_cache
+            .borrow_mut()
+            .insert(key, dependencies.clone());
+        Ok(dependencies)
+    }
+}
+
+impl<'r, 'c> RepositoryProvider<'r, 'c> {
+    fn find_dependencies(
+        &self,
+        package: &Name,
+        candidate: &Candidate,
+    ) -> PDependencies<Name, VersionSet<Candidate>> {
+        let dependencies = match package.extra() {
+            Some(extra) => candidate
+                .dependencies()
+                .get_with_extras(&[extra.to_string()]),
+            None => candidate.dependencies().get(),
+        };
+        match dependencies {
             None => {
                 trace!(
                     target: LOGNAME,
                     "could not determine dependencies for {package}"
                 );
 
-                Ok(PDependencies::Unknown)
+                PDependencies::Unknown
             }
             Some(deps) => {
                 if log_enabled!(log::Level::Trace) {
@@ -128,7 +211,10 @@ impl<'r, 'c> DependencyProvider<Name, VersionSet<Candidate>> for RepositoryProvi
                 for (dep, req) in deps.iter() {
                     result.insert(dep.clone(), req.into());
                 }
-                Ok(PDependencies::Known(result))
+                if package.extra().is_some() {
+                    result.insert(package.base(), VersionSet::singleton(candidate.clone()));
+                }
+                PDependencies::Known(result)
             }
         }
     }
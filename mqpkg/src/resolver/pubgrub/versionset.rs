@@ -147,6 +147,12 @@ impl<C: Candidate> VersionSet<C> {
         }
     }
 
+    // A pre-release is only ever contained when it's in both range and pre, so
+    // the set as a whole is empty whenever range is.
+    pub(in crate::resolver) fn is_empty(&self) -> bool {
+        self.range == Range::none()
+    }
+
     pub(in crate::resolver) fn with_normal(&self, other: &VersionSet<C>) -> VersionSet<C> {
         VersionSet {
             range: self.range.intersection(&other.range),
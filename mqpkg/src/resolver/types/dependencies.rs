@@ -7,11 +7,20 @@ use std::fmt;
 
 use dyn_clone::DynClone;
 
+use crate::logging::warn;
 use crate::resolver::types::name::Name;
 use crate::resolver::types::requirement::Requirement;
 
+const LOGNAME: &str = "mqpkg::resolver";
+
 pub(crate) trait Dependencies: fmt::Debug + DynClone {
     fn get(&self) -> Option<HashMap<Name, Requirement>>;
+
+    /// The dependencies along with those from each of the named groups of
+    /// optional dependencies in `extras`.
+    fn get_with_extras(&self, _extras: &[String]) -> Option<HashMap<Name, Requirement>> {
+        self.get()
+    }
 }
 
 dyn_clone::clone_trait_object!(Dependencies);
@@ -23,6 +32,7 @@ pub(in crate::resolver) trait WithDependencies {
 #[derive(Debug, Clone)]
 pub(crate) struct StaticDependencies {
     dependencies: HashMap<Name, Requirement>,
+    optional: HashMap<String, HashMap<Name, Requirement>>,
 }
 
 impl StaticDependencies {
@@ -34,12 +44,55 @@ impl StaticDependencies {
                 .into_iter()
                 .map(|(p, r)| (p.into(), r.into()))
                 .collect(),
+            optional: HashMap::new(),
         }
     }
+
+    pub(crate) fn with_optional<N: Into<Name>, R: Into<Requirement>>(
+        mut self,
+        optional: HashMap<String, HashMap<N, R>>,
+    ) -> StaticDependencies {
+        self.optional = optional
+            .into_iter()
+            .map(|(group, deps)| {
+                let deps = deps
+                    .into_iter()
+                    .map(|(p, r)| (p.into(), r.into()))
+                    .collect();
+                (group, deps)
+            })
+            .collect();
+        self
+    }
 }
 
 impl Dependencies for StaticDependencies {
     fn get(&self) -> Option<HashMap<Name, Requirement>> {
         Some(self.dependencies.clone())
     }
+
+    fn get_with_extras(&self, extras: &[String]) -> Option<HashMap<Name, Requirement>> {
+        let mut dependencies = self.dependencies.clone();
+        for extra in extras {
+            let group = match self.optional.get(extra) {
+                Some(group) => group,
+                None => {
+                    warn!(target: LOGNAME, "unknown extra {extra}, ignoring it");
+                    continue;
+                }
+            };
+
+            // A package that is in more than one group has to satisfy all of
+            // them at once.
+            for (name, req) in group.iter() {
+                let req = match dependencies.get(name) {
+                    Some(existing) => existing.and(req),
+                    None => req.clone(),
+                };
+                dependencies.insert(name.clone(), req);
+            }
+        }
+
+        Some(dependencies)
+    }
 }
@@ -2,11 +2,11 @@
 // 2.0, and the BSD License. See the LICENSE file in the root of this repository
 // for complete details.
 
-pub(crate) use crate::resolver::types::dependencies::StaticDependencies;
+pub(crate) use crate::resolver::types::dependencies::{Dependencies, StaticDependencies};
 pub(crate) use crate::resolver::types::name::Name;
 pub(crate) use crate::resolver::types::requirement::Requirement;
 
-pub(super) use crate::resolver::types::dependencies::{Dependencies, WithDependencies};
+pub(super) use crate::resolver::types::dependencies::WithDependencies;
 pub(super) use crate::resolver::types::version::Version;
 
 mod dependencies;
@@ -15,6 +15,11 @@ const ROOT_NAME: &str = "requested packages";
 pub struct Name {
     root: bool,
 
+    // Requesting a package with extras is a dependency on a package of its own
+    // for each extra, which depends on the package itself at the same version,
+    // and on that extra's group of optional dependencies.
+    extra: Option<String>,
+
     // For reasons I have yet to figure out, putting name not last breaks
     // resolving with pubgrub due to the derived hash implementation not
     // hashing it last.
@@ -29,24 +34,51 @@ pub struct Name {
 impl Name {
     pub(in crate::resolver) fn new(name: PackageName) -> Name {
         assert!(name.to_string() != ROOT_NAME);
-        Name { name, root: false }
+        Name {
+            name,
+            root: false,
+            extra: None,
+        }
+    }
+
+    pub(in crate::resolver) fn with_extra(name: PackageName, extra: &str) -> Name {
+        Name {
+            extra: Some(extra.to_string()),
+            ..Name::new(name)
+        }
     }
 
     pub(in crate::resolver) fn root() -> Name {
         Name {
             name: PackageName::new(ROOT_NAME),
             root: true,
+            extra: None,
         }
     }
 
     pub(in crate::resolver) fn is_root(&self) -> bool {
         self.root
     }
+
+    pub(in crate::resolver) fn extra(&self) -> Option<&str> {
+        self.extra.as_deref()
+    }
+
+    /// The package itself, for a name that stands in for one of its extras.
+    pub(in crate::resolver) fn base(&self) -> Name {
+        Name {
+            extra: None,
+            ..self.clone()
+        }
+    }
 }
 
 impl fmt::Display for Name {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.name)
+        match &self.extra {
+            Some(extra) => write!(f, "{}[{}]", self.name, extra),
+            None => write!(f, "{}", self.name),
+        }
     }
 }
 
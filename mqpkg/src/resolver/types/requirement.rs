@@ -17,6 +17,20 @@ impl Requirement {
     pub(crate) fn new(req: VersionReq) -> Requirement {
         Requirement(req)
     }
+
+    /// A requirement that is only satisfied by versions that satisfy both.
+    pub(crate) fn and(&self, other: &Requirement) -> Requirement {
+        let mut req = self.0.clone();
+        req.comparators.extend(other.0.comparators.iter().cloned());
+        Requirement(req)
+    }
+
+    /// Whether there is no version at all that could satisfy both requirements.
+    pub(crate) fn is_disjoint(&self, other: &Requirement) -> bool {
+        VersionSet::<Candidate>::from(self)
+            .intersection(&other.into())
+            .is_empty()
+    }
 }
 
 impl fmt::Display for Requirement {
@@ -4,14 +4,19 @@
 
 use std::cmp::Ordering;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 
 use pubgrub::version::Version as PubGrubVersion;
 
 use crate::resolver::pubgrub::CandidateVersion;
 
+// Build metadata never takes part in comparing versions, so it's kept apart
+// from our version, and only shows up again when displaying it or turning it
+// back into a semver::Version.
 #[derive(Debug, Clone)]
 pub struct Version {
     version: semver::Version,
+    build_metadata: semver::BuildMetadata,
     source_id: u64,
     source_discriminator: u64,
     suppress_display: bool,
@@ -21,6 +26,7 @@ impl Version {
     fn new(major: u64, minor: u64, patch: u64) -> Version {
         Version {
             version: semver::Version::new(major, minor, patch),
+            build_metadata: semver::BuildMetadata::EMPTY,
             source_id: 0,
             source_discriminator: 0,
             suppress_display: false,
@@ -59,6 +65,9 @@ impl fmt::Display for Version {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if !self.suppress_display {
             write!(f, "{}", self.version)?;
+            if !self.build_metadata.is_empty() {
+                write!(f, "+{}", self.build_metadata)?;
+            }
         }
 
         Ok(())
@@ -73,6 +82,13 @@ impl PartialEq for Version {
 }
 impl Eq for Version {}
 
+// This has to agree with PartialEq, so build metadata is left out here too.
+impl Hash for Version {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (&self.version, self.source_id, self.source_discriminator).hash(state);
+    }
+}
+
 impl Ord for Version {
     fn cmp(&self, other: &Self) -> Ordering {
         match self.version.cmp(&other.version) {
@@ -114,8 +130,12 @@ impl PubGrubVersion for Version {
 
 impl From<&semver::Version> for Version {
     fn from(version: &semver::Version) -> Version {
+        let mut without_build = version.clone();
+        without_build.build = semver::BuildMetadata::EMPTY;
+
         Version {
-            version: version.clone(),
+            version: without_build,
+            build_metadata: version.build.clone(),
             source_id: 0,
             source_discriminator: 0,
             suppress_display: false,
@@ -125,6 +145,55 @@ impl From<&semver::Version> for Version {
 
 impl From<&Version> for semver::Version {
     fn from(version: &Version) -> semver::Version {
-        version.version.clone()
+        let mut with_build = version.version.clone();
+        with_build.build = version.build_metadata.clone();
+        with_build
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+
+    use super::*;
+
+    fn version(s: &str) -> Version {
+        Version::from(&semver::Version::parse(s).unwrap())
+    }
+
+    fn hash(version: &Version) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        version.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn build_metadata_is_ignored_when_comparing() {
+        let local = version("1.0.0+local");
+        let ci = version("1.0.0+ci.42");
+
+        assert_eq!(local, ci);
+        assert_eq!(local, version("1.0.0"));
+        assert_eq!(local.cmp(&ci), Ordering::Equal);
+        assert_eq!(hash(&local), hash(&ci));
+        assert!(version("1.0.1+a") > version("1.0.0+z"));
+    }
+
+    #[test]
+    fn build_metadata_is_displayed() {
+        assert_eq!(version("1.0.0+local").to_string(), "1.0.0+local");
+        assert_eq!(version("1.0.0+ci.42").to_string(), "1.0.0+ci.42");
+        assert_eq!(version("1.0.0-rc.1+ci.42").to_string(), "1.0.0-rc.1+ci.42");
+        assert_eq!(version("1.0.0").to_string(), "1.0.0");
+    }
+
+    #[test]
+    fn build_metadata_round_trips() {
+        for s in ["1.0.0", "1.0.0+local", "1.0.0-rc.1+ci.42"] {
+            let semver = semver::Version::parse(s).unwrap();
+            let back = semver::Version::from(&Version::from(&semver));
+            assert_eq!(back, semver);
+            assert_eq!(back.build, semver.build);
+        }
     }
 }
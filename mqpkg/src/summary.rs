@@ -0,0 +1,405 @@
+// This file is dual licensed under the terms of the Apache License, Version
+// 2.0, and the BSD License. See the LICENSE file in the root of this repository
+// for complete details.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use semver::{Version, VersionReq};
+use serde::Serialize;
+use url::Url;
+
+use crate::types::{render_tree, PackageName, SolverSolution, TreeNode};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PackageChange {
+    pub name: PackageName,
+    pub from: Option<Version>,
+    pub to: Option<Version>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SolutionDiff {
+    pub installed: Vec<PackageChange>,
+    pub upgraded: Vec<PackageChange>,
+    pub downgraded: Vec<PackageChange>,
+    pub removed: Vec<PackageChange>,
+    pub unchanged: Vec<PackageChange>,
+}
+
+impl SolutionDiff {
+    pub(crate) fn new(
+        installed: &HashMap<PackageName, Version>,
+        solution: &SolverSolution,
+    ) -> SolutionDiff {
+        let mut diff = SolutionDiff::default();
+
+        // A solution is always sorted by name, so everything we add from it
+        // will be as well.
+        for (name, package) in solution.iter() {
+            let change = PackageChange {
+                name: name.clone(),
+                from: installed.get(name).cloned(),
+                to: Some(package.version().clone()),
+            };
+
+            match &change.from {
+                None => diff.installed.push(change),
+                Some(from) if from < package.version() => diff.upgraded.push(change),
+                Some(from) if from > package.version() => diff.downgraded.push(change),
+                Some(_) => diff.unchanged.push(change),
+            }
+        }
+
+        for (name, version) in installed.iter() {
+            if !solution.contains(name) {
+                diff.removed.push(PackageChange {
+                    name: name.clone(),
+                    from: Some(version.clone()),
+                    to: None,
+                });
+            }
+        }
+        diff.removed.sort_by(|l, r| l.name.cmp(&r.name));
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.installed.is_empty()
+            && self.upgraded.is_empty()
+            && self.downgraded.is_empty()
+            && self.removed.is_empty()
+    }
+
+    pub fn upgrade_plan(&self) -> UpgradePlan {
+        UpgradePlan {
+            to_install: self.installed.clone(),
+            to_remove: self.removed.clone(),
+            to_upgrade: self.upgraded.clone(),
+        }
+    }
+
+    pub fn install_plan(&self) -> InstallPlan {
+        // Every change has whichever side of it we're asking for, apart from
+        // the ones that we never ask about, so nothing actually gets skipped.
+        let planned = |c: &PackageChange, version: &Option<Version>| {
+            Some(PlannedPackage {
+                name: c.name.clone(),
+                version: version.clone()?,
+            })
+        };
+
+        InstallPlan {
+            to_install: self
+                .installed
+                .iter()
+                .filter_map(|c| planned(c, &c.to))
+                .collect(),
+            to_remove: self
+                .removed
+                .iter()
+                .filter_map(|c| planned(c, &c.from))
+                .collect(),
+            to_upgrade: self
+                .upgraded
+                .iter()
+                .chain(self.downgraded.iter())
+                .filter_map(|c| Some((planned(c, &c.from)?, planned(c, &c.to)?)))
+                .collect(),
+            to_keep: self
+                .unchanged
+                .iter()
+                .filter_map(|c| planned(c, &c.to))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn is_changed(&self, name: &PackageName) -> bool {
+        self.installed
+            .iter()
+            .chain(self.upgraded.iter())
+            .chain(self.downgraded.iter())
+            .any(|c| &c.name == name)
+    }
+}
+
+/// The changes an upgrade makes, anything that had to be downgraded is only
+/// in the full SolutionDiff.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct UpgradePlan {
+    pub to_install: Vec<PackageChange>,
+    pub to_remove: Vec<PackageChange>,
+    pub to_upgrade: Vec<PackageChange>,
+}
+
+#[derive(Serialize, Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PlannedPackage {
+    pub name: PackageName,
+    pub version: Version,
+}
+
+/// Everything that installing some packages would do, without having done any
+/// of it.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct InstallPlan {
+    pub to_install: Vec<PlannedPackage>,
+    pub to_remove: Vec<PlannedPackage>,
+    /// Packages that would change version, as what is installed now and what
+    /// it would become, which may be a downgrade.
+    pub to_upgrade: Vec<(PlannedPackage, PlannedPackage)>,
+    pub to_keep: Vec<PlannedPackage>,
+}
+
+impl InstallPlan {
+    pub fn is_empty(&self) -> bool {
+        self.to_install.is_empty() && self.to_remove.is_empty() && self.to_upgrade.is_empty()
+    }
+}
+
+/// A way in which what is installed differs from what it should be.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub enum CheckError {
+    /// A package in the lock file that isn't installed at all.
+    Missing(PackageName, Version),
+    /// A package that is installed at a different version than it's locked at.
+    VersionMismatch {
+        name: PackageName,
+        locked: Version,
+        installed: Version,
+    },
+    /// A package that is installed, but isn't in the lock file.
+    NotLocked(PackageName, Version),
+    /// A locked package whose archive isn't in the package cache.
+    NotCached(PackageName, Version),
+    /// A locked package whose cached archive doesn't match the lock file.
+    DigestMismatch {
+        name: PackageName,
+        version: Version,
+        expected: String,
+        got: String,
+    },
+}
+
+/// Why a package is installed, working backwards through everything that
+/// depends on it until we reach the packages that were requested.
+#[derive(Serialize, Debug, Clone)]
+pub struct DependencyChain {
+    pub package: PackageName,
+    /// What directly requires `package`, and each of the packages that
+    /// requires it in turn, all the way back to the requested packages. Every
+    /// package is only listed once, no matter how many paths go through it.
+    pub required_by: BTreeMap<PackageName, Vec<DependencyEdge>>,
+    /// The requirement that each of those packages was requested with, for
+    /// the ones that were.
+    pub requested: BTreeMap<PackageName, VersionReq>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DependencyEdge {
+    pub requirer: PackageName,
+    pub constraint: VersionReq,
+}
+
+impl DependencyChain {
+    /// Render every path from the requested packages to `package` as a tree,
+    /// with anything that requires a package that has already been shown left
+    /// out, rather than repeated.
+    pub fn render(&self) -> String {
+        let root = ChainNode {
+            chain: self,
+            package: &self.package,
+            constraint: None,
+        };
+        render_tree(vec![root], None, true)
+    }
+}
+
+struct ChainNode<'c> {
+    chain: &'c DependencyChain,
+    package: &'c PackageName,
+    // The constraint that this package placed on its child, which isn't set
+    // for `package` itself.
+    constraint: Option<&'c VersionReq>,
+}
+
+impl<'c> TreeNode for ChainNode<'c> {
+    fn id(&self) -> String {
+        self.package.to_string()
+    }
+
+    fn label(&self) -> String {
+        let label = match self.constraint {
+            Some(constraint) => format!("required by {} ({constraint})", self.package),
+            None => self.package.to_string(),
+        };
+        match self.chain.requested.get(self.package) {
+            Some(req) => format!("{label} [requested {req}]"),
+            None => label,
+        }
+    }
+
+    fn children(&self) -> Vec<Self> {
+        self.chain
+            .required_by
+            .get(self.package)
+            .map(|edges| {
+                edges
+                    .iter()
+                    .map(|edge| ChainNode {
+                        chain: self.chain,
+                        package: &edge.requirer,
+                        constraint: Some(&edge.constraint),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// A package that is available from the configured repositories, which
+/// matched a search.
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchResult {
+    pub name: PackageName,
+    pub latest_version: Version,
+    pub description: Option<String>,
+    /// How well this package matched, matches on the name always score higher
+    /// than matches on the description.
+    pub score: f32,
+}
+
+/// A package that is currently installed.
+#[derive(Serialize, Debug, Clone)]
+pub struct InstalledPackage {
+    pub name: PackageName,
+    /// What this package was requested with, if it was requested at all rather
+    /// than only being installed as a dependency.
+    pub requested_version: Option<VersionReq>,
+    pub resolved_version: Version,
+    /// The repository this package was installed from, which isn't known for
+    /// anything installed before we started recording it.
+    pub source_name: Option<String>,
+    pub install_time: Option<SystemTime>,
+}
+
+/// An installed package that has a newer version available, which still
+/// satisfies whatever it was requested with.
+#[derive(Serialize, Debug, Clone)]
+pub struct OutdatedPackage {
+    pub name: PackageName,
+    pub installed: Version,
+    pub available: Version,
+    /// The repository that the newer version is available from.
+    pub source: String,
+}
+
+/// Everything we know about a package, which is the installed version if it
+/// is installed, and otherwise the newest version that is available. What
+/// isn't recorded in the pkgdb comes from the repository, so it's missing if
+/// the installed version is no longer available.
+#[derive(Serialize, Debug, Clone)]
+pub struct PackageDetail {
+    pub name: PackageName,
+    pub version: Version,
+    pub installed: bool,
+    pub install_time: Option<SystemTime>,
+    pub requested_constraint: Option<VersionReq>,
+    pub dependencies: Vec<PackageDependency>,
+    /// Every version that is available from the repositories, newest first.
+    pub available_versions: Vec<Version>,
+    pub source_url: Option<Url>,
+    pub description: Option<String>,
+    pub homepage: Option<Url>,
+    pub license: Option<String>,
+    pub authors: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+/// Something that a package depends on, along with the version of it that is
+/// installed, if any.
+#[derive(Serialize, Debug, Clone)]
+pub struct PackageDependency {
+    pub name: PackageName,
+    pub requirement: VersionReq,
+    pub installed: Option<Version>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct InstallTimings {
+    pub fetch_metadata_ms: u64,
+    pub resolve_ms: u64,
+    pub download_ms: u64,
+    pub extract_ms: u64,
+    pub commit_ms: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct InstallSummary {
+    pub diff: SolutionDiff,
+    pub timings: InstallTimings,
+    pub resolver_iterations: u64,
+}
+
+pub(crate) fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// The files that garbage collecting the caches removed, or for a dry run,
+/// would have removed.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GcSummary {
+    pub removed_files: Vec<PathBuf>,
+    pub bytes_freed: u64,
+}
+
+/// What was found in a repository's index when checking it.
+#[derive(Serialize, Debug, Clone)]
+pub struct RepositoryReport {
+    pub url: Url,
+    pub packages: usize,
+    pub releases: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edge(requirer: &str, constraint: &str) -> DependencyEdge {
+        DependencyEdge {
+            requirer: requirer.parse().unwrap(),
+            constraint: constraint.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn chain_renders_shared_requirers_once() {
+        // app requires mid, which requires both left and right, which both
+        // require base.
+        let chain = DependencyChain {
+            package: "base".parse().unwrap(),
+            required_by: BTreeMap::from([
+                (
+                    "base".parse().unwrap(),
+                    vec![edge("left", "^1"), edge("right", "^1")],
+                ),
+                ("left".parse().unwrap(), vec![edge("mid", "^2")]),
+                ("right".parse().unwrap(), vec![edge("mid", "^3")]),
+                ("mid".parse().unwrap(), vec![edge("app", "^4")]),
+                ("app".parse().unwrap(), vec![]),
+            ]),
+            requested: BTreeMap::from([("app".parse().unwrap(), "*".parse().unwrap())]),
+        };
+
+        let expected = [
+            "base",
+            "├── required by left (^1)",
+            "│   └── required by mid (^2)",
+            "│       └── required by app (^4) [requested *]",
+            "└── required by right (^1)",
+            "    └── required by mid (^3) [shown above]",
+        ];
+        assert_eq!(chain.render(), expected.join("\n") + "\n");
+    }
+}
@@ -4,24 +4,66 @@
 
 use std::clone::Clone;
 use std::cmp::{Eq, PartialEq};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
+use std::fs::File;
 use std::hash::Hash;
+use std::io::Read;
+use std::path::Path;
 use std::str::FromStr;
 
 use dyn_clone::DynClone;
-use semver::{Version, VersionReq};
-use serde::{Deserialize, Serialize};
+use flate2::read::GzDecoder;
+use semver::{Comparator, Op, Version, VersionReq};
+use serde::{Deserialize, Deserializer, Serialize};
+use tar::Archive as TarArchive;
+use url::Url;
 
-use crate::errors::{PackageNameError, PackageSpecifierError};
+use crate::errors::{ManifestError, PackageNameError, PackageSpecifierError};
+use crate::summary::SolutionDiff;
 
-#[derive(Serialize, Deserialize, Clone, Eq, Debug, Hash, PartialEq, Ord, PartialOrd)]
+pub(crate) const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// The name of a package, which is always kept in its canonical form, so that
+/// names which only differ by case, or by hyphens vs underscores, are the same
+/// package.
+#[derive(Serialize, Clone, Eq, Debug, Hash, PartialEq, Ord, PartialOrd)]
 pub struct PackageName(String);
 
 impl PackageName {
     pub(crate) fn new<S: Into<String>>(s: S) -> PackageName {
         PackageName(s.into())
     }
+
+    /// The canonical form of this name, which is what it's compared by.
+    pub fn canonical(&self) -> &str {
+        &self.0
+    }
+
+    /// Everything before the last dot of a namespaced name, like `org.example`
+    /// for `org.example.pkg`, or None if the name isn't namespaced.
+    pub fn namespace(&self) -> Option<&str> {
+        self.0.rsplit_once('.').map(|(namespace, _)| namespace)
+    }
+
+    /// Everything after the last dot of a namespaced name, which is the whole
+    /// name if it isn't namespaced.
+    pub fn base(&self) -> &str {
+        match self.0.rsplit_once('.') {
+            Some((_, base)) => base,
+            None => &self.0,
+        }
+    }
+}
+
+fn canonicalize(name: &str) -> String {
+    name.to_ascii_lowercase().replace('-', "_")
+}
+
+impl AsRef<PackageName> for PackageName {
+    fn as_ref(&self) -> &PackageName {
+        self
+    }
 }
 
 impl fmt::Display for PackageName {
@@ -30,10 +72,39 @@ impl fmt::Display for PackageName {
     }
 }
 
+impl<'de> Deserialize<'de> for PackageName {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Anything we've serialized before might not be in the canonical form,
+        // so we have to normalize it as we read it.
+        let name = String::deserialize(deserializer)?;
+        Ok(PackageName(canonicalize(&name)))
+    }
+}
+
 impl FromStr for PackageName {
     type Err = PackageNameError;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // The position counts characters, while the offset and length are in
+        // bytes, which is what anything pointing into the name needs.
+        let invalid =
+            |character: char, position: usize, offset: usize| PackageNameError::InvalidCharacter {
+                name: value.to_string(),
+                character: character.to_string(),
+                position,
+                offset,
+                length: character.len_utf8(),
+            };
+
+        // A dot is a valid character, just not here, so we treat it like one
+        // that's misplaced anywhere else rather than as a bad first letter.
+        if value.starts_with('.') {
+            return Err(invalid('.', 0, 0));
+        }
+
         // Check that the first letter is only alpha, and if we don't have
         // a first letter, then this is invalid anyways.
         if !value.starts_with(|c: char| c.is_ascii_alphabetic()) {
@@ -41,49 +112,590 @@ impl FromStr for PackageName {
                 Some(c) => Err(PackageNameError::NoStartingAlpha {
                     name: value.to_string(),
                     character: c.to_string(),
+                    position: 0,
+                    offset: 0,
+                    length: c.len_utf8(),
                 }),
                 None => Err(PackageNameError::TooShort),
             };
         }
 
-        // Iterate over the rest of our letters, and make sure that they're alphanumeric
-        for c in value.chars() {
-            if !c.is_ascii_alphanumeric() {
-                return Err(PackageNameError::InvalidCharacter {
-                    name: value.to_string(),
-                    character: c.to_string(),
-                });
+        // Iterate over the rest of our letters, and make sure that they're valid,
+        // dots separate the parts of a namespaced name, so every one of those
+        // parts has to have something in it.
+        let last = value.chars().count() - 1;
+        let mut previous = None;
+        for (position, (offset, c)) in value.char_indices().enumerate() {
+            let misplaced_dot = c == '.' && (position == last || previous == Some('.'));
+            if misplaced_dot || !is_namespaced_name_char(c) {
+                return Err(invalid(c, position, offset));
             }
+            previous = Some(c);
         }
 
-        Ok(PackageName(value.to_ascii_lowercase()))
+        Ok(PackageName(canonicalize(value)))
     }
 }
 
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+// Only package names can be namespaced, extras can't.
+fn is_namespaced_name_char(c: char) -> bool {
+    is_name_char(c) || c == '.'
+}
+
+/// A package, along with the versions of it that are acceptable, which is
+/// serialized the same way that it's displayed, like `name[extra]>=1.0.0`.
 #[derive(Serialize, Deserialize, Clone, Eq, Debug, Hash, PartialEq)]
+#[serde(try_from = "PackageSpecifierRepr", into = "String")]
 pub struct PackageSpecifier {
     pub(crate) name: PackageName,
     pub(crate) version: VersionReq,
+    /// The optional dependency groups to install along with the package, which
+    /// are always sorted, and never repeated.
+    #[serde(default)]
+    pub(crate) extras: Vec<String>,
 }
 
 impl FromStr for PackageSpecifier {
     type Err = PackageSpecifierError;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
-        let (name_s, version_s) = match value.find(|c: char| !c.is_ascii_alphanumeric()) {
+        // A bare name means that any version is acceptable, which is the only
+        // way to get a wildcard requirement from parsing.
+        let (name_s, rest) = match value.find(|c: char| !is_namespaced_name_char(c)) {
             Some(idx) => value.split_at(idx),
-            None => (value, "*"),
+            None => return Ok(PackageSpecifier::any(value.parse()?)),
         };
 
         let name: PackageName = name_s.parse()?;
-        let version: VersionReq = version_s.parse()?;
+        let (extras, version_s) = match rest.strip_prefix('[') {
+            Some(rest) => {
+                let invalid = || PackageSpecifierError::InvalidExtras {
+                    extras: rest.to_string(),
+                };
+                let end = rest.find(']').ok_or_else(invalid)?;
+                let mut extras: Vec<String> = rest[..end]
+                    .split(',')
+                    .map(|e| e.trim().to_string())
+                    .collect();
+                if extras
+                    .iter()
+                    .any(|e| e.is_empty() || !e.chars().all(is_name_char))
+                {
+                    return Err(invalid());
+                }
+                extras.sort();
+                extras.dedup();
+
+                (extras, &rest[end + 1..])
+            }
+            None => (Vec::new(), rest),
+        };
+
+        // Extras can be given without a version, which is the same as a bare name.
+        if version_s.is_empty() && !extras.is_empty() {
+            return Ok(PackageSpecifier {
+                extras,
+                ..PackageSpecifier::any(name)
+            });
+        }
+
+        let version: VersionReq = version_s.parse().map_err(|source| {
+            PackageSpecifierError::InvalidVersionRequirement {
+                requirement: version_s.to_string(),
+                source,
+            }
+        })?;
+
+        if version.comparators.is_empty() {
+            return Err(PackageSpecifierError::EmptyVersionRequirement {
+                requirement: version_s.to_string(),
+            });
+        }
+
+        Ok(PackageSpecifier {
+            name,
+            version,
+            extras,
+        })
+    }
+}
+
+// Specifiers used to be serialized field by field, and both ways are still
+// read, so that files written before then keep working.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PackageSpecifierRepr {
+    Specifier(String),
+    Fields {
+        name: PackageName,
+        version: VersionReq,
+        #[serde(default)]
+        extras: Vec<String>,
+    },
+}
+
+impl TryFrom<PackageSpecifierRepr> for PackageSpecifier {
+    type Error = PackageSpecifierError;
+
+    fn try_from(repr: PackageSpecifierRepr) -> Result<PackageSpecifier, Self::Error> {
+        match repr {
+            PackageSpecifierRepr::Specifier(s) => s.parse(),
+            PackageSpecifierRepr::Fields {
+                name,
+                version,
+                mut extras,
+            } => {
+                extras.sort();
+                extras.dedup();
+                Ok(PackageSpecifier {
+                    name,
+                    version,
+                    extras,
+                })
+            }
+        }
+    }
+}
+
+impl From<PackageSpecifier> for String {
+    fn from(specifier: PackageSpecifier) -> String {
+        specifier.to_string()
+    }
+}
+
+// This is the same format that FromStr parses, so that parsing what we display
+// always gives back the same specifier.
+impl fmt::Display for PackageSpecifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.extras.is_empty() {
+            write!(f, "[{}]", self.extras.join(","))?;
+        }
+        if let Some(first) = self.version.comparators.first() {
+            // A wildcard like 1.* doesn't start with an operator, so without
+            // something in between, its version would get read as part of
+            // the name.
+            if first.op == Op::Wildcard && self.extras.is_empty() {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", self.to_req_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PackageSpecifier {
+    pub fn any(name: PackageName) -> PackageSpecifier {
+        PackageSpecifier {
+            name,
+            version: VersionReq::STAR,
+            extras: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &PackageName {
+        &self.name
+    }
+
+    pub fn name_str(&self) -> &str {
+        self.name.canonical()
+    }
+
+    pub fn version(&self) -> &VersionReq {
+        &self.version
+    }
+
+    /// Just the version requirement, the same way that it's written in the
+    /// full specifier, or `*` if any version is acceptable.
+    pub fn to_req_string(&self) -> String {
+        if self.version.comparators.is_empty() {
+            return "*".to_string();
+        }
+
+        self.version
+            .comparators
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
 
-        Ok(PackageSpecifier { name, version })
+    /// This same specifier, but for only the package itself.
+    pub fn without_extras(&self) -> PackageSpecifier {
+        PackageSpecifier {
+            extras: Vec::new(),
+            ..self.clone()
+        }
     }
 }
 
 pub(crate) type Packages = BTreeMap<PackageName, Package>;
 
+/// A full set of packages that satisfies everything that was requested, along
+/// with how it differs from what is currently installed.
+#[derive(Default)]
+pub struct SolverSolution {
+    packages: Packages,
+    diff: SolutionDiff,
+}
+
+impl SolverSolution {
+    pub(crate) fn new(
+        packages: Packages,
+        installed: &HashMap<PackageName, Version>,
+    ) -> SolverSolution {
+        let mut solution = SolverSolution {
+            packages,
+            ..Default::default()
+        };
+        solution.diff = SolutionDiff::new(installed, &solution);
+
+        solution
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&PackageName, &Package)> {
+        self.packages.iter()
+    }
+
+    pub(crate) fn get(&self, name: &PackageName) -> Option<&Package> {
+        self.packages.get(name)
+    }
+
+    pub(crate) fn contains(&self, name: &PackageName) -> bool {
+        self.packages.contains_key(name)
+    }
+
+    /// The version of every package in this solution, sorted by name.
+    pub fn versions(&self) -> impl Iterator<Item = (&PackageName, &Version)> {
+        self.packages.iter().map(|(name, p)| (name, p.version()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+    }
+
+    pub fn package_count(&self) -> usize {
+        self.packages.len()
+    }
+
+    /// How this solution differs from what was installed when it was found.
+    pub fn diff(&self) -> &SolutionDiff {
+        &self.diff
+    }
+}
+
+/// The metadata for a package, which lives in the manifest.json at the root
+/// of its .mqp archive (a tar.gz).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PackageManifest {
+    pub name: PackageName,
+    pub version: Version,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub homepage: Option<Url>,
+    #[serde(default)]
+    pub license: Option<String>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub dependencies: HashMap<PackageName, VersionReq>,
+}
+
+impl PackageManifest {
+    pub fn from_archive(path: &Path) -> Result<PackageManifest, ManifestError> {
+        PackageManifest::from_reader(File::open(path)?)
+    }
+
+    pub(crate) fn from_reader<R: Read>(reader: R) -> Result<PackageManifest, ManifestError> {
+        let mut archive = TarArchive::new(GzDecoder::new(reader));
+        for entry in archive.entries()? {
+            let entry = entry?;
+
+            // Archives that were created from within the package directory
+            // have ./ in front of every path.
+            let path = entry.path()?;
+            if path.strip_prefix("./").unwrap_or(&path) == Path::new(MANIFEST_FILENAME) {
+                return Ok(serde_json::from_reader(entry)?);
+            }
+        }
+
+        Err(ManifestError::Missing)
+    }
+}
+
+/// Everything that is installed, as a tree that starts from the packages that
+/// were requested.
+#[derive(Serialize, Debug, Clone)]
+pub struct DependencyTree {
+    pub root: Vec<DependencyNode>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DependencyNode {
+    pub name: PackageName,
+    pub version: Version,
+    pub deps: Vec<DependencyNode>,
+    /// This package is already one of its own ancestors, so its dependencies
+    /// aren't expanded again.
+    pub cycle: bool,
+}
+
+impl DependencyTree {
+    pub fn render(&self) -> String {
+        render_tree(self.root.iter().collect(), None, false)
+    }
+
+    /// Render the tree like `render`, but only `depth` levels below each of the
+    /// requested packages, and with the dependencies of any package that has
+    /// already been shown left out, rather than repeated.
+    pub fn render_collapsed(&self, depth: Option<u32>) -> String {
+        render_tree(self.root.iter().collect(), depth, true)
+    }
+
+    /// The tree as a Graphviz directed graph, with each package only appearing
+    /// once no matter how many times it is depended on.
+    pub fn to_dot(&self) -> String {
+        let graph = self.graph();
+
+        let mut out = String::from("digraph dependencies {\n");
+        for (id, kind) in graph.nodes.iter() {
+            out.push_str(&format!("    \"{id}\" [color={}];\n", kind.color()));
+        }
+        for ((from, to), cycle) in graph.edges.iter() {
+            if *cycle {
+                out.push_str(&format!(
+                    "    \"{from}\" -> \"{to}\" [label=\"[cycle]\"];\n"
+                ));
+            } else {
+                out.push_str(&format!("    \"{from}\" -> \"{to}\";\n"));
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// The tree as a Mermaid flowchart, which GitHub renders within markdown.
+    pub fn to_mermaid(&self) -> String {
+        let graph = self.graph();
+
+        // Mermaid ids can't contain most of what is in a version, so each node
+        // gets an id of its own, and the package is only used as its label.
+        let ids: BTreeMap<&String, String> = graph
+            .nodes
+            .keys()
+            .enumerate()
+            .map(|(idx, id)| (id, format!("n{idx}")))
+            .collect();
+
+        let mut out = String::from("graph TD\n");
+        for (id, kind) in graph.nodes.iter() {
+            out.push_str(&format!("    {}[\"{id}\"]:::{}\n", ids[id], kind.class()));
+        }
+        for ((from, to), cycle) in graph.edges.iter() {
+            let arrow = if *cycle { "-->|\"[cycle]\"|" } else { "-->" };
+            out.push_str(&format!("    {} {arrow} {}\n", ids[from], ids[to]));
+        }
+        for kind in [NodeKind::Requested, NodeKind::Transitive, NodeKind::Shared] {
+            out.push_str(&format!(
+                "    classDef {} stroke:{}\n",
+                kind.class(),
+                kind.color()
+            ));
+        }
+        out
+    }
+
+    fn graph(&self) -> DependencyGraph {
+        let mut graph = DependencyGraph::default();
+        let mut walked = BTreeSet::new();
+        for node in self.root.iter() {
+            graph.nodes.insert(node.id(), NodeKind::Requested);
+            node.walk(&mut graph, &mut walked);
+        }
+
+        // Anything that wasn't requested is a transitive dependency, which we
+        // call out when more than one package requires it.
+        let mut requirers = BTreeMap::<&String, usize>::new();
+        for (_, to) in graph.edges.keys() {
+            *requirers.entry(to).or_default() += 1;
+        }
+        for (id, count) in requirers.into_iter() {
+            let kind = graph
+                .nodes
+                .entry(id.clone())
+                .or_insert(NodeKind::Transitive);
+            if *kind != NodeKind::Requested && count > 1 {
+                *kind = NodeKind::Shared;
+            }
+        }
+
+        graph
+    }
+}
+
+#[derive(Debug, Default)]
+struct DependencyGraph {
+    nodes: BTreeMap<String, NodeKind>,
+    // (from, to) -> whether this edge closes a cycle
+    edges: BTreeMap<(String, String), bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NodeKind {
+    Requested,
+    Transitive,
+    Shared,
+}
+
+impl NodeKind {
+    fn color(&self) -> &'static str {
+        match self {
+            NodeKind::Requested => "blue",
+            NodeKind::Transitive => "gray",
+            NodeKind::Shared => "orange",
+        }
+    }
+
+    fn class(&self) -> &'static str {
+        match self {
+            NodeKind::Requested => "requested",
+            NodeKind::Transitive => "transitive",
+            NodeKind::Shared => "shared",
+        }
+    }
+}
+
+impl DependencyNode {
+    fn id(&self) -> String {
+        format!("{}@{}", self.name, self.version)
+    }
+
+    // A package that is depended on more than once appears in the tree each
+    // time, with the same deps, so we only walk the first one of them.
+    fn walk(&self, graph: &mut DependencyGraph, walked: &mut BTreeSet<String>) {
+        if !walked.insert(self.id()) {
+            return;
+        }
+        for dep in self.deps.iter() {
+            let cycle = graph.edges.entry((self.id(), dep.id())).or_default();
+            *cycle |= dep.cycle;
+            dep.walk(graph, walked);
+        }
+    }
+}
+
+impl<'n> TreeNode for &'n DependencyNode {
+    fn id(&self) -> String {
+        DependencyNode::id(self)
+    }
+
+    fn label(&self) -> String {
+        if self.cycle {
+            format!("{} {} [cycle]", self.name, self.version)
+        } else {
+            format!("{} {}", self.name, self.version)
+        }
+    }
+
+    fn children(&self) -> Vec<Self> {
+        self.deps.iter().collect()
+    }
+}
+
+/// Something that can be rendered as a tree, like `pnpm ls`.
+pub(crate) trait TreeNode: Sized {
+    /// What identifies this node, so that it can be collapsed when it has
+    /// already been shown.
+    fn id(&self) -> String;
+
+    fn label(&self) -> String;
+
+    fn children(&self) -> Vec<Self>;
+}
+
+/// Render each of `roots` as a tree, only going `depth` levels below each of
+/// them. When `collapse` is set, anything that has already been shown has its
+/// children left out, rather than repeated.
+pub(crate) fn render_tree<N: TreeNode>(
+    roots: Vec<N>,
+    depth: Option<u32>,
+    collapse: bool,
+) -> String {
+    let mut shown = collapse.then(BTreeSet::new);
+    let mut out = String::new();
+    for node in roots.iter() {
+        out.push_str(&node.label());
+        out.push('\n');
+        if let Some(shown) = shown.as_mut() {
+            shown.insert(node.id());
+        }
+        render_children(node, "", depth, &mut shown, &mut out);
+    }
+    out
+}
+
+fn render_children<N: TreeNode>(
+    node: &N,
+    prefix: &str,
+    depth: Option<u32>,
+    shown: &mut Option<BTreeSet<String>>,
+    out: &mut String,
+) {
+    if depth == Some(0) {
+        return;
+    }
+
+    let children = node.children();
+    for (idx, child) in children.iter().enumerate() {
+        let (branch, indent) = if idx == children.len() - 1 {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+
+        let repeated = match shown.as_mut() {
+            Some(shown) => !shown.insert(child.id()) && !child.children().is_empty(),
+            None => false,
+        };
+        if repeated {
+            out.push_str(&format!(
+                "{prefix}{branch}{} [shown above]\n",
+                child.label()
+            ));
+            continue;
+        }
+
+        out.push_str(&format!("{prefix}{branch}{}\n", child.label()));
+        render_children(
+            child,
+            &format!("{prefix}{indent}"),
+            depth.map(|d| d - 1),
+            shown,
+            out,
+        );
+    }
+}
+
+pub(crate) fn version_req(op: Op, version: &Version) -> VersionReq {
+    // We build this directly, rather than parsing a formatted string, since
+    // a version may have build metadata which isn't valid in a requirement.
+    VersionReq {
+        comparators: vec![Comparator {
+            op,
+            major: version.major,
+            minor: Some(version.minor),
+            patch: Some(version.patch),
+            pre: version.pre.clone(),
+        }],
+    }
+}
+
 pub(crate) trait Source: fmt::Debug + fmt::Display + DynClone + Sync + Send {
     fn id(&self) -> u64;
 
@@ -115,6 +727,14 @@ impl Package {
             source,
         }
     }
+
+    pub(crate) fn name(&self) -> &PackageName {
+        &self.name
+    }
+
+    pub(crate) fn version(&self) -> &Version {
+        &self.version
+    }
 }
 
 impl WithSource for Package {
@@ -134,3 +754,143 @@ impl fmt::Display for Package {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    // A name that is always valid, which may or may not be namespaced.
+    const NAME: &str = "[a-zA-Z][a-zA-Z0-9_-]{0,8}(\\.[a-zA-Z0-9_-]{1,8}){0,3}";
+
+    proptest! {
+        #[test]
+        fn name_round_trips(s in NAME) {
+            let name: PackageName = s.parse().unwrap();
+            prop_assert_eq!(name.to_string(), canonicalize(&s));
+            prop_assert_eq!(name.to_string().parse::<PackageName>().unwrap(), name);
+        }
+
+        #[test]
+        fn any_valid_name_round_trips(s in "\\PC{0,16}") {
+            if let Ok(name) = s.parse::<PackageName>() {
+                prop_assert_eq!(name.to_string().parse::<PackageName>().unwrap(), name);
+            }
+        }
+
+        #[test]
+        fn namespace_and_base_make_up_name(s in NAME) {
+            let name: PackageName = s.parse().unwrap();
+            let joined = match name.namespace() {
+                Some(namespace) => format!("{namespace}.{}", name.base()),
+                None => name.base().to_string(),
+            };
+            prop_assert_eq!(joined, name.canonical());
+        }
+
+        #[test]
+        fn misplaced_dots_are_invalid(s in NAME, dots in "\\.|\\.\\.") {
+            for invalid in [format!("{dots}{s}"), format!("{s}{dots}")] {
+                let is_invalid_character = matches!(
+                    invalid.parse::<PackageName>(),
+                    Err(PackageNameError::InvalidCharacter { .. })
+                );
+                prop_assert!(is_invalid_character, "{:?}", invalid);
+            }
+            let invalid = format!("{s}..{s}");
+            let is_invalid_character = matches!(
+                invalid.parse::<PackageName>(),
+                Err(PackageNameError::InvalidCharacter { .. })
+            );
+            prop_assert!(is_invalid_character, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn dots_after_separators() {
+        for s in ["a.b", "a-.b", "a_.b", "a.-b", "a._b", "ab-.c_.d"] {
+            let name: PackageName = s.parse().unwrap();
+            assert_eq!(name.to_string(), canonicalize(s));
+        }
+
+        let name: PackageName = "a-.b".parse().unwrap();
+        assert_eq!(name.namespace(), Some("a_"));
+        assert_eq!(name.base(), "b");
+    }
+
+    #[test]
+    fn specifier_serializes_as_string() {
+        for s in [
+            "foo",
+            "foo>=1.0.0, <2.0.0",
+            "foo[a,b]^1.2.3",
+            "foo 1.*",
+            "foo[a]",
+        ] {
+            let specifier: PackageSpecifier = s.parse().unwrap();
+            let json = serde_json::to_string(&specifier).unwrap();
+            assert_eq!(json, format!("\"{specifier}\""));
+            let back: PackageSpecifier = serde_json::from_str(&json).unwrap();
+            assert_eq!(back, specifier);
+
+            let yaml = serde_yaml::to_string(&specifier).unwrap();
+            let back: PackageSpecifier = serde_yaml::from_str(&yaml).unwrap();
+            assert_eq!(back, specifier);
+        }
+    }
+
+    #[test]
+    fn specifier_deserializes_from_fields() {
+        let specifier: PackageSpecifier =
+            serde_yaml::from_str("name: Foo\nversion: \">=1.0.0\"\nextras: [b, a, b]\n").unwrap();
+        assert_eq!(specifier, "foo[a,b]>=1.0.0".parse().unwrap());
+    }
+
+    #[test]
+    fn specifier_rejects_invalid_string() {
+        assert!(serde_json::from_str::<PackageSpecifier>("\"foo>=\"").is_err());
+    }
+
+    fn node(name: &str, deps: Vec<DependencyNode>) -> DependencyNode {
+        DependencyNode {
+            name: name.parse().unwrap(),
+            version: Version::new(1, 0, 0),
+            deps,
+            cycle: false,
+        }
+    }
+
+    #[test]
+    fn dot_emits_each_edge_once() {
+        // app requires left and right, which both require base, which
+        // requires app again.
+        let base = || {
+            let mut cycle = node("app", vec![]);
+            cycle.cycle = true;
+            node("base", vec![cycle])
+        };
+        let tree = DependencyTree {
+            root: vec![node(
+                "app",
+                vec![node("left", vec![base()]), node("right", vec![base()])],
+            )],
+        };
+
+        let dot = tree.to_dot();
+        let edges: Vec<&str> = dot.lines().filter(|l| l.contains("->")).collect();
+        assert_eq!(
+            edges,
+            [
+                "    \"app@1.0.0\" -> \"left@1.0.0\";",
+                "    \"app@1.0.0\" -> \"right@1.0.0\";",
+                "    \"base@1.0.0\" -> \"app@1.0.0\" [label=\"[cycle]\"];",
+                "    \"left@1.0.0\" -> \"base@1.0.0\";",
+                "    \"right@1.0.0\" -> \"base@1.0.0\";",
+            ]
+        );
+        assert!(dot.contains("    \"app@1.0.0\" [color=blue];"));
+        assert!(dot.contains("    \"base@1.0.0\" [color=orange];"));
+        assert!(dot.contains("    \"left@1.0.0\" [color=gray];"));
+    }
+}